@@ -65,6 +65,26 @@ impl Vec3 {
     pub fn normalized(self) -> Vec3 {
         self * self.length().recip()
     }
+
+    // Component-wise minimum.
+    pub fn min(&self, rhs: &Vec3) -> Vec3 {
+        Vec3([self.x().min(rhs.x()), self.y().min(rhs.y()), self.z().min(rhs.z())])
+    }
+
+    // Component-wise maximum.
+    pub fn max(&self, rhs: &Vec3) -> Vec3 {
+        Vec3([self.x().max(rhs.x()), self.y().max(rhs.y()), self.z().max(rhs.z())])
+    }
+
+    // Some unit vector perpendicular to `self`, which must be non-zero.
+    pub fn any_perpendicular(&self) -> Vec3 {
+        let axis = if self.x().abs() < 0.9 * self.length() {
+            Vec3::new(1., 0., 0.)
+        } else {
+            Vec3::new(0., 1., 0.)
+        };
+        self.cross(&axis).normalized()
+    }
 }
 
 // Macro to automatically declare operator overloads for all value and borrow type
@@ -176,3 +196,116 @@ impl ops::Neg for Vec3 {
         Vec3([-self.x(), -self.y(), -self.z()])
     }
 }
+
+impl ops::Index<usize> for Vec3 {
+    type Output = f32;
+    fn index(&self, axis: usize) -> &f32 {
+        &self.0[axis]
+    }
+}
+
+// Affine transform, stored as the top three rows of a 4x4 matrix so that it can be uploaded
+// as three vec4s.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Transform {
+    pub rows: [[f32; 4]; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Self::linear([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]])
+    }
+
+    pub fn translation(offset: Vec3) -> Transform {
+        let mut transform = Self::identity();
+        for i in 0..3 {
+            transform.rows[i][3] = offset[i];
+        }
+        transform
+    }
+
+    pub fn scale(factors: Vec3) -> Transform {
+        Self::linear([[factors.x(), 0., 0.], [0., factors.y(), 0.], [0., 0., factors.z()]])
+    }
+
+    // Counter-clockwise rotation by `degrees` about `axis` (Rodrigues' formula).
+    pub fn rotation(axis: Vec3, degrees: f32) -> Transform {
+        let a = axis.normalized();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let t = 1. - cos;
+        let (x, y, z) = (a.x(), a.y(), a.z());
+        Self::linear([
+            [t * x * x + cos, t * x * y - sin * z, t * x * z + sin * y],
+            [t * x * y + sin * z, t * y * y + cos, t * y * z - sin * x],
+            [t * x * z - sin * y, t * y * z + sin * x, t * z * z + cos],
+        ])
+    }
+
+    // Same as the book's `rotate_y`.
+    pub fn rotation_y(degrees: f32) -> Transform {
+        Self::rotation(Vec3::new(0., 1., 0.), degrees)
+    }
+
+    fn linear(m: [[f32; 3]; 3]) -> Transform {
+        Transform {
+            rows: m.map(|[a, b, c]| [a, b, c, 0.]),
+        }
+    }
+
+    // The transform that applies `self` first and `next` second.
+    pub fn then(&self, next: &Transform) -> Transform {
+        let (a, b) = (&next.rows, &self.rows);
+        let mut rows = [[0.; 4]; 3];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| a[i][k] * b[k][j]).sum::<f32>();
+            }
+            row[3] += a[i][3];
+        }
+        Transform { rows }
+    }
+
+    // Inverse of the transform, which must not be singular.
+    pub fn inverse(&self) -> Transform {
+        let m = &self.rows;
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+        // Transposed cofactor matrix of the linear part, divided by the determinant.
+        let adjugate = [
+            [cofactor(1, 2, 1, 2), -cofactor(0, 2, 1, 2), cofactor(0, 1, 1, 2)],
+            [-cofactor(1, 2, 0, 2), cofactor(0, 2, 0, 2), -cofactor(0, 1, 0, 2)],
+            [cofactor(1, 2, 0, 1), -cofactor(0, 2, 0, 1), cofactor(0, 1, 0, 1)],
+        ];
+        let inv_det = 1. / self.determinant();
+        let mut inverse = Self::linear(adjugate.map(|row| row.map(|v| v * inv_det)));
+        let translation = -inverse.transform_vector(&Vec3::new(m[0][3], m[1][3], m[2][3]));
+        for i in 0..3 {
+            inverse.rows[i][3] = translation[i];
+        }
+        inverse
+    }
+
+    // Determinant of the linear part, negative if the transform mirrors.
+    pub fn determinant(&self) -> f32 {
+        let r = self.rows.map(|[a, b, c, _]| Vec3::new(a, b, c));
+        r[0].dot(&r[1].cross(&r[2]))
+    }
+
+    pub fn transform_point(&self, p: &Vec3) -> Vec3 {
+        let row = |[a, b, c, d]: [f32; 4]| a * p.x() + b * p.y() + c * p.z() + d;
+        Vec3::new(row(self.rows[0]), row(self.rows[1]), row(self.rows[2]))
+    }
+
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        let row = |[a, b, c, _]: [f32; 4]| a * v.x() + b * v.y() + c * v.z();
+        Vec3::new(row(self.rows[0]), row(self.rows[1]), row(self.rows[2]))
+    }
+}
@@ -0,0 +1,110 @@
+// benchmark.rs
+// `--benchmark`: renders the scene offscreen from its view, or from `--bookmark`, with the
+// binary and then the quantized 4-wide mesh BLAS (wide_bvh.rs), and reports the time per frame
+// and the node memory of both layouts. Both runs start from the same sample index, so they trace the same
+// rays, and their images are compared as a check that the layouts find the same hits.
+//
+// Frames are submitted back to back and timed until the GPU is idle, so the result includes
+// the uniform uploads but no presentation.
+use crate::{
+    bvh::{Bvh, BvhNode},
+    camera::{Camera, CameraPose},
+    render::{self, MAX_STORAGE_BUFFERS, PathTracer},
+    scene::Scene,
+    wide_bvh::WideNode,
+};
+use {
+    anyhow::{Context, Result},
+    std::time::{Duration, Instant},
+};
+
+const WARMUP_FRAMES: u32 = 4;
+const FRAMES: u32 = 32;
+
+struct Run {
+    frame_time: Duration,
+    radiance: Vec<[f32; 4]>,
+}
+
+pub async fn run(scene: &Scene, scene_name: &str, pose: Option<CameraPose>, width: u32, height: u32) -> Result<()> {
+    let (device, queue) = render::connect_headless(MAX_STORAGE_BUFFERS).await.with_context(|| {
+        format!("no GPU adapter with the {MAX_STORAGE_BUFFERS} storage buffers per shader stage the renderer needs")
+    })?;
+    if scene.triangles.is_empty() {
+        println!("warning: {scene_name} has no meshes, so both layouts render the same work");
+    }
+    let mut camera = Camera::look_at(scene.view.origin, scene.view.center, scene.view.up);
+    camera.set_shutter(scene.view.shutter.0, scene.view.shutter.1);
+    if let Some(pose) = pose {
+        camera.set_pose(pose);
+    }
+    let format = wgpu::TextureFormat::Rgba8Unorm;
+    let mut renderer = PathTracer::new(&device, &queue, format, width, height, scene, &mut Bvh::build)?;
+    let target = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("benchmark target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+
+    println!(
+        "{scene_name}: {} triangles, {width}x{height}, {FRAMES} frames per layout",
+        scene.triangles.len()
+    );
+    let mut run = |wide: bool| {
+        renderer.set_wide_bvh(wide);
+        renderer.reset_samples();
+        for _ in 0..WARMUP_FRAMES {
+            renderer.render_frame(&camera, &device, &queue, &view);
+        }
+        device.poll(wgpu::Maintain::Wait);
+        // Restart so that the timed frames of both layouts use the same samples.
+        renderer.reset_samples();
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            renderer.render_frame(&camera, &device, &queue, &view);
+        }
+        device.poll(wgpu::Maintain::Wait);
+        Run {
+            frame_time: start.elapsed() / FRAMES,
+            radiance: renderer.read_radiance(&device, &queue),
+        }
+    };
+    let binary = run(false);
+    let wide = run(true);
+
+    let report = |name: &str, run: &Run, nodes: usize, node_size: usize| {
+        println!(
+            "  {name:<8} {:>9.2} ms/frame  {nodes:>9} BLAS nodes, {:.2} MiB",
+            run.frame_time.as_secs_f64() * 1e3,
+            (nodes * node_size) as f64 / (1024. * 1024.),
+        );
+    };
+    report("binary", &binary, scene.blas_nodes.len(), size_of::<BvhNode>());
+    report("wide", &wide, scene.wide_blas_nodes.len(), size_of::<WideNode>());
+    println!(
+        "  speedup  {:.2}x",
+        binary.frame_time.as_secs_f64() / wide.frame_time.as_secs_f64()
+    );
+
+    // Hits at the same distance can still differ in the last bits of `t`, which sends a few
+    // paths elsewhere, so only the average difference is meaningful.
+    let difference: f64 = binary
+        .radiance
+        .iter()
+        .zip(&wide.radiance)
+        .map(|(a, b)| (0..3).map(|c| (a[c] - b[c]).abs() as f64).sum::<f64>() / 3.)
+        .sum::<f64>()
+        / binary.radiance.len() as f64;
+    println!("  mean radiance difference between the layouts: {difference:.2e}");
+    Ok(())
+}
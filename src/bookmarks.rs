@@ -0,0 +1,307 @@
+// bookmarks.rs
+// Numbered camera bookmarks, persisted in a plain-text sidecar file next to the scene:
+//
+//   [1] front view
+//   center = 0 0.5 0
+//   up = 0 1 0
+//   distance = 1.5
+//   azimuth = 0
+//   altitude = 0.0333
+//
+// Unknown keys are ignored so the format can grow without breaking old files.
+//
+// A bookmark is the orbit pose and nothing else. The camera is a pinhole without aperture or
+// focus distance, so there are no lens settings to store; once it has some, they belong here.
+// `--bookmark` picks the starting view of the interactive viewer and the view `--benchmark`
+// renders from; the other modes ignore the camera and reject it.
+use crate::{algebra::Vec3, camera::CameraPose};
+use {
+    anyhow::{Context, Result, anyhow, bail},
+    std::{
+        fmt::Write as _,
+        fs,
+        path::{Path, PathBuf},
+    },
+};
+
+pub const SLOT_COUNT: usize = 9;
+
+#[derive(Debug, Clone)]
+pub struct Bookmark {
+    pub name: String,
+    pub pose: CameraPose,
+}
+
+pub struct Bookmarks {
+    path: PathBuf,
+    slots: [Option<Bookmark>; SLOT_COUNT],
+}
+
+impl Bookmarks {
+    // The sidecar file for `scene`, e.g. `scenes/room.txt` -> `scenes/room.bookmarks`.
+    pub fn sidecar_path(scene: &Path) -> PathBuf {
+        scene.with_extension("bookmarks")
+    }
+
+    // Loads the bookmarks stored at `path`. A missing file yields an empty set.
+    pub fn load(path: PathBuf) -> Result<Bookmarks> {
+        let mut bookmarks = Bookmarks {
+            path,
+            slots: Default::default(),
+        };
+        let text = match fs::read_to_string(&bookmarks.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(bookmarks),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to read {}", bookmarks.path.display()));
+            }
+        };
+        bookmarks
+            .parse(&text)
+            .with_context(|| format!("failed to parse {}", bookmarks.path.display()))?;
+        Ok(bookmarks)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut text = String::new();
+        for (index, bookmark) in self.slots.iter().enumerate() {
+            let Some(bookmark) = bookmark else { continue };
+            let pose = &bookmark.pose;
+            let _ = writeln!(text, "[{}] {}", index + 1, bookmark.name);
+            let _ = writeln!(text, "center = {}", format_vec3(&pose.center));
+            let _ = writeln!(text, "up = {}", format_vec3(&pose.up));
+            let _ = writeln!(text, "distance = {}", pose.distance);
+            let _ = writeln!(text, "azimuth = {}", pose.azimuth);
+            let _ = writeln!(text, "altitude = {}", pose.altitude);
+            text.push('\n');
+        }
+        fs::write(&self.path, text)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // `slot` is 1-based, matching the number keys.
+    pub fn get(&self, slot: usize) -> Option<&Bookmark> {
+        self.slots.get(slot.checked_sub(1)?)?.as_ref()
+    }
+
+    // Stores `pose` in `slot`, keeping the slot's existing name.
+    pub fn set(&mut self, slot: usize, pose: CameraPose) {
+        let Some(entry) = slot.checked_sub(1).and_then(|i| self.slots.get_mut(i)) else {
+            return;
+        };
+        let name = match entry.take() {
+            Some(bookmark) => bookmark.name,
+            None => format!("slot {slot}"),
+        };
+        *entry = Some(Bookmark { name, pose });
+    }
+
+    // Looks a bookmark up by name or by slot number.
+    pub fn find(&self, key: &str) -> Option<&Bookmark> {
+        if let Ok(slot) = key.parse::<usize>() {
+            return self.get(slot);
+        }
+        self.slots.iter().flatten().find(|b| b.name == key)
+    }
+
+    fn parse(&mut self, text: &str) -> Result<()> {
+        let mut current: Option<PartialBookmark> = None;
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let context = || format!("line {}", line_number + 1);
+            if let Some(header) = line.strip_prefix('[') {
+                if let Some(partial) = current.take() {
+                    partial.finish(&mut self.slots)?;
+                }
+                let (slot, name) = header
+                    .split_once(']')
+                    .ok_or_else(|| anyhow!("unterminated slot header"))
+                    .with_context(context)?;
+                let slot: usize = slot.trim().parse().with_context(context)?;
+                if !(1..=SLOT_COUNT).contains(&slot) {
+                    bail!("{}: slot {slot} is out of range 1..={SLOT_COUNT}", context());
+                }
+                current = Some(PartialBookmark::new(slot, name.trim()));
+                continue;
+            }
+            let Some(partial) = current.as_mut() else {
+                bail!("{}: property outside of a bookmark", context());
+            };
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected `key = value`"))
+                .with_context(context)?;
+            let value = value.trim();
+            match key.trim() {
+                "center" => partial.center = Some(parse_vec3(value).with_context(context)?),
+                "up" => partial.up = Some(parse_vec3(value).with_context(context)?),
+                "distance" => partial.distance = Some(value.parse().with_context(context)?),
+                "azimuth" => partial.azimuth = Some(value.parse().with_context(context)?),
+                "altitude" => partial.altitude = Some(value.parse().with_context(context)?),
+                _ => {}
+            }
+        }
+        match current {
+            Some(partial) => partial.finish(&mut self.slots),
+            None => Ok(()),
+        }
+    }
+}
+
+// A bookmark whose properties are still being read from the file.
+struct PartialBookmark {
+    slot: usize,
+    name: String,
+    center: Option<Vec3>,
+    up: Option<Vec3>,
+    distance: Option<f32>,
+    azimuth: Option<f32>,
+    altitude: Option<f32>,
+}
+
+impl PartialBookmark {
+    fn new(slot: usize, name: &str) -> PartialBookmark {
+        let name = match name {
+            "" => format!("slot {slot}"),
+            name => name.to_string(),
+        };
+        PartialBookmark {
+            slot,
+            name,
+            center: None,
+            up: None,
+            distance: None,
+            azimuth: None,
+            altitude: None,
+        }
+    }
+
+    fn finish(self, slots: &mut [Option<Bookmark>; SLOT_COUNT]) -> Result<()> {
+        let slot = self.slot;
+        let missing = |key: &str| anyhow!("bookmark {slot} is missing `{key}`");
+        let pose = CameraPose {
+            center: self.center.ok_or_else(|| missing("center"))?,
+            up: self.up.ok_or_else(|| missing("up"))?,
+            distance: self.distance.ok_or_else(|| missing("distance"))?,
+            azimuth: self.azimuth.ok_or_else(|| missing("azimuth"))?,
+            altitude: self.altitude.ok_or_else(|| missing("altitude"))?,
+        };
+        slots[slot - 1] = Some(Bookmark {
+            name: self.name,
+            pose,
+        });
+        Ok(())
+    }
+}
+
+fn format_vec3(v: &Vec3) -> String {
+    format!("{} {} {}", v.x(), v.y(), v.z())
+}
+
+fn parse_vec3(text: &str) -> Result<Vec3> {
+    let components = text
+        .split_whitespace()
+        .map(str::parse::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    match components[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => bail!("expected 3 numbers, found {}", components.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(text: &str) -> Result<Bookmarks> {
+        let mut bookmarks = Bookmarks {
+            path: PathBuf::from("test.bookmarks"),
+            slots: Default::default(),
+        };
+        bookmarks.parse(text)?;
+        Ok(bookmarks)
+    }
+
+    fn pose(seed: f32) -> CameraPose {
+        CameraPose {
+            center: Vec3::new(seed, 0.5 * seed, -seed),
+            up: Vec3::new(0., 1., 0.),
+            distance: 1.5 + seed,
+            azimuth: 0.1 * seed,
+            altitude: -0.03 * seed,
+        }
+    }
+
+    #[test]
+    fn saved_bookmarks_load_back() {
+        let path = std::env::temp_dir().join(format!("bookmarks_{}.bookmarks", std::process::id()));
+        let mut saved = Bookmarks::load(path.clone()).unwrap();
+        saved.set(2, pose(1.));
+        saved.set(SLOT_COUNT, pose(-2.5));
+        saved.save().unwrap();
+        let loaded = Bookmarks::load(path.clone());
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        for slot in 1..=SLOT_COUNT {
+            match (saved.get(slot), loaded.get(slot)) {
+                (None, None) => {}
+                (Some(saved), Some(loaded)) => {
+                    assert_eq!(saved.name, loaded.name);
+                    let (a, b) = (&saved.pose, &loaded.pose);
+                    assert_eq!(format_vec3(&a.center), format_vec3(&b.center));
+                    assert_eq!(format_vec3(&a.up), format_vec3(&b.up));
+                    assert_eq!((a.distance, a.azimuth, a.altitude), (b.distance, b.azimuth, b.altitude));
+                }
+                (saved, loaded) => panic!("slot {slot}: saved {saved:?}, loaded {loaded:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn bookmarks_are_found_by_name_and_slot() {
+        let bookmarks = parsed(
+            "[1] front view\ncenter = 0 0.5 0\nup = 0 1 0\ndistance = 1.5\nazimuth = 0\naltitude = 0\n\n\
+             [3]\ncenter = 1 2 3\nup = 0 1 0\ndistance = 2\nazimuth = 1\naltitude = 0.5\nlens = 35\n",
+        )
+        .unwrap();
+        assert_eq!(bookmarks.find("front view").unwrap().pose.distance, 1.5);
+        assert_eq!(bookmarks.find("1").unwrap().name, "front view");
+        assert_eq!(bookmarks.find("3").unwrap().name, "slot 3");
+        assert_eq!(bookmarks.find("slot 3").unwrap().pose.azimuth, 1.);
+        assert!(bookmarks.find("2").is_none());
+        assert!(bookmarks.find("0").is_none());
+        assert!(bookmarks.find(&(SLOT_COUNT + 1).to_string()).is_none());
+        assert!(bookmarks.find("back view").is_none());
+        assert!(bookmarks.get(0).is_none());
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let full = "center = 0 0 0\nup = 0 1 0\ndistance = 1\nazimuth = 0\naltitude = 0\n";
+        let cases = [
+            (format!("[1 front\n{full}"), "line 1: unterminated slot header"),
+            (format!("[one] front\n{full}"), "line 1: invalid digit"),
+            (format!("[0] front\n{full}"), "line 1: slot 0 is out of range 1..=9"),
+            (format!("[10] front\n{full}"), "line 1: slot 10 is out of range 1..=9"),
+            (format!("distance = 1\n[1]\n{full}"), "line 1: property outside of a bookmark"),
+            ("[1]\ncenter 0 0 0\n".to_string(), "line 2: expected `key = value`"),
+            ("[1]\ncenter = 0 0\n".to_string(), "line 2: expected 3 numbers, found 2"),
+            ("[1]\ndistance = far\n".to_string(), "line 2: invalid float literal"),
+            (format!("[1]\n{}", full.replace("up = 0 1 0\n", "")), "bookmark 1 is missing `up`"),
+            (format!("[1]\n{}[2]\n{full}", full.replace("altitude = 0\n", "")), "bookmark 1 is missing `altitude`"),
+        ];
+        for (text, expected) in cases {
+            let error = parsed(&text).err().unwrap_or_else(|| panic!("accepted {text:?}"));
+            let message = format!("{error:#}");
+            assert!(message.contains(expected), "{text:?}: {message}");
+        }
+    }
+}
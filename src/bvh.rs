@@ -0,0 +1,404 @@
+// bvh.rs
+// Bounding volume hierarchies over arbitrary primitives, built on the CPU with binned SAH
+// (Wald, "On fast Construction of SAH-based Bounding Volume Hierarchies", 2007).
+//
+// The same node layout serves the per-mesh BLAS over triangles, the BVHs over spheres and
+// analytic shapes, and the TLAS over instances. Nodes are stored depth-first with the two
+// children of an interior node next to each other, so an interior node only stores the index
+// of its left child.
+use crate::algebra::{Transform, Vec3};
+use bytemuck::{Pod, Zeroable};
+
+// Must not exceed BVH_STACK_SIZE in shaders.wgsl: the traversal stack never holds more
+// entries than the depth of the tree.
+pub const MAX_DEPTH: u32 = 32;
+const BIN_COUNT: usize = 12;
+// Cost of visiting a node relative to intersecting one primitive.
+const TRAVERSAL_COST: f32 = 1.;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    // The box containing nothing, the identity of `union`.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::all(f32::MAX),
+            max: Vec3::all(-f32::MAX),
+        }
+    }
+
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Vec3>) -> Aabb {
+        points.into_iter().fold(Self::empty(), |aabb, p| aabb.union(&Aabb { min: *p, max: *p }))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    pub fn intersection(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.max(&other.min),
+            max: self.max.min(&other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| self.min[axis] > self.max[axis])
+    }
+
+    pub fn contains(&self, other: &Aabb) -> bool {
+        other.is_empty() || (0..3).all(|axis| self.min[axis] <= other.min[axis] && other.max[axis] <= self.max[axis])
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    // Half the surface area, which is all SAH needs.
+    pub fn half_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let d = self.max - self.min;
+        d.x() * d.y() + d.y() * d.z() + d.z() * d.x()
+    }
+
+    // Bounds of the box after applying `transform`, from its eight corners.
+    pub fn transformed(&self, transform: &Transform) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                let pick = |axis: usize| {
+                    if i & (1 << axis) == 0 { self.min[axis] } else { self.max[axis] }
+                };
+                transform.transform_point(&Vec3::new(pick(0), pick(1), pick(2)))
+            })
+            .collect();
+        Self::from_points(&corners)
+    }
+}
+
+// A node is a leaf if `count` is non-zero, in which case it covers the primitives
+// `left_first..left_first + count` of the reordered primitive list. Otherwise `left_first` is
+// the index of its left child and the right child follows it.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct BvhNode {
+    pub min: Vec3,
+    pub left_first: u32,
+    pub max: Vec3,
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    fn set_bounds(&mut self, bounds: &Aabb) {
+        self.min = bounds.min;
+        self.max = bounds.max;
+    }
+}
+
+pub struct Bvh {
+    // Never empty. Without primitives, the root has count 0 and an empty box, which the
+    // traversal checks for before anything else.
+    pub nodes: Vec<BvhNode>,
+    // The primitive indices in leaf order. Leaves index into this list, so the caller
+    // reorders its primitives accordingly.
+    pub order: Vec<u32>,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let centroids: Vec<Vec3> = bounds.iter().map(Aabb::centroid).collect();
+        let mut order: Vec<u32> = (0..bounds.len() as u32).collect();
+        let mut nodes = vec![BvhNode {
+            min: Vec3::zero(),
+            left_first: 0,
+            max: Vec3::zero(),
+            count: bounds.len() as u32,
+        }];
+        nodes[0].set_bounds(&Aabb::empty());
+
+        // (node, depth) pairs still to be split.
+        let mut pending = vec![(0usize, 0u32)];
+        while let Some((index, depth)) = pending.pop() {
+            let (first, count) = (nodes[index].left_first as usize, nodes[index].count as usize);
+            let range = &mut order[first..first + count];
+            let node_bounds = range
+                .iter()
+                .fold(Aabb::empty(), |aabb, &i| aabb.union(&bounds[i as usize]));
+            nodes[index].set_bounds(&node_bounds);
+            if count <= 1 || depth + 1 >= MAX_DEPTH {
+                continue;
+            }
+            let Some(split) = find_split(range, bounds, &centroids, &node_bounds) else {
+                continue;
+            };
+
+            // Partition the range so that primitives in bins below the split come first.
+            let mut left_count = 0;
+            for i in 0..count {
+                if split.bin_of(&centroids[range[i] as usize]) < split.bin {
+                    range.swap(i, left_count);
+                    left_count += 1;
+                }
+            }
+            if left_count == 0 || left_count == count {
+                continue;
+            }
+
+            let left = nodes.len();
+            let child = |first: usize, count: usize| BvhNode {
+                min: Vec3::zero(),
+                left_first: first as u32,
+                max: Vec3::zero(),
+                count: count as u32,
+            };
+            nodes.push(child(first, left_count));
+            nodes.push(child(first + left_count, count - left_count));
+            nodes[index].left_first = left as u32;
+            nodes[index].count = 0;
+            pending.push((left + 1, depth + 1));
+            pending.push((left, depth + 1));
+        }
+        Bvh { nodes, order }
+    }
+
+    // Recomputes the bounds of the leaves holding the primitives `moved` and of their
+    // ancestors, after the primitives changed their bounds to `bounds`. The topology stays the
+    // same, so the tree gets worse the further the primitives move. Returns the indices of the
+    // nodes whose bounds changed, in ascending order.
+    pub fn refit(&mut self, bounds: &[Aabb], moved: &[usize]) -> Vec<usize> {
+        if self.order.is_empty() {
+            return Vec::new();
+        }
+        // Parent of every node and leaf of every position in `order`. The root has no parent.
+        let mut parents = vec![usize::MAX; self.nodes.len()];
+        let mut leaves = vec![0; self.order.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            let first = node.left_first as usize;
+            if node.is_leaf() {
+                leaves[first..first + node.count as usize].fill(index);
+            } else {
+                parents[first] = index;
+                parents[first + 1] = index;
+            }
+        }
+        let mut positions = vec![0; self.order.len()];
+        for (position, &primitive) in self.order.iter().enumerate() {
+            positions[primitive as usize] = position;
+        }
+        let mut dirty = vec![false; self.nodes.len()];
+        for &primitive in moved {
+            let mut index = leaves[positions[primitive]];
+            while index != usize::MAX && !dirty[index] {
+                dirty[index] = true;
+                index = parents[index];
+            }
+        }
+
+        // Children before parents, visiting dirty nodes only.
+        let mut changed = Vec::new();
+        let mut pending = if dirty[0] { vec![(0, false)] } else { Vec::new() };
+        while let Some((index, expanded)) = pending.pop() {
+            let node = self.nodes[index];
+            let first = node.left_first as usize;
+            let fitted = if node.is_leaf() {
+                self.order[first..first + node.count as usize]
+                    .iter()
+                    .fold(Aabb::empty(), |aabb, &i| aabb.union(&bounds[i as usize]))
+            } else if !expanded {
+                pending.push((index, true));
+                pending.extend([first, first + 1].into_iter().filter(|&c| dirty[c]).map(|c| (c, false)));
+                continue;
+            } else {
+                self.nodes[first].bounds().union(&self.nodes[first + 1].bounds())
+            };
+            if bytemuck::bytes_of(&fitted.min) != bytemuck::bytes_of(&node.min)
+                || bytemuck::bytes_of(&fitted.max) != bytemuck::bytes_of(&node.max)
+            {
+                self.nodes[index].set_bounds(&fitted);
+                changed.push(index);
+            }
+        }
+        changed.sort_unstable();
+        changed
+    }
+}
+
+// SAH cost of the tree under `root`: the expected cost of tracing a ray that hits the root box,
+// counting TRAVERSAL_COST per node visited and 1 per primitive tested, with each node hit with
+// the probability of its surface area relative to the root's. The tree must not be empty.
+pub fn sah_cost(nodes: &[BvhNode], root: usize) -> f32 {
+    let root_area = nodes[root].bounds().half_area();
+    if root_area <= 0. {
+        return 0.;
+    }
+    let mut cost = 0.;
+    let mut pending = vec![root];
+    while let Some(index) = pending.pop() {
+        let node = &nodes[index];
+        let area = node.bounds().half_area() / root_area;
+        if node.is_leaf() {
+            cost += area * node.count as f32;
+        } else {
+            cost += area * TRAVERSAL_COST;
+            pending.extend([node.left_first as usize, node.left_first as usize + 1]);
+        }
+    }
+    cost
+}
+
+// A plane splitting the centroid bounds into BIN_COUNT equal bins along `axis`; primitives
+// in bins below `bin` go left.
+struct Split {
+    axis: usize,
+    bin: usize,
+    origin: f32,
+    scale: f32,
+}
+
+impl Split {
+    fn bin_of(&self, centroid: &Vec3) -> usize {
+        (((centroid[self.axis] - self.origin) * self.scale) as usize).min(BIN_COUNT - 1)
+    }
+}
+
+// The split with the lowest SAH cost, or None if keeping the node as a leaf is cheaper.
+fn find_split(range: &[u32], bounds: &[Aabb], centroids: &[Vec3], node_bounds: &Aabb) -> Option<Split> {
+    let centroid_bounds = Aabb::from_points(range.iter().map(|&i| &centroids[i as usize]));
+    let leaf_cost = range.len() as f32;
+    let mut best: Option<(f32, Split)> = None;
+    for axis in 0..3 {
+        let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+        if extent <= 0. {
+            continue;
+        }
+        let split = |bin| Split {
+            axis,
+            bin,
+            origin: centroid_bounds.min[axis],
+            scale: BIN_COUNT as f32 / extent,
+        };
+        let binning = split(0);
+        let mut bins = [(Aabb::empty(), 0u32); BIN_COUNT];
+        for &i in range {
+            let bin = &mut bins[binning.bin_of(&centroids[i as usize])];
+            bin.0 = bin.0.union(&bounds[i as usize]);
+            bin.1 += 1;
+        }
+
+        // Sweep from the right to get the area and count right of every plane, then from the
+        // left to evaluate the planes.
+        let mut right_cost = [0f32; BIN_COUNT];
+        let (mut aabb, mut count) = (Aabb::empty(), 0);
+        for b in (1..BIN_COUNT).rev() {
+            aabb = aabb.union(&bins[b].0);
+            count += bins[b].1;
+            right_cost[b] = aabb.half_area() * count as f32;
+        }
+        let (mut aabb, mut count) = (Aabb::empty(), 0);
+        for b in 1..BIN_COUNT {
+            aabb = aabb.union(&bins[b - 1].0);
+            count += bins[b - 1].1;
+            let cost = aabb.half_area() * count as f32 + right_cost[b];
+            if best.as_ref().is_none_or(|(best_cost, _)| cost < *best_cost) {
+                best = Some((cost, split(b)));
+            }
+        }
+    }
+    let (cost, split) = best?;
+    let area = node_bounds.half_area();
+    let split_cost = if area > 0. { TRAVERSAL_COST + cost / area } else { f32::MAX };
+    (split_cost < leaf_cost).then_some(split)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::lbvh::Rng};
+
+    fn random_boxes(rng: &mut Rng, count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let min = 10. * rng.vec3();
+                Aabb { min, max: min + 0.5 * rng.vec3() }
+            })
+            .collect()
+    }
+
+    // The box of `index` computed from the primitives under it, ignoring the stored boxes.
+    fn fit(bvh: &Bvh, bounds: &[Aabb], index: usize) -> Aabb {
+        let node = bvh.nodes[index];
+        let first = node.left_first as usize;
+        if node.is_leaf() {
+            let order = &bvh.order[first..first + node.count as usize];
+            order.iter().fold(Aabb::empty(), |aabb, &i| aabb.union(&bounds[i as usize]))
+        } else {
+            fit(bvh, bounds, first).union(&fit(bvh, bounds, first + 1))
+        }
+    }
+
+    fn node_bytes(nodes: &[BvhNode]) -> Vec<&[u8]> {
+        nodes.iter().map(bytemuck::bytes_of).collect()
+    }
+
+    #[test]
+    fn refit_matches_fitting_the_same_topology() {
+        let mut rng = Rng(0x2545f491);
+        let original = random_boxes(&mut rng, 500);
+        let mut bvh = Bvh::build(&original);
+        let mut bounds = original.clone();
+        let moved: Vec<usize> = (0..20).map(|_| (rng.next_f32() * 500.) as usize).collect();
+        for &i in &moved {
+            let offset = 3. * rng.vec3() - Vec3::all(1.5);
+            bounds[i] = Aabb { min: bounds[i].min + offset, max: bounds[i].max + offset };
+        }
+
+        let before = bvh.nodes.clone();
+        let changed = bvh.refit(&bounds, &moved);
+        for (index, node) in bvh.nodes.iter().enumerate() {
+            let fitted = fit(&bvh, &bounds, index);
+            assert_eq!(bytemuck::bytes_of(&node.min), bytemuck::bytes_of(&fitted.min), "node {index}");
+            assert_eq!(bytemuck::bytes_of(&node.max), bytemuck::bytes_of(&fitted.max), "node {index}");
+            assert_eq!(node.left_first, before[index].left_first);
+            assert_eq!(node.count, before[index].count);
+        }
+        let differing: Vec<usize> = (0..before.len())
+            .filter(|&i| bytemuck::bytes_of(&before[i]) != bytemuck::bytes_of(&bvh.nodes[i]))
+            .collect();
+        assert!(!changed.is_empty());
+        assert_eq!(changed, differing);
+
+        // Moving everything back restores the fresh build exactly.
+        bvh.refit(&original, &moved);
+        assert_eq!(node_bytes(&bvh.nodes), node_bytes(&Bvh::build(&original).nodes));
+    }
+
+    #[test]
+    fn refit_without_moves_changes_nothing() {
+        let bounds = random_boxes(&mut Rng(7), 100);
+        let mut bvh = Bvh::build(&bounds);
+        assert!(bvh.refit(&bounds, &[]).is_empty());
+        assert!(bvh.refit(&bounds, &[3, 50]).is_empty());
+        assert!(Bvh::build(&[]).refit(&[], &[]).is_empty());
+    }
+}
@@ -4,19 +4,106 @@ use {
     std::f32::consts::{FRAC_PI_2, PI},
 };
 
+const MAX_ALT: f32 = FRAC_PI_2 - 1e-6;
+
+// Rate (1/s) at which coasting velocity decays after the user lets go.
+const DEFAULT_DAMPING: f32 = 6.0;
+
+// Velocities below this are treated as stopped.
+const MIN_VELOCITY: f32 = 1e-3;
+
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
 pub struct CameraUniforms {
     origin: Vec3,
-    _pad0: u32,
+    // Every camera ray gets a time sampled uniformly between these two. Moving primitives
+    // are at their start position at time 0 and at their end position at time 1.
+    shutter_open: f32,
     u: Vec3,
-    _pad1: u32,
+    shutter_close: f32,
     v: Vec3,
     _pad2: u32,
     w: Vec3,
     _pad3: u32,
 }
 
+// The orbit parameters that fully determine the camera's view. The eye sits `distance` away
+// from the pivot `center`, in the direction given by `azimuth` and `altitude`.
+#[derive(Debug, Copy, Clone)]
+pub struct CameraPose {
+    pub center: Vec3,
+    pub up: Vec3,
+    pub distance: f32,
+    pub azimuth: f32,
+    pub altitude: f32,
+}
+
+impl CameraPose {
+    // Interpolates between two poses. The azimuth takes the shorter way around the circle.
+    pub fn lerp(&self, other: &CameraPose, t: f32) -> CameraPose {
+        let mut delta_azimuth = (other.azimuth - self.azimuth) % (2. * PI);
+        if delta_azimuth > PI {
+            delta_azimuth -= 2. * PI;
+        } else if delta_azimuth < -PI {
+            delta_azimuth += 2. * PI;
+        }
+        CameraPose {
+            center: self.center + t * (other.center - self.center),
+            up: (self.up + t * (other.up - self.up)).normalized(),
+            distance: self.distance + t * (other.distance - self.distance),
+            azimuth: self.azimuth + t * delta_azimuth,
+            altitude: self.altitude + t * (other.altitude - self.altitude),
+        }
+    }
+}
+
+// Camera movement expressed in the units of `Camera::orbit`, `Camera::pan`, `Camera::look`
+// and `Camera::zoom`. Used both for per-frame input and for the inertial velocity.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CameraMotion {
+    pub orbit: (f32, f32),
+    pub pan: (f32, f32),
+    pub look: (f32, f32),
+    pub zoom: f32,
+}
+
+impl CameraMotion {
+    pub fn is_zero(&self) -> bool {
+        self.magnitude() == 0.
+    }
+
+    fn magnitude(&self) -> f32 {
+        [
+            self.orbit.0,
+            self.orbit.1,
+            self.pan.0,
+            self.pan.1,
+            self.look.0,
+            self.look.1,
+            self.zoom,
+        ]
+        .iter()
+        .map(|x| x.abs())
+        .fold(0., f32::max)
+    }
+
+    fn scaled(&self, s: f32) -> CameraMotion {
+        CameraMotion {
+            orbit: (self.orbit.0 * s, self.orbit.1 * s),
+            pan: (self.pan.0 * s, self.pan.1 * s),
+            look: (self.look.0 * s, self.look.1 * s),
+            zoom: self.zoom * s,
+        }
+    }
+}
+
+struct Transition {
+    from: CameraPose,
+    to: CameraPose,
+    elapsed: f32,
+    duration: f32,
+}
+
 pub struct Camera {
     uniforms: CameraUniforms,
     center: Vec3,
@@ -24,6 +111,10 @@ pub struct Camera {
     distance: f32,
     azimuth: f32,
     altitude: f32,
+    // Movement per second that keeps being applied after input stops, decaying at `damping`.
+    velocity: CameraMotion,
+    damping: f32,
+    transition: Option<Transition>,
 }
 
 impl Camera {
@@ -50,34 +141,184 @@ impl Camera {
             distance,
             azimuth,
             altitude,
+            velocity: CameraMotion::default(),
+            damping: DEFAULT_DAMPING,
+            transition: None,
         };
         camera.calculate_uniforms();
         camera
     }
 
+    // Sets the shutter interval; an empty interval (`open == close`) disables motion blur.
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.uniforms.shutter_open = open;
+        self.uniforms.shutter_close = close;
+    }
+
     pub fn uniforms(&self) -> &CameraUniforms {
         &self.uniforms
     }
 
+    pub fn pose(&self) -> CameraPose {
+        CameraPose {
+            center: self.center,
+            up: self.up,
+            distance: self.distance,
+            azimuth: self.azimuth,
+            altitude: self.altitude,
+        }
+    }
+
+    // Jumps to `pose`, cancelling any motion or transition in progress.
+    pub fn set_pose(&mut self, pose: CameraPose) {
+        self.stop();
+        self.apply_pose(pose);
+    }
+
+    // Smoothly moves the camera to `pose` over `duration` seconds. The transition is advanced
+    // by `update`.
+    pub fn animate_to(&mut self, pose: CameraPose, duration: f32) {
+        self.velocity = CameraMotion::default();
+        if duration <= 0. {
+            self.transition = None;
+            self.apply_pose(pose);
+            return;
+        }
+        self.transition = Some(Transition {
+            from: self.pose(),
+            to: pose,
+            elapsed: 0.,
+            duration,
+        });
+    }
+
+    // Sets how quickly inertial motion dies out (1/s), `--damping` in the viewer. Zero disables
+    // damping, infinity disables inertia altogether.
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping = damping.max(0.);
+    }
+
+    pub fn stop(&mut self) {
+        self.velocity = CameraMotion::default();
+        self.transition = None;
+    }
+
     pub fn zoom(&mut self, displacement: f32) {
-        // self.uniforms.origin += displacement * self.uniforms.w;
         self.distance = (self.distance - displacement).max(0.0); // Prevent negative distance
-        self.uniforms.origin = self.center - self.distance * self.uniforms.w;
+        self.calculate_uniforms();
     }
 
+    // Moves the pivot in the image plane; the eye follows, so later orbits and zooms happen
+    // around the panned pivot.
     pub fn pan(&mut self, du: f32, dv: f32) {
         let pan = du * self.uniforms.u + dv * self.uniforms.v;
-        self.uniforms.origin += pan;
+        self.center += pan;
+        self.calculate_uniforms();
+    }
+
+    // First-person translation. `forward` and `right` follow the view direction, `up` follows
+    // the camera's up vector. The pivot moves along with the eye.
+    pub fn fly(&mut self, forward: f32, right: f32, up: f32) {
+        let translation = forward * self.uniforms.w + right * self.uniforms.u + up * self.up;
+        self.center += translation;
+        self.calculate_uniforms();
+    }
+
+    // First-person mouse-look: rotates the view direction while the eye stays in place.
+    pub fn look(&mut self, du: f32, dv: f32) {
+        let origin = self.uniforms.origin;
+        self.altitude = (self.altitude - dv).clamp(-MAX_ALT, MAX_ALT);
+        self.azimuth -= du;
+        self.azimuth %= 2. * PI;
+        self.calculate_uniforms();
+        // Keep the eye fixed by moving the pivot in front of it.
+        self.center = origin + self.distance * self.uniforms.w;
+        self.uniforms.origin = origin;
     }
 
     pub fn orbit(&mut self, du: f32, dv: f32) {
-        const MAX_ALT: f32 = FRAC_PI_2 - 1e-6;
-        self.altitude = (self.altitude + dv).clamp(-FRAC_PI_2, FRAC_PI_2);
+        self.altitude = (self.altitude + dv).clamp(-MAX_ALT, MAX_ALT);
         self.azimuth += du;
         self.azimuth %= 2. * PI;
         self.calculate_uniforms();
     }
 
+    pub fn apply(&mut self, motion: &CameraMotion) {
+        if motion.orbit != (0., 0.) {
+            self.orbit(motion.orbit.0, motion.orbit.1);
+        }
+        if motion.pan != (0., 0.) {
+            self.pan(motion.pan.0, motion.pan.1);
+        }
+        if motion.look != (0., 0.) {
+            self.look(motion.look.0, motion.look.1);
+        }
+        if motion.zoom != 0. {
+            self.zoom(motion.zoom);
+        }
+    }
+
+    // Advances the camera by `dt` seconds. `input` is the movement requested by the user since
+    // the last update; `grabbed` is true while the user holds the camera (e.g. a mouse button is
+    // down), which suppresses coasting. Returns true if the view changed.
+    //
+    // Frames without input are common during a drag when the frame rate exceeds the rate of
+    // mouse events, so a grabbed frame keeps the velocity for the release, only letting it decay
+    // as it would while coasting: a flick still coasts, holding still before letting go does
+    // not.
+    pub fn update(&mut self, dt: f32, input: &CameraMotion, grabbed: bool) -> bool {
+        if !input.is_zero() {
+            self.transition = None;
+            self.apply(input);
+            self.velocity = if dt > 0. && self.damping.is_finite() {
+                input.scaled(dt.recip())
+            } else {
+                CameraMotion::default()
+            };
+            return true;
+        }
+
+        if let Some(transition) = &mut self.transition {
+            transition.elapsed += dt;
+            let t = (transition.elapsed / transition.duration).min(1.);
+            // Smoothstep easing so the camera starts and stops gently.
+            let eased = t * t * (3. - 2. * t);
+            // Land exactly on the target so recalled poses are reproducible.
+            let pose = if t >= 1. {
+                transition.to
+            } else {
+                transition.from.lerp(&transition.to, eased)
+            };
+            if t >= 1. {
+                self.transition = None;
+            }
+            self.apply_pose(pose);
+            return true;
+        }
+
+        if grabbed {
+            self.velocity = self.velocity.scaled((-self.damping * dt).exp());
+            return false;
+        }
+        if self.velocity.magnitude() < MIN_VELOCITY {
+            self.velocity = CameraMotion::default();
+            return false;
+        }
+        let velocity = self.velocity;
+        self.apply(&velocity.scaled(dt));
+        self.velocity = velocity.scaled((-self.damping * dt).exp());
+        true
+    }
+
+    fn apply_pose(&mut self, pose: CameraPose) {
+        self.center = pose.center;
+        self.up = pose.up;
+        self.distance = pose.distance.max(0.);
+        self.azimuth = pose.azimuth % (2. * PI);
+        self.altitude = pose.altitude.clamp(-MAX_ALT, MAX_ALT);
+        self.calculate_uniforms();
+    }
+
     fn calculate_uniforms(&mut self) {
         let w = {
             let (y, xz_scale) = self.altitude.sin_cos();
@@ -93,3 +334,119 @@ impl Camera {
         self.uniforms.w = w;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn pose(azimuth: f32) -> CameraPose {
+        CameraPose { center: Vec3::zero(), up: Vec3::new(0., 1., 0.), distance: 2., azimuth, altitude: 0.3 }
+    }
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < EPSILON, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn lerp_hits_both_endpoints() {
+        let a = CameraPose { center: Vec3::new(1., 2., 3.), distance: 5., ..pose(0.5) };
+        let b = CameraPose { center: Vec3::new(-1., 0., 4.), up: Vec3::new(0., 0., 1.), ..pose(2.) };
+        for (t, expected) in [(0., a), (1., b)] {
+            let p = a.lerp(&b, t);
+            assert_close(p.center, expected.center);
+            assert_close(p.up, expected.up);
+            assert!((p.distance - expected.distance).abs() < EPSILON);
+            assert!((p.azimuth - expected.azimuth).abs() < EPSILON);
+            assert!((p.altitude - expected.altitude).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn lerp_takes_the_short_way_around() {
+        // From just below pi to just above -pi crosses pi, not 0.
+        let middle = pose(3.).lerp(&pose(-3.), 0.5);
+        assert!((middle.azimuth - PI).abs() < EPSILON, "{}", middle.azimuth);
+        let middle = pose(-3.).lerp(&pose(3.), 0.5);
+        assert!((middle.azimuth + PI).abs() < EPSILON, "{}", middle.azimuth);
+        // Whole turns make no difference.
+        let end = pose(0.1).lerp(&pose(0.2 + 4. * PI), 1.);
+        assert!((end.azimuth - 0.2).abs() < EPSILON, "{}", end.azimuth);
+    }
+
+    #[test]
+    fn pan_moves_the_pivot_with_the_eye() {
+        let mut camera = Camera::look_at(Vec3::new(0., 1., 5.), Vec3::zero(), Vec3::new(0., 1., 0.));
+        let (origin, u, v) = (camera.uniforms.origin, camera.uniforms.u, camera.uniforms.v);
+        camera.pan(0.5, -0.25);
+        let offset = 0.5 * u - 0.25 * v;
+        assert_close(camera.pose().center, offset);
+        assert_close(camera.uniforms.origin, origin + offset);
+        // Orbits and zooms now turn around the panned pivot.
+        camera.orbit(0.7, 0.2);
+        camera.zoom(1.);
+        let distance = (camera.uniforms.origin - offset).length();
+        assert!((distance - (26f32.sqrt() - 1.)).abs() < EPSILON, "{distance}");
+    }
+
+    fn flick() -> CameraMotion {
+        CameraMotion { orbit: (0.02, 0.01), ..Default::default() }
+    }
+
+    #[test]
+    fn damping_decays_coasting_to_zero() {
+        let mut camera = Camera::look_at(Vec3::new(0., 0., 5.), Vec3::zero(), Vec3::new(0., 1., 0.));
+        let dt = 1. / 60.;
+        assert!(camera.update(dt, &flick(), true));
+        let mut speed = camera.velocity.magnitude();
+        let mut frames = 0;
+        while camera.update(dt, &CameraMotion::default(), false) {
+            let next = camera.velocity.magnitude();
+            assert!(next < speed);
+            speed = next;
+            frames += 1;
+            assert!(frames < 1000, "still coasting after {frames} frames");
+        }
+        assert!(camera.velocity.is_zero());
+        assert!(frames > 1);
+    }
+
+    #[test]
+    fn grabbed_frames_without_input_keep_the_flick() {
+        let mut camera = Camera::look_at(Vec3::new(0., 0., 5.), Vec3::zero(), Vec3::new(0., 1., 0.));
+        let dt = 1. / 240.;
+        camera.update(dt, &flick(), true);
+        // No mouse event this frame: the camera does not move, but the flick survives.
+        let pose = camera.pose();
+        assert!(!camera.update(dt, &CameraMotion::default(), true));
+        assert_eq!(camera.pose().azimuth, pose.azimuth);
+        assert!(camera.update(dt, &CameraMotion::default(), false));
+        assert!(camera.pose().azimuth > pose.azimuth);
+
+        // Holding still for two seconds before letting go leaves nothing to coast with.
+        camera.update(dt, &flick(), true);
+        for _ in 0..480 {
+            camera.update(dt, &CameraMotion::default(), true);
+        }
+        assert!(!camera.update(dt, &CameraMotion::default(), false));
+    }
+
+    #[test]
+    fn infinite_damping_disables_inertia() {
+        let mut camera = Camera::look_at(Vec3::new(0., 0., 5.), Vec3::zero(), Vec3::new(0., 1., 0.));
+        camera.set_damping(f32::INFINITY);
+        assert!(camera.update(1. / 60., &flick(), false));
+        assert!(camera.velocity.is_zero());
+        assert!(!camera.update(1. / 60., &CameraMotion::default(), false));
+
+        // Without damping the camera keeps coasting at the speed of the flick.
+        camera.set_damping(0.);
+        camera.update(1. / 60., &flick(), false);
+        let speed = camera.velocity.magnitude();
+        for _ in 0..100 {
+            assert!(camera.update(1. / 60., &CameraMotion::default(), false));
+        }
+        assert_eq!(camera.velocity.magnitude(), speed);
+    }
+}
@@ -0,0 +1,383 @@
+// furnace.rs
+// `--furnace`: Monte Carlo checks of the CPU reference BSDFs in microfacet.rs.
+//
+// - Weak white furnace: with F = 1 and G2 replaced by G1(wo), the GGX lobe integrates to
+//   exactly 1 over the sphere (Heitz, "Understanding the Masking-Shadowing Function", 2014).
+// - The sampling pdfs integrate to the fraction of samples that are not absorbed.
+// - Every sample's weight and pdf agree with `eval` and `pdf` for the sampled direction.
+// - White furnace: a perfect conductor and a rough dielectric never reflect more energy than
+//   they receive, and lose almost none at low roughness. (At higher roughness, single
+//   scattering GGX loses the energy of light bouncing between microfacets.)
+//
+// The first two use uniform sphere sampling and are skipped for alphas below
+// UNIFORM_MIN_ALPHA, whose peaked lobes would need far more samples.
+//
+// The tests run these checks, and put the shader's `scatter` through a white furnace on the
+// GPU, see `shader_scatter_passes_the_white_furnace`.
+use crate::{
+    algebra::Vec3,
+    microfacet::{self, Conductor, RoughDielectric},
+};
+use {anyhow::{Result, bail}, std::f32::consts::PI};
+
+const SAMPLE_COUNT: u32 = 1 << 20;
+const ALPHAS: [f32; 4] = [0.05, 0.2, 0.5, 1.0];
+const UNIFORM_MIN_ALPHA: f32 = 0.2;
+// Lobes this smooth must pass the white furnace with (almost) no energy loss.
+const SMOOTH_ALPHA: f32 = 0.05;
+const COS_THETAS: [f32; 3] = [1.0, 0.6, 0.25];
+const IOR: f32 = 1.5;
+
+// Tolerance of the Monte Carlo estimates.
+const TOLERANCE: f32 = 0.03;
+// Tolerance of the per-sample consistency checks, relative.
+const CONSISTENCY_TOLERANCE: f32 = 1e-3;
+// Fraction of samples allowed to fail the consistency checks because of f32 round-off at
+// grazing angles.
+const MAX_MISMATCHES: f32 = 1e-4;
+
+// Same generator as the shader's `xorshift32`.
+struct Rng(u32);
+
+impl Rng {
+    fn next_f32(&mut self) -> f32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        f32::from_bits(0x3f800000 | (x >> 9)) - 1.
+    }
+
+    fn pair(&mut self) -> (f32, f32) {
+        (self.next_f32(), self.next_f32())
+    }
+
+    fn sphere(&mut self) -> Vec3 {
+        let (r0, r1) = self.pair();
+        let z = 1. - 2. * r0;
+        let r = (1. - z * z).max(0.).sqrt();
+        let phi = 2. * PI * r1;
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+}
+
+// Runs all checks, printing one line per configuration. Fails if any check fails.
+pub fn run() -> Result<()> {
+    let mut rng = Rng(0x2545f491);
+    let mut failures = 0;
+    let mut report = |name: &str, value: f32, expected: &str, ok: bool| {
+        println!("  {name:<28} {value:>9.5}  (expected {expected}){}", if ok { "" } else { "  FAIL" });
+        if !ok {
+            failures += 1;
+        }
+    };
+
+    for cos_i in [1.0, 0.8, 0.5, 0.2, 0.05] {
+        let reference = microfacet::fresnel_dielectric(cos_i, IOR);
+        let conductor = microfacet::fresnel_conductor(cos_i, IOR, 0.);
+        let ok = (reference - conductor).abs() <= 1e-5;
+        println!("fresnel cos = {cos_i}");
+        report("conductor(k = 0) - dielectric", conductor - reference, "0", ok);
+    }
+
+    for alpha in ALPHAS {
+        for cos_o in COS_THETAS {
+            let wo = Vec3::new((1. - cos_o * cos_o).sqrt(), 0., cos_o);
+            println!("alpha = {alpha}, cos(theta_o) = {cos_o}");
+
+            // Uniform sphere sampling, pdf 1 / (4 pi).
+            let uniform_checks = alpha >= UNIFORM_MIN_ALPHA;
+            let mut weak = 0f64;
+            let mut conductor_pdf = 0f64;
+            let mut dielectric_pdf = [0f64; 2];
+            for _ in 0..if uniform_checks { SAMPLE_COUNT } else { 0 } {
+                let wi = rng.sphere();
+                let m = (wo + wi).normalized();
+                let d = microfacet::ggx_d(&m, alpha) * microfacet::ggx_g1(&wo, alpha) / (4. * wo.z());
+                weak += d as f64;
+                conductor_pdf += Conductor { alpha, fresnel: None }.pdf(&wo, &wi) as f64;
+                for (sum, eta) in dielectric_pdf.iter_mut().zip([IOR, 1. / IOR]) {
+                    *sum += RoughDielectric { alpha, eta }.pdf(&wo, &wi) as f64;
+                }
+            }
+            let uniform = |sum: f64| (sum * 4. * std::f64::consts::PI / SAMPLE_COUNT as f64) as f32;
+            if uniform_checks {
+                let weak = uniform(weak);
+                report("weak furnace", weak, "1", (weak - 1.).abs() <= TOLERANCE);
+            }
+
+            let conductor = Conductor { alpha, fresnel: None };
+            let (albedo, valid, mismatches) = importance_sample(&mut rng, |rng| {
+                let sample = conductor.sample(&wo, rng.pair());
+                let eval = conductor.eval(&wo, &sample.wi) * sample.wi.z().abs();
+                (sample, eval, conductor.pdf(&wo, &sample.wi))
+            });
+            if uniform_checks {
+                let pdf_integral = uniform(conductor_pdf);
+                report(
+                    "conductor pdf integral",
+                    pdf_integral,
+                    &format!("{valid:.5}"),
+                    (pdf_integral - valid).abs() <= TOLERANCE,
+                );
+            }
+            report("conductor sample mismatches", mismatches, "~0", mismatches <= MAX_MISMATCHES);
+            report(
+                "conductor white furnace",
+                albedo,
+                "<= 1",
+                albedo <= 1. + TOLERANCE && (alpha > SMOOTH_ALPHA || albedo >= 1. - TOLERANCE),
+            );
+
+            for (eta, sum) in [IOR, 1. / IOR].into_iter().zip(dielectric_pdf) {
+                let dielectric = RoughDielectric { alpha, eta };
+                let (albedo, valid, mismatches) = importance_sample(&mut rng, |rng| {
+                    let (u0, u1) = rng.pair();
+                    let sample = dielectric.sample(&wo, (u0, u1, rng.next_f32()));
+                    let eval = dielectric.eval(&wo, &sample.wi) * sample.wi.z().abs();
+                    (sample, eval, dielectric.pdf(&wo, &sample.wi))
+                });
+                if uniform_checks {
+                    let pdf_integral = uniform(sum);
+                    report(
+                        &format!("dielectric({eta:.3}) pdf integral"),
+                        pdf_integral,
+                        &format!("{valid:.5}"),
+                        (pdf_integral - valid).abs() <= TOLERANCE,
+                    );
+                }
+                report(&format!("dielectric({eta:.3}) mismatches"), mismatches, "~0", mismatches <= MAX_MISMATCHES);
+                report(
+                    &format!("dielectric({eta:.3}) furnace"),
+                    albedo,
+                    "<= 1",
+                    albedo <= 1. + TOLERANCE && (alpha > SMOOTH_ALPHA || albedo >= 1. - TOLERANCE),
+                );
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{failures} furnace checks failed");
+    }
+    println!("all furnace checks passed");
+    Ok(())
+}
+
+// Draws SAMPLE_COUNT samples from `sample`, which returns the sample along with
+// BSDF * |cos| and the pdf recomputed for its direction. Returns the mean weight, the
+// fraction of samples that were not absorbed and the fraction of samples whose weight or pdf
+// disagree with the recomputed values.
+fn importance_sample(
+    rng: &mut Rng,
+    mut sample: impl FnMut(&mut Rng) -> (microfacet::BsdfSample, f32, f32),
+) -> (f32, f32, f32) {
+    let (mut weight, mut valid, mut mismatches) = (0f64, 0u32, 0u32);
+    for _ in 0..SAMPLE_COUNT {
+        let (s, eval, pdf) = sample(rng);
+        if s.pdf <= 0. {
+            continue;
+        }
+        valid += 1;
+        weight += s.weight as f64;
+        let expected_weight = eval / s.pdf;
+        let close = |a: f32, b: f32| (a - b).abs() <= CONSISTENCY_TOLERANCE * a.abs().max(b.abs()).max(1.);
+        if !close(s.weight, expected_weight) || !close(s.pdf, pdf) {
+            mismatches += 1;
+        }
+    }
+    let n = SAMPLE_COUNT as f32;
+    ((weight / n as f64) as f32, valid as f32 / n, mismatches as f32 / n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        render,
+        scene::{Material, Principled, Texture},
+    };
+    use {bytemuck::{Pod, Zeroable}, wgpu::util::DeviceExt};
+
+    #[test]
+    fn reference_bsdfs_pass_the_furnace() {
+        run().unwrap();
+    }
+
+    // A compute entry point that scatters rays arriving from `wo` at a surface in the z = 0
+    // plane with the shader's `scatter`, and averages the weights of GPU_SAMPLES samples. In a
+    // white furnace the weight is the energy sent in the sampled direction, so the average is
+    // the albedo.
+    const FURNACE_WGSL: &str = r"
+struct FurnaceCase {
+  material: u32,
+  cos_theta: f32,
+}
+
+@group(0) @binding(4) var<storage, read> furnace_cases: array<FurnaceCase>;
+@group(0) @binding(5) var<storage, read_write> furnace_albedos: array<vec4f>;
+
+@compute @workgroup_size(64)
+fn furnace_scatter(@builtin(global_invocation_id) id: vec3u) {
+  let furnace_case = furnace_cases[id.y];
+  rng.state = jenkins_hash(id.y * 64u + id.x + 1u);
+  let c = furnace_case.cos_theta;
+  let wo = vec3(sqrt(1. - c * c), 0., c);
+  let n = vec3(0., 0., 1.);
+  let ray = Ray(wo, -wo, 0.);
+  let hit = Intersection(n, 1., vec2(0.5), furnace_case.material, n, vec4(1., 0., 0., 1.), vec3(1.));
+  let material = materials[furnace_case.material];
+  var sum = vec3(0.);
+  var invalid = 0u;
+  for (var i = 0u; i < GPU_SAMPLES; i++) {
+    let weight = scatter(ray, hit, material).attenuation;
+    if is_nan(weight.x + weight.y + weight.z) || is_infinite(weight.x + weight.y + weight.z) {
+      invalid += 1u;
+    } else {
+      sum += weight;
+    }
+  }
+  furnace_albedos[id.y * 64u + id.x] = vec4(sum / f32(GPU_SAMPLES), f32(invalid));
+}
+";
+    const INVOCATIONS: u32 = 64;
+    const GPU_SAMPLES: u32 = 2048;
+
+    #[derive(Copy, Clone, Pod, Zeroable)]
+    #[repr(C)]
+    struct FurnaceCase {
+        material: u32,
+        cos_theta: f32,
+    }
+
+    // Materials that neither absorb nor emit, whether their albedo must be close to 1 and not
+    // just at most 1.
+    fn white_materials() -> Vec<(&'static str, Material, bool)> {
+        let white = Vec3::all(1.);
+        // With eta = 0, the Fresnel term of a conductor is 1 at every angle.
+        let mirror = |roughness| Material::conductor(Vec3::zero(), Vec3::all(1e4), roughness);
+        let principled = |params: Principled| Material::principled(&Principled { base_color: white, ..params });
+        vec![
+            ("lambertian", Material::lambertian(white), true),
+            ("metal", Material::metal(white, 0.), true),
+            ("fuzzy metal", Material::metal(white, 0.3), true),
+            ("dielectric", Material::dielectric(IOR), true),
+            ("smooth conductor", mirror(0.2), true),
+            ("rough conductor", mirror(0.7), false),
+            ("smooth rough dielectric", Material::rough_dielectric(IOR, 0.2), true),
+            ("rough dielectric", Material::rough_dielectric(IOR, 1.), false),
+            ("principled metal", principled(Principled { metallic: 1., roughness: 0.2, ..Default::default() }), true),
+            ("principled glass", principled(Principled { transmission: 1., roughness: 0.2, ..Default::default() }), false),
+        ]
+    }
+
+    #[test]
+    fn shader_scatter_passes_the_white_furnace() {
+        let Some((device, queue)) = pollster::block_on(render::connect_headless(4)) else {
+            eprintln!("no GPU adapter, skipping");
+            return;
+        };
+        let materials = white_materials();
+        let cases: Vec<FurnaceCase> = (0..materials.len() as u32)
+            .flat_map(|material| COS_THETAS.map(|cos_theta| FurnaceCase { material, cos_theta }))
+            .collect();
+
+        let source = format!(
+            "{}\n{FURNACE_WGSL}\nconst GPU_SAMPLES: u32 = {GPU_SAMPLES}u;",
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/shaders.wgsl"))
+        );
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("furnace"),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("furnace"),
+            layout: None,
+            module: &module,
+            entry_point: "furnace_scatter",
+        });
+
+        let storage = |label, contents: &[u8]| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            })
+        };
+        let gpu_materials: Vec<Material> = materials.iter().map(|(_, material, _)| *material).collect();
+        let material_buffer = storage("materials", bytemuck::cast_slice(&gpu_materials));
+        let texture_buffer = storage("textures", bytemuck::bytes_of(&Texture::solid(Vec3::all(1.))));
+        let case_buffer = storage("cases", bytemuck::cast_slice(&cases));
+        let results = vec![[0f32; 4]; cases.len() * INVOCATIONS as usize];
+        let result_buffer = storage("albedos", bytemuck::cast_slice(&results));
+        let image = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("image textures"),
+            size: wgpu::Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let image_view = image.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        let bind_group = |group, entries: &[(u32, wgpu::BindingResource)]| {
+            let entries: Vec<wgpu::BindGroupEntry> = entries
+                .iter()
+                .map(|(binding, resource)| wgpu::BindGroupEntry { binding: *binding, resource: resource.clone() })
+                .collect();
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("furnace"),
+                layout: &pipeline.get_bind_group_layout(group),
+                entries: &entries,
+            })
+        };
+        let outputs = bind_group(0, &[(4, case_buffer.as_entire_binding()), (5, result_buffer.as_entire_binding())]);
+        let scene = bind_group(
+            1,
+            &[
+                (1, material_buffer.as_entire_binding()),
+                (2, texture_buffer.as_entire_binding()),
+                (3, wgpu::BindingResource::TextureView(&image_view)),
+                (4, wgpu::BindingResource::Sampler(&sampler)),
+            ],
+        );
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("furnace readback"),
+            size: result_buffer.size(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &outputs, &[]);
+        pass.set_bind_group(1, &scene, &[]);
+        pass.dispatch_workgroups(1, cases.len() as u32, 1);
+        drop(pass);
+        encoder.copy_buffer_to_buffer(&result_buffer, 0, &readback, 0, result_buffer.size());
+        queue.submit(Some(encoder.finish()));
+        readback.slice(..).map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        device.poll(wgpu::Maintain::Wait);
+        let results: Vec<[f32; 4]> = bytemuck::cast_slice(&readback.slice(..).get_mapped_range()).to_vec();
+
+        let mut failures = Vec::new();
+        for (case, albedos) in cases.iter().zip(results.chunks_exact(INVOCATIONS as usize)) {
+            let (name, _, lossless) = materials[case.material as usize];
+            let invalid: f32 = albedos.iter().map(|a| a[3]).sum();
+            let albedo = [0, 1, 2].map(|c| albedos.iter().map(|a| a[c]).sum::<f32>() / INVOCATIONS as f32);
+            let ok = invalid == 0.
+                && albedo.iter().all(|&a| a <= 1. + TOLERANCE && (!lossless || a >= 1. - TOLERANCE));
+            if !ok {
+                failures.push(format!("{name} at cos {:.2}: albedo {albedo:.4?}, {invalid} invalid", case.cos_theta));
+            }
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,117 @@
+// heightfield.rs
+// Height maps for terrain, read from grayscale images or generated from fractal noise. 16-bit
+// PNGs keep their full precision; 8-bit and color images are converted to 16-bit luma by the
+// `image` crate.
+use {
+    anyhow::{Context, Result, bail},
+    std::path::Path,
+};
+
+pub struct HeightMap {
+    // Number of samples along x and z; cells lie between four neighboring samples.
+    pub columns: u32,
+    pub rows: u32,
+    // Heights in [0, 1], x fastest.
+    pub samples: Vec<f32>,
+}
+
+impl HeightMap {
+    pub fn load(path: &Path) -> Result<HeightMap> {
+        let image = image::open(path)
+            .with_context(|| format!("failed to load {}", path.display()))?
+            .into_luma16();
+        let (columns, rows) = image.dimensions();
+        if columns < 2 || rows < 2 {
+            bail!("{} is {columns}x{rows}, a height map needs at least 2x2 samples", path.display());
+        }
+        let samples = image.pixels().map(|p| p.0[0] as f32 / u16::MAX as f32).collect();
+        Ok(HeightMap { columns, rows, samples })
+    }
+
+    // A `size` x `size` landscape of fractal value noise: six octaves of smoothly interpolated
+    // random heights on ever finer lattices, stretched to [0, 1]. The same `seed` always gives
+    // the same landscape.
+    pub fn procedural(size: u32, seed: u32) -> HeightMap {
+        const OCTAVES: u32 = 6;
+        let lattice = |x: i32, z: i32, octave: u32| {
+            let mut h = (x as u32).wrapping_mul(0x8da6b343)
+                ^ (z as u32).wrapping_mul(0xd8163841)
+                ^ seed.wrapping_add(octave).wrapping_mul(0xcb1ab31f);
+            h ^= h >> 15;
+            h = h.wrapping_mul(0x2c1b3c6d);
+            h ^= h >> 12;
+            (h >> 8) as f32 / (1 << 24) as f32
+        };
+        let noise = |x: f32, z: f32, octave: u32| {
+            let (x0, z0) = (x.floor(), z.floor());
+            let smooth = |t: f32| t * t * (3. - 2. * t);
+            let (sx, sz) = (smooth(x - x0), smooth(z - z0));
+            let (x0, z0) = (x0 as i32, z0 as i32);
+            let row = |z: i32| {
+                let (a, b) = (lattice(x0, z, octave), lattice(x0 + 1, z, octave));
+                a + sx * (b - a)
+            };
+            let (a, b) = (row(z0), row(z0 + 1));
+            a + sz * (b - a)
+        };
+        let mut samples: Vec<f32> = (0..size * size)
+            .map(|i| {
+                let (x, z) = ((i % size) as f32 / size as f32, (i / size) as f32 / size as f32);
+                (0..OCTAVES)
+                    .map(|octave| {
+                        let frequency = 4. * (1 << octave) as f32;
+                        noise(x * frequency, z * frequency, octave) / (1 << octave) as f32
+                    })
+                    .sum()
+            })
+            .collect();
+        let (lo, hi) = samples.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &h| (lo.min(h), hi.max(h)));
+        for h in &mut samples {
+            *h = (*h - lo) / (hi - lo).max(f32::MIN_POSITIVE);
+        }
+        HeightMap { columns: size, rows: size, samples }
+    }
+
+    pub fn height(&self, x: u32, z: u32) -> f32 {
+        self.samples[(z * self.columns + x) as usize]
+    }
+
+    // Lowest and highest sample of every square of `block` x `block` cells, row by row. The
+    // shader skips the blocks a ray passes above or below.
+    pub fn block_ranges(&self, block: u32) -> Vec<[f32; 2]> {
+        let blocks_x = (self.columns - 2) / block + 1;
+        let blocks_z = (self.rows - 2) / block + 1;
+        let mut ranges = Vec::with_capacity((blocks_x * blocks_z) as usize);
+        for bz in 0..blocks_z {
+            for bx in 0..blocks_x {
+                let mut range = [f32::MAX, f32::MIN];
+                for z in bz * block..=((bz + 1) * block).min(self.rows - 1) {
+                    for x in bx * block..=((bx + 1) * block).min(self.columns - 1) {
+                        let height = self.height(x, z);
+                        range = [range[0].min(height), range[1].max(height)];
+                    }
+                }
+                ranges.push(range);
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn procedural_maps_span_zero_to_one() {
+        let map = HeightMap::procedural(65, 1);
+        assert_eq!((map.columns, map.rows, map.samples.len()), (65, 65, 65 * 65));
+        let (lo, hi) = map.samples.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &h| (lo.min(h), hi.max(h)));
+        assert_eq!((lo, hi), (0., 1.));
+        assert_eq!(map.samples, HeightMap::procedural(65, 1).samples);
+        assert_ne!(map.samples, HeightMap::procedural(65, 2).samples);
+        // Neighboring samples differ by a small part of the full range, a landscape, not noise.
+        let steepest = (1..65).map(|x| (map.height(x, 32) - map.height(x - 1, 32)).abs()).fold(0., f32::max);
+        assert!(steepest < 0.25, "steepest step {steepest}");
+    }
+}
@@ -0,0 +1,216 @@
+// inspector.rs
+// The pixel inspector: Ctrl+click traces the path of one pixel again with a fixed seed and
+// records every vertex in a storage buffer (`path_record` in shaders.wgsl), which is read back
+// and printed. Clicking the same pixel again traces it with the next seed.
+//
+// The recorded sample also goes into the accumulated image like any other, which a single
+// sample does not visibly change.
+use crate::algebra::Vec3;
+use {
+    bytemuck::{Pod, Zeroable},
+    std::fmt::Write,
+};
+
+// MAX_PATH_LENGTH in shaders.wgsl: every iteration of the path loop records one vertex.
+pub const MAX_VERTICES: usize = 50;
+pub const RECORD_SIZE: usize = size_of::<RecordHeader>() + MAX_VERTICES * size_of::<PathVertex>();
+
+// Vertex kinds.
+const SURFACE: u32 = 0;
+const MEDIUM: u32 = 1;
+const ESCAPED: u32 = 2;
+
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+struct RecordHeader {
+    pixel: [u32; 2],
+    seed: u32,
+    vertex_count: u32,
+    // The sample the path added to the pixel.
+    radiance: Vec3,
+    _pad: u32,
+}
+
+// The layouts of `PathRecord` and `PathVertex` in shaders.wgsl.
+const _: () = assert!(size_of::<RecordHeader>() == 32);
+const _: () = assert!(size_of::<PathVertex>() == 96);
+
+// One iteration of the path loop. For an escaped ray, `position` and `direction` are the ray
+// that left the scene.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct PathVertex {
+    position: Vec3,
+    kind: u32,
+    // Geometric normal of a surface hit, facing out of the object.
+    normal: Vec3,
+    material: u32,
+    // Direction of the scattered ray.
+    direction: Vec3,
+    // Distance from the previous vertex.
+    t: f32,
+    // Weight the scatter multiplied the throughput with.
+    attenuation: Vec3,
+    _pad0: u32,
+    // Path throughput after this vertex.
+    throughput: Vec3,
+    _pad1: u32,
+    // Radiance this vertex added to the sample: emission or sky times the throughput before it.
+    contribution: Vec3,
+    _pad2: u32,
+}
+
+pub struct InspectedPath {
+    pub pixel: [u32; 2],
+    pub seed: u32,
+    pub radiance: Vec3,
+    pub vertices: Vec<PathVertex>,
+}
+
+impl InspectedPath {
+    // Parses a record of RECORD_SIZE bytes as written by the shader.
+    pub fn from_bytes(bytes: &[u8]) -> InspectedPath {
+        let (header, vertices) = bytes.split_at(size_of::<RecordHeader>());
+        let header: RecordHeader = bytemuck::pod_read_unaligned(header);
+        let vertices: Vec<PathVertex> = vertices
+            .chunks_exact(size_of::<PathVertex>())
+            .take((header.vertex_count as usize).min(MAX_VERTICES))
+            .map(bytemuck::pod_read_unaligned)
+            .collect();
+        InspectedPath {
+            pixel: header.pixel,
+            seed: header.seed,
+            radiance: header.radiance,
+            vertices,
+        }
+    }
+
+    // One line per vertex. Non-finite values are printed as they are, which is usually what
+    // one is looking for.
+    pub fn report(&self) -> String {
+        let vec3 = |v: &Vec3| format!("({:.4}, {:.4}, {:.4})", v.x(), v.y(), v.z());
+        let mut out = String::new();
+        let [x, y] = self.pixel;
+        writeln!(
+            out,
+            "path of pixel ({x}, {y}), seed {}: {} vertices, radiance {}",
+            self.seed,
+            self.vertices.len(),
+            vec3(&self.radiance)
+        )
+        .unwrap();
+        for (i, v) in self.vertices.iter().enumerate() {
+            match v.kind {
+                SURFACE => writeln!(
+                    out,
+                    "  {i:>2} surface  t {:.4} at {} normal {} material {}\n     \
+                     scatter {} attenuation {} throughput {} added {}",
+                    v.t,
+                    vec3(&v.position),
+                    vec3(&v.normal),
+                    v.material,
+                    vec3(&v.direction),
+                    vec3(&v.attenuation),
+                    vec3(&v.throughput),
+                    vec3(&v.contribution),
+                ),
+                MEDIUM => writeln!(
+                    out,
+                    "  {i:>2} medium   t {:.4} at {}\n     scatter {} attenuation {} throughput {} added {}",
+                    v.t,
+                    vec3(&v.position),
+                    vec3(&v.direction),
+                    vec3(&v.attenuation),
+                    vec3(&v.throughput),
+                    vec3(&v.contribution),
+                ),
+                ESCAPED => writeln!(
+                    out,
+                    "  {i:>2} escaped  from {} towards {} added {}",
+                    vec3(&v.position),
+                    vec3(&v.direction),
+                    vec3(&v.contribution),
+                ),
+                kind => writeln!(out, "  {i:>2} unknown vertex kind {kind}"),
+            }
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_vertices_is_the_shader_path_length() {
+        let shader = include_str!("shaders.wgsl");
+        let length = shader
+            .lines()
+            .find_map(|line| line.strip_prefix("const MAX_PATH_LENGTH: u32 = "))
+            .expect("MAX_PATH_LENGTH not found");
+        assert_eq!(length, format!("{MAX_VERTICES}u;"));
+    }
+
+    fn vertex(kind: u32, t: f32) -> PathVertex {
+        PathVertex {
+            position: Vec3::new(1., 2., 3.),
+            kind,
+            normal: Vec3::new(0., 1., 0.),
+            material: 5,
+            direction: Vec3::new(0., 0., -1.),
+            t,
+            attenuation: Vec3::all(0.5),
+            throughput: Vec3::all(0.25),
+            contribution: Vec3::new(f32::NAN, 0., 0.),
+            ..Zeroable::zeroed()
+        }
+    }
+
+    // A record as the shader writes it, with `vertex_count` in the header and `vertices`
+    // filled in, the rest zero.
+    fn record(vertex_count: u32, vertices: &[PathVertex]) -> Vec<u8> {
+        let header = RecordHeader {
+            pixel: [3, 4],
+            seed: 7,
+            vertex_count,
+            radiance: Vec3::new(0.5, 0.25, 0.125),
+            _pad: 0,
+        };
+        let mut bytes = bytemuck::bytes_of(&header).to_vec();
+        bytes.extend(bytemuck::cast_slice(vertices));
+        bytes.resize(RECORD_SIZE, 0);
+        bytes
+    }
+
+    #[test]
+    fn reads_and_reports_a_record() {
+        let vertices = [vertex(SURFACE, 1.5), vertex(MEDIUM, 0.25), vertex(ESCAPED, 0.), vertex(9, 0.)];
+        let path = InspectedPath::from_bytes(&record(4, &vertices));
+        assert_eq!((path.pixel, path.seed, path.vertices.len()), ([3, 4], 7, 4));
+        assert_eq!(bytemuck::cast_slice::<_, u8>(&path.vertices), bytemuck::cast_slice::<_, u8>(&vertices));
+
+        let report = path.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 1 + 2 + 2 + 1 + 1);
+        assert_eq!(lines[0], "path of pixel (3, 4), seed 7: 4 vertices, radiance (0.5000, 0.2500, 0.1250)");
+        assert_eq!(
+            lines[1],
+            "   0 surface  t 1.5000 at (1.0000, 2.0000, 3.0000) normal (0.0000, 1.0000, 0.0000) material 5"
+        );
+        assert!(lines[2].contains("scatter (0.0000, 0.0000, -1.0000) attenuation (0.5000, 0.5000, 0.5000)"));
+        assert!(lines[2].ends_with("added (NaN, 0.0000, 0.0000)"));
+        assert_eq!(lines[3], "   1 medium   t 0.2500 at (1.0000, 2.0000, 3.0000)");
+        assert!(lines[5].starts_with("   2 escaped  from (1.0000, 2.0000, 3.0000) towards (0.0000, 0.0000, -1.0000)"));
+        assert_eq!(lines[6], "   3 unknown vertex kind 9");
+    }
+
+    #[test]
+    fn vertex_count_is_clamped_to_the_record() {
+        let vertices = vec![vertex(SURFACE, 1.); MAX_VERTICES];
+        assert_eq!(InspectedPath::from_bytes(&record(1, &vertices)).vertices.len(), 1);
+        assert_eq!(InspectedPath::from_bytes(&record(1000, &vertices)).vertices.len(), MAX_VERTICES);
+        assert!(InspectedPath::from_bytes(&record(0, &[])).vertices.is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+// json.rs
+// The two pieces of JSON that need care when reports are formatted by hand: strings, with
+// quotes, backslashes and control characters escaped, and numbers, which JSON has no NaN or
+// infinity for.
+use std::fmt::{Display, Write};
+
+// `s` as a quoted JSON string.
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// `x` as a JSON number, or `null` if it is NaN or infinite.
+pub fn number<T: Display + Copy + Into<f64>>(x: T) -> String {
+    if x.into().is_finite() { x.to_string() } else { "null".to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(string("cornell_box"), r#""cornell_box""#);
+        assert_eq!(string(r#"scenes\a "b".scene"#), r#""scenes\\a \"b\".scene""#);
+        assert_eq!(string("a\nb\tc\rd"), r#""a\nb\tc\rd""#);
+        assert_eq!(string("\u{0}\u{1b}\u{1f} "), r#""\u0000\u001b\u001f ""#);
+        assert_eq!(string("héllo ☃"), "\"héllo ☃\"");
+    }
+
+    #[test]
+    fn non_finite_numbers_are_null() {
+        assert_eq!(number(1.5f32), "1.5");
+        assert_eq!(number(-0.25f64), "-0.25");
+        assert_eq!(number(1e-7f64), "0.0000001");
+        assert_eq!(number(f32::NAN), "null");
+        assert_eq!(number(f32::INFINITY), "null");
+        assert_eq!(number(f64::NEG_INFINITY), "null");
+    }
+}
@@ -0,0 +1,829 @@
+// lbvh.rs
+// Linear BVHs (Karras, "Maximizing Parallelism in the Construction of BVHs, Octrees, and k-d
+// Trees", 2012): the primitives are sorted along a Morton curve through their centroids and
+// the hierarchy follows from the common prefixes of neighboring codes. The trees are worse
+// than binned SAH, but every step runs in parallel.
+//
+// The CPU build below is the reference for the compute shaders in lbvh.wgsl, and `validate`
+// checks the invariants the traversal relies on. The viewer builds the BVHs of scene files with
+// `GpuLbvh::build`, when loading and on every reload, so editing a scene never waits for the
+// SAH builder of bvh.rs; the builtin scenes, `--stats` and `--benchmark` keep the SAH trees.
+// `--lbvh-check` and the tests compare the CPU and GPU builds on a set of inputs.
+//
+// A tree of n > 0 primitives has 2n - 1 nodes in the layout of bvh.rs with one primitive per
+// leaf. Nodes are not depth-first: the children of the interior node that splits the sorted
+// primitives between `gamma` and `gamma + 1` are stored at 2 * gamma + 1 and 2 * gamma + 2.
+use crate::{
+    algebra::Vec3,
+    bvh::{self, Aabb, Bvh, BvhNode},
+    render,
+};
+use {
+    anyhow::{Context, Result, bail},
+    bytemuck::{Pod, Zeroable},
+    std::borrow::Cow,
+};
+
+// Cells of the Morton grid along every axis, 10 bits each.
+const MORTON_GRID: f32 = 1024.;
+// Must match lbvh.wgsl.
+const WORKGROUP_SIZE: u32 = 256;
+const RADIX_BITS: u32 = 4;
+const SORT_PASSES: u32 = 32 / RADIX_BITS;
+// Every dispatch covers the primitives with at most this many workgroups.
+pub const MAX_PRIMITIVES: u32 = 65535 * WORKGROUP_SIZE;
+// Storage buffers of the build's bind group, more than the default limit of 8.
+pub const STORAGE_BUFFERS: u32 = 9;
+
+// Input of the GPU build, one per primitive.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct PrimitiveBounds {
+    pub min: Vec3,
+    _pad0: u32,
+    pub max: Vec3,
+    _pad1: u32,
+}
+
+impl From<&Aabb> for PrimitiveBounds {
+    fn from(aabb: &Aabb) -> Self {
+        PrimitiveBounds {
+            min: aabb.min,
+            _pad0: 0,
+            max: aabb.max,
+            _pad1: 0,
+        }
+    }
+}
+
+// Spreads the low 10 bits of `v` out to every third bit.
+fn expand_bits(v: u32) -> u32 {
+    let mut x = v & 0x3ff;
+    x = (x | (x << 16)) & 0x030000ff;
+    x = (x | (x << 8)) & 0x0300f00f;
+    x = (x | (x << 4)) & 0x030c30c3;
+    x = (x | (x << 2)) & 0x09249249;
+    x
+}
+
+// 30-bit Morton codes of the centroids, on a grid over the centroid bounds.
+pub fn morton_codes(bounds: &[Aabb]) -> Vec<u32> {
+    let centroids: Vec<Vec3> = bounds.iter().map(Aabb::centroid).collect();
+    let grid = Aabb::from_points(&centroids);
+    let extent = grid.max - grid.min;
+    centroids
+        .iter()
+        .map(|c| {
+            // Flat axes map to the first cell.
+            let cell = |axis: usize| {
+                let scale = if extent[axis] > 0. { MORTON_GRID / extent[axis] } else { 0. };
+                ((c[axis] - grid.min[axis]) * scale).clamp(0., MORTON_GRID - 1.) as u32
+            };
+            (expand_bits(cell(0)) << 2) | (expand_bits(cell(1)) << 1) | expand_bits(cell(2))
+        })
+        .collect()
+}
+
+pub fn build(bounds: &[Aabb]) -> Bvh {
+    let codes = morton_codes(bounds);
+    let mut order: Vec<u32> = (0..bounds.len() as u32).collect();
+    // Stable, like the radix sort on the GPU.
+    order.sort_by_key(|&i| codes[i as usize]);
+    let keys: Vec<u32> = order.iter().map(|&i| codes[i as usize]).collect();
+    let nodes = hierarchy(&keys, &order, bounds);
+    Bvh { nodes, order }
+}
+
+// Length of the common prefix of `keys[i]` and `keys[j]`, or -1 if `j` is out of range. Equal
+// keys compare their indices instead, so that all keys are distinct.
+fn common_prefix(keys: &[u32], i: i32, j: i32) -> i32 {
+    if j < 0 || j >= keys.len() as i32 {
+        return -1;
+    }
+    let (a, b) = (keys[i as usize], keys[j as usize]);
+    if a == b {
+        32 + (i as u32 ^ j as u32).leading_zeros() as i32
+    } else {
+        (a ^ b).leading_zeros() as i32
+    }
+}
+
+// The other end of the range of interior node `i` and the position of its split, such that
+// its children cover `..=gamma` and `gamma + 1..` of the range.
+fn karras_node(keys: &[u32], i: i32) -> (i32, i32) {
+    let prefix = |j: i32| common_prefix(keys, i, j);
+    let d = if prefix(i + 1) > prefix(i - 1) { 1 } else { -1 };
+    let min_prefix = prefix(i - d);
+    let mut range = 2;
+    while prefix(i + range * d) > min_prefix {
+        range *= 2;
+    }
+    let mut length = 0;
+    let mut step = range / 2;
+    while step > 0 {
+        if prefix(i + (length + step) * d) > min_prefix {
+            length += step;
+        }
+        step /= 2;
+    }
+    let j = i + length * d;
+
+    let node_prefix = prefix(j);
+    let (mut split, mut step) = (0, length);
+    loop {
+        step = (step + 1) / 2;
+        if prefix(i + (split + step) * d) > node_prefix {
+            split += step;
+        }
+        if step <= 1 {
+            break;
+        }
+    }
+    (j, i + split * d + d.min(0))
+}
+
+// Emits the nodes over the sorted `keys`, whose primitives are `order`. Follows
+// `build_hierarchy` and `fit_bounds` in lbvh.wgsl.
+pub fn hierarchy(keys: &[u32], order: &[u32], bounds: &[Aabb]) -> Vec<BvhNode> {
+    let n = keys.len();
+    let empty = Aabb::empty();
+    if n == 0 {
+        return vec![BvhNode { min: empty.min, left_first: 0, max: empty.max, count: 0 }];
+    }
+    let mut nodes = vec![BvhNode::zeroed(); 2 * n - 1];
+    let mut parents = vec![0usize; 2 * n - 1];
+    let mut leaf_slots = vec![0usize; n];
+    for i in 0..n - 1 {
+        let (j, gamma) = karras_node(keys, i as i32);
+        let (first, last, gamma) = (i.min(j as usize), i.max(j as usize), gamma as usize);
+        // Node i is the left child of its parent if its range ends at i, the right child if it
+        // starts there. Leaves follow the same rule.
+        let slot = match i {
+            0 => 0,
+            _ if last == i => 2 * i + 1,
+            _ => 2 * i,
+        };
+        nodes[slot].left_first = 2 * gamma as u32 + 1;
+        parents[2 * gamma + 1] = slot;
+        parents[2 * gamma + 2] = slot;
+        if first == gamma {
+            leaf_slots[gamma] = 2 * gamma + 1;
+        }
+        if last == gamma + 1 {
+            leaf_slots[gamma + 1] = 2 * gamma + 2;
+        }
+    }
+
+    // From every leaf up to the root, where the second child to reach a node fits its box.
+    let mut arrivals = vec![0u8; 2 * n - 1];
+    for (k, &slot) in leaf_slots.iter().enumerate() {
+        let leaf = &bounds[order[k] as usize];
+        nodes[slot] = BvhNode { min: leaf.min, left_first: k as u32, max: leaf.max, count: 1 };
+        let mut slot = slot;
+        while slot != 0 {
+            slot = parents[slot];
+            arrivals[slot] += 1;
+            if arrivals[slot] == 1 {
+                break;
+            }
+            let left = nodes[slot].left_first as usize;
+            let fitted = nodes[left].bounds().union(&nodes[left + 1].bounds());
+            nodes[slot].min = fitted.min;
+            nodes[slot].max = fitted.max;
+        }
+    }
+    nodes
+}
+
+// Checks that every node is reachable exactly once from the root, that the leaves cover every
+// primitive exactly once, that every box contains the boxes below it, and that the tree is
+// no deeper than the traversal stack of the renderer. Returns the depth of the tree. Works
+// for the SAH trees of bvh.rs as well.
+pub fn validate(nodes: &[BvhNode], order: &[u32], bounds: &[Aabb]) -> Result<u32> {
+    let n = bounds.len();
+    if order.len() != n {
+        bail!("{} primitives in leaf order, expected {n}", order.len());
+    }
+    let mut listed = vec![false; n];
+    for &i in order {
+        if listed.get(i as usize).copied() != Some(false) {
+            bail!("primitive {i} is out of range or listed twice");
+        }
+        listed[i as usize] = true;
+    }
+    let root = nodes.first().context("no root node")?;
+    if n == 0 {
+        if root.is_leaf() || !root.bounds().is_empty() {
+            bail!("the root of an empty tree must be an empty interior node");
+        }
+        return Ok(1);
+    }
+
+    let mut visited = vec![false; nodes.len()];
+    let mut covered = vec![false; n];
+    let mut depth = 0;
+    let mut pending = vec![(0usize, 1u32)];
+    while let Some((index, level)) = pending.pop() {
+        if std::mem::replace(&mut visited[index], true) {
+            bail!("node {index} is reachable twice");
+        }
+        depth = depth.max(level);
+        let node = &nodes[index];
+        let (first, count) = (node.left_first as usize, node.count as usize);
+        if node.is_leaf() {
+            if first + count > n {
+                bail!("leaf {index} covers primitives {first}..{} of {n}", first + count);
+            }
+            for (position, &primitive) in order.iter().enumerate().skip(first).take(count) {
+                if std::mem::replace(&mut covered[position], true) {
+                    bail!("primitive {position} of the leaf order is in two leaves");
+                }
+                if !node.bounds().contains(&bounds[primitive as usize]) {
+                    bail!("leaf {index} does not contain primitive {primitive}");
+                }
+            }
+        } else {
+            if first + 1 >= nodes.len() {
+                bail!("node {index} has children {first} and {} of {}", first + 1, nodes.len());
+            }
+            for child in [first, first + 1] {
+                if !node.bounds().contains(&nodes[child].bounds()) {
+                    bail!("node {index} does not contain its child {child}");
+                }
+                pending.push((child, level + 1));
+            }
+        }
+    }
+    if let Some(index) = visited.iter().position(|v| !v) {
+        bail!("node {index} is unreachable");
+    }
+    if let Some(position) = covered.iter().position(|c| !c) {
+        bail!("primitive {position} of the leaf order is in no leaf");
+    }
+    if depth > bvh::MAX_DEPTH {
+        bail!("depth {depth} overflows the traversal stack of {} entries", bvh::MAX_DEPTH);
+    }
+    Ok(depth)
+}
+
+// Buffers of one build, sized for up to `capacity` primitives.
+pub struct LbvhBuffers {
+    capacity: u32,
+    params: wgpu::Buffer,
+    // Distance between the parameters of consecutive sort passes.
+    params_stride: u32,
+    // Input: a PrimitiveBounds for every primitive.
+    pub bounds: wgpu::Buffer,
+    // Output: the BvhNodes, and the primitive indices in leaf order.
+    pub nodes: wgpu::Buffer,
+    pub order: wgpu::Buffer,
+    // The sorted Morton codes, matching `order`.
+    pub keys: wgpu::Buffer,
+    centroid_bounds: wgpu::Buffer,
+    // The sort passes alternate between them; the first one binds `keys` and `order` as input.
+    bind_groups: [wgpu::BindGroup; 2],
+}
+
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+struct Params {
+    count: u32,
+    shift: u32,
+    blocks: u32,
+    _pad: u32,
+}
+
+pub struct GpuLbvh {
+    layout: wgpu::BindGroupLayout,
+    reduce_centroids: wgpu::ComputePipeline,
+    morton_codes: wgpu::ComputePipeline,
+    count_digits: wgpu::ComputePipeline,
+    scan_digits: wgpu::ComputePipeline,
+    scatter_digits: wgpu::ComputePipeline,
+    build_hierarchy: wgpu::ComputePipeline,
+    fit_bounds: wgpu::ComputePipeline,
+    decode_bounds: wgpu::ComputePipeline,
+}
+
+impl GpuLbvh {
+    pub fn new(device: &wgpu::Device) -> GpuLbvh {
+        let code = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lbvh.wgsl"));
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("lbvh"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(code)),
+        });
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let mut entries = vec![
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<Params>() as u64),
+                },
+                count: None,
+            },
+            storage_entry(1, true),
+        ];
+        entries.extend((2..=STORAGE_BUFFERS).map(|binding| storage_entry(binding, false)));
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("lbvh"),
+            entries: &entries,
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("lbvh"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &module,
+                entry_point,
+            })
+        };
+        GpuLbvh {
+            reduce_centroids: pipeline("reduce_centroids"),
+            morton_codes: pipeline("morton_codes"),
+            count_digits: pipeline("count_digits"),
+            scan_digits: pipeline("scan_digits"),
+            scatter_digits: pipeline("scatter_digits"),
+            build_hierarchy: pipeline("build_hierarchy"),
+            fit_bounds: pipeline("fit_bounds"),
+            decode_bounds: pipeline("decode_bounds"),
+            layout,
+        }
+    }
+
+    pub fn create_buffers(&self, device: &wgpu::Device, capacity: u32) -> LbvhBuffers {
+        assert!(capacity <= MAX_PRIMITIVES, "an LBVH holds at most {MAX_PRIMITIVES} primitives");
+        let capacity = capacity.max(1);
+        let blocks = capacity.div_ceil(WORKGROUP_SIZE);
+        let buffer = |label, elements: u32, size: usize, usage| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: (elements as usize * size) as u64,
+                usage: wgpu::BufferUsages::STORAGE | usage,
+                mapped_at_creation: false,
+            })
+        };
+        let u32_size = std::mem::size_of::<u32>();
+        let copy = wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST;
+        let bounds = buffer("lbvh bounds", capacity, std::mem::size_of::<PrimitiveBounds>(), copy);
+        let nodes = buffer("lbvh nodes", 2 * capacity - 1, std::mem::size_of::<BvhNode>(), copy);
+        let keys = [buffer("lbvh keys", capacity, u32_size, copy), buffer("lbvh keys", capacity, u32_size, copy)];
+        let values = [buffer("lbvh order", capacity, u32_size, copy), buffer("lbvh order", capacity, u32_size, copy)];
+        let histograms = buffer("lbvh histograms", (1 << RADIX_BITS) * blocks, u32_size, copy);
+        let centroid_bounds = buffer("lbvh centroid bounds", 6, u32_size, copy);
+        let parents = buffer("lbvh parents", 2 * capacity - 1, u32_size, copy);
+
+        let params_stride = device.limits().min_uniform_buffer_offset_alignment.max(std::mem::size_of::<Params>() as u32);
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("lbvh params"),
+            size: (params_stride * SORT_PASSES) as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = |input: usize| {
+            let output = 1 - input;
+            let resources = [
+                &bounds,
+                &keys[input],
+                &values[input],
+                &keys[output],
+                &values[output],
+                &histograms,
+                &centroid_bounds,
+                &nodes,
+                &parents,
+            ];
+            let mut entries = vec![wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &params,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<Params>() as u64),
+                }),
+            }];
+            entries.extend(resources.iter().zip(1..).map(|(buffer, binding)| wgpu::BindGroupEntry {
+                binding,
+                resource: buffer.as_entire_binding(),
+            }));
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("lbvh"),
+                layout: &self.layout,
+                entries: &entries,
+            })
+        };
+        let bind_groups = [bind_group(0), bind_group(1)];
+        let [keys, _] = keys;
+        let [order, _] = values;
+        LbvhBuffers {
+            capacity,
+            params,
+            params_stride,
+            bounds,
+            nodes,
+            order,
+            keys,
+            centroid_bounds,
+            bind_groups,
+        }
+    }
+
+    // Builds the tree over `bounds` and reads it back. Falls back to the SAH build if the tree
+    // fails `validate`, i.e. if it is deeper than the traversal stack, which happens with
+    // inputs clustered far below the resolution of the Morton grid.
+    pub fn build(&self, device: &wgpu::Device, queue: &wgpu::Queue, bounds: &[Aabb]) -> Bvh {
+        if bounds.len() > MAX_PRIMITIVES as usize {
+            return Bvh::build(bounds);
+        }
+        let buffers = self.run(device, queue, bounds);
+        let nodes = read_buffer(device, queue, &buffers.nodes, (2 * bounds.len()).max(2) - 1);
+        let order = read_buffer(device, queue, &buffers.order, bounds.len());
+        match validate(&nodes, &order, bounds) {
+            Ok(_) => Bvh { nodes, order },
+            Err(error) => {
+                eprintln!("warning: GPU LBVH over {} primitives: {error:#}, using SAH", bounds.len());
+                Bvh::build(bounds)
+            }
+        }
+    }
+
+    // Uploads `bounds` to new buffers and submits the build over them.
+    fn run(&self, device: &wgpu::Device, queue: &wgpu::Queue, bounds: &[Aabb]) -> LbvhBuffers {
+        let count = bounds.len() as u32;
+        let buffers = self.create_buffers(device, count);
+        let gpu_bounds: Vec<PrimitiveBounds> = bounds.iter().map(PrimitiveBounds::from).collect();
+        queue.write_buffer(&buffers.bounds, 0, bytemuck::cast_slice(&gpu_bounds));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("lbvh build"),
+        });
+        self.encode(queue, &mut encoder, &buffers, count);
+        queue.submit(Some(encoder.finish()));
+        buffers
+    }
+
+    // Records the build over the first `count` entries of `buffers.bounds` into `encoder`.
+    pub fn encode(&self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder, buffers: &LbvhBuffers, count: u32) {
+        assert!(count <= buffers.capacity, "{count} primitives exceed the LBVH capacity of {}", buffers.capacity);
+        if count == 0 {
+            let empty = Aabb::empty();
+            let root = BvhNode { min: empty.min, left_first: 0, max: empty.max, count: 0 };
+            queue.write_buffer(&buffers.nodes, 0, bytemuck::bytes_of(&root));
+            return;
+        }
+        let blocks = count.div_ceil(WORKGROUP_SIZE);
+        for pass in 0..SORT_PASSES {
+            let params = Params { count, shift: pass * RADIX_BITS, blocks, _pad: 0 };
+            queue.write_buffer(&buffers.params, (pass * buffers.params_stride) as u64, bytemuck::bytes_of(&params));
+        }
+        encoder.clear_buffer(&buffers.centroid_bounds, 0, None);
+        encoder.clear_buffer(&buffers.nodes, 0, None);
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("lbvh"),
+            timestamp_writes: None,
+        });
+        let mut dispatch = |pipeline, bind_group, sort_pass: u32, workgroups| {
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[sort_pass * buffers.params_stride]);
+            pass.dispatch_workgroups(workgroups, 1, 1);
+        };
+        let [first, second] = &buffers.bind_groups;
+        dispatch(&self.reduce_centroids, first, 0, blocks);
+        dispatch(&self.morton_codes, first, 0, blocks);
+        // An even number of passes leaves the sorted keys where they started.
+        for sort_pass in 0..SORT_PASSES {
+            let bind_group = if sort_pass % 2 == 0 { first } else { second };
+            dispatch(&self.count_digits, bind_group, sort_pass, blocks);
+            dispatch(&self.scan_digits, bind_group, sort_pass, 1);
+            dispatch(&self.scatter_digits, bind_group, sort_pass, blocks);
+        }
+        if count > 1 {
+            dispatch(&self.build_hierarchy, first, 0, (count - 1).div_ceil(WORKGROUP_SIZE));
+        }
+        dispatch(&self.fit_bounds, first, 0, blocks);
+        dispatch(&self.decode_bounds, first, 0, blocks);
+    }
+}
+
+// Copies the first `len` elements of `buffer` back to the CPU.
+fn read_buffer<T: Pod>(device: &wgpu::Device, queue: &wgpu::Queue, buffer: &wgpu::Buffer, len: usize) -> Vec<T> {
+    let size = (len * std::mem::size_of::<T>()) as u64;
+    if size == 0 {
+        return Vec::new();
+    }
+    let readback = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("lbvh readback"),
+        size,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("lbvh readback"),
+    });
+    encoder.copy_buffer_to_buffer(buffer, 0, &readback, 0, size);
+    queue.submit(Some(encoder.finish()));
+    let slice = readback.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |result| {
+        result.expect("failed to map the readback buffer");
+    });
+    device.poll(wgpu::Maintain::Wait);
+    let data = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+    readback.unmap();
+    data
+}
+
+// Same generator as the one in furnace.rs, also used by the tests of bvh.rs and wide_bvh.rs.
+pub(crate) struct Rng(pub(crate) u32);
+
+impl Rng {
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        f32::from_bits(0x3f800000 | (x >> 9)) - 1.
+    }
+
+    pub(crate) fn vec3(&mut self) -> Vec3 {
+        Vec3::new(self.next_f32(), self.next_f32(), self.next_f32())
+    }
+}
+
+// The inputs of `--lbvh-check` and the tests, named.
+fn check_inputs() -> Vec<(&'static str, Vec<Aabb>)> {
+    let mut rng = Rng(0x9e3779b9);
+    let mut boxes = |count: usize, spread: Vec3, size: f32| -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let p = rng.vec3();
+                let min = Vec3::new(p.x() * spread.x(), p.y() * spread.y(), p.z() * spread.z());
+                Aabb { min, max: min + size * rng.vec3() }
+            })
+            .collect()
+    };
+    let point = Aabb { min: Vec3::all(1.), max: Vec3::all(2.) };
+    vec![
+        ("empty", Vec::new()),
+        ("one box", vec![point]),
+        ("two boxes", boxes(2, Vec3::all(1.), 0.1)),
+        ("three boxes", boxes(3, Vec3::all(1.), 0.1)),
+        ("identical boxes", vec![point; 1000]),
+        ("flat", boxes(5000, Vec3::new(10., 0., 10.), 0.)),
+        ("random 1k", boxes(1000, Vec3::all(1.), 0.05)),
+        ("random 100k", boxes(100_000, Vec3::new(100., 10., 50.), 0.2)),
+        ("random 1M", boxes(1_000_000, Vec3::all(10.), 0.01)),
+    ]
+}
+
+// Builds `bounds` on the GPU and checks that the result is a valid tree, that its keys are
+// the CPU's Morton codes sorted stably, and that the CPU emits the same nodes from the GPU's
+// sorted keys.
+fn check_gpu_build(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    builder: &GpuLbvh,
+    bounds: &[Aabb],
+) -> [(&'static str, Result<String>); 3] {
+    let buffers = builder.run(device, queue, bounds);
+    let nodes: Vec<BvhNode> = read_buffer(device, queue, &buffers.nodes, (2 * bounds.len()).max(2) - 1);
+    let order: Vec<u32> = read_buffer(device, queue, &buffers.order, bounds.len());
+    let keys: Vec<u32> = read_buffer(device, queue, &buffers.keys, bounds.len());
+
+    let expected = hierarchy(&keys, &order, bounds);
+    let mismatches = nodes.iter().zip(&expected).filter(|(a, b)| bytemuck::bytes_of(*a) != bytemuck::bytes_of(*b)).count();
+    [
+        ("gpu lbvh", validate(&nodes, &order, bounds).map(|depth| format!("depth {depth}"))),
+        ("gpu sort", check_sort(&keys, &order, &morton_codes(bounds))),
+        (
+            "gpu hierarchy",
+            if mismatches == 0 { Ok(String::new()) } else { Err(anyhow::anyhow!("{mismatches} nodes differ from the CPU")) },
+        ),
+    ]
+}
+
+// `--lbvh-check`: validates the CPU builds on every input, then compares them with the GPU
+// build, see `check_gpu_build`.
+pub async fn check() -> Result<()> {
+    let inputs = check_inputs();
+    let gpu = render::connect_headless(STORAGE_BUFFERS).await;
+    if gpu.is_none() {
+        println!("no GPU adapter available, only checking the CPU build");
+    }
+    let builder = gpu.as_ref().map(|(device, _)| GpuLbvh::new(device));
+    let mut failures = 0;
+    let mut report = |name: &str, result: Result<String>| match result {
+        Ok(detail) => println!("  {name:<24} ok  {detail}"),
+        Err(error) => {
+            println!("  {name:<24} FAIL  {error:#}");
+            failures += 1;
+        }
+    };
+
+    for (name, bounds) in &inputs {
+        println!("{name}: {} primitives", bounds.len());
+        let sah = Bvh::build(bounds);
+        report("sah", validate(&sah.nodes, &sah.order, bounds).map(|depth| format!("depth {depth}")));
+        let cpu = build(bounds);
+        report("cpu lbvh", validate(&cpu.nodes, &cpu.order, bounds).map(|depth| format!("depth {depth}")));
+
+        let (Some((device, queue)), Some(builder)) = (&gpu, &builder) else {
+            continue;
+        };
+        for (check, result) in check_gpu_build(device, queue, builder, bounds) {
+            report(check, result);
+        }
+    }
+
+    if failures > 0 {
+        bail!("{failures} LBVH checks failed");
+    }
+    println!("all LBVH checks passed");
+    Ok(())
+}
+// Fraction of sorted keys allowed to differ from the CPU's Morton codes, as the GPU may round
+// the grid scale differently.
+const MAX_CODE_MISMATCHES: f32 = 1e-3;
+
+fn check_sort(keys: &[u32], order: &[u32], codes: &[u32]) -> Result<String> {
+    for (k, pair) in keys.windows(2).enumerate() {
+        if pair[0] > pair[1] {
+            bail!("keys {k} and {} are out of order", k + 1);
+        }
+        if pair[0] == pair[1] && order[k] > order[k + 1] {
+            bail!("equal keys {k} and {} swapped their primitives", k + 1);
+        }
+    }
+    let mismatches = keys.iter().zip(order).filter(|&(&key, &i)| key != codes[i as usize]).count();
+    let fraction = mismatches as f32 / keys.len().max(1) as f32;
+    if fraction > MAX_CODE_MISMATCHES {
+        bail!("{mismatches} keys differ from the CPU's Morton codes");
+    }
+    Ok(format!("{mismatches} keys differ from the CPU"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The million boxes take too long in a debug build; `--lbvh-check` covers them.
+    fn inputs() -> Vec<(&'static str, Vec<Aabb>)> {
+        check_inputs().into_iter().filter(|(_, bounds)| bounds.len() <= 100_000).collect()
+    }
+
+    #[test]
+    fn morton_codes_interleave_the_cells() {
+        assert_eq!(expand_bits(0b101), 0b1_000_001);
+        assert_eq!(expand_bits(0x3ff), 0x09249249);
+        let corner = |p: f32| Aabb { min: Vec3::all(p), max: Vec3::all(p) };
+        assert_eq!(morton_codes(&[corner(0.), corner(1.)]), [0, 0x3fffffff]);
+        let x = Aabb { min: Vec3::new(1., 0., 0.), max: Vec3::new(1., 0., 0.) };
+        let z = Aabb { min: Vec3::new(0., 0., 1.), max: Vec3::new(0., 0., 1.) };
+        // x is the most significant bit of every cell, flat axes stay in the first cell.
+        assert_eq!(morton_codes(&[corner(0.), x]), [0, 0x24924924]);
+        assert_eq!(morton_codes(&[corner(0.), z]), [0, 0x09249249]);
+    }
+
+    #[test]
+    fn build_sorts_by_morton_code_stably() {
+        for (name, bounds) in inputs() {
+            let codes = morton_codes(&bounds);
+            let bvh = build(&bounds);
+            for pair in bvh.order.windows(2) {
+                let (a, b) = (codes[pair[0] as usize], codes[pair[1] as usize]);
+                assert!(a < b || (a == b && pair[0] < pair[1]), "{name}: {pair:?} out of order");
+            }
+        }
+    }
+
+    // The example of Karras' paper, figure 3.
+    const KEYS: [u32; 8] = [0b00001, 0b00010, 0b00100, 0b00101, 0b10011, 0b11000, 0b11001, 0b11110];
+
+    #[test]
+    fn karras_nodes_split_at_the_highest_differing_bit() {
+        assert_eq!(karras_node(&KEYS, 0), (7, 3));
+        assert_eq!(karras_node(&KEYS, 3), (0, 1));
+        assert_eq!(karras_node(&KEYS, 4), (7, 4));
+        assert_eq!(karras_node(&KEYS, 5), (7, 6));
+        for (_, bounds) in inputs().into_iter().filter(|(_, bounds)| bounds.len() <= 5000) {
+            let bvh = build(&bounds);
+            let codes = morton_codes(&bounds);
+            let keys: Vec<u32> = bvh.order.iter().map(|&i| codes[i as usize]).collect();
+            for i in 0..keys.len() as i32 - 1 {
+                let (j, gamma) = karras_node(&keys, i);
+                let node_prefix = common_prefix(&keys, i.min(j), i.max(j));
+                assert_eq!(common_prefix(&keys, gamma, gamma + 1), node_prefix, "node {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn hierarchy_stores_children_next_to_their_split() {
+        let bounds: Vec<Aabb> = (0..8).map(|i| Aabb { min: Vec3::all(i as f32), max: Vec3::all(i as f32 + 1.) }).collect();
+        let order: Vec<u32> = (0..8).collect();
+        let nodes = hierarchy(&KEYS, &order, &bounds);
+        assert_eq!(nodes.len(), 15);
+        // The root splits after key 3, its left child after key 1, whose left child is the
+        // node over keys 0 and 1 with leaf 0 at slot 2 * 0 + 1.
+        assert_eq!(nodes[0].left_first, 7);
+        assert_eq!(nodes[7].left_first, 3);
+        assert_eq!(nodes[3].left_first, 1);
+        assert_eq!((nodes[1].left_first, nodes[1].count), (0, 1));
+        assert_eq!(nodes[0].bounds().min[0], 0.);
+        assert_eq!(nodes[0].bounds().max[0], 8.);
+        assert_eq!(validate(&nodes, &order, &bounds).unwrap(), 5);
+    }
+
+    #[test]
+    fn cpu_builds_are_valid() {
+        for (name, bounds) in inputs() {
+            let sah = Bvh::build(&bounds);
+            validate(&sah.nodes, &sah.order, &bounds).unwrap_or_else(|e| panic!("{name} sah: {e:#}"));
+            let lbvh = build(&bounds);
+            validate(&lbvh.nodes, &lbvh.order, &bounds).unwrap_or_else(|e| panic!("{name} lbvh: {e:#}"));
+        }
+    }
+
+    #[test]
+    fn validate_rejects_broken_trees() {
+        let (_, bounds) = inputs().swap_remove(6);
+        let bvh = build(&bounds);
+        let mut nodes = bvh.nodes.clone();
+        let leaf = nodes.iter().position(BvhNode::is_leaf).unwrap();
+        nodes[leaf].max = nodes[leaf].min;
+        assert!(validate(&nodes, &bvh.order, &bounds).is_err());
+        let mut order = bvh.order.clone();
+        order[1] = order[0];
+        assert!(validate(&bvh.nodes, &order, &bounds).is_err());
+
+        // Every key differs from the next one in a lower bit, which makes a chain of 33 leaves.
+        let keys: Vec<u32> = std::iter::once(0).chain((0..32).map(|bit| 1 << bit)).collect();
+        let bounds = vec![Aabb { min: Vec3::zero(), max: Vec3::all(1.) }; keys.len()];
+        let order: Vec<u32> = (0..keys.len() as u32).collect();
+        let error = validate(&hierarchy(&keys, &order, &bounds), &order, &bounds).unwrap_err();
+        assert!(error.to_string().contains("overflows the traversal stack"), "{error:#}");
+    }
+
+    #[test]
+    fn gpu_build_matches_cpu() {
+        let Some((device, queue)) = pollster::block_on(render::connect_headless(STORAGE_BUFFERS)) else {
+            eprintln!("no GPU adapter, skipping");
+            return;
+        };
+        let builder = GpuLbvh::new(&device);
+        for (name, bounds) in inputs() {
+            for (check, result) in check_gpu_build(&device, &queue, &builder, &bounds) {
+                result.unwrap_or_else(|e| panic!("{name} {check}: {e:#}"));
+            }
+        }
+    }
+
+    // Single-bit Morton codes nest one level per bit, and the identical boxes at the origin add
+    // more levels below them, beyond what the traversal stack holds.
+    fn deep_input() -> Vec<Aabb> {
+        let mut centroids = vec![Vec3::zero(); 16];
+        for axis in 0..3 {
+            for bit in 0..10 {
+                let mut c = [0.; 3];
+                c[axis] = (1 << bit) as f32;
+                centroids.push(Vec3::new(c[0], c[1], c[2]));
+            }
+        }
+        centroids.push(Vec3::all(1023.));
+        centroids.into_iter().map(|c| Aabb { min: c, max: c }).collect()
+    }
+
+    #[test]
+    fn gpu_build_reads_back_valid_trees() {
+        let Some((device, queue)) = pollster::block_on(render::connect_headless(STORAGE_BUFFERS)) else {
+            eprintln!("no GPU adapter, skipping");
+            return;
+        };
+        let builder = GpuLbvh::new(&device);
+        for (name, bounds) in inputs() {
+            let bvh = builder.build(&device, &queue, &bounds);
+            validate(&bvh.nodes, &bvh.order, &bounds).unwrap_or_else(|e| panic!("{name}: {e:#}"));
+        }
+        // Too deep as an LBVH, so the build falls back to SAH.
+        let bounds = deep_input();
+        let cpu = build(&bounds);
+        let error = validate(&cpu.nodes, &cpu.order, &bounds).unwrap_err();
+        assert!(error.to_string().contains("overflows the traversal stack"), "{error:#}");
+        let bvh = builder.build(&device, &queue, &bounds);
+        validate(&bvh.nodes, &bvh.order, &bounds).unwrap();
+    }
+}
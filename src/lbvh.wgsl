@@ -0,0 +1,342 @@
+// lbvh.wgsl
+// Linear BVH construction (Karras, "Maximizing Parallelism in the Construction of BVHs,
+// Octrees, and k-d Trees", 2012). lbvh.rs dispatches the entry points in order:
+//
+//   reduce_centroids   bounds of the primitive centroids
+//   morton_codes       30-bit Morton code of every centroid within those bounds
+//   count_digits       \
+//   scan_digits         > one least significant digit radix sort pass, 8 times
+//   scatter_digits     /
+//   build_hierarchy    one interior node per invocation, from the sorted codes
+//   fit_bounds         every leaf merges its box into all of its ancestors
+//   decode_bounds      the merged boxes back to floats
+//
+// The nodes use the layout of bvh.rs. Interior node `i` of Karras' tree splits its range of
+// sorted keys between `gamma` and `gamma + 1`, and every split position belongs to exactly
+// one node. Its children are stored at 2 * gamma + 1 and 2 * gamma + 2, the root at 0, so the
+// two children of a node are always next to each other.
+
+const WORKGROUP_SIZE: u32 = 256u;
+const RADIX_BITS: u32 = 4u;
+const RADIX: u32 = 16u;
+// Cells of the Morton grid along every axis.
+const MORTON_GRID: f32 = 1024.;
+const FLT_MAX: f32 = 3.40282346638528859812e+38;
+// u32 words per BvhNode.
+const NODE_WORDS: u32 = 8u;
+
+struct Params {
+  count: u32,
+  // First bit of the digit sorted by this pass.
+  shift: u32,
+  // Workgroups of the per-primitive passes.
+  blocks: u32,
+}
+
+struct PrimitiveBounds {
+  min: vec3f,
+  max: vec3f,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> bounds: array<PrimitiveBounds>;
+@group(0) @binding(2) var<storage, read_write> keys: array<u32>;
+@group(0) @binding(3) var<storage, read_write> values: array<u32>;
+// The other half of the sort's ping-pong buffers. Once the keys are sorted, `keys_out` holds
+// the node slot of every leaf.
+@group(0) @binding(4) var<storage, read_write> keys_out: array<u32>;
+@group(0) @binding(5) var<storage, read_write> values_out: array<u32>;
+// Count of every digit in every workgroup, digit-major, then their exclusive prefix sum.
+@group(0) @binding(6) var<storage, read_write> histograms: array<u32>;
+// Centroid bounds as order-preserving integers (see `ordered_float`). The minimums are stored
+// complemented, so that a zeroed buffer is the empty box and both sides grow with atomicMax.
+@group(0) @binding(7) var<storage, read_write> centroid_bounds: array<atomic<u32>, 6>;
+// BvhNodes as words, zeroed before every build. Until `decode_bounds`, the bounds are
+// order-preserving integers like `centroid_bounds`, merged by many invocations at once.
+@group(0) @binding(8) var<storage, read_write> nodes: array<atomic<u32>>;
+@group(0) @binding(9) var<storage, read_write> parents: array<u32>;
+
+var<workgroup> reduce_min: array<vec3f, WORKGROUP_SIZE>;
+var<workgroup> reduce_max: array<vec3f, WORKGROUP_SIZE>;
+var<workgroup> digit_counts: array<atomic<u32>, RADIX>;
+var<workgroup> digit_starts: array<u32, RADIX>;
+var<workgroup> scan_sums: array<u32, WORKGROUP_SIZE>;
+var<workgroup> sort_keys: array<u32, WORKGROUP_SIZE>;
+var<workgroup> sort_values: array<u32, WORKGROUP_SIZE>;
+
+fn centroid(i: u32) -> vec3f {
+  return 0.5 * (bounds[i].min + bounds[i].max);
+}
+
+// Maps floats to integers with the same order.
+fn ordered_float(x: f32) -> u32 {
+  let bits = bitcast<u32>(x);
+  return select(bits | 0x80000000u, ~bits, (bits & 0x80000000u) != 0u);
+}
+
+fn unordered_float(x: u32) -> f32 {
+  return bitcast<f32>(select(~x, x & 0x7fffffffu, (x & 0x80000000u) != 0u));
+}
+
+@compute @workgroup_size(256)
+fn reduce_centroids(@builtin(global_invocation_id) id: vec3u, @builtin(local_invocation_index) local: u32) {
+  var lo = vec3(FLT_MAX);
+  var hi = vec3(-FLT_MAX);
+  if id.x < params.count {
+    lo = centroid(id.x);
+    hi = lo;
+  }
+  reduce_min[local] = lo;
+  reduce_max[local] = hi;
+  for (var stride = WORKGROUP_SIZE / 2u; stride > 0u; stride /= 2u) {
+    workgroupBarrier();
+    if local < stride {
+      reduce_min[local] = min(reduce_min[local], reduce_min[local + stride]);
+      reduce_max[local] = max(reduce_max[local], reduce_max[local + stride]);
+    }
+  }
+  if local == 0u {
+    for (var axis = 0; axis < 3; axis++) {
+      atomicMax(&centroid_bounds[axis], ~ordered_float(reduce_min[0][axis]));
+      atomicMax(&centroid_bounds[3 + axis], ordered_float(reduce_max[0][axis]));
+    }
+  }
+}
+
+// Spreads the low 10 bits of `v` out to every third bit.
+fn expand_bits(v: u32) -> u32 {
+  var x = v & 0x3ffu;
+  x = (x | (x << 16u)) & 0x030000ffu;
+  x = (x | (x << 8u)) & 0x0300f00fu;
+  x = (x | (x << 4u)) & 0x030c30c3u;
+  x = (x | (x << 2u)) & 0x09249249u;
+  return x;
+}
+
+@compute @workgroup_size(256)
+fn morton_codes(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= params.count {
+    return;
+  }
+  var lo: vec3f;
+  var hi: vec3f;
+  for (var axis = 0; axis < 3; axis++) {
+    lo[axis] = unordered_float(~atomicLoad(&centroid_bounds[axis]));
+    hi[axis] = unordered_float(atomicLoad(&centroid_bounds[3 + axis]));
+  }
+  // Flat axes map to the first cell.
+  let extent = hi - lo;
+  let scale = select(vec3(0.), MORTON_GRID / extent, extent > vec3(0.));
+  let cell = vec3u(clamp((centroid(id.x) - lo) * scale, vec3(0.), vec3(MORTON_GRID - 1.)));
+  keys[id.x] = (expand_bits(cell.x) << 2u) | (expand_bits(cell.y) << 1u) | expand_bits(cell.z);
+  values[id.x] = id.x;
+}
+
+fn digit(key: u32) -> u32 {
+  return (key >> params.shift) & (RADIX - 1u);
+}
+
+@compute @workgroup_size(256)
+fn count_digits(@builtin(workgroup_id) group: vec3u, @builtin(global_invocation_id) id: vec3u,
+                @builtin(local_invocation_index) local: u32) {
+  if id.x < params.count {
+    atomicAdd(&digit_counts[digit(keys[id.x])], 1u);
+  }
+  workgroupBarrier();
+  if local < RADIX {
+    histograms[local * params.blocks + group.x] = atomicLoad(&digit_counts[local]);
+  }
+}
+
+// Exclusive prefix sum of `value` over the workgroup. Afterwards, the last entry of
+// `scan_sums` holds the total until the next barrier.
+fn workgroup_exclusive_scan(local: u32, value: u32) -> u32 {
+  scan_sums[local] = value;
+  for (var offset = 1u; offset < WORKGROUP_SIZE; offset *= 2u) {
+    workgroupBarrier();
+    var other = 0u;
+    if local >= offset {
+      other = scan_sums[local - offset];
+    }
+    workgroupBarrier();
+    scan_sums[local] += other;
+  }
+  workgroupBarrier();
+  return scan_sums[local] - value;
+}
+
+// Dispatched with a single workgroup: every invocation sums a contiguous chunk of the
+// histograms, then offsets its chunk by the sums before it.
+@compute @workgroup_size(256)
+fn scan_digits(@builtin(local_invocation_index) local: u32) {
+  let size = RADIX * params.blocks;
+  let chunk = (size + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+  let first = min(local * chunk, size);
+  let last = min(first + chunk, size);
+  var sum = 0u;
+  for (var i = first; i < last; i++) {
+    sum += histograms[i];
+  }
+  var offset = workgroup_exclusive_scan(local, sum);
+  for (var i = first; i < last; i++) {
+    let count = histograms[i];
+    histograms[i] = offset;
+    offset += count;
+  }
+}
+
+@compute @workgroup_size(256)
+fn scatter_digits(@builtin(workgroup_id) group: vec3u, @builtin(global_invocation_id) id: vec3u,
+                  @builtin(local_invocation_index) local: u32) {
+  // Past the end, keys are all ones and stay behind the valid keys of the last workgroup.
+  var key = 0xffffffffu;
+  var value = 0u;
+  if id.x < params.count {
+    key = keys[id.x];
+    value = values[id.x];
+  }
+  // Stable sort of the workgroup by the digit, one bit at a time, so that the keys with the
+  // same digit are contiguous and in their original order.
+  for (var bit = 0u; bit < RADIX_BITS; bit++) {
+    let one = (key >> (params.shift + bit)) & 1u;
+    let zeros_before = workgroup_exclusive_scan(local, 1u - one);
+    let zeros = scan_sums[WORKGROUP_SIZE - 1u];
+    let position = select(zeros_before, zeros + local - zeros_before, one == 1u);
+    sort_keys[position] = key;
+    sort_values[position] = value;
+    workgroupBarrier();
+    key = sort_keys[local];
+    value = sort_values[local];
+  }
+  let d = digit(key);
+  if local == 0u || d != digit(sort_keys[local - 1u]) {
+    digit_starts[d] = local;
+  }
+  workgroupBarrier();
+  if id.x < params.count {
+    let position = histograms[d * params.blocks + group.x] + local - digit_starts[d];
+    keys_out[position] = key;
+    values_out[position] = value;
+  }
+}
+
+// Length of the common prefix of the sorted keys at `i` and `j`, or -1 if `j` is out of
+// range. Equal keys compare their indices instead, so that all keys are distinct.
+fn common_prefix(i: i32, j: i32) -> i32 {
+  if j < 0 || j >= i32(params.count) {
+    return -1;
+  }
+  let a = keys[i];
+  let b = keys[j];
+  if a == b {
+    return 32 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+  }
+  return i32(countLeadingZeros(a ^ b));
+}
+
+@compute @workgroup_size(256)
+fn build_hierarchy(@builtin(global_invocation_id) id: vec3u) {
+  if id.x + 1u >= params.count {
+    return;
+  }
+  let i = i32(id.x);
+  // Direction of the node's range, and its other end by exponential then binary search.
+  let d = select(-1, 1, common_prefix(i, i + 1) > common_prefix(i, i - 1));
+  let min_prefix = common_prefix(i, i - d);
+  var range = 2;
+  while common_prefix(i, i + range * d) > min_prefix {
+    range *= 2;
+  }
+  var length = 0;
+  for (var step = range / 2; step > 0; step /= 2) {
+    if common_prefix(i, i + (length + step) * d) > min_prefix {
+      length += step;
+    }
+  }
+  let j = i + length * d;
+
+  // The split is the last key sharing more than the node's prefix with key i.
+  let node_prefix = common_prefix(i, j);
+  var split = 0;
+  var step = length;
+  loop {
+    step = (step + 1) / 2;
+    if common_prefix(i, i + (split + step) * d) > node_prefix {
+      split += step;
+    }
+    if step <= 1 {
+      break;
+    }
+  }
+  let gamma = u32(i + split * d + min(d, 0));
+
+  // Node i is the left child of its parent if its range ends at i, the right child if it
+  // starts there. Leaves follow the same rule.
+  var slot = 0u;
+  if i > 0 {
+    slot = select(2u * id.x, 2u * id.x + 1u, max(i, j) == i);
+  }
+  let base = slot * NODE_WORDS;
+  atomicStore(&nodes[base + 3u], 2u * gamma + 1u);
+  atomicStore(&nodes[base + 7u], 0u);
+  parents[2u * gamma + 1u] = slot;
+  parents[2u * gamma + 2u] = slot;
+  if u32(min(i, j)) == gamma {
+    keys_out[gamma] = 2u * gamma + 1u;
+  }
+  if u32(max(i, j)) == gamma + 1u {
+    keys_out[gamma + 1u] = 2u * gamma + 2u;
+  }
+}
+
+// Grows the box of node `slot` by `lo` and `hi`. atomicMax is commutative, so the result
+// does not depend on the order in which invocations arrive, and no invocation ever reads a
+// box another one is still writing.
+fn merge_node_bounds(slot: u32, lo: vec3f, hi: vec3f) {
+  let base = slot * NODE_WORDS;
+  for (var axis = 0u; axis < 3u; axis++) {
+    atomicMax(&nodes[base + axis], ~ordered_float(lo[axis]));
+    atomicMax(&nodes[base + 4u + axis], ordered_float(hi[axis]));
+  }
+}
+
+@compute @workgroup_size(256)
+fn fit_bounds(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= params.count {
+    return;
+  }
+  // A single primitive is a leaf at the root.
+  var slot = 0u;
+  if params.count > 1u {
+    slot = keys_out[id.x];
+  }
+  let leaf = bounds[values[id.x]];
+  let base = slot * NODE_WORDS;
+  atomicStore(&nodes[base + 3u], id.x);
+  atomicStore(&nodes[base + 7u], 1u);
+  loop {
+    merge_node_bounds(slot, leaf.min, leaf.max);
+    if slot == 0u {
+      break;
+    }
+    slot = parents[slot];
+  }
+}
+
+// Invocation i decodes nodes i and count + i, which covers the 2 * count - 1 nodes with the
+// workgroups of the per-primitive passes.
+@compute @workgroup_size(256)
+fn decode_bounds(@builtin(global_invocation_id) id: vec3u) {
+  if id.x >= params.count {
+    return;
+  }
+  for (var slot = id.x; slot < 2u * params.count - 1u; slot += params.count) {
+    let base = slot * NODE_WORDS;
+    for (var axis = 0u; axis < 3u; axis++) {
+      let lo = unordered_float(~atomicLoad(&nodes[base + axis]));
+      let hi = unordered_float(atomicLoad(&nodes[base + 4u + axis]));
+      atomicStore(&nodes[base + axis], bitcast<u32>(lo));
+      atomicStore(&nodes[base + 4u + axis], bitcast<u32>(hi));
+    }
+  }
+}
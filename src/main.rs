@@ -3,39 +3,199 @@ use {
     anyhow::{Context, Result},
     winit::{
         event::{DeviceEvent, Event, MouseScrollDelta, WindowEvent,ElementState},       
-        event_loop::{ControlFlow, EventLoop},
+        event_loop::EventLoop,
+        keyboard::{KeyCode, PhysicalKey},
         window::{Window, WindowBuilder},
     },
-    wgpu,
 };
-use crate::{algebra::Vec3, camera::Camera};
+use crate::{
+    bookmarks::Bookmarks,
+    bvh::{Aabb, Bvh},
+    camera::{Camera, CameraMotion},
+    scene::Scene,
+    scene_diff::SceneDiff,
+};
+use std::{collections::HashSet, path::{Path, PathBuf}, time::Instant};
 
 pub mod render;
 pub mod algebra;
+pub mod benchmark;
 pub mod camera;
+pub mod bookmarks;
+pub mod screenshot;
+pub mod scene;
+pub mod scene_diff;
+pub mod mesh;
+pub mod mesh_file;
+pub mod microfacet;
+pub mod furnace;
+pub mod volume;
+pub mod bvh;
+pub mod lbvh;
+pub mod heightfield;
+pub mod inspector;
+pub mod json;
+pub mod scene_file;
+pub mod stats;
+pub mod wide_bvh;
 const WIDTH: u32 = 800;
 const HEIGHT: u32 = 600;
 
+// 飞行模式的基础移动速度（场景单位/秒）
+const FLY_SPEED: f32 = 2.0;
+
+// Home 键回到初始视角的动画时长（秒）
+const HOME_TRANSITION_SECONDS: f32 = 1.0;
+
+// 切换书签视角的动画时长（秒）
+const BOOKMARK_TRANSITION_SECONDS: f32 = 0.5;
+
+// 默认的内置场景，书签文件保存为与场景同名的 .bookmarks 文件
+const DEFAULT_SCENE: &str = "week1_final";
+
+// 截图保存目录
+const SCREENSHOT_DIR: &str = "screenshots";
+
+struct Options {
+    // 内置场景的名字，或场景文件的路径
+    scene: String,
+    // 启动时使用的书签（名字或 1..9 的编号）
+    bookmark: Option<String>,
+    // 相机惯性运动的衰减率（1/秒），0 表示不衰减，inf 表示没有惯性
+    damping: Option<f32>,
+    // 只运行 BSDF 的白炉测试然后退出
+    furnace: bool,
+    // 只运行 LBVH 构建的 CPU/GPU 对照检查然后退出
+    lbvh_check: bool,
+    // 只在离屏渲染中比较二叉与四叉 BVH 的速度然后退出
+    benchmark: bool,
+    // 只打印场景和 BVH 的统计信息然后退出，--json 时输出 JSON
+    stats: bool,
+    json: bool,
+}
+
+fn parse_args() -> Result<Options> {
+    let mut options = Options { scene: DEFAULT_SCENE.to_string(), bookmark: None, damping: None, furnace: false, lbvh_check: false, benchmark: false, stats: false, json: false };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scene" => {
+                options.scene = args.next().context("--scene expects a scene name or file")?;
+            }
+            "--bookmark" => {
+                options.bookmark = Some(args.next().context("--bookmark expects a name or slot number")?);
+            }
+            "--damping" => {
+                let rate = args.next().context("--damping expects a rate per second")?;
+                let rate: f32 = rate.parse().with_context(|| format!("invalid --damping `{rate}`"))?;
+                if rate.is_nan() || rate < 0. {
+                    anyhow::bail!("--damping must be zero or positive, got {rate}");
+                }
+                options.damping = Some(rate);
+            }
+            "--furnace" => options.furnace = true,
+            "--lbvh-check" => options.lbvh_check = true,
+            "--benchmark" => options.benchmark = true,
+            "--stats" => options.stats = true,
+            "--json" => options.json = true,
+            _ => anyhow::bail!("unknown argument `{arg}`\nusage: RayTracing_withGPU [--scene NAME|FILE] [--bookmark NAME|SLOT] [--damping RATE] [--furnace] [--lbvh-check] [--benchmark] [--stats [--json]]"),
+        }
+    }
+    if options.json && !options.stats {
+        anyhow::bail!("--json only applies to --stats");
+    }
+    // 书签只用于交互窗口和 --benchmark 的相机，其他模式会忽略它，所以直接报错
+    if options.bookmark.is_some() && (options.furnace || options.lbvh_check || options.stats) {
+        anyhow::bail!("--bookmark only applies to the interactive viewer and --benchmark");
+    }
+    if options.damping.is_some() && (options.furnace || options.lbvh_check || options.benchmark || options.stats) {
+        anyhow::bail!("--damping only applies to the interactive viewer");
+    }
+    Ok(options)
+}
+
+fn digit_slot(code: KeyCode) -> Option<usize> {
+    Some(match code {
+        KeyCode::Digit1 => 1,
+        KeyCode::Digit2 => 2,
+        KeyCode::Digit3 => 3,
+        KeyCode::Digit4 => 4,
+        KeyCode::Digit5 => 5,
+        KeyCode::Digit6 => 6,
+        KeyCode::Digit7 => 7,
+        KeyCode::Digit8 => 8,
+        KeyCode::Digit9 => 9,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NavigationMode {
+    // 鼠标左键绕中心旋转
+    Orbit,
+    // WASD/QE 移动，鼠标左键环视
+    Fly,
+}
+
+// Returns the (forward, right, up) movement direction requested by the held keys, or `None`
+// if no movement key is held.
+fn fly_direction(keys: &HashSet<KeyCode>) -> Option<(f32, f32, f32)> {
+    let axis = |positive: KeyCode, negative: KeyCode| {
+        keys.contains(&positive) as i32 as f32 - keys.contains(&negative) as i32 as f32
+    };
+    let forward = axis(KeyCode::KeyW, KeyCode::KeyS);
+    let right = axis(KeyCode::KeyD, KeyCode::KeyA);
+    let up = axis(KeyCode::KeyE, KeyCode::KeyQ);
+    if forward == 0. && right == 0. && up == 0. {
+        return None;
+    }
+    Some((forward, right, up))
+}
+
+// Shift 加速，Ctrl 减速
+fn fly_speed_modifier(keys: &HashSet<KeyCode>) -> f32 {
+    if keys.contains(&KeyCode::ShiftLeft) || keys.contains(&KeyCode::ShiftRight) {
+        4.0
+    } else if keys.contains(&KeyCode::ControlLeft) || keys.contains(&KeyCode::ControlRight) {
+        0.25
+    } else {
+        1.0
+    }
+}
+
 struct AppState {
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     renderer: render::PathTracer,
+    scene_name: String,
+    // 在 GPU 上构建 BVH，加载和重新加载场景都不用等 CPU 上的 SAH 构建
+    lbvh: lbvh::GpuLbvh,
 }
 
 impl AppState {
-    async fn new(window: &Window) -> Result<(Self, wgpu::Surface)> {
+    // 连接 GPU 后才加载场景，这样场景的 BVH 也能在 GPU 上构建
+    async fn new<'a>(window: &'a Window, scene_name: &str) -> Result<(Self, wgpu::Surface<'a>, Scene)> {
         let (device, queue, surface, config) = connect_to_gpu(window).await?;
-        let mut renderer = render::PathTracer::new(&device, &queue, config.format, WIDTH, HEIGHT);
+        let lbvh = lbvh::GpuLbvh::new(&device);
+        let build = &mut |bounds: &[Aabb]| lbvh.build(&device, &queue, bounds);
+        let scene = load_scene_with(scene_name, build)?;
+        let renderer = render::PathTracer::new(&device, &queue, config.format, WIDTH, HEIGHT, &scene, build)?;
         
         let state = Self {
             device,
             queue,
             config,
             renderer,
+            scene_name: scene_name.to_string(),
+            lbvh,
         };
         
-        Ok((state, surface))
+        Ok((state, surface, scene))
+    }
+
+    fn build_bvh(&self, bounds: &[Aabb]) -> Bvh {
+        self.lbvh.build(&self.device, &self.queue, bounds)
     }
 
     fn resize(&mut self, surface: &wgpu::Surface, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -52,16 +212,53 @@ impl AppState {
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
 
-        self.renderer.render_frame(&camera, &self.device, &self.queue, &view);
+        self.renderer.render_frame(camera, &self.device, &self.queue, &view);
         output.present();
         Ok(())
     }
+
+    // 编辑场景文件后重新加载：物体只是移动或换了参数时原地更新 GPU 数据并重新拟合 BVH，
+    // 否则重建整个渲染器，新的 BVH 在 GPU 上构建。相机不受影响
+    fn reload_scene(&mut self, scene: &Scene) {
+        match self.renderer.update_scene(&self.queue, scene) {
+            SceneDiff::Update(writes) => {
+                let bytes: usize = writes.iter().map(|w| w.data.len()).sum();
+                println!("reloaded {}: {} buffer writes, {bytes} bytes", self.scene_name, writes.len());
+            }
+            SceneDiff::Rebuild(reason) => {
+                println!("reloaded {}: {reason}, uploading the whole scene", self.scene_name);
+                let temporal = self.renderer.temporal();
+                let build = &mut |bounds: &[Aabb]| self.build_bvh(bounds);
+                let renderer =
+                    render::PathTracer::new(&self.device, &self.queue, self.config.format, WIDTH, HEIGHT, scene, build);
+                match renderer {
+                    Ok(renderer) => self.renderer = renderer,
+                    Err(e) => return eprintln!("{:?}", e),
+                }
+                self.renderer.set_temporal(temporal);
+            }
+        }
+    }
+
+    // 把当前累积的图像读回 CPU，保存 PNG、EXR 和 JSON 元数据
+    fn screenshot(&self, camera: &Camera) -> Result<PathBuf> {
+        let (width, height) = self.renderer.size();
+        let pixels = self.renderer.read_radiance(&self.device, &self.queue);
+        let info = screenshot::ScreenshotInfo {
+            scene: &self.scene_name,
+            samples_per_pixel: self.renderer.samples_per_pixel(),
+            render_time: self.renderer.render_time(),
+            debug_mode: self.renderer.debug_mode(),
+            pose: camera.pose(),
+        };
+        screenshot::save(Path::new(SCREENSHOT_DIR), width, height, &pixels, &info)
+    }
 }
 
 // wgpu::Device : connection to the GPU
 // wgpu::Queue : issue commands to the GPU
 // wgpu::Surface : present frames to the window.
-async fn connect_to_gpu(window: &Window) -> Result<(wgpu::Device, wgpu::Queue, wgpu::Surface, wgpu::SurfaceConfiguration)> {
+async fn connect_to_gpu(window: &Window) -> Result<(wgpu::Device, wgpu::Queue, wgpu::Surface<'_>, wgpu::SurfaceConfiguration)> {
     let instance = wgpu::Instance::default();
     let surface = instance.create_surface(window)?; //Surface 是一个用于显示图形的区域，通常与显示设备（如显示器）相关联
     let adapter = instance
@@ -72,9 +269,29 @@ async fn connect_to_gpu(window: &Window) -> Result<(wgpu::Device, wgpu::Queue, w
         })
         .await
         .context("failed to find a compatible adapter")?;
+    // 先检查适配器的上限，否则 request_device 失败时只会给出难懂的错误
+    let available = adapter.limits().max_storage_buffers_per_shader_stage;
+    if available < render::MAX_STORAGE_BUFFERS {
+        anyhow::bail!(
+            "no GPU adapter with the {} storage buffers per shader stage the renderer needs \
+             ({} has {available})",
+            render::MAX_STORAGE_BUFFERS,
+            adapter.get_info().name
+        );
+    }
     // 请求 GPU 设备和命令队列
     let (device, queue) = adapter
-        .request_device(&wgpu::DeviceDescriptor::default(), None)
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                // 场景绑定组用到的 storage buffer 超过了默认上限 8
+                required_limits: wgpu::Limits {
+                    max_storage_buffers_per_shader_stage: render::MAX_STORAGE_BUFFERS,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        )
         .await
         .context("failed to connect to the GPU")?;
     //  配置 Surface 以进行渲染
@@ -101,8 +318,47 @@ async fn connect_to_gpu(window: &Window) -> Result<(wgpu::Device, wgpu::Queue, w
     Ok((device, queue, surface, config))
 }
 
+// 存在同名文件时从场景文件加载，否则使用内置场景
+fn load_scene(name: &str) -> Result<Scene> {
+    load_scene_with(name, &mut Bvh::build)
+}
+
+// 同上，场景文件中网格的 BVH 由 `build` 构建。内置场景不会改变，总是用 SAH 构建
+fn load_scene_with(name: &str, build: &mut dyn FnMut(&[Aabb]) -> Bvh) -> Result<Scene> {
+    if Path::new(name).is_file() {
+        scene_file::load_with(Path::new(name), build)
+    } else {
+        Scene::builtin(name)
+    }
+}
+
 #[pollster::main]
 async fn main() -> Result<()> {
+    let options = parse_args()?;
+    if options.furnace {
+        return furnace::run();
+    }
+    if options.lbvh_check {
+        return lbvh::check().await;
+    }
+    if options.stats {
+        return stats::run(&options.scene, options.json);
+    }
+    let mut bookmarks = Bookmarks::load(Bookmarks::sidecar_path(Path::new(&options.scene)))?;
+    let start_pose = match &options.bookmark {
+        Some(key) => Some(
+            bookmarks
+                .find(key)
+                .with_context(|| format!("no bookmark `{key}` in {}", bookmarks.path().display()))?
+                .pose,
+        ),
+        None => None,
+    };
+    if options.benchmark {
+        let scene = load_scene(&options.scene)?;
+        return benchmark::run(&scene, &options.scene, start_pose, WIDTH, HEIGHT).await;
+    }
+
     let event_loop = EventLoop::new()?;
     let window_size = winit::dpi::PhysicalSize::new(WIDTH, HEIGHT);
     let window = WindowBuilder::new()
@@ -111,12 +367,19 @@ async fn main() -> Result<()> {
         .with_title("GPU Path Tracer".to_string())
         .build(&event_loop)?;
 
-    let (mut state, surface) = AppState::new(&window).await?;
+    let (mut state, surface, scene) = AppState::new(&window, &options.scene).await?;
 
     let mut mouse_button_pressed = false;
     let mut last_mouse_pos: Option<winit::dpi::PhysicalPosition<f64>> = None; 
     let mut left_mouse_button_pressed = false;
     let mut right_mouse_button_pressed = false;
+    // 光标当前位置（物理像素），Ctrl+点击时用来确定要检查的像素
+    let mut cursor_position: Option<winit::dpi::PhysicalPosition<f64>> = None;
+    // 上一次检查的像素和种子，再次点击同一像素时换下一个种子
+    let mut last_inspection: Option<([u32; 2], u32)> = None;
+    let mut pressed_keys: HashSet<KeyCode> = HashSet::new();
+    let mut navigation_mode = NavigationMode::Orbit;
+    let mut last_frame = Instant::now();
 
     // let mut camera = Camera::with_spherical_coords(
     //     Vec3::new(0., 0., -1.),
@@ -125,11 +388,17 @@ async fn main() -> Result<()> {
     //     0.,
     //     0.,
     // );
-    let mut camera = Camera::look_at(
-        Vec3::new(0., 0.55, 1.5),
-        Vec3::new(0., 0.5, 0.),
-        Vec3::new(0., 1., 0.),
-    );
+    let mut camera = Camera::look_at(scene.view.origin, scene.view.center, scene.view.up);
+    camera.set_shutter(scene.view.shutter.0, scene.view.shutter.1);
+    let home_pose = camera.pose();
+    if let Some(pose) = start_pose {
+        camera.set_pose(pose);
+    }
+    if let Some(damping) = options.damping {
+        camera.set_damping(damping);
+    }
+    // 两帧之间累积的鼠标输入，在下一次重绘时交给相机（带惯性）
+    let mut camera_input = CameraMotion::default();
     event_loop.run(|event, control_handle| {
         match event {
             Event::WindowEvent {
@@ -141,6 +410,23 @@ async fn main() -> Result<()> {
                     state.resize(&surface, *physical_size);
                 }
                 WindowEvent::RedrawRequested => {
+                    // 按帧间隔移动，使飞行速度与帧率无关
+                    let now = Instant::now();
+                    let dt = (now - last_frame).as_secs_f32();
+                    last_frame = now;
+                    if navigation_mode == NavigationMode::Fly
+                        && let Some((forward, right, up)) = fly_direction(&pressed_keys)
+                    {
+                        let step = FLY_SPEED * fly_speed_modifier(&pressed_keys) * dt;
+                        camera.stop();
+                        camera.fly(forward * step, right * step, up * step);
+                        state.renderer.reset_samples();
+                    }
+                    let grabbed = left_mouse_button_pressed || right_mouse_button_pressed;
+                    if camera.update(dt, &camera_input, grabbed) {
+                        state.renderer.reset_samples();
+                    }
+                    camera_input = CameraMotion::default();
                     match state.render(&surface, &camera) {
                         Ok(_) => {}
                         // 重新配置surface如果过时
@@ -155,11 +441,38 @@ async fn main() -> Result<()> {
                         // 其他错误
                         Err(e) => eprintln!("{:?}", e),
                     }
+                    match state.renderer.take_inspection() {
+                        Some(Ok(path)) => print!("{}", path.report()),
+                        Some(Err(e)) => eprintln!("{:?}", e),
+                        None => {}
+                    }
                     window.request_redraw();
                 }
                 // 添加鼠标按钮处理
-                WindowEvent::MouseInput { state, button, .. } => {
-                    mouse_button_pressed = *state == ElementState::Pressed;
+                WindowEvent::MouseInput { state: button_state, button, .. } => {
+                    let pressed = *button_state == ElementState::Pressed;
+                    let ctrl = pressed_keys.contains(&KeyCode::ControlLeft)
+                        || pressed_keys.contains(&KeyCode::ControlRight);
+                    // Ctrl+左键点击像素：用固定种子重新追踪该像素的路径，并打印每次弹射（不开始拖动）
+                    if pressed
+                        && ctrl
+                        && *button == winit::event::MouseButton::Left
+                        && let Some(position) = cursor_position
+                    {
+                        let (width, height) = state.renderer.size();
+                        let pixel = [
+                            (position.x.max(0.) as u32).min(width - 1),
+                            (position.y.max(0.) as u32).min(height - 1),
+                        ];
+                        let seed = match last_inspection {
+                            Some((last, seed)) if last == pixel => seed + 1,
+                            _ => 0,
+                        };
+                        last_inspection = Some((pixel, seed));
+                        state.renderer.inspect_pixel(pixel[0], pixel[1], seed);
+                        return;
+                    }
+                    mouse_button_pressed = pressed;
                     match button {
                         winit::event::MouseButton::Left => left_mouse_button_pressed = mouse_button_pressed,
                         winit::event::MouseButton::Right => right_mouse_button_pressed = mouse_button_pressed,
@@ -170,49 +483,126 @@ async fn main() -> Result<()> {
                     }
                     
                 }
-                // 添加鼠标移动处理
-                WindowEvent::CursorMoved { position, .. } => {
-                    if mouse_button_pressed {
-                        if let Some(last_pos) = last_mouse_pos {
-                            let dx = position.x - last_pos.x;
-                            let dy = position.y - last_pos.y;
-                            
-                            // println!("=== 鼠标移动 ===");
-                            // println!("当前位置: ({}, {})", position.x, position.y);
-                            // println!("上次位置: ({}, {})", last_pos.x, last_pos.y);
-                            // println!("增量: dx={}, dy={}", dx, dy);
-                            
-                            let sensitivity = 0.01;
-                            let du = dx as f32 * sensitivity;
-                            let dv = dy as f32 * (-sensitivity);  // 翻转Y轴
-                            
-                            // println!("left={}, right={}", left_mouse_button_pressed, right_mouse_button_pressed);
-                            
-                            if left_mouse_button_pressed {
-                                camera.orbit(du,dv);
-                                state.renderer.reset_samples();
+                // 键盘处理
+                WindowEvent::KeyboardInput { event, .. } => {
+                    let PhysicalKey::Code(code) = event.physical_key else {
+                        return;
+                    };
+                    match event.state {
+                        ElementState::Pressed => pressed_keys.insert(code),
+                        ElementState::Released => pressed_keys.remove(&code),
+                    };
+                    if event.state == ElementState::Pressed && !event.repeat {
+                        match code {
+                            // T 键切换时间累积（相机移动时重投影历史帧）
+                            KeyCode::KeyT => {
+                                let temporal = !state.renderer.temporal();
+                                state.renderer.set_temporal(temporal);
+                                println!("temporal accumulation: {}", if temporal { "on" } else { "off" });
+                            }
+                            // B 键切换网格 BLAS 的二叉/四叉量化布局，两者命中结果相同，不需要重新累积
+                            KeyCode::KeyB => {
+                                let wide = !state.renderer.wide_bvh();
+                                state.renderer.set_wide_bvh(wide);
+                                println!("mesh BVH layout: {}", if wide { "4-wide quantized" } else { "binary" });
                             }
-                            if right_mouse_button_pressed {
-                                camera.pan(du,dv);
-                                state.renderer.reset_samples();
+                            // V 键循环切换调试显示模式（法线、深度、BVH 热力图等），Shift+V 反向
+                            KeyCode::KeyV => {
+                                let shift = pressed_keys.contains(&KeyCode::ShiftLeft)
+                                    || pressed_keys.contains(&KeyCode::ShiftRight);
+                                let mode = state.renderer.debug_mode().cycle(shift);
+                                state.renderer.set_debug_mode(mode);
+                                println!("debug view: {}", mode.name());
+                            }
+                            // F 键切换轨道/飞行模式
+                            KeyCode::KeyF => {
+                                navigation_mode = match navigation_mode {
+                                    NavigationMode::Orbit => NavigationMode::Fly,
+                                    NavigationMode::Fly => NavigationMode::Orbit,
+                                };
+                                println!("navigation mode: {:?}", navigation_mode);
+                            }
+                            // R 键重新加载场景
+                            KeyCode::KeyR => {
+                                let loaded = load_scene_with(&state.scene_name, &mut |bounds| state.build_bvh(bounds));
+                                match loaded {
+                                    Ok(scene) => state.reload_scene(&scene),
+                                    Err(e) => eprintln!("{:?}", e),
+                                }
+                            }
+                            // P 键截图
+                            KeyCode::KeyP => match state.screenshot(&camera) {
+                                Ok(path) => println!("saved screenshot {}.{{png,exr,json}}", path.display()),
+                                Err(e) => eprintln!("{:?}", e),
+                            },
+                            // Home 键平滑回到初始视角
+                            KeyCode::Home => camera.animate_to(home_pose, HOME_TRANSITION_SECONDS),
+                            // Ctrl+1..9 保存书签，1..9 切换到书签
+                            code => {
+                                let Some(slot) = digit_slot(code) else { return };
+                                let ctrl = pressed_keys.contains(&KeyCode::ControlLeft)
+                                    || pressed_keys.contains(&KeyCode::ControlRight);
+                                if ctrl {
+                                    bookmarks.set(slot, camera.pose());
+                                    match bookmarks.save() {
+                                        Ok(()) => println!("saved bookmark {slot} to {}", bookmarks.path().display()),
+                                        Err(e) => eprintln!("{:?}", e),
+                                    }
+                                } else if let Some(bookmark) = bookmarks.get(slot) {
+                                    println!("bookmark {slot}: {}", bookmark.name);
+                                    camera.animate_to(bookmark.pose, BOOKMARK_TRANSITION_SECONDS);
+                                }
                             }
                         }
-                        last_mouse_pos = Some(*position);
                     }
                 }
-                _ => {}
-            },
-            Event::DeviceEvent { event, .. } => match event {
-                DeviceEvent::MouseWheel { delta } => {
-                    let delta = match delta {
-                        MouseScrollDelta::PixelDelta(delta) => 0.001 * delta.y as f32,
-                        MouseScrollDelta::LineDelta(_, y) => y * 0.1,
-                    };
-                    camera.zoom(delta);
-                    state.renderer.reset_samples();
+                // 窗口失去焦点时不会收到按键释放事件
+                WindowEvent::Focused(false) => pressed_keys.clear(),
+                // 添加鼠标移动处理
+                WindowEvent::CursorMoved { position, .. } => {
+                    cursor_position = Some(*position);
+                    if !mouse_button_pressed {
+                        return;
+                    }
+                    if let Some(last_pos) = last_mouse_pos {
+                        let dx = position.x - last_pos.x;
+                        let dy = position.y - last_pos.y;
+                        
+                        // println!("=== 鼠标移动 ===");
+                        // println!("当前位置: ({}, {})", position.x, position.y);
+                        // println!("上次位置: ({}, {})", last_pos.x, last_pos.y);
+                        // println!("增量: dx={}, dy={}", dx, dy);
+                        
+                        let sensitivity = 0.01;
+                        let du = dx as f32 * sensitivity;
+                        let dv = dy as f32 * (-sensitivity);  // 翻转Y轴
+                        
+                        // println!("left={}, right={}", left_mouse_button_pressed, right_mouse_button_pressed);
+                        
+                        if left_mouse_button_pressed {
+                            let motion = match navigation_mode {
+                                NavigationMode::Orbit => &mut camera_input.orbit,
+                                NavigationMode::Fly => &mut camera_input.look,
+                            };
+                            motion.0 += du;
+                            motion.1 += dv;
+                        }
+                        if right_mouse_button_pressed {
+                            camera_input.pan.0 += du;
+                            camera_input.pan.1 += dv;
+                        }
+                    }
+                    last_mouse_pos = Some(*position);
                 }
-                _ => (),
+                _ => {}
             },
+            Event::DeviceEvent { event: DeviceEvent::MouseWheel { delta }, .. } => {
+                let delta = match delta {
+                    MouseScrollDelta::PixelDelta(delta) => 0.001 * delta.y as f32,
+                    MouseScrollDelta::LineDelta(_, y) => y * 0.1,
+                };
+                camera_input.zoom += delta;
+            }
             Event::AboutToWait => {
                 // RedrawRequested 只会在手动请求时触发
                 // 除非用户请求重绘
@@ -0,0 +1,143 @@
+// mesh.rs
+// Indexed triangle meshes with the per-vertex attributes needed for shading: normals, UVs
+// and tangents for normal mapping.
+use crate::algebra::Vec3;
+
+#[derive(Debug, Clone, Default)]
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    // Per-vertex shading normals. Empty until `compute_normals` is called or the loader
+    // provides them.
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<[f32; 2]>,
+    // Per-vertex tangents along +u; w is the handedness of the (tangent, bitangent, normal)
+    // frame, so that bitangent = cross(normal, tangent) * w points along +v.
+    pub tangents: Vec<[f32; 4]>,
+    // Per-vertex linear RGB colors, multiplied into the albedo of the material. Empty for
+    // white.
+    pub colors: Vec<Vec3>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl Mesh {
+    // A `width` x `depth` rectangle in the xz-plane centered at `center`, facing +y. UVs run
+    // from 0 to `uv_scale` across the rectangle.
+    pub fn plane(center: Vec3, width: f32, depth: f32, uv_scale: f32) -> Mesh {
+        let (hw, hd) = (0.5 * width, 0.5 * depth);
+        let mut mesh = Mesh {
+            positions: vec![
+                center + Vec3::new(-hw, 0., hd),
+                center + Vec3::new(hw, 0., hd),
+                center + Vec3::new(hw, 0., -hd),
+                center + Vec3::new(-hw, 0., -hd),
+            ],
+            uvs: vec![[0., 0.], [uv_scale, 0.], [uv_scale, uv_scale], [0., uv_scale]],
+            indices: vec![[0, 1, 2], [0, 2, 3]],
+            ..Default::default()
+        };
+        mesh.compute_normals();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Axis-aligned box with corners `min` and `max`, with flat faces that each carry the full
+    // 0..1 UV square, like the six quads of `Scene::add_box`.
+    pub fn cuboid(min: Vec3, max: Vec3) -> Mesh {
+        let d = max - min;
+        let (dx, dy, dz) = (Vec3::new(d.x(), 0., 0.), Vec3::new(0., d.y(), 0.), Vec3::new(0., 0., d.z()));
+        let faces = [
+            (Vec3::new(min.x(), min.y(), max.z()), dx, dy),
+            (Vec3::new(max.x(), min.y(), max.z()), -dz, dy),
+            (Vec3::new(max.x(), min.y(), min.z()), -dx, dy),
+            (min, dz, dy),
+            (Vec3::new(min.x(), max.y(), max.z()), dx, -dz),
+            (min, dx, dz),
+        ];
+        let mut mesh = Mesh::default();
+        for (q, u, v) in faces {
+            let base = mesh.positions.len() as u32;
+            mesh.positions.extend([q, q + u, q + u + v, q + v]);
+            mesh.normals.extend([u.cross(&v).normalized(); 4]);
+            mesh.uvs.extend([[0., 0.], [1., 0.], [1., 1.], [0., 1.]]);
+            mesh.indices.extend([[base, base + 1, base + 2], [base, base + 2, base + 3]]);
+        }
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Area-weighted vertex normals from the face normals.
+    pub fn compute_normals(&mut self) {
+        self.normals = self.vertex_normals();
+    }
+
+    // The normals `compute_normals` stores, for callers that cannot modify the mesh.
+    pub fn vertex_normals(&self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::zero(); self.positions.len()];
+        for &[a, b, c] in &self.indices {
+            let [pa, pb, pc] = [a, b, c].map(|i| self.positions[i as usize]);
+            // The cross product's length is twice the triangle area.
+            let face_normal = (pb - pa).cross(&(pc - pa));
+            for i in [a, b, c] {
+                normals[i as usize] += face_normal;
+            }
+        }
+        normals
+            .into_iter()
+            .map(|n| {
+                if n.length_squared() > 0. {
+                    n.normalized()
+                } else {
+                    Vec3::new(0., 1., 0.)
+                }
+            })
+            .collect()
+    }
+
+    // Per-vertex tangents from the UV parameterization (Lengyel, "Computing Tangent Space Basis
+    // Vectors for an Arbitrary Mesh"). Requires normals and UVs.
+    pub fn compute_tangents(&mut self) {
+        self.tangents = self.vertex_tangents(&self.normals);
+    }
+
+    // The tangents `compute_tangents` stores, for the given per-vertex `normals`.
+    pub fn vertex_tangents(&self, normals: &[Vec3]) -> Vec<[f32; 4]> {
+        let vertex_count = self.positions.len();
+        if self.uvs.len() != vertex_count || normals.len() != vertex_count {
+            return vec![[1., 0., 0., 1.]; vertex_count];
+        }
+        let mut tangents = vec![Vec3::zero(); vertex_count];
+        let mut bitangents = vec![Vec3::zero(); vertex_count];
+        for &[a, b, c] in &self.indices {
+            let [pa, pb, pc] = [a, b, c].map(|i| self.positions[i as usize]);
+            let [ta, tb, tc] = [a, b, c].map(|i| self.uvs[i as usize]);
+            let (e1, e2) = (pb - pa, pc - pa);
+            let (du1, dv1) = (tb[0] - ta[0], tb[1] - ta[1]);
+            let (du2, dv2) = (tc[0] - ta[0], tc[1] - ta[1]);
+            let det = du1 * dv2 - du2 * dv1;
+            if det.abs() < 1e-12 {
+                continue;
+            }
+            let r = det.recip();
+            let sdir = (dv2 * e1 - dv1 * e2) * r;
+            let tdir = (du1 * e2 - du2 * e1) * r;
+            for i in [a, b, c] {
+                tangents[i as usize] += sdir;
+                bitangents[i as usize] += tdir;
+            }
+        }
+        (0..vertex_count)
+            .map(|i| {
+                let n = normals[i];
+                // Gram-Schmidt orthogonalize against the normal.
+                let t = tangents[i] - n.dot(&tangents[i]) * n;
+                let t = if t.length_squared() > 1e-12 {
+                    t.normalized()
+                } else {
+                    n.any_perpendicular()
+                };
+                let w = if n.cross(&t).dot(&bitangents[i]) < 0. { -1. } else { 1. };
+                [t.x(), t.y(), t.z(), w]
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,707 @@
+// mesh_file.rs
+// Triangle meshes from PLY (ASCII and binary, with optional normals, UVs and vertex colors)
+// and STL (ASCII and binary) files, as produced by 3D scanners.
+//
+// Polygons are split into triangle fans. STL stores every facet with its own corners, so
+// corners at bit-identical positions are merged to recover a connected, smoothly shaded mesh.
+// Vertex colors are assumed to be sRGB and converted to linear values on load.
+//
+// Inputs with at least PROGRESS_THRESHOLD elements report their progress on stderr, and every
+// load ends with a summary of the mesh size and memory use there, leaving stdout to reports
+// like `--stats --json`.
+use crate::{
+    algebra::Vec3,
+    bvh::BvhNode,
+    mesh::Mesh,
+    scene::{Triangle, Vertex},
+    wide_bvh::WideNode,
+};
+use {
+    anyhow::{Context, Result, anyhow, bail},
+    std::{
+        collections::HashMap,
+        fs::File,
+        io::{BufRead, BufReader, Read, Write},
+        mem::size_of,
+        path::Path,
+    },
+};
+
+const PROGRESS_THRESHOLD: u64 = 100_000;
+const STL_HEADER_SIZE: u64 = 84;
+const STL_FACET_SIZE: u64 = 50;
+
+pub fn load(path: &Path) -> Result<Mesh> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    let reader = BufReader::new(file);
+    let label = path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+    let mesh = match extension.as_str() {
+        "ply" => load_ply(reader, &label),
+        "stl" => load_stl(reader, size, &label),
+        _ => Err(anyhow!("unknown mesh format `.{extension}`, expected .ply or .stl")),
+    }
+    .with_context(|| format!("failed to load {}", path.display()))?;
+    if mesh.positions.is_empty() || mesh.indices.is_empty() {
+        bail!("{} contains no triangles", path.display());
+    }
+    report(&label, &mesh);
+    Ok(mesh)
+}
+
+// Prints the vertex and triangle counts, the memory held by the mesh and an estimate of the
+// storage buffer space it takes once uploaded with its BLAS.
+fn report(label: &str, mesh: &Mesh) {
+    let (vertices, triangles) = (mesh.positions.len(), mesh.indices.len());
+    let cpu = (vertices + mesh.normals.len()) * size_of::<Vec3>()
+        + mesh.uvs.len() * size_of::<[f32; 2]>()
+        + mesh.tangents.len() * size_of::<[f32; 4]>()
+        + mesh.colors.len() * size_of::<Vec3>()
+        + triangles * size_of::<[u32; 3]>();
+    // A binary BLAS has up to two nodes per triangle, its wide version about half a node.
+    let gpu = vertices * size_of::<Vertex>()
+        + triangles * (size_of::<Triangle>() + 2 * size_of::<BvhNode>())
+        + triangles / 2 * size_of::<WideNode>();
+    eprintln!(
+        "loaded {label}: {vertices} vertices, {triangles} triangles, {:.1} MiB in memory, about {:.1} MiB on the GPU",
+        mebibytes(cpu),
+        mebibytes(gpu)
+    );
+}
+
+fn mebibytes(bytes: usize) -> f64 {
+    bytes as f64 / (1024. * 1024.)
+}
+
+// Percentage of `total` items done, printed on one line whenever it changes.
+struct Progress<'a> {
+    label: &'a str,
+    stage: &'a str,
+    total: u64,
+    percent: u64,
+}
+
+impl<'a> Progress<'a> {
+    fn new(label: &'a str, stage: &'a str, total: u64) -> Progress<'a> {
+        Progress {
+            label,
+            stage,
+            total,
+            percent: u64::MAX,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.total >= PROGRESS_THRESHOLD
+    }
+
+    fn update(&mut self, done: u64) {
+        if !self.enabled() {
+            return;
+        }
+        let percent = done * 100 / self.total;
+        if percent != self.percent {
+            self.percent = percent;
+            eprint!("\rloading {}: {} {percent}%", self.label, self.stage);
+            let _ = std::io::stderr().flush();
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.enabled() {
+            self.update(self.total);
+            eprintln!();
+        }
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+// Splits the polygon `corners` into a fan of triangles.
+fn push_polygon(mesh: &mut Mesh, corners: &[u32]) -> Result<()> {
+    if corners.len() < 3 {
+        bail!("a face needs at least 3 corners, not {}", corners.len());
+    }
+    for i in 2..corners.len() {
+        mesh.indices.push([corners[0], corners[i - 1], corners[i]]);
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Copy, Clone)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<PlyType> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            other => bail!("unknown property type `{other}`"),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+
+    // The largest value of integer types, which maps colors to [0, 1].
+    fn color_scale(self) -> f64 {
+        match self {
+            PlyType::I8 => i8::MAX as f64,
+            PlyType::U8 => u8::MAX as f64,
+            PlyType::I16 => i16::MAX as f64,
+            PlyType::U16 => u16::MAX as f64,
+            PlyType::I32 => i32::MAX as f64,
+            PlyType::U32 => u32::MAX as f64,
+            PlyType::F32 | PlyType::F64 => 1.,
+        }
+    }
+}
+
+enum PlyProperty {
+    Scalar(PlyType),
+    // Type of the item count, type of the items.
+    List(PlyType, PlyType),
+}
+
+struct PlyElement {
+    name: String,
+    count: u64,
+    properties: Vec<(String, PlyProperty)>,
+}
+
+impl PlyElement {
+    fn property(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|(name, _)| names.contains(&name.as_str()))
+    }
+}
+
+// Reads the values of the body one at a time, whatever the format.
+struct PlyReader<R> {
+    reader: R,
+    format: PlyFormat,
+    // The rest of the current line of an ASCII body, in reverse order.
+    tokens: Vec<String>,
+}
+
+impl<R: BufRead> PlyReader<R> {
+    fn value(&mut self, ty: PlyType) -> Result<f64> {
+        if self.format == PlyFormat::Ascii {
+            let token = match self.tokens.pop() {
+                Some(token) => token,
+                None => {
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if self.reader.read_line(&mut line)? == 0 {
+                            bail!("unexpected end of file");
+                        }
+                        if !line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    self.tokens = line.split_whitespace().rev().map(str::to_string).collect();
+                    self.tokens.pop().unwrap()
+                }
+            };
+            return token.parse::<f64>().with_context(|| format!("invalid number `{token}`"));
+        }
+
+        let mut bytes = [0u8; 8];
+        let bytes = &mut bytes[..ty.size()];
+        self.reader.read_exact(bytes).context("unexpected end of file")?;
+        if self.format == PlyFormat::BigEndian {
+            bytes.reverse();
+        }
+        Ok(match ty {
+            PlyType::I8 => bytes[0] as i8 as f64,
+            PlyType::U8 => bytes[0] as f64,
+            PlyType::I16 => i16::from_le_bytes(bytes.try_into()?) as f64,
+            PlyType::U16 => u16::from_le_bytes(bytes.try_into()?) as f64,
+            PlyType::I32 => i32::from_le_bytes(bytes.try_into()?) as f64,
+            PlyType::U32 => u32::from_le_bytes(bytes.try_into()?) as f64,
+            PlyType::F32 => f32::from_le_bytes(bytes.try_into()?) as f64,
+            PlyType::F64 => f64::from_le_bytes(bytes.try_into()?),
+        })
+    }
+
+    // Reads one property into `values`, the items of a list or a single scalar.
+    fn property(&mut self, property: &PlyProperty, values: &mut Vec<f64>) -> Result<()> {
+        values.clear();
+        match *property {
+            PlyProperty::Scalar(ty) => values.push(self.value(ty)?),
+            PlyProperty::List(count_type, item_type) => {
+                let count = self.value(count_type)? as usize;
+                for _ in 0..count {
+                    values.push(self.value(item_type)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Ends an element; in ASCII files, every element is on a line of its own.
+    fn end_element(&mut self) {
+        self.tokens.clear();
+    }
+}
+
+fn parse_ply_header(reader: &mut impl BufRead) -> Result<(PlyFormat, Vec<PlyElement>)> {
+    let mut line = String::new();
+    let mut next_line = |line: &mut String| -> Result<()> {
+        line.clear();
+        if reader.read_line(line)? == 0 {
+            bail!("unexpected end of file in the header");
+        }
+        Ok(())
+    };
+    next_line(&mut line)?;
+    if line.trim() != "ply" {
+        bail!("not a PLY file");
+    }
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    loop {
+        next_line(&mut line)?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["end_header"] => break,
+            ["format", name, _version] => {
+                format = Some(match *name {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::LittleEndian,
+                    "binary_big_endian" => PlyFormat::BigEndian,
+                    other => bail!("unknown format `{other}`"),
+                })
+            }
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count.parse().with_context(|| format!("invalid element count `{count}`"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_type, item_type, name] => {
+                let property = PlyProperty::List(PlyType::parse(count_type)?, PlyType::parse(item_type)?);
+                let element = elements.last_mut().ok_or_else(|| anyhow!("property before any element"))?;
+                element.properties.push((name.to_string(), property));
+            }
+            ["property", ty, name] => {
+                let property = PlyProperty::Scalar(PlyType::parse(ty)?);
+                let element = elements.last_mut().ok_or_else(|| anyhow!("property before any element"))?;
+                element.properties.push((name.to_string(), property));
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => bail!("invalid header line `{}`", line.trim()),
+        }
+    }
+    let format = format.ok_or_else(|| anyhow!("missing `format` line"))?;
+    Ok((format, elements))
+}
+
+fn load_ply(mut reader: impl BufRead, label: &str) -> Result<Mesh> {
+    let (format, elements) = parse_ply_header(&mut reader)?;
+    let mut reader = PlyReader {
+        reader,
+        format,
+        tokens: Vec::new(),
+    };
+    let mut mesh = Mesh::default();
+    let mut values = Vec::new();
+    let mut vertex_count = 0;
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                vertex_count = element.count;
+                read_ply_vertices(&mut reader, element, &mut mesh, label)?;
+            }
+            "face" => {
+                let Some(indices) = element.property(&["vertex_indices", "vertex_index"]) else {
+                    bail!("faces without a `vertex_indices` list");
+                };
+                mesh.indices.reserve(element.count as usize);
+                let mut progress = Progress::new(label, "faces", element.count);
+                let mut corners = Vec::new();
+                for i in 0..element.count {
+                    for (p, (_, property)) in element.properties.iter().enumerate() {
+                        reader.property(property, &mut values)?;
+                        if p == indices {
+                            corners.clear();
+                            for &index in &values {
+                                if index < 0. || index >= vertex_count as f64 {
+                                    bail!("face {i} refers to vertex {index}, but there are {vertex_count}");
+                                }
+                                corners.push(index as u32);
+                            }
+                        }
+                    }
+                    reader.end_element();
+                    push_polygon(&mut mesh, &corners).with_context(|| format!("invalid face {i}"))?;
+                    progress.update(i);
+                }
+                progress.finish();
+            }
+            _ => {
+                // Skip elements we have no use for, such as edges or materials.
+                for _ in 0..element.count {
+                    for (_, property) in &element.properties {
+                        reader.property(property, &mut values)?;
+                    }
+                    reader.end_element();
+                }
+            }
+        }
+    }
+    if mesh.normals.iter().any(|n| n.length_squared() == 0.) {
+        let computed = mesh.vertex_normals();
+        for (normal, computed) in mesh.normals.iter_mut().zip(computed) {
+            if normal.length_squared() == 0. {
+                *normal = computed;
+            }
+        }
+    }
+    Ok(mesh)
+}
+
+fn read_ply_vertices<R: BufRead>(
+    reader: &mut PlyReader<R>,
+    element: &PlyElement,
+    mesh: &mut Mesh,
+    label: &str,
+) -> Result<()> {
+    let find = |names: &[&str]| element.property(names);
+    let (Some(x), Some(y), Some(z)) = (find(&["x"]), find(&["y"]), find(&["z"])) else {
+        bail!("vertices without x, y and z");
+    };
+    let normal = (find(&["nx"]), find(&["ny"]), find(&["nz"]));
+    let uv = (find(&["u", "s", "texture_u"]), find(&["v", "t", "texture_v"]));
+    let color = (
+        find(&["red", "r", "diffuse_red"]),
+        find(&["green", "g", "diffuse_green"]),
+        find(&["blue", "b", "diffuse_blue"]),
+    );
+    let color_scale = match color.0.map(|p| &element.properties[p].1) {
+        Some(PlyProperty::Scalar(ty)) => ty.color_scale(),
+        _ => 1.,
+    };
+
+    let count = element.count as usize;
+    mesh.positions.reserve(count);
+    let mut row = vec![0f64; element.properties.len()];
+    let mut values = Vec::new();
+    let mut progress = Progress::new(label, "vertices", element.count);
+    for i in 0..element.count {
+        for (p, (_, property)) in element.properties.iter().enumerate() {
+            reader.property(property, &mut values)?;
+            row[p] = values.first().copied().unwrap_or(0.);
+        }
+        reader.end_element();
+        let vec3 = |x: usize, y: usize, z: usize| Vec3::new(row[x] as f32, row[y] as f32, row[z] as f32);
+        mesh.positions.push(vec3(x, y, z));
+        if let (Some(nx), Some(ny), Some(nz)) = normal {
+            // Zero normals are kept as they are and replaced once the faces are known.
+            let n = vec3(nx, ny, nz);
+            mesh.normals.push(if n.length_squared() > 0. { n.normalized() } else { n });
+        }
+        if let (Some(u), Some(v)) = uv {
+            mesh.uvs.push([row[u] as f32, row[v] as f32]);
+        }
+        if let (Some(r), Some(g), Some(b)) = color {
+            let channel = |c: usize| srgb_to_linear((row[c] / color_scale).clamp(0., 1.) as f32);
+            mesh.colors.push(Vec3::new(channel(r), channel(g), channel(b)));
+        }
+        progress.update(i);
+    }
+    progress.finish();
+    Ok(())
+}
+
+fn load_stl(mut reader: impl BufRead, size: u64, label: &str) -> Result<Mesh> {
+    let mut header = vec![0u8; STL_HEADER_SIZE.min(size) as usize];
+    reader.read_exact(&mut header).context("file too short")?;
+    // Binary files may also start with "solid", so trust the size implied by the facet count.
+    let binary = header.len() as u64 == STL_HEADER_SIZE && {
+        let count = u32::from_le_bytes(header[80..84].try_into()?) as u64;
+        size == STL_HEADER_SIZE + count * STL_FACET_SIZE
+    };
+    let mut merger = CornerMerger::default();
+    if binary {
+        let count = u32::from_le_bytes(header[80..84].try_into()?) as u64;
+        merger.mesh.indices.reserve(count as usize);
+        let mut progress = Progress::new(label, "facets", count);
+        let mut facet = [0u8; STL_FACET_SIZE as usize];
+        for i in 0..count {
+            reader.read_exact(&mut facet).context("unexpected end of file")?;
+            let float = |k: usize| f32::from_le_bytes(facet[4 * k..4 * k + 4].try_into().unwrap());
+            // Skip the facet normal; vertex normals are recomputed from the merged mesh.
+            let corners = [3, 6, 9].map(|k| merger.corner(Vec3::new(float(k), float(k + 1), float(k + 2))));
+            merger.mesh.indices.push(corners);
+            progress.update(i);
+        }
+        progress.finish();
+        return Ok(merger.mesh);
+    }
+
+    if !header.starts_with(b"solid") {
+        bail!("neither an ASCII STL file (starting with `solid`) nor a binary one of the right size");
+    }
+    // The header holds the first bytes of the text; read on from there.
+    let mut reader = BufReader::new(header.as_slice().chain(reader));
+    let mut progress = Progress::new(label, "bytes", size);
+    let mut read = 0;
+    let mut corners = Vec::with_capacity(3);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let length = reader.read_line(&mut line)?;
+        if length == 0 {
+            break;
+        }
+        read += length as u64;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["vertex", coordinates @ ..] => {
+                let p = parse_stl_vertex(coordinates).with_context(|| format!("invalid vertex `{}`", line.trim()))?;
+                corners.push(merger.corner(p));
+            }
+            ["endloop"] => {
+                push_polygon(&mut merger.mesh, &corners).context("invalid facet")?;
+                corners.clear();
+            }
+            _ => {}
+        }
+        progress.update(read);
+    }
+    progress.finish();
+    Ok(merger.mesh)
+}
+
+fn parse_stl_vertex(coordinates: &[&str]) -> Result<Vec3> {
+    let [x, y, z] = coordinates else {
+        bail!("expected 3 coordinates");
+    };
+    Ok(Vec3::new(x.parse()?, y.parse()?, z.parse()?))
+}
+
+// Gives corners at the same position the same vertex.
+#[derive(Default)]
+struct CornerMerger {
+    mesh: Mesh,
+    indices: HashMap<[u32; 3], u32>,
+}
+
+impl CornerMerger {
+    fn corner(&mut self, p: Vec3) -> u32 {
+        let key = [p.x().to_bits(), p.y().to_bits(), p.z().to_bits()];
+        *self.indices.entry(key).or_insert_with(|| {
+            self.mesh.positions.push(p);
+            self.mesh.positions.len() as u32 - 1
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A unit square in the xy-plane as one quad, with normals along +z and a red, a green, a
+    // blue and a white corner.
+    const SQUARE: [([f32; 3], [u8; 3]); 4] = [
+        ([0., 0., 0.], [255, 0, 0]),
+        ([1., 0., 0.], [0, 255, 0]),
+        ([1., 1., 0.], [0, 0, 255]),
+        ([0., 1., 0.], [255, 255, 255]),
+    ];
+
+    fn ply_header(format: &str) -> String {
+        format!(
+            "ply\nformat {format} 1.0\ncomment made by hand\nelement vertex 4\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property float nx\nproperty float ny\nproperty float nz\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n"
+        )
+    }
+
+    fn ascii_ply() -> Vec<u8> {
+        let mut text = ply_header("ascii");
+        for ([x, y, z], [r, g, b]) in SQUARE {
+            text += &format!("{x} {y} {z} 0 0 1 {r} {g} {b}\n");
+        }
+        text += "4 0 1 2 3\n";
+        text.into_bytes()
+    }
+
+    fn binary_ply(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut bytes = ply_header(format).into_bytes();
+        let float = |bytes: &mut Vec<u8>, f: f32| {
+            bytes.extend(if big_endian { f.to_be_bytes() } else { f.to_le_bytes() })
+        };
+        for (position, color) in SQUARE {
+            for f in position.into_iter().chain([0., 0., 1.]) {
+                float(&mut bytes, f);
+            }
+            bytes.extend(color);
+        }
+        bytes.push(4);
+        for i in 0..4i32 {
+            bytes.extend(if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        bytes
+    }
+
+    fn xyz(v: Vec3) -> [f32; 3] {
+        [v.x(), v.y(), v.z()]
+    }
+
+    fn ply(bytes: &[u8]) -> Result<Mesh> {
+        load_ply(bytes, "test.ply")
+    }
+
+    fn stl(bytes: &[u8]) -> Result<Mesh> {
+        load_stl(bytes, bytes.len() as u64, "test.stl")
+    }
+
+    fn error(result: Result<Mesh>) -> String {
+        format!("{:#}", result.expect_err("loading should fail"))
+    }
+
+    fn assert_square(mesh: &Mesh) {
+        assert_eq!(mesh.positions.iter().map(|&p| xyz(p)).collect::<Vec<_>>(), SQUARE.map(|(p, _)| p));
+        assert_eq!(mesh.indices, [[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.normals.iter().all(|&n| xyz(n) == [0., 0., 1.]));
+        let colors: Vec<_> = mesh.colors.iter().map(|&c| xyz(c)).collect();
+        assert_eq!(colors, SQUARE.map(|(_, c)| c.map(|c| c as f32 / 255.)));
+    }
+
+    #[test]
+    fn ply_formats_load_the_same_mesh() {
+        assert_square(&ply(&ascii_ply()).unwrap());
+        assert_square(&ply(&binary_ply(false)).unwrap());
+        assert_square(&ply(&binary_ply(true)).unwrap());
+    }
+
+    #[test]
+    fn ply_zero_normals_are_computed() {
+        let text = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                    property float z\nproperty float nx\nproperty float ny\nproperty float nz\n\
+                    element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+                    0 0 0 0 0 0\n1 0 0 0 0 2\n0 1 0 0 0 0\n3 0 1 2\n";
+        let mesh = ply(text.as_bytes()).unwrap();
+        assert!(mesh.normals.iter().all(|&n| xyz(n) == [0., 0., 1.]));
+    }
+
+    #[test]
+    fn ply_errors() {
+        assert!(error(ply(b"solid cube\n")).contains("not a PLY file"));
+        assert!(error(ply(b"ply\nformat utf8 1.0\nend_header\n")).contains("unknown format `utf8`"));
+        assert!(error(ply(b"ply\nformat ascii 1.0\nelement vertex four\n")).contains("invalid element count"));
+        assert!(error(ply(b"ply\nformat ascii 1.0\nproperty float x\n")).contains("property before any element"));
+        assert!(error(ply(b"ply\nformat ascii 1.0\nelement vertex 3\n")).contains("end of file in the header"));
+
+        let bytes = ascii_ply();
+        let truncated = &bytes[..bytes.len() - "2 3\n".len()];
+        assert!(error(ply(truncated)).contains("unexpected end of file"));
+        let bytes = binary_ply(false);
+        assert!(error(ply(&bytes[..bytes.len() - 1])).contains("unexpected end of file"));
+
+        let mut vertices = ply_header("ascii");
+        for ([x, y, z], [r, g, b]) in SQUARE {
+            vertices += &format!("{x} {y} {z} 0 0 1 {r} {g} {b}\n");
+        }
+        let face = |face: &str| ply(format!("{vertices}{face}\n").as_bytes());
+        assert!(error(face("2 0 1")).contains("invalid face 0: a face needs at least 3 corners, not 2"));
+        assert!(error(face("3 0 1 4")).contains("face 0 refers to vertex 4, but there are 4"));
+        assert!(error(face("3 0 1 x")).contains("invalid number `x`"));
+    }
+
+    // The square as two STL facets, which share the corners of their diagonal.
+    const FACETS: [[[f32; 3]; 3]; 2] = [
+        [SQUARE[0].0, SQUARE[1].0, SQUARE[2].0],
+        [SQUARE[0].0, SQUARE[2].0, SQUARE[3].0],
+    ];
+
+    fn ascii_stl() -> String {
+        let mut text = "solid square\n".to_string();
+        for facet in FACETS {
+            text += "facet normal 0 0 1\n  outer loop\n";
+            for [x, y, z] in facet {
+                text += &format!("    vertex {x} {y} {z}\n");
+            }
+            text += "  endloop\nendfacet\n";
+        }
+        text + "endsolid square\n"
+    }
+
+    fn binary_stl(header: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0u8; STL_HEADER_SIZE as usize];
+        bytes[..header.len()].copy_from_slice(header);
+        bytes[80..84].copy_from_slice(&(FACETS.len() as u32).to_le_bytes());
+        for facet in FACETS {
+            for f in [0., 0., 1.].into_iter().chain(facet.into_iter().flatten()) {
+                bytes.extend(f32::to_le_bytes(f));
+            }
+            bytes.extend([0, 0]);
+        }
+        bytes
+    }
+
+    fn assert_merged_square(mesh: &Mesh) {
+        assert_eq!(mesh.positions.iter().map(|&p| xyz(p)).collect::<Vec<_>>(), SQUARE.map(|(p, _)| p));
+        assert_eq!(mesh.indices, [[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.normals.is_empty() && mesh.colors.is_empty());
+    }
+
+    #[test]
+    fn stl_formats_merge_shared_corners() {
+        assert_merged_square(&stl(ascii_stl().as_bytes()).unwrap());
+        assert_merged_square(&stl(&binary_stl(b"made by hand")).unwrap());
+        // Binary files that start like ASCII ones are told apart by their size.
+        assert_merged_square(&stl(&binary_stl(b"solid square")).unwrap());
+    }
+
+    #[test]
+    fn stl_errors() {
+        assert!(error(stl(b"")).contains("neither an ASCII STL file"));
+        assert!(error(stl(b"cube\n")).contains("neither an ASCII STL file"));
+        let bytes = binary_stl(b"made by hand");
+        assert!(error(stl(&bytes[..bytes.len() - 1])).contains("neither an ASCII STL file"));
+        // A file whose size promises more facets than the reader delivers.
+        let truncated = load_stl(&bytes[..bytes.len() - 1], bytes.len() as u64, "test.stl");
+        assert!(error(truncated).contains("unexpected end of file"));
+
+        let text = ascii_stl().replace("vertex 1 1 0", "vertex 1 one 0");
+        assert!(error(stl(text.as_bytes())).contains("invalid vertex `vertex 1 one 0`"));
+        let text = ascii_stl().replacen("    vertex 1 1 0\n", "", 1);
+        assert!(error(stl(text.as_bytes())).contains("invalid facet: a face needs at least 3 corners, not 2"));
+    }
+}
@@ -0,0 +1,256 @@
+// microfacet.rs
+// CPU reference of the GGX microfacet BSDFs in shaders.wgsl, used by the furnace test in
+// furnace.rs. The functions mirror their WGSL counterparts one to one (per color channel);
+// keep the two in sync.
+//
+// Directions are in the local shading frame with the normal along +z, and `wo` points away
+// from the surface towards the viewer.
+use crate::algebra::Vec3;
+use std::f32::consts::PI;
+
+pub fn ggx_d(m: &Vec3, alpha: f32) -> f32 {
+    if m.z() <= 0. {
+        return 0.;
+    }
+    let a2 = alpha * alpha;
+    let d = m.z() * m.z() * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+// Smith's Lambda for the GGX distribution.
+pub fn ggx_lambda(w: &Vec3, alpha: f32) -> f32 {
+    let cos2 = w.z() * w.z();
+    let tan2 = (1. - cos2).max(0.) / cos2.max(1e-12);
+    0.5 * ((1. + alpha * alpha * tan2).sqrt() - 1.)
+}
+
+pub fn ggx_g1(w: &Vec3, alpha: f32) -> f32 {
+    1. / (1. + ggx_lambda(w, alpha))
+}
+
+// Height-correlated masking-shadowing.
+pub fn ggx_g2(wo: &Vec3, wi: &Vec3, alpha: f32) -> f32 {
+    1. / (1. + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha))
+}
+
+// Density of the normals visible from `wo`, the pdf of `sample_ggx_vndf`.
+pub fn ggx_vndf_pdf(wo: &Vec3, m: &Vec3, alpha: f32) -> f32 {
+    ggx_g1(wo, alpha) * wo.dot(m).max(0.) * ggx_d(m, alpha) / wo.z()
+}
+
+// Heitz, "Sampling the GGX Distribution of Visible Normals", JCGT 2018.
+pub fn sample_ggx_vndf(wo: &Vec3, alpha: f32, u: (f32, f32)) -> Vec3 {
+    let vh = Vec3::new(alpha * wo.x(), alpha * wo.y(), wo.z()).normalized();
+    let lensq = vh.x() * vh.x() + vh.y() * vh.y();
+    let t1 = if lensq > 0. {
+        Vec3::new(-vh.y(), vh.x(), 0.) / lensq.sqrt()
+    } else {
+        Vec3::new(1., 0., 0.)
+    };
+    let t2 = vh.cross(&t1);
+    let r = u.0.sqrt();
+    let phi = 2. * PI * u.1;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1. + vh.z());
+    let p2 = (1. - s) * (1. - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1. - p1 * p1 - p2 * p2).max(0.).sqrt() * vh;
+    Vec3::new(alpha * nh.x(), alpha * nh.y(), nh.z().max(0.)).normalized()
+}
+
+// Fresnel reflectance of a dielectric interface for the relative IOR `eta` = n_t / n_i.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1. {
+        return 1.;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+// Fresnel reflectance of a conductor with the complex IOR `eta + i k`.
+pub fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let c2 = cos_i * cos_i;
+    let s2 = 1. - c2;
+    let t0 = eta * eta - k * k - s2;
+    let a2b2 = (t0 * t0 + 4. * eta * eta * k * k).sqrt();
+    let t1 = a2b2 + c2;
+    let a = (0.5 * (a2b2 + t0)).max(0.).sqrt();
+    let t2 = 2. * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = c2 * a2b2 + s2 * s2;
+    let t4 = t2 * s2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BsdfSample {
+    pub wi: Vec3,
+    // BSDF * |cos(wi)| / pdf, zero if the sample was absorbed.
+    pub weight: f32,
+    pub pdf: f32,
+}
+
+impl BsdfSample {
+    fn absorbed(wi: Vec3) -> BsdfSample {
+        BsdfSample {
+            wi,
+            weight: 0.,
+            pdf: 0.,
+        }
+    }
+}
+
+// GGX metal. `fresnel` is the complex IOR `(eta, k)`, or None for a perfect reflector, which
+// is what the white furnace test needs.
+#[derive(Debug, Copy, Clone)]
+pub struct Conductor {
+    pub alpha: f32,
+    pub fresnel: Option<(f32, f32)>,
+}
+
+impl Conductor {
+    fn fresnel(&self, cos_i: f32) -> f32 {
+        match self.fresnel {
+            Some((eta, k)) => fresnel_conductor(cos_i, eta, k),
+            None => 1.,
+        }
+    }
+
+    pub fn eval(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return 0.;
+        }
+        let m = (wo + wi).normalized();
+        self.fresnel(wo.dot(&m)) * ggx_d(&m, self.alpha) * ggx_g2(wo, wi, self.alpha)
+            / (4. * wo.z() * wi.z())
+    }
+
+    pub fn pdf(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return 0.;
+        }
+        let m = (wo + wi).normalized();
+        ggx_vndf_pdf(wo, &m, self.alpha) / (4. * wo.dot(&m))
+    }
+
+    pub fn sample(&self, wo: &Vec3, u: (f32, f32)) -> BsdfSample {
+        let m = sample_ggx_vndf(wo, self.alpha, u);
+        let wi = reflect(&-*wo, &m);
+        if wi.z() <= 0. {
+            return BsdfSample::absorbed(wi);
+        }
+        let wo_m = wo.dot(&m);
+        BsdfSample {
+            wi,
+            weight: self.fresnel(wo_m) * ggx_g2(wo, &wi, self.alpha) / ggx_g1(wo, self.alpha),
+            pdf: ggx_vndf_pdf(wo, &m, self.alpha) / (4. * wo_m),
+        }
+    }
+}
+
+// GGX glass (Walter et al. 2007). `eta` is n_t / n_i for light arriving from the side of +z.
+#[derive(Debug, Copy, Clone)]
+pub struct RoughDielectric {
+    pub alpha: f32,
+    pub eta: f32,
+}
+
+impl RoughDielectric {
+    fn refraction_half_vector(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let m = (wo + self.eta * wi).normalized();
+        if m.z() < 0. { -m } else { m }
+    }
+
+    pub fn eval(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        let (alpha, eta) = (self.alpha, self.eta);
+        if wo.z() <= 0. || wi.z() == 0. {
+            return 0.;
+        }
+        if wi.z() > 0. {
+            let m = (wo + wi).normalized();
+            let f = fresnel_dielectric(wo.dot(&m), eta);
+            return f * ggx_d(&m, alpha) * ggx_g2(wo, wi, alpha) / (4. * wo.z() * wi.z());
+        }
+        let m = self.refraction_half_vector(wo, wi);
+        let (wo_m, wi_m) = (wo.dot(&m), wi.dot(&m));
+        if wo_m <= 0. || wi_m >= 0. {
+            return 0.;
+        }
+        let f = fresnel_dielectric(wo_m, eta);
+        let denom = wo_m + eta * wi_m;
+        (1. - f) * ggx_d(&m, alpha) * ggx_g2(wo, wi, alpha) * wi_m.abs() * wo_m * eta * eta
+            / (wo.z() * wi.z().abs() * denom * denom)
+    }
+
+    pub fn pdf(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        let (alpha, eta) = (self.alpha, self.eta);
+        if wo.z() <= 0. || wi.z() == 0. {
+            return 0.;
+        }
+        if wi.z() > 0. {
+            let m = (wo + wi).normalized();
+            let wo_m = wo.dot(&m);
+            return fresnel_dielectric(wo_m, eta) * ggx_vndf_pdf(wo, &m, alpha) / (4. * wo_m);
+        }
+        let m = self.refraction_half_vector(wo, wi);
+        let (wo_m, wi_m) = (wo.dot(&m), wi.dot(&m));
+        if wo_m <= 0. || wi_m >= 0. {
+            return 0.;
+        }
+        let denom = wo_m + eta * wi_m;
+        let jacobian = eta * eta * wi_m.abs() / (denom * denom);
+        (1. - fresnel_dielectric(wo_m, eta)) * ggx_vndf_pdf(wo, &m, alpha) * jacobian
+    }
+
+    // `u.2` chooses between reflection and refraction in proportion to the Fresnel term.
+    pub fn sample(&self, wo: &Vec3, u: (f32, f32, f32)) -> BsdfSample {
+        let (alpha, eta) = (self.alpha, self.eta);
+        let m = sample_ggx_vndf(wo, alpha, (u.0, u.1));
+        let wo_m = wo.dot(&m);
+        let f = fresnel_dielectric(wo_m, eta);
+        let vndf_pdf = ggx_vndf_pdf(wo, &m, alpha);
+        if u.2 < f {
+            let wi = reflect(&-*wo, &m);
+            if wi.z() <= 0. {
+                return BsdfSample::absorbed(wi);
+            }
+            return BsdfSample {
+                wi,
+                weight: ggx_g2(wo, &wi, alpha) / ggx_g1(wo, alpha),
+                pdf: f * vndf_pdf / (4. * wo_m),
+            };
+        }
+        let Some(wi) = refract(&-*wo, &m, 1. / eta) else {
+            return BsdfSample::absorbed(Vec3::zero());
+        };
+        if wi.z() >= 0. {
+            return BsdfSample::absorbed(wi);
+        }
+        let wi_m = wi.dot(&m);
+        let denom = wo_m + eta * wi_m;
+        let jacobian = eta * eta * wi_m.abs() / (denom * denom);
+        BsdfSample {
+            wi,
+            weight: ggx_g2(wo, &wi, alpha) / ggx_g1(wo, alpha),
+            pdf: (1. - f) * vndf_pdf * jacobian,
+        }
+    }
+}
+
+// Same as WGSL's `reflect`.
+fn reflect(e: &Vec3, n: &Vec3) -> Vec3 {
+    e - 2. * n.dot(e) * n
+}
+
+// Same as WGSL's `refract`, except that total internal reflection yields None.
+fn refract(e: &Vec3, n: &Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = n.dot(e);
+    let k = 1. - eta * eta * (1. - cos_i * cos_i);
+    if k < 0. {
+        return None;
+    }
+    Some(eta * e - (eta * cos_i + k.sqrt()) * n)
+}
@@ -1,49 +1,196 @@
 // render.rs
-use crate::camera::{Camera, CameraUniforms};
-use crate::wgpu;
-use bytemuck::{Pod, Zeroable};
+use crate::{
+    bvh::{Aabb, Bvh},
+    camera::{Camera, CameraUniforms},
+    inspector::{self, InspectedPath},
+    scene::{Fog, Scene},
+    scene_diff::{SceneBuffer, SceneDiff, SceneMirror},
+    volume::DensityGrid,
+};
+use {
+    anyhow::{Context, Result, bail},
+    bytemuck::{Pod, Zeroable},
+    image::{RgbaImage, imageops::FilterType},
+    std::time::{Duration, Instant},
+    wgpu::util::DeviceExt,
+};
 
 #[derive(Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
 pub struct Uniforms {
     camera: CameraUniforms,
+    // Camera of the previous frame, used to reproject the accumulated history.
+    prev_camera: CameraUniforms,
     width: u32,
     height: u32,
     frame_count: u32,
+    temporal: u32,
+    fog: Fog,
+    // Constant background radiance in rgb; w is 0 to use the sky gradient instead.
+    background: [f32; 4],
+    // Non-zero to traverse the mesh BLAS in their 4-wide layout.
+    wide_bvh: u32,
+    debug_mode: u32,
+    // View depth shown as mid-gray in `DebugMode::Depth`, the camera's orbit distance.
+    depth_scale: f32,
     _pad: u32,
+    // Pixel and seed of a path to record for the inspector, for one frame if `inspect` is set.
+    inspect_pixel: [u32; 2],
+    inspect_seed: u32,
+    inspect: u32,
+}
+
+// What the renderer shows instead of the radiance, see `debug_sample` in shaders.wgsl. The
+// heatmaps go from blue to red and count the work for the primary ray, or the bounces of the
+// whole path.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugMode {
+    Off,
+    ShadingNormals,
+    GeometricNormals,
+    Uvs,
+    Albedo,
+    Depth,
+    // Red at DEBUG_MAX_NODES = 128 in shaders.wgsl. Counts BVH nodes, the bounding boxes of
+    // SDFs, CSG objects and heightfields, CSG operations and heightfield blocks.
+    NodesVisited,
+    // Red at DEBUG_MAX_TESTS = 64. Counts primitives and triangles, every shape of an SDF at
+    // every march step, CSG leaves and two triangles per heightfield cell.
+    PrimitiveTests,
+    // Red at DEBUG_MAX_PATH_LENGTH = 16 bounces.
+    PathLength,
+    NanInf,
+}
+
+impl DebugMode {
+    const ALL: [DebugMode; 10] = [
+        DebugMode::Off,
+        DebugMode::ShadingNormals,
+        DebugMode::GeometricNormals,
+        DebugMode::Uvs,
+        DebugMode::Albedo,
+        DebugMode::Depth,
+        DebugMode::NodesVisited,
+        DebugMode::PrimitiveTests,
+        DebugMode::PathLength,
+        DebugMode::NanInf,
+    ];
+
+    // The mode after this one, or before it if `backwards`, wrapping around.
+    pub fn cycle(self, backwards: bool) -> DebugMode {
+        let count = Self::ALL.len();
+        let step = if backwards { count - 1 } else { 1 };
+        Self::ALL[(self as usize + step) % count]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugMode::Off => "off",
+            DebugMode::ShadingNormals => "shading normals",
+            DebugMode::GeometricNormals => "geometric normals",
+            DebugMode::Uvs => "uvs",
+            DebugMode::Albedo => "albedo",
+            DebugMode::Depth => "linear depth",
+            DebugMode::NodesVisited => "nodes visited (blue 0, red 128)",
+            DebugMode::PrimitiveTests => "primitive tests per ray (blue 0, red 64)",
+            DebugMode::PathLength => "path length (blue 0, red 16 bounces)",
+            DebugMode::NanInf => "nan (magenta) and inf (cyan) samples",
+        }
+    }
 }
 
 pub struct PathTracer {
     uniforms: Uniforms,
     uniform_buffer: wgpu::Buffer,
+    // Counts every submitted frame and picks the ping-pong bind group. Unlike
+    // `uniforms.frame_count` it is never reset, so the texture written last frame is
+    // always the one read as history.
+    frame_index: u32,
+    // Time of the last sample reset, to report how long the current image took to converge.
+    reset_time: Instant,
+    radiance_samples: [wgpu::Texture; 2],
     display_pipeline: wgpu::RenderPipeline,
     display_bind_groups: [wgpu::BindGroup; 2],
+    scene_bind_group: wgpu::BindGroup,
+    // What the scene buffers hold, to update them in place after edits.
+    scene_mirror: SceneMirror,
+    scene_buffers: EditableBuffers,
+    // Written by the shader when a pixel is inspected and copied to `path_readback`.
+    path_record: wgpu::Buffer,
+    path_readback: wgpu::Buffer,
+    inspection: Option<Result<InspectedPath>>,
+}
+
+// The scene buffers that `SceneMirror` updates in place.
+struct EditableBuffers {
+    spheres: wgpu::Buffer,
+    sphere_nodes: wgpu::Buffer,
+    quads: wgpu::Buffer,
+    shapes: wgpu::Buffer,
+    shape_nodes: wgpu::Buffer,
+    instances: wgpu::Buffer,
+    tlas_nodes: wgpu::Buffer,
+    materials: wgpu::Buffer,
+}
+
+impl EditableBuffers {
+    fn get(&self, buffer: SceneBuffer) -> &wgpu::Buffer {
+        match buffer {
+            SceneBuffer::Spheres => &self.spheres,
+            SceneBuffer::SphereNodes => &self.sphere_nodes,
+            SceneBuffer::Quads => &self.quads,
+            SceneBuffer::Shapes => &self.shapes,
+            SceneBuffer::ShapeNodes => &self.shape_nodes,
+            SceneBuffer::Instances => &self.instances,
+            SceneBuffer::TlasNodes => &self.tlas_nodes,
+            SceneBuffer::Materials => &self.materials,
+        }
+    }
 }
 
 impl PathTracer {
     pub fn new(
         device: &wgpu::Device,
-        _queue: &wgpu::Queue,
+        queue: &wgpu::Queue,
         surface_format: wgpu::TextureFormat,
         width: u32,
         height: u32,
-    ) -> PathTracer {
+        scene: &Scene,
+        build: &mut dyn FnMut(&[Aabb]) -> Bvh,
+    ) -> Result<PathTracer> {
+        check_volume_atlas(device, &scene.grids)?;
         device.on_uncaptured_error(Box::new(|error| {
             panic!("Aborting due to an error: {}", error);
         }));
 
         let shader_module = compile_shader_module(device);
 
+        let scene_mirror = SceneMirror::new(scene, build);
+        let (scene_layout, scene_bind_group, scene_buffers) =
+            create_scene_bind_group(device, queue, scene, &scene_mirror);
         let (display_pipeline, display_layout) =
-            create_display_pipeline(device, &shader_module, surface_format);
+            create_display_pipeline(device, &shader_module, surface_format, &scene_layout);
 
         // Initialize the uniform buffer.
         let uniforms = Uniforms {
             camera: CameraUniforms::zeroed(),
+            prev_camera: CameraUniforms::zeroed(),
             width,
             height,
             frame_count: 0,
+            temporal: 0,
+            fog: scene.fog,
+            background: match scene.background {
+                Some(color) => [color.x(), color.y(), color.z(), 1.],
+                None => [0.; 4],
+            },
+            wide_bvh: 0,
+            debug_mode: DebugMode::Off as u32,
+            depth_scale: 1.,
             _pad: 0,
+            inspect_pixel: [0; 2],
+            inspect_seed: 0,
+            inspect: 0,
         };
 
         let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
@@ -53,7 +200,19 @@ impl PathTracer {
             mapped_at_creation: false,
         });
 
-        let radiance_samples = create_sample_texture(&device, width, height);
+        let radiance_samples = create_sample_texture(device, width, height);
+        let path_record = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("path record"),
+            size: inspector::RECORD_SIZE as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let path_readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("path readback"),
+            size: inspector::RECORD_SIZE as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
         // uniform_buffer
         //     .slice(..)
         //     .get_mapped_range_mut()
@@ -61,22 +220,158 @@ impl PathTracer {
         // uniform_buffer.unmap();
 
         let display_bind_groups = create_display_bind_groups(
-            &device,
+            device,
             &display_layout,
             &radiance_samples,
             &uniform_buffer,
+            &path_record,
         );
 
-        PathTracer {
+        Ok(PathTracer {
             uniforms,
             uniform_buffer,
+            frame_index: 0,
+            reset_time: Instant::now(),
+            radiance_samples,
             display_pipeline,
             display_bind_groups,
+            scene_bind_group,
+            scene_mirror,
+            scene_buffers,
+            path_record,
+            path_readback,
+            inspection: None,
+        })
+    }
+
+    // Uploads the edits between the scene the renderer holds and `scene` with as few buffer
+    // writes as possible. Accumulation restarts only if something changed. On
+    // `SceneDiff::Rebuild` nothing is written and the caller needs a new renderer.
+    pub fn update_scene(&mut self, queue: &wgpu::Queue, scene: &Scene) -> SceneDiff {
+        let diff = self.scene_mirror.diff(scene);
+        if let SceneDiff::Update(writes) = &diff
+            && !writes.is_empty()
+        {
+            for write in writes {
+                queue.write_buffer(self.scene_buffers.get(write.buffer), write.offset, &write.data);
+            }
+            self.reset_samples();
         }
+        diff
     }
 
     pub fn reset_samples(&mut self) {
         self.uniforms.frame_count = 0;
+        self.reset_time = Instant::now();
+    }
+
+    // Number of samples per pixel accumulated since the last reset.
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.uniforms.frame_count
+    }
+
+    // Wall-clock time spent accumulating the current image.
+    pub fn render_time(&self) -> Duration {
+        self.reset_time.elapsed()
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.uniforms.width, self.uniforms.height)
+    }
+
+    // Copies the most recently written accumulation texture back to the CPU. Returns the
+    // linear radiance average of every pixel, row by row; the alpha channel holds the
+    // primary hit depth.
+    pub fn read_radiance(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<[f32; 4]> {
+        // Bind group `i` writes to texture `1 - i`, see `create_display_bind_groups`.
+        let texture = &self.radiance_samples[1 - (self.frame_index % 2) as usize];
+        let (width, height) = self.size();
+        let unpadded_bytes_per_row = width * std::mem::size_of::<[f32; 4]>() as u32;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("radiance readback"),
+            size: (bytes_per_row * height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("radiance readback"),
+        });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("failed to map the readback buffer");
+        });
+        device.poll(wgpu::Maintain::Wait);
+
+        let data = slice.get_mapped_range();
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for row in data.chunks_exact(bytes_per_row as usize) {
+            let row = &row[..unpadded_bytes_per_row as usize];
+            pixels.extend_from_slice(bytemuck::cast_slice::<u8, [f32; 4]>(row));
+        }
+        drop(data);
+        buffer.unmap();
+        pixels
+    }
+
+    // With temporal accumulation enabled, a reset no longer discards the history: the
+    // first frame after it reprojects the previous image and blends it in with an
+    // exponential moving average.
+    pub fn set_temporal(&mut self, enabled: bool) {
+        self.uniforms.temporal = enabled as u32;
+    }
+
+    pub fn temporal(&self) -> bool {
+        self.uniforms.temporal != 0
+    }
+
+    // Switches the mesh BLAS between the binary layout and the quantized 4-wide one. Both
+    // find the same hits, so the accumulated image stays valid.
+    pub fn set_wide_bvh(&mut self, enabled: bool) {
+        self.uniforms.wide_bvh = enabled as u32;
+    }
+
+    pub fn wide_bvh(&self) -> bool {
+        self.uniforms.wide_bvh != 0
+    }
+
+    // Accumulation restarts, since the samples of different modes do not mix.
+    pub fn set_debug_mode(&mut self, mode: DebugMode) {
+        self.uniforms.debug_mode = mode as u32;
+        self.reset_samples();
+    }
+
+    pub fn debug_mode(&self) -> DebugMode {
+        DebugMode::ALL[self.uniforms.debug_mode as usize]
+    }
+
+    // Records the path of pixel (x, y) during the next frame, traced with the RNG seeded by
+    // `seed` instead of the sample index. The result is available from `take_inspection`
+    // once that frame has been rendered.
+    pub fn inspect_pixel(&mut self, x: u32, y: u32, seed: u32) {
+        self.uniforms.inspect_pixel = [x, y];
+        self.uniforms.inspect_seed = seed;
+        self.uniforms.inspect = 1;
+    }
+
+    pub fn take_inspection(&mut self) -> Option<Result<InspectedPath>> {
+        self.inspection.take()
     }
 
     pub fn render_frame(
@@ -86,8 +381,11 @@ impl PathTracer {
         queue: &wgpu::Queue,
         target: &wgpu::TextureView,
     ) {
+        self.uniforms.prev_camera = self.uniforms.camera;
         self.uniforms.camera = *camera.uniforms();
-        *&mut self.uniforms.frame_count += 1;
+        self.uniforms.depth_scale = camera.pose().distance;
+        self.uniforms.frame_count += 1;
+        self.frame_index += 1;
         queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&self.uniforms));
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("render frame"),
@@ -108,14 +406,58 @@ impl PathTracer {
         // render_pass.set_bind_group(0, &self.display_bind_group, &[]);
         render_pass.set_bind_group(
             0,
-            &self.display_bind_groups[(self.uniforms.frame_count % 2) as usize],
+            &self.display_bind_groups[(self.frame_index % 2) as usize],
             &[],
         );
+        render_pass.set_bind_group(1, &self.scene_bind_group, &[]);
         render_pass.draw(0..6, 0..1);
         drop(render_pass);
+        let inspecting = self.uniforms.inspect != 0;
+        if inspecting {
+            let size = inspector::RECORD_SIZE as u64;
+            encoder.copy_buffer_to_buffer(&self.path_record, 0, &self.path_readback, 0, size);
+        }
         let command_buffer = encoder.finish();
         queue.submit(Some(command_buffer));
+        if inspecting {
+            self.uniforms.inspect = 0;
+            self.inspection = Some(self.read_path_record(device));
+        }
     }
+
+    // Waits for the frame that recorded a path to finish.
+    fn read_path_record(&self, device: &wgpu::Device) -> Result<InspectedPath> {
+        let slice = self.path_readback.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv()?.context("failed to map the path readback buffer")?;
+        let path = InspectedPath::from_bytes(&slice.get_mapped_range());
+        self.path_readback.unmap();
+        Ok(path)
+    }
+}
+
+// A device without a window for the check and benchmark modes, or None if there is no adapter.
+pub async fn connect_headless(max_storage_buffers: u32) -> Option<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::default();
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            compatible_surface: None,
+        })
+        .await?;
+    let descriptor = wgpu::DeviceDescriptor {
+        required_limits: wgpu::Limits {
+            max_storage_buffers_per_shader_stage: max_storage_buffers,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    adapter.request_device(&descriptor, None).await.ok()
 }
 
 fn compile_shader_module(device: &wgpu::Device) -> wgpu::ShaderModule {
@@ -131,6 +473,7 @@ fn create_display_pipeline(
     device: &wgpu::Device,
     shader_module: &wgpu::ShaderModule,
     surface_format: wgpu::TextureFormat,
+    scene_layout: &wgpu::BindGroupLayout,
 ) -> (wgpu::RenderPipeline, wgpu::BindGroupLayout) {
     let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
         label: None,
@@ -167,6 +510,17 @@ fn create_display_pipeline(
                 },
                 count: None,
             },
+            // path record of the pixel inspector
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
         ],
     });
 
@@ -174,7 +528,7 @@ fn create_display_pipeline(
         label: Some("display"),
         layout: Some(
             &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                bind_group_layouts: &[&bind_group_layout],
+                bind_group_layouts: &[&bind_group_layout, scene_layout],
                 ..Default::default()
             }),
         ),
@@ -218,7 +572,9 @@ fn create_sample_texture(device: &wgpu::Device, width: u32, height: u32) -> [wgp
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
         format: wgpu::TextureFormat::Rgba32Float,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
         view_formats: &[],
     };
     [device.create_texture(&desc), device.create_texture(&desc)]
@@ -229,6 +585,7 @@ fn create_display_bind_groups(
     layout: &wgpu::BindGroupLayout,
     textures: &[wgpu::Texture; 2],
     uniform_buffer: &wgpu::Buffer,
+    path_record: &wgpu::Buffer,
 ) -> [wgpu::BindGroup; 2] {
     let views = [
         textures[0].create_view(&wgpu::TextureViewDescriptor::default()),
@@ -256,6 +613,10 @@ fn create_display_bind_groups(
                     binding: 2,
                     resource: wgpu::BindingResource::TextureView(&views[1]),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: path_record.as_entire_binding(),
+                },
             ],
         }),
         // Bind group with view[1] assigned to binding 1 and view[0] assigned to binding 2.
@@ -279,7 +640,371 @@ fn create_display_bind_groups(
                     binding: 2,
                     resource: wgpu::BindingResource::TextureView(&views[0]),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: path_record.as_entire_binding(),
+                },
             ],
         }),
     ]
 }
+
+// Bindings of the storage buffers in the scene bind group (group 1). The others hold the
+// image textures, their sampler and the volume atlas.
+const SCENE_STORAGE_BUFFERS: [u32; 21] = [0, 1, 2, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23];
+
+// Storage buffers the fragment shader binds: the scene's and the path record of group 0. The
+// device has to be requested with this limit, the default is 8.
+pub const MAX_STORAGE_BUFFERS: u32 = SCENE_STORAGE_BUFFERS.len() as u32 + 1;
+
+// Uploads the scene description to GPU storage buffers and the image texture array.
+fn create_scene_bind_group(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    scene: &Scene,
+    mirror: &SceneMirror,
+) -> (wgpu::BindGroupLayout, wgpu::BindGroup, EditableBuffers) {
+    let storage_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    let mut entries: Vec<_> = SCENE_STORAGE_BUFFERS.iter().map(|&binding| storage_entry(binding)).collect();
+    entries.extend([
+        wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 9,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D3,
+                multisampled: false,
+            },
+            count: None,
+        },
+    ]);
+    let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("scene"),
+        entries: &entries,
+    });
+
+    let sphere_nodes = create_storage_buffer(device, "sphere nodes", mirror.spheres.nodes());
+    let spheres = create_storage_buffer(device, "spheres", &mirror.spheres.gpu_items());
+    let materials = create_storage_buffer(device, "materials", &mirror.materials.gpu_items());
+    let textures = create_storage_buffer(device, "textures", &scene.textures);
+    let quads = create_storage_buffer(device, "quads", &mirror.quads.gpu_items());
+    let shape_nodes = create_storage_buffer(device, "shape nodes", mirror.shapes.nodes());
+    let shapes = create_storage_buffer(device, "shapes", &mirror.shapes.gpu_items());
+    let sdf_objects = create_storage_buffer(device, "sdf objects", &scene.sdf_objects);
+    let sdf_shapes = create_storage_buffer(device, "sdf shapes", &scene.sdf_shapes);
+    let csg_objects = create_storage_buffer(device, "csg objects", &scene.csg_objects);
+    let csg_nodes = create_storage_buffer(device, "csg nodes", &scene.csg_nodes);
+    let heightfields = create_storage_buffer(device, "heightfields", &scene.heightfields);
+    let heightfield_data = create_storage_buffer(device, "heightfield data", &scene.heightfield_data);
+    let vertices = create_storage_buffer(device, "vertices", &scene.vertices);
+    let triangles = create_storage_buffer(device, "triangles", &scene.triangles);
+    let blas_nodes = create_storage_buffer(device, "blas nodes", &scene.blas_nodes);
+    let wide_blas_nodes = create_storage_buffer(device, "wide blas nodes", &scene.wide_blas_nodes);
+    let tlas_nodes = create_storage_buffer(device, "tlas nodes", mirror.instances.nodes());
+    let instances = create_storage_buffer(device, "instances", &mirror.instances.gpu_items());
+    let media = create_storage_buffer(device, "media", &scene.media);
+    let volumes = create_storage_buffer(device, "volumes", &scene.volumes);
+    let volume_atlas = create_volume_atlas(device, queue, &scene.grids);
+    let volume_atlas_view = volume_atlas.create_view(&wgpu::TextureViewDescriptor::default());
+    let images = create_image_texture_array(device, queue, &scene.images);
+    let images_view = images.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("image textures"),
+        address_mode_u: wgpu::AddressMode::Repeat,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("scene"),
+        layout: &layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: spheres.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: materials.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: textures.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&images_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::Sampler(&sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: vertices.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: triangles.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: media.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 8,
+                resource: volumes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 9,
+                resource: wgpu::BindingResource::TextureView(&volume_atlas_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 10,
+                resource: quads.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 11,
+                resource: blas_nodes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 12,
+                resource: tlas_nodes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 13,
+                resource: instances.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 14,
+                resource: sphere_nodes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 15,
+                resource: sdf_objects.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 16,
+                resource: sdf_shapes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 17,
+                resource: shapes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 18,
+                resource: shape_nodes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 19,
+                resource: csg_objects.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 20,
+                resource: csg_nodes.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 21,
+                resource: heightfields.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 22,
+                resource: heightfield_data.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 23,
+                resource: wide_blas_nodes.as_entire_binding(),
+            },
+        ],
+    });
+    let buffers = EditableBuffers {
+        spheres,
+        sphere_nodes,
+        quads,
+        shapes,
+        shape_nodes,
+        instances,
+        tlas_nodes,
+        materials,
+    };
+    (layout, bind_group, buffers)
+}
+
+// Storage buffers cannot be empty, so an empty slice is uploaded as a single zeroed element.
+fn create_storage_buffer<T: Pod>(device: &wgpu::Device, label: &str, data: &[T]) -> wgpu::Buffer {
+    let zeroed = [T::zeroed()];
+    let data = if data.is_empty() { &zeroed[..] } else { data };
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(label),
+        contents: bytemuck::cast_slice(data),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+// Packs all image textures into the layers of one texture array. Layers must share a size,
+// so smaller images are scaled up to the largest one. The texels are uploaded as-is; the
+// shader decodes sRGB layers itself so that data textures (normal maps etc.) stay linear.
+fn create_image_texture_array(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    images: &[RgbaImage],
+) -> wgpu::Texture {
+    let width = images.iter().map(|i| i.width()).max().unwrap_or(1);
+    let height = images.iter().map(|i| i.height()).max().unwrap_or(1);
+    let mut data = Vec::with_capacity((width * height * 4) as usize * images.len().max(1));
+    for image in images {
+        if image.dimensions() == (width, height) {
+            data.extend_from_slice(image.as_raw());
+        } else {
+            let resized = image::imageops::resize(image, width, height, FilterType::Triangle);
+            data.extend_from_slice(resized.as_raw());
+        }
+    }
+    if images.is_empty() {
+        data.extend_from_slice(&[255; 4]);
+    }
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("image textures"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: images.len().max(1) as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        &data,
+    )
+}
+
+// The atlas of `create_volume_atlas` has to fit in one 3D texture of the device.
+fn check_volume_atlas(device: &wgpu::Device, grids: &[DensityGrid]) -> Result<()> {
+    let max = device.limits().max_texture_dimension_3d;
+    let width = grids.iter().map(|g| g.size[0]).max().unwrap_or(1);
+    let height = grids.iter().map(|g| g.size[1]).max().unwrap_or(1);
+    let depth = grids.iter().map(|g| g.size[2]).sum::<u32>();
+    if width.max(height).max(depth) > max {
+        bail!(
+            "the density grids need a {width}x{height}x{depth} volume atlas, but 3D textures on this device are \
+             limited to {max} voxels per side"
+        );
+    }
+    Ok(())
+}
+
+// Stacks all density grids along z in one 3D texture, holding density and emission. Grids
+// smaller than the largest one in x or y are padded; the shader clamps lookups to each grid's
+// own extent. Rg32Float is not filterable, so the shader interpolates by hand.
+fn create_volume_atlas(device: &wgpu::Device, queue: &wgpu::Queue, grids: &[DensityGrid]) -> wgpu::Texture {
+    let width = grids.iter().map(|g| g.size[0]).max().unwrap_or(1);
+    let height = grids.iter().map(|g| g.size[1]).max().unwrap_or(1);
+    let depth = grids.iter().map(|g| g.size[2]).sum::<u32>().max(1);
+    let mut data = vec![[0f32; 2]; (width * height * depth) as usize];
+    let mut z_offset = 0;
+    for grid in grids {
+        let [nx, ny, nz] = grid.size;
+        for z in 0..nz {
+            for y in 0..ny {
+                let src = ((z * ny + y) * nx) as usize;
+                let dst = (((z_offset + z) * height + y) * width) as usize;
+                data[dst..dst + nx as usize].copy_from_slice(&grid.voxels[src..src + nx as usize]);
+            }
+        }
+        z_offset += nz;
+    }
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("volume atlas"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: depth,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::Rg32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&data),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The value of the `f32` constant `name` in shaders.wgsl, without its trailing point.
+    fn shader_constant(name: &str) -> String {
+        let shader = include_str!("shaders.wgsl");
+        let prefix = format!("const {name}: f32 = ");
+        let line = shader.lines().find_map(|line| line.strip_prefix(&prefix)).expect("constant not found");
+        line.trim_end_matches(';').trim_end_matches('.').to_string()
+    }
+
+    #[test]
+    fn storage_buffer_limit_counts_the_shader_bindings() {
+        let shader = include_str!("shaders.wgsl");
+        let bindings = |group: &str| -> Vec<u32> {
+            let prefix = format!("@group({group}) @binding(");
+            let declarations = shader.lines().filter_map(|line| line.strip_prefix(&prefix));
+            let storage = declarations.filter(|rest| rest.contains("var<storage"));
+            storage.map(|rest| rest[..rest.find(')').unwrap()].parse().unwrap()).collect()
+        };
+        assert_eq!(bindings("0"), [3]);
+        assert_eq!(bindings("1"), SCENE_STORAGE_BUFFERS);
+        assert_eq!(MAX_STORAGE_BUFFERS as usize, bindings("0").len() + bindings("1").len());
+    }
+
+    #[test]
+    fn heatmap_names_show_the_shader_scales() {
+        for (mode, constant) in [
+            (DebugMode::NodesVisited, "DEBUG_MAX_NODES"),
+            (DebugMode::PrimitiveTests, "DEBUG_MAX_TESTS"),
+            (DebugMode::PathLength, "DEBUG_MAX_PATH_LENGTH"),
+        ] {
+            let red = format!("red {}", shader_constant(constant));
+            assert!(mode.name().contains(&red), "`{}` should mention `{red}`", mode.name());
+        }
+    }
+}
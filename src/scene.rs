@@ -0,0 +1,2184 @@
+// scene.rs
+// CPU-side scene description. Everything in here is uploaded to GPU storage buffers by
+// `render::PathTracer`, so the `#[repr(C)]` structs must match their WGSL counterparts in
+// shaders.wgsl field for field.
+use crate::{
+    algebra::{Transform, Vec3},
+    bvh::{Aabb, Bvh, BvhNode},
+    heightfield::HeightMap,
+    mesh::Mesh,
+    volume::DensityGrid,
+    wide_bvh::{self, WideNode},
+};
+use {
+    anyhow::{Context, Result, bail},
+    bytemuck::{Pod, Zeroable},
+    image::RgbaImage,
+    std::{borrow::Cow, path::Path},
+};
+
+// Marks a material that uses its constant color instead of a texture.
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+// Names accepted by `Scene::builtin`.
+pub const BUILTIN_SCENES: &[&str] = &[
+    "week1_final",
+    "bouncing_spheres",
+    "checkered_spheres",
+    "earth",
+    "perlin_spheres",
+    "bump_maps",
+    "microfacets",
+    "principled",
+    "smoke",
+    "clouds",
+    "volume_grid",
+    "quads",
+    "cornell_box",
+    "instances",
+    "shapes",
+    "csg",
+    "terrain",
+];
+
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+// Moves linearly from `center` at time 0 to `center1` at time 1, like the book's moving
+// spheres. Static spheres have both centers equal.
+pub struct Sphere {
+    center: Vec3,
+    radius: f32,
+    center1: Vec3,
+    material: u32,
+}
+
+impl Sphere {
+    // Bounds covering the whole motion.
+    pub fn bounds(&self) -> Aabb {
+        let r = Vec3::all(self.radius);
+        let (c0, c1) = (self.center, self.center1);
+        Aabb::from_points(&[c0 - r, c0 + r, c1 - r, c1 + r])
+    }
+}
+
+// Quad kinds, see `intersect_quad` in shaders.wgsl.
+const QUAD_PARALLELOGRAM: u32 = 0;
+const QUAD_DISK: u32 = 1;
+const QUAD_PLANE: u32 = 2;
+
+// Planar primitives spanned by a point `q` and two edge vectors `u` and `v`, facing
+// cross(u, v). Depending on `kind`, the primitive is the parallelogram q + a u + b v with a
+// and b in [0, 1] ("The Next Week" section 6), the disk centered at q with a² + b² <= 1 (u and
+// v are orthogonal radii), or the whole plane (u and v set the texture scale).
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Quad {
+    q: Vec3,
+    kind: u32,
+    u: Vec3,
+    material: u32,
+    v: Vec3,
+    _pad: u32,
+}
+
+// Shape kinds. Cylinders are cones with equal radii.
+const SHAPE_CONE: u32 = 0;
+const SHAPE_TORUS: u32 = 1;
+
+// Capped cylinders and cones, and tori, each in a local frame whose y axis is `axis`.
+// Cones run from `center` with `radius` to `center + height * axis` with `radius2`. Tori lie
+// in the plane through `center` orthogonal to `axis`, with ring radius `radius` and tube
+// radius `radius2`.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Shape {
+    center: Vec3,
+    kind: u32,
+    axis: Vec3,
+    material: u32,
+    radius: f32,
+    radius2: f32,
+    height: f32,
+    _pad: u32,
+}
+
+impl Shape {
+    // Cylinder standing on the disk at `base`, extending `height` along `axis`.
+    pub fn cylinder(base: Vec3, axis: Vec3, radius: f32, height: f32, material: u32) -> Shape {
+        Self::cone(base, axis, radius, radius, height, material)
+    }
+
+    // Truncated cone, a full cone if `top_radius` is 0.
+    pub fn cone(base: Vec3, axis: Vec3, base_radius: f32, top_radius: f32, height: f32, material: u32) -> Shape {
+        Self::new(SHAPE_CONE, base, axis, base_radius, top_radius, height, material)
+    }
+
+    pub fn torus(center: Vec3, axis: Vec3, major: f32, minor: f32, material: u32) -> Shape {
+        Self::new(SHAPE_TORUS, center, axis, major, minor, 0., material)
+    }
+
+    fn new(kind: u32, center: Vec3, axis: Vec3, radius: f32, radius2: f32, height: f32, material: u32) -> Shape {
+        Shape {
+            center,
+            kind,
+            axis: axis.normalized(),
+            material,
+            radius,
+            radius2,
+            height,
+            _pad: 0,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let disk = |center: Vec3, radius: f32| disk_bounds(center, self.axis, radius);
+        match self.kind {
+            SHAPE_TORUS => {
+                let ring = disk(self.center, self.radius);
+                let tube = Vec3::all(self.radius2);
+                Aabb {
+                    min: ring.min - tube,
+                    max: ring.max + tube,
+                }
+            }
+            _ => disk(self.center, self.radius).union(&disk(self.center + self.height * self.axis, self.radius2)),
+        }
+    }
+}
+
+// Bounds of a disk around the unit vector `normal`, which extends radius * sqrt(1 - normal_i^2)
+// along axis i.
+fn disk_bounds(center: Vec3, normal: Vec3, radius: f32) -> Aabb {
+    let extent = |axis: usize| radius * (1. - normal[axis] * normal[axis]).max(0.).sqrt();
+    let extent = Vec3::new(extent(0), extent(1), extent(2));
+    Aabb {
+        min: center - extent,
+        max: center + extent,
+    }
+}
+
+// SDF shape kinds, see `sdf_shape` in shaders.wgsl.
+const SDF_SPHERE: u32 = 0;
+const SDF_TORUS: u32 = 1;
+const SDF_ROUND_BOX: u32 = 2;
+const SDF_CAPSULE: u32 = 3;
+
+// An axis-aligned signed distance field primitive. The meaning of `size` and `radius`
+// depends on the kind, see the constructors.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct SdfShape {
+    center: Vec3,
+    kind: u32,
+    size: Vec3,
+    radius: f32,
+}
+
+impl SdfShape {
+    pub fn sphere(center: Vec3, radius: f32) -> SdfShape {
+        Self::new(SDF_SPHERE, center, Vec3::zero(), radius)
+    }
+
+    // Torus around the y axis: `major` is the radius of the ring, `minor` that of the tube.
+    pub fn torus(center: Vec3, major: f32, minor: f32) -> SdfShape {
+        Self::new(SDF_TORUS, center, Vec3::new(major, 0., 0.), minor)
+    }
+
+    // Box with half extents `half_size` whose edges are rounded off with `rounding`.
+    pub fn round_box(center: Vec3, half_size: Vec3, rounding: f32) -> SdfShape {
+        Self::new(SDF_ROUND_BOX, center, half_size, rounding)
+    }
+
+    // Cylinder with hemispherical caps around the segment from `a` to `b`.
+    pub fn capsule(a: Vec3, b: Vec3, radius: f32) -> SdfShape {
+        Self::new(SDF_CAPSULE, a, b, radius)
+    }
+
+    fn new(kind: u32, center: Vec3, size: Vec3, radius: f32) -> SdfShape {
+        SdfShape {
+            center,
+            kind,
+            size,
+            radius,
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        let extent = match self.kind {
+            SDF_TORUS => Vec3::new(self.size.x() + self.radius, self.radius, self.size.x() + self.radius),
+            SDF_ROUND_BOX => self.size,
+            _ => Vec3::all(self.radius),
+        };
+        let bounds = Aabb {
+            min: self.center - extent,
+            max: self.center + extent,
+        };
+        if self.kind == SDF_CAPSULE {
+            let r = Vec3::all(self.radius);
+            return bounds.union(&Aabb::from_points(&[self.size - r, self.size + r]));
+        }
+        bounds
+    }
+}
+
+// A group of SDF shapes merged with a smooth union, sphere traced inside its bounding box.
+pub struct Sdf {
+    pub shapes: Vec<SdfShape>,
+    pub material: u32,
+    // Blend radius of the smooth union, 0 for a sharp union.
+    pub smoothing: f32,
+    // Repeats the shapes every `repeat` units along the axes where it is positive. The shapes
+    // should fit in one cell, centered at the origin.
+    pub repeat: Vec3,
+    // Required with repetition; otherwise computed from the shapes if None.
+    pub bounds: Option<Aabb>,
+}
+
+impl Sdf {
+    pub fn new(material: u32) -> Sdf {
+        Sdf {
+            shapes: Vec::new(),
+            material,
+            smoothing: 0.,
+            repeat: Vec3::zero(),
+            bounds: None,
+        }
+    }
+
+    pub fn with_shape(mut self, shape: SdfShape) -> Sdf {
+        self.shapes.push(shape);
+        self
+    }
+
+    pub fn with_smoothing(mut self, smoothing: f32) -> Sdf {
+        self.smoothing = smoothing.max(0.);
+        self
+    }
+
+    // Repeats the shapes within `bounds`.
+    pub fn with_repetition(mut self, period: Vec3, bounds: Aabb) -> Sdf {
+        self.repeat = period;
+        self.bounds = Some(bounds);
+        self
+    }
+}
+
+// An `Sdf` as uploaded to the GPU, referencing `shape_count` shapes from `first_shape`.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct SdfObject {
+    bounds_min: Vec3,
+    first_shape: u32,
+    bounds_max: Vec3,
+    shape_count: u32,
+    repeat: Vec3,
+    smoothing: f32,
+    material: u32,
+    _pad: [u32; 3],
+}
+
+// CSG node kinds, see `intersect_csg` in shaders.wgsl. Leaves come before operations.
+const CSG_SPHERE: u32 = 0;
+const CSG_CUBOID: u32 = 1;
+const CSG_CYLINDER: u32 = 2;
+const CSG_UNION: u32 = 3;
+const CSG_INTERSECTION: u32 = 4;
+const CSG_DIFFERENCE: u32 = 5;
+// Must match CSG_STACK_SIZE and CSG_MAX_SPANS in shaders.wgsl.
+const CSG_STACK_SIZE: usize = 8;
+const CSG_MAX_SPANS: usize = 4;
+
+// Constructive solid geometry over convex primitives. Every leaf keeps its own material, so
+// the walls of a hole show the material of the primitive that was subtracted.
+#[derive(Debug, Clone)]
+pub enum Csg {
+    Sphere { center: Vec3, radius: f32, material: u32 },
+    Cuboid { min: Vec3, max: Vec3, material: u32 },
+    // Capped cylinder around the segment from `base` to `top`.
+    Cylinder { base: Vec3, top: Vec3, radius: f32, material: u32 },
+    Union(Box<Csg>, Box<Csg>),
+    Intersection(Box<Csg>, Box<Csg>),
+    Difference(Box<Csg>, Box<Csg>),
+}
+
+impl Csg {
+    pub fn sphere(center: Vec3, radius: f32, material: u32) -> Csg {
+        Csg::Sphere { center, radius, material }
+    }
+
+    // Axis-aligned box between the opposite corners `a` and `b`.
+    pub fn cuboid(a: Vec3, b: Vec3, material: u32) -> Csg {
+        Csg::Cuboid {
+            min: a.min(&b),
+            max: a.max(&b),
+            material,
+        }
+    }
+
+    pub fn cylinder(base: Vec3, top: Vec3, radius: f32, material: u32) -> Csg {
+        Csg::Cylinder {
+            base,
+            top,
+            radius,
+            material,
+        }
+    }
+
+    pub fn union(self, other: Csg) -> Csg {
+        Csg::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Csg) -> Csg {
+        Csg::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn difference(self, other: Csg) -> Csg {
+        Csg::Difference(Box::new(self), Box::new(other))
+    }
+
+    fn bounds(&self) -> Aabb {
+        match self {
+            Csg::Sphere { center, radius, .. } => Aabb {
+                min: *center - Vec3::all(*radius),
+                max: *center + Vec3::all(*radius),
+            },
+            Csg::Cuboid { min, max, .. } => Aabb { min: *min, max: *max },
+            Csg::Cylinder { base, top, radius, .. } => {
+                let axis = (*top - *base).normalized();
+                disk_bounds(*base, axis, *radius).union(&disk_bounds(*top, axis, *radius))
+            }
+            Csg::Union(a, b) => a.bounds().union(&b.bounds()),
+            Csg::Intersection(a, b) => a.bounds().intersection(&b.bounds()),
+            Csg::Difference(a, _) => a.bounds(),
+        }
+    }
+
+    // The most interval lists the shader holds at once while evaluating the tree in post-order:
+    // the left result waits on the stack while the right subtree is evaluated.
+    fn stack_depth(&self) -> usize {
+        match self {
+            Csg::Union(a, b) | Csg::Intersection(a, b) | Csg::Difference(a, b) => {
+                a.stack_depth().max(1 + b.stack_depth())
+            }
+            _ => 1,
+        }
+    }
+
+    // The most spans the tree can cut a ray into. Every primitive is convex, so a leaf gives one
+    // span, an intersection of m and n spans gives at most m + n - 1, and a union or difference
+    // at most m + n.
+    fn max_spans(&self) -> usize {
+        match self {
+            Csg::Union(a, b) | Csg::Difference(a, b) => a.max_spans() + b.max_spans(),
+            Csg::Intersection(a, b) => a.max_spans() + b.max_spans() - 1,
+            _ => 1,
+        }
+    }
+
+    // Appends the tree in post-order, so that the operands of an operation precede it.
+    fn flatten(&self, nodes: &mut Vec<CsgNode>) {
+        let node = |kind, a, b, radius, material| CsgNode {
+            a,
+            kind,
+            b,
+            material,
+            radius,
+            _pad: [0; 3],
+        };
+        let operation = |kind, lhs: &Csg, rhs: &Csg, nodes: &mut Vec<CsgNode>| {
+            lhs.flatten(nodes);
+            rhs.flatten(nodes);
+            nodes.push(node(kind, Vec3::zero(), Vec3::zero(), 0., 0));
+        };
+        match self {
+            Csg::Sphere { center, radius, material } => {
+                nodes.push(node(CSG_SPHERE, *center, Vec3::zero(), *radius, *material))
+            }
+            Csg::Cuboid { min, max, material } => nodes.push(node(CSG_CUBOID, *min, *max, 0., *material)),
+            Csg::Cylinder {
+                base,
+                top,
+                radius,
+                material,
+            } => nodes.push(node(CSG_CYLINDER, *base, *top, *radius, *material)),
+            Csg::Union(a, b) => operation(CSG_UNION, a, b, nodes),
+            Csg::Intersection(a, b) => operation(CSG_INTERSECTION, a, b, nodes),
+            Csg::Difference(a, b) => operation(CSG_DIFFERENCE, a, b, nodes),
+        }
+    }
+}
+
+// A node of a flattened `Csg`. `a` and `b` are the center of a sphere, the corners of a
+// cuboid or the ends of a cylinder.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct CsgNode {
+    a: Vec3,
+    kind: u32,
+    b: Vec3,
+    material: u32,
+    radius: f32,
+    _pad: [u32; 3],
+}
+
+// A `Csg` as uploaded to the GPU, referencing `node_count` nodes in post-order from
+// `first_node`.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct CsgObject {
+    bounds_min: Vec3,
+    first_node: u32,
+    bounds_max: Vec3,
+    node_count: u32,
+}
+
+// Cells per side of the blocks whose height ranges let rays skip parts of a heightfield. Must
+// match HEIGHTFIELD_BLOCK in shaders.wgsl.
+const HEIGHTFIELD_BLOCK: u32 = 8;
+
+// A `HeightMap` placed in the world: sample (x, z) of height h lies at
+// `origin + (x, h, z) * cell_size`. The samples and the block ranges are stored in
+// `Scene::heightfield_data` from `first_sample` and `first_block`.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Heightfield {
+    origin: Vec3,
+    columns: u32,
+    cell_size: Vec3,
+    rows: u32,
+    first_sample: u32,
+    first_block: u32,
+    material: u32,
+    _pad: u32,
+}
+
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Vertex {
+    position: Vec3,
+    u: f32,
+    normal: Vec3,
+    v: f32,
+    tangent: [f32; 4],
+    color: Vec3,
+    _pad: u32,
+}
+
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Triangle {
+    // Indices into the scene's vertex buffer.
+    indices: [u32; 3],
+    material: u32,
+}
+
+// A mesh added with `Scene::add_geometry`, whose triangles and BLAS nodes are stored once no
+// matter how many instances reference it.
+#[derive(Debug, Copy, Clone)]
+pub struct Geometry {
+    // Index of the BLAS root in `Scene::blas_nodes`.
+    pub root: u32,
+    // Index of the same BLAS collapsed into `Scene::wide_blas_nodes`, or
+    // `wide_bvh::NO_WIDE_BLAS` if it is too deep for the wide traversal.
+    pub wide_root: u32,
+    // Range of its triangles in `Scene::triangles`.
+    pub first_triangle: u32,
+    pub triangle_count: u32,
+}
+
+// A placement of a geometry in the world, the leaves of the TLAS.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Instance {
+    to_world: Transform,
+    // Moves rays into object space; its transpose moves normals back.
+    to_object: Transform,
+    // Transform at time 1 if `moving` is non-zero. The transform at time t interpolates the
+    // matrices linearly, so every point moves on a straight line and the bounds at times 0
+    // and 1 together bound the whole motion.
+    to_world1: Transform,
+    blas_root: u32,
+    moving: u32,
+    wide_root: u32,
+    _pad: u32,
+}
+
+// A homogeneous participating medium filling a sphere, like the book's `ConstantMedium`.
+// The boundary itself is invisible.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Medium {
+    center: Vec3,
+    radius: f32,
+    albedo: Vec3,
+    // Extinction coefficient, in 1 / scene units.
+    density: f32,
+    // Henyey-Greenstein asymmetry, 0 for isotropic scattering.
+    g: f32,
+    _pad: [u32; 3],
+}
+
+impl Medium {
+    pub fn sphere(center: Vec3, radius: f32, density: f32, albedo: Vec3) -> Medium {
+        Medium {
+            center,
+            radius,
+            albedo,
+            density: density.max(0.),
+            g: 0.,
+            _pad: [0; 3],
+        }
+    }
+
+    // Uses a Henyey-Greenstein phase function; `g` > 0 scatters forward, `g` < 0 backward.
+    pub fn with_phase(mut self, g: f32) -> Medium {
+        self.g = g.clamp(-0.99, 0.99);
+        self
+    }
+}
+
+// Volume kinds, see `volume_sample` in shaders.wgsl.
+const VOLUME_GRID: u32 = 0;
+const VOLUME_NOISE: u32 = 1;
+
+// A heterogeneous medium filling an axis-aligned box, with its density read from a voxel grid
+// or generated from noise. Tracked with a majorant, so the density may vary freely.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Volume {
+    bounds_min: Vec3,
+    kind: u32,
+    bounds_max: Vec3,
+    // Extinction coefficient where the normalized density is 1.
+    density: f32,
+    albedo: Vec3,
+    // Upper bound of the extinction coefficient inside the box.
+    majorant: f32,
+    // Emitted radiance per unit length where the emission channel is 1.
+    emission: Vec3,
+    g: f32,
+    // Resolution of the grid and its first z layer in the volume atlas.
+    grid_size: [u32; 3],
+    z_offset: u32,
+    // Frequency of the noise, for noise volumes.
+    frequency: f32,
+    // Index into `Scene::grids`, for grid volumes. Not read by the shader: it and `_pad` fill
+    // the 12 bytes WGSL pads `struct Volume` with after `frequency`.
+    grid: u32,
+    _pad: [u32; 2],
+}
+
+// The array stride of `volumes` in shaders.wgsl.
+const _: () = assert!(size_of::<Volume>() == 96);
+
+impl Volume {
+    // Samples the grid returned by `Scene::add_density_grid`, stretched over the box.
+    // `density` scales the grid values.
+    pub fn grid(grid: u32, bounds_min: Vec3, bounds_max: Vec3, density: f32) -> Volume {
+        let mut volume = Self::new(VOLUME_GRID, bounds_min, bounds_max, density);
+        volume.grid = grid;
+        volume
+    }
+
+    // A fractal noise cloud with a soft spherical falloff, which needs no asset. `frequency`
+    // is the number of noise features across the box.
+    pub fn noise(bounds_min: Vec3, bounds_max: Vec3, density: f32, frequency: f32) -> Volume {
+        let mut volume = Self::new(VOLUME_NOISE, bounds_min, bounds_max, density);
+        volume.frequency = frequency;
+        volume.majorant = volume.density;
+        volume
+    }
+
+    pub fn with_albedo(mut self, albedo: Vec3) -> Volume {
+        self.albedo = albedo;
+        self
+    }
+
+    // Makes the volume glow with `radiance` per unit length, modulated by the grid's emission
+    // channel (or the density, for noise volumes).
+    pub fn with_emission(mut self, radiance: Vec3) -> Volume {
+        self.emission = radiance;
+        self
+    }
+
+    // See `Medium::with_phase`.
+    pub fn with_phase(mut self, g: f32) -> Volume {
+        self.g = g.clamp(-0.99, 0.99);
+        self
+    }
+
+    fn new(kind: u32, bounds_min: Vec3, bounds_max: Vec3, density: f32) -> Volume {
+        Volume {
+            bounds_min,
+            kind,
+            bounds_max,
+            density: density.max(0.),
+            albedo: Vec3::all(1.),
+            majorant: 0.,
+            emission: Vec3::zero(),
+            g: 0.,
+            grid_size: [0; 3],
+            z_offset: 0,
+            frequency: 0.,
+            grid: 0,
+            _pad: [0; 2],
+        }
+    }
+}
+
+// Scene-wide exponential height fog. The density is `density` at `height` and changes by a
+// factor of e every 1 / `falloff` units of altitude; a zero density disables the fog.
+#[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Fog {
+    albedo: Vec3,
+    density: f32,
+    height: f32,
+    falloff: f32,
+    g: f32,
+    _pad: u32,
+}
+
+impl Fog {
+    pub fn new(density: f32, height: f32, falloff: f32) -> Fog {
+        Fog {
+            albedo: Vec3::all(1.),
+            density: density.max(0.),
+            height,
+            falloff: falloff.max(0.),
+            g: 0.,
+            _pad: 0,
+        }
+    }
+
+    pub fn with_albedo(mut self, albedo: Vec3) -> Fog {
+        self.albedo = albedo;
+        self
+    }
+
+    // See `Medium::with_phase`.
+    pub fn with_phase(mut self, g: f32) -> Fog {
+        self.g = g.clamp(-0.99, 0.99);
+        self
+    }
+}
+
+/*
+材质编码（与 shaders.wgsl 中的 Material 一致）：
+- specular_or_ior = 0.0: 漫反射材质(Lambertian)
+- specular_or_ior > 0.0: 金属材质(Metal)，1.0 + fuzz
+- specular_or_ior < 0.0: 透明材质(Dielectric)，-ior
+`texture` 不是 NO_TEXTURE 时，反照率为纹理值乘以 `color`。
+其余的贴图（法线、凹凸、粗糙度、金属度）同样是纹理索引，NO_TEXTURE 表示不使用。
+`kind` 不是 MATERIAL_BASIC 时改用 GGX 微表面模型，specular_or_ior 被忽略：
+- MATERIAL_CONDUCTOR: 复折射率 eta + i·k 的金属，`color` 作为额外的染色
+- MATERIAL_ROUGH_DIELECTRIC: 折射率为 eta.x 的粗糙玻璃，`color` 为透射颜色
+- MATERIAL_PRINCIPLED: Disney 风格的分层材质，参数见 `Principled`，`color` 为基础色，
+  eta.x 为透射的折射率
+两者的 `roughness` 为感知粗糙度，GGX 的 alpha = roughness²。
+任何材质都可以带 `emission`（自发光的辐亮度）。
+*/
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Material {
+    color: Vec3,
+    specular_or_ior: f32,
+    texture: u32,
+    normal_map: u32,
+    bump_map: u32,
+    bump_scale: f32,
+    roughness_map: u32,
+    metallic_map: u32,
+    _pad: [u32; 2],
+    eta: Vec3,
+    kind: u32,
+    k: Vec3,
+    roughness: f32,
+    emission: Vec3,
+    metallic: f32,
+    specular: f32,
+    sheen: f32,
+    sheen_tint: f32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    transmission: f32,
+    _pad2: [u32; 2],
+}
+
+// Material kinds, see `scatter` in shaders.wgsl.
+const MATERIAL_BASIC: u32 = 0;
+const MATERIAL_CONDUCTOR: u32 = 1;
+const MATERIAL_ROUGH_DIELECTRIC: u32 = 2;
+const MATERIAL_PRINCIPLED: u32 = 3;
+
+// Parameters of the principled material, named and scaled like Blender's Principled BSDF
+// (which is also what glTF importers map to). All factors are in [0, 1].
+#[derive(Debug, Copy, Clone)]
+pub struct Principled {
+    pub base_color: Vec3,
+    pub metallic: f32,
+    pub roughness: f32,
+    // Strength of the dielectric specular reflection; 0.5 corresponds to an IOR of 1.5.
+    pub specular: f32,
+    // Retro-reflective velvet layer at grazing angles, tinted towards the base color by
+    // `sheen_tint`.
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    // Second, colorless specular layer on top with its own roughness.
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    // Fraction of the dielectric base that is glass instead of diffuse.
+    pub transmission: f32,
+    pub ior: f32,
+    pub emission: Vec3,
+    pub emission_strength: f32,
+}
+
+impl Default for Principled {
+    // Blender's defaults.
+    fn default() -> Principled {
+        Principled {
+            base_color: Vec3::all(0.8),
+            metallic: 0.,
+            roughness: 0.5,
+            specular: 0.5,
+            sheen: 0.,
+            sheen_tint: 0.5,
+            clearcoat: 0.,
+            clearcoat_roughness: 0.03,
+            transmission: 0.,
+            ior: 1.45,
+            emission: Vec3::zero(),
+            emission_strength: 1.,
+        }
+    }
+}
+
+impl Material {
+    pub fn lambertian(color: Vec3) -> Material {
+        Self::new(color, 0.)
+    }
+
+    pub fn metal(color: Vec3, fuzz: f32) -> Material {
+        Self::new(color, 1. + fuzz.max(0.))
+    }
+
+    pub fn dielectric(ior: f32) -> Material {
+        Self::new(Vec3::all(1.), -ior)
+    }
+
+    // A black surface emitting `radiance`, like the book's `DiffuseLight`.
+    pub fn diffuse_light(radiance: Vec3) -> Material {
+        Self::lambertian(Vec3::zero()).with_emission(radiance)
+    }
+
+    // GGX microfacet metal with the complex index of refraction `eta + i k`, given per RGB
+    // channel.
+    pub fn conductor(eta: Vec3, k: Vec3, roughness: f32) -> Material {
+        let mut material = Self::new(Vec3::all(1.), 0.);
+        material.kind = MATERIAL_CONDUCTOR;
+        material.eta = eta;
+        material.k = k;
+        material.roughness = roughness.clamp(0., 1.);
+        material
+    }
+
+    // Conductors with measured indices of refraction, sampled at 650, 550 and 450 nm.
+    pub fn gold(roughness: f32) -> Material {
+        let eta = Vec3::new(0.143, 0.374, 1.442);
+        Self::conductor(eta, Vec3::new(3.983, 2.385, 1.603), roughness)
+    }
+
+    pub fn copper(roughness: f32) -> Material {
+        let eta = Vec3::new(0.200, 0.924, 1.102);
+        Self::conductor(eta, Vec3::new(3.912, 2.452, 2.142), roughness)
+    }
+
+    pub fn aluminium(roughness: f32) -> Material {
+        let eta = Vec3::new(1.657, 0.880, 0.521);
+        Self::conductor(eta, Vec3::new(9.224, 6.270, 4.837), roughness)
+    }
+
+    // GGX microfacet glass, reflecting and transmitting through a rough interface.
+    pub fn rough_dielectric(ior: f32, roughness: f32) -> Material {
+        let mut material = Self::new(Vec3::all(1.), 0.);
+        material.kind = MATERIAL_ROUGH_DIELECTRIC;
+        material.eta = Vec3::all(ior);
+        material.roughness = roughness.clamp(0., 1.);
+        material
+    }
+
+    pub fn principled(params: &Principled) -> Material {
+        let mut material = Self::new(params.base_color, 0.);
+        material.kind = MATERIAL_PRINCIPLED;
+        material.eta = Vec3::all(params.ior);
+        material.roughness = params.roughness.clamp(0., 1.);
+        material.emission = params.emission_strength.max(0.) * params.emission;
+        material.metallic = params.metallic.clamp(0., 1.);
+        material.specular = params.specular.max(0.);
+        material.sheen = params.sheen.max(0.);
+        material.sheen_tint = params.sheen_tint.clamp(0., 1.);
+        material.clearcoat = params.clearcoat.max(0.);
+        material.clearcoat_roughness = params.clearcoat_roughness.clamp(0., 1.);
+        material.transmission = params.transmission.clamp(0., 1.);
+        material
+    }
+
+    // Makes the surface a light source emitting `radiance`. Works with every material kind.
+    pub fn with_emission(mut self, radiance: Vec3) -> Material {
+        self.emission = radiance;
+        self
+    }
+
+    // Tints the reflection of a conductor or the transmission of a rough dielectric.
+    pub fn with_color(mut self, color: Vec3) -> Material {
+        self.color = color;
+        self
+    }
+
+    // Modulates the material color with `texture`, an index returned by `Scene::add_texture`.
+    pub fn with_texture(mut self, texture: u32) -> Material {
+        self.texture = texture;
+        self
+    }
+
+    // Perturbs the shading normal with a tangent-space normal map (OpenGL convention, +y
+    // along +v). The map should be loaded with `Scene::add_data_texture`.
+    pub fn with_normal_map(mut self, texture: u32) -> Material {
+        self.normal_map = texture;
+        self
+    }
+
+    // Perturbs the shading normal by the gradient of the red channel of `texture`, which may
+    // also be a procedural texture. `scale` sets the bump strength.
+    pub fn with_bump_map(mut self, texture: u32, scale: f32) -> Material {
+        self.bump_map = texture;
+        self.bump_scale = scale;
+        self
+    }
+
+    // Scales the fuzz of metals by the green channel of `texture` (glTF metallic-roughness
+    // layout). On a Lambertian material, the value is used as the fuzz of its metal lobe.
+    pub fn with_roughness_map(mut self, texture: u32) -> Material {
+        self.roughness_map = texture;
+        self
+    }
+
+    // Blue channel of `texture` is the probability of scattering off a metal lobe instead of
+    // the diffuse one (glTF metallic-roughness layout). Only applies to Lambertian and metal
+    // materials.
+    pub fn with_metallic_map(mut self, texture: u32) -> Material {
+        self.metallic_map = texture;
+        self
+    }
+
+    fn new(color: Vec3, specular_or_ior: f32) -> Material {
+        Material {
+            color,
+            specular_or_ior,
+            texture: NO_TEXTURE,
+            normal_map: NO_TEXTURE,
+            bump_map: NO_TEXTURE,
+            bump_scale: 0.,
+            roughness_map: NO_TEXTURE,
+            metallic_map: NO_TEXTURE,
+            _pad: [0; 2],
+            eta: Vec3::zero(),
+            kind: MATERIAL_BASIC,
+            k: Vec3::zero(),
+            roughness: 0.,
+            emission: Vec3::zero(),
+            metallic: 0.,
+            specular: 0.,
+            sheen: 0.,
+            sheen_tint: 0.,
+            clearcoat: 0.,
+            clearcoat_roughness: 0.,
+            transmission: 0.,
+            _pad2: [0; 2],
+        }
+    }
+}
+
+// Texture kinds, see `texture_value` in shaders.wgsl.
+const TEXTURE_SOLID: u32 = 0;
+const TEXTURE_CHECKER: u32 = 1;
+const TEXTURE_IMAGE: u32 = 2;
+const TEXTURE_NOISE: u32 = 3;
+const TEXTURE_TURBULENCE: u32 = 4;
+const TEXTURE_MARBLE: u32 = 5;
+
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Texture {
+    color0: Vec3,
+    kind: u32,
+    color1: Vec3,
+    scale: f32,
+    // Layer of the image texture array, for image textures.
+    layer: u32,
+    // Non-zero if the image holds sRGB-encoded colors rather than linear data.
+    srgb: u32,
+    _pad: [u32; 2],
+}
+
+impl Texture {
+    pub fn solid(color: Vec3) -> Texture {
+        Self::new(TEXTURE_SOLID, color, Vec3::zero(), 1.)
+    }
+
+    // A 3D checker pattern with cells of size `scale`, evaluated at the hit point.
+    pub fn checker(scale: f32, even: Vec3, odd: Vec3) -> Texture {
+        Self::new(TEXTURE_CHECKER, even, odd, scale)
+    }
+
+    // Perlin noise at frequency `scale`, as in "The Next Week" section 5.
+    pub fn noise(scale: f32) -> Texture {
+        Self::new(TEXTURE_NOISE, Vec3::all(1.), Vec3::zero(), scale)
+    }
+
+    // Turbulence (summed octaves of Perlin noise).
+    pub fn turbulence(scale: f32) -> Texture {
+        Self::new(TEXTURE_TURBULENCE, Vec3::all(1.), Vec3::zero(), scale)
+    }
+
+    // Marble-like stripes whose phase is perturbed by turbulence.
+    pub fn marble(scale: f32) -> Texture {
+        Self::new(TEXTURE_MARBLE, Vec3::all(1.), Vec3::zero(), scale)
+    }
+
+    fn image(layer: u32, srgb: bool) -> Texture {
+        let mut texture = Self::new(TEXTURE_IMAGE, Vec3::all(1.), Vec3::zero(), 1.);
+        texture.layer = layer;
+        texture.srgb = srgb as u32;
+        texture
+    }
+
+    fn new(kind: u32, color0: Vec3, color1: Vec3, scale: f32) -> Texture {
+        Texture {
+            color0,
+            kind,
+            color1,
+            scale,
+            layer: 0,
+            srgb: 0,
+            _pad: [0; 2],
+        }
+    }
+}
+
+// Where the camera starts when the scene is opened.
+#[derive(Debug, Copy, Clone)]
+pub struct View {
+    pub origin: Vec3,
+    pub center: Vec3,
+    pub up: Vec3,
+    // Shutter open and close times, see `Camera::set_shutter`.
+    pub shutter: (f32, f32),
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View {
+            origin: Vec3::new(0., 0.55, 1.5),
+            center: Vec3::new(0., 0.5, 0.),
+            up: Vec3::new(0., 1., 0.),
+            shutter: (0., 1.),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Scene {
+    pub view: View,
+    // Constant background radiance, or None for the sky gradient.
+    pub background: Option<Vec3>,
+    pub spheres: Vec<Sphere>,
+    pub quads: Vec<Quad>,
+    pub shapes: Vec<Shape>,
+    pub sdf_objects: Vec<SdfObject>,
+    pub sdf_shapes: Vec<SdfShape>,
+    pub csg_objects: Vec<CsgObject>,
+    pub csg_nodes: Vec<CsgNode>,
+    pub heightfields: Vec<Heightfield>,
+    // Samples and block ranges of all heightfields.
+    pub heightfield_data: Vec<f32>,
+    pub vertices: Vec<Vertex>,
+    pub triangles: Vec<Triangle>,
+    pub geometries: Vec<Geometry>,
+    // The BLAS of every geometry. Child indices are absolute and leaves index `triangles`.
+    pub blas_nodes: Vec<BvhNode>,
+    // The same BLAS as 4-wide trees over the same triangle order, for the wide traversal.
+    pub wide_blas_nodes: Vec<WideNode>,
+    pub instances: Vec<Instance>,
+    pub materials: Vec<Material>,
+    pub textures: Vec<Texture>,
+    pub media: Vec<Medium>,
+    pub fog: Fog,
+    pub volumes: Vec<Volume>,
+    // Grids referenced by grid volumes, stacked along z in one 3D texture on the GPU.
+    pub grids: Vec<DensityGrid>,
+    // Images referenced by image textures, one layer of the GPU texture array each.
+    pub images: Vec<RgbaImage>,
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene::default()
+    }
+
+    pub fn builtin(name: &str) -> Result<Scene> {
+        match name {
+            "week1_final" => Ok(Self::week1_final()),
+            "bouncing_spheres" => Ok(Self::bouncing_spheres()),
+            "checkered_spheres" => Ok(Self::checkered_spheres()),
+            "earth" => Self::earth(Path::new("earthmap.jpg")),
+            "perlin_spheres" => Ok(Self::perlin_spheres()),
+            "bump_maps" => Ok(Self::bump_maps()),
+            "microfacets" => Ok(Self::microfacets()),
+            "principled" => Ok(Self::principled()),
+            "smoke" => Ok(Self::smoke()),
+            "clouds" => Self::clouds(),
+            "volume_grid" => Self::volume_grid(Path::new("density.vol")),
+            "quads" => Self::quads(),
+            "cornell_box" => Self::cornell_box(),
+            "instances" => Self::instances(),
+            "shapes" => Self::shapes(),
+            "csg" => Self::csg(),
+            "terrain" => Self::terrain(Path::new("heightmap.png")),
+            _ => bail!(
+                "unknown scene `{name}`, expected one of: {}",
+                BUILTIN_SCENES.join(", ")
+            ),
+        }
+    }
+
+    pub fn add_texture(&mut self, texture: Texture) -> u32 {
+        self.textures.push(texture);
+        self.textures.len() as u32 - 1
+    }
+
+    pub fn add_medium(&mut self, medium: Medium) {
+        self.media.push(medium);
+    }
+
+    // Returns the index to pass to `Volume::grid`.
+    pub fn add_density_grid(&mut self, grid: DensityGrid) -> u32 {
+        self.grids.push(grid);
+        self.grids.len() as u32 - 1
+    }
+
+    pub fn add_volume(&mut self, mut volume: Volume) -> Result<()> {
+        if volume.kind == VOLUME_GRID {
+            let index = volume.grid as usize;
+            let Some(grid) = self.grids.get(index) else {
+                bail!("volume refers to density grid {index}, but the scene has {}", self.grids.len());
+            };
+            volume.grid_size = grid.size;
+            volume.z_offset = self.grids[..index].iter().map(|g| g.size[2]).sum();
+            volume.majorant = volume.density * grid.max_density();
+        }
+        self.volumes.push(volume);
+        Ok(())
+    }
+
+    // Loads an sRGB color image as a texture sampled by the surface UV coordinates.
+    pub fn add_image_texture(&mut self, path: &Path) -> Result<u32> {
+        self.load_image(path, true)
+    }
+
+    // Loads an image holding linear data, such as a normal, bump or roughness map.
+    pub fn add_data_texture(&mut self, path: &Path) -> Result<u32> {
+        self.load_image(path, false)
+    }
+
+    fn load_image(&mut self, path: &Path, srgb: bool) -> Result<u32> {
+        let image = image::open(path)
+            .with_context(|| format!("failed to load texture {}", path.display()))?
+            .to_rgba8();
+        self.images.push(image);
+        Ok(self.add_texture(Texture::image(self.images.len() as u32 - 1, srgb)))
+    }
+
+    pub fn add_material(&mut self, material: Material) -> u32 {
+        self.materials.push(material);
+        self.materials.len() as u32 - 1
+    }
+
+    pub fn add_sphere(&mut self, center: Vec3, radius: f32, material: u32) {
+        self.add_moving_sphere(center, center, radius, material);
+    }
+
+    // Sphere at `center0` at time 0 and at `center1` at time 1.
+    pub fn add_moving_sphere(&mut self, center0: Vec3, center1: Vec3, radius: f32, material: u32) {
+        self.spheres.push(Sphere {
+            center: center0,
+            radius,
+            center1,
+            material,
+        });
+    }
+
+    pub fn add_shape(&mut self, shape: Shape) {
+        self.shapes.push(shape);
+    }
+
+    pub fn add_sdf(&mut self, sdf: &Sdf) -> Result<()> {
+        if sdf.shapes.is_empty() {
+            bail!("SDF without shapes");
+        }
+        let bounds = match sdf.bounds {
+            Some(bounds) => bounds,
+            None if (0..3).any(|axis| sdf.repeat[axis] > 0.) => bail!("repeated SDF without bounds"),
+            None => {
+                // A smooth union bulges out by at most a quarter of the blend radius.
+                let bounds = sdf.shapes.iter().fold(Aabb::empty(), |b, s| b.union(&s.bounds()));
+                let margin = Vec3::all(0.25 * sdf.smoothing);
+                Aabb {
+                    min: bounds.min - margin,
+                    max: bounds.max + margin,
+                }
+            }
+        };
+        self.sdf_objects.push(SdfObject {
+            bounds_min: bounds.min,
+            first_shape: self.sdf_shapes.len() as u32,
+            bounds_max: bounds.max,
+            shape_count: sdf.shapes.len() as u32,
+            repeat: sdf.repeat,
+            smoothing: sdf.smoothing,
+            material: sdf.material,
+            _pad: [0; 3],
+        });
+        self.sdf_shapes.extend_from_slice(&sdf.shapes);
+        Ok(())
+    }
+
+    // Stretches `map` over the box from `origin` to `origin + size`, with the lowest possible
+    // height at the bottom and the highest at the top.
+    pub fn add_heightfield(&mut self, map: &HeightMap, origin: Vec3, size: Vec3, material: u32) {
+        let first_sample = self.heightfield_data.len() as u32;
+        self.heightfield_data.extend_from_slice(&map.samples);
+        let first_block = self.heightfield_data.len() as u32;
+        self.heightfield_data
+            .extend(map.block_ranges(HEIGHTFIELD_BLOCK).iter().flatten());
+        self.heightfields.push(Heightfield {
+            origin,
+            columns: map.columns,
+            cell_size: Vec3::new(
+                size.x() / (map.columns - 1) as f32,
+                size.y(),
+                size.z() / (map.rows - 1) as f32,
+            ),
+            rows: map.rows,
+            first_sample,
+            first_block,
+            material,
+            _pad: 0,
+        });
+    }
+
+    pub fn add_csg(&mut self, csg: &Csg) -> Result<()> {
+        if csg.stack_depth() > CSG_STACK_SIZE {
+            bail!("CSG tree too deep, its right operands may nest at most {} levels", CSG_STACK_SIZE - 1);
+        }
+        let bounds = csg.bounds();
+        if bounds.is_empty() {
+            // An intersection of disjoint primitives, which is nothing at all.
+            return Ok(());
+        }
+        if csg.max_spans() > CSG_MAX_SPANS {
+            eprintln!(
+                "warning: CSG object {} can cut a ray into up to {} spans, only the nearest {} are kept",
+                self.csg_objects.len(),
+                csg.max_spans(),
+                CSG_MAX_SPANS
+            );
+        }
+        let first_node = self.csg_nodes.len();
+        csg.flatten(&mut self.csg_nodes);
+        self.csg_objects.push(CsgObject {
+            bounds_min: bounds.min,
+            first_node: first_node as u32,
+            bounds_max: bounds.max,
+            node_count: (self.csg_nodes.len() - first_node) as u32,
+        });
+        Ok(())
+    }
+
+    // Parallelogram with corner `q` and edges `u` and `v`, facing cross(u, v). The edges must
+    // span an area; the shader never hits a degenerate quad.
+    pub fn add_quad(&mut self, q: Vec3, u: Vec3, v: Vec3, material: u32) -> Result<()> {
+        if !u.cross(&v).length().is_normal() {
+            bail!("quad edges `u` and `v` are parallel or zero");
+        }
+        self.push_quad(QUAD_PARALLELOGRAM, q, u, v, material);
+        Ok(())
+    }
+
+    pub fn add_disk(&mut self, center: Vec3, normal: Vec3, radius: f32, material: u32) -> Result<()> {
+        if !normal.length().is_normal() {
+            bail!("disk normal has zero length");
+        }
+        if !(radius.is_finite() && radius > 0.) {
+            bail!("disk radius must be positive");
+        }
+        let u = radius * normal.any_perpendicular();
+        let v = normal.normalized().cross(&u);
+        self.push_quad(QUAD_DISK, center, u, v, material);
+        Ok(())
+    }
+
+    // Infinite plane through `point`. Textures repeat every scene unit.
+    pub fn add_plane(&mut self, point: Vec3, normal: Vec3, material: u32) -> Result<()> {
+        if !normal.length().is_normal() {
+            bail!("plane normal has zero length");
+        }
+        let u = normal.any_perpendicular();
+        let v = normal.normalized().cross(&u);
+        self.push_quad(QUAD_PLANE, point, u, v, material);
+        Ok(())
+    }
+
+    // Axis-aligned box with opposite corners `a` and `b`, made of six outward-facing quads
+    // ("The Next Week" section 8). The corners must differ on every axis.
+    pub fn add_box(&mut self, a: Vec3, b: Vec3, material: u32) -> Result<()> {
+        let min = Vec3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z()));
+        let max = Vec3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z()));
+        if (0..3).any(|axis| max[axis] - min[axis] <= 0.) {
+            bail!("box corners must differ on every axis");
+        }
+        let dx = Vec3::new(max.x() - min.x(), 0., 0.);
+        let dy = Vec3::new(0., max.y() - min.y(), 0.);
+        let dz = Vec3::new(0., 0., max.z() - min.z());
+        self.add_quad(Vec3::new(min.x(), min.y(), max.z()), dx, dy, material)?; // front
+        self.add_quad(Vec3::new(max.x(), min.y(), max.z()), -dz, dy, material)?; // right
+        self.add_quad(Vec3::new(max.x(), min.y(), min.z()), -dx, dy, material)?; // back
+        self.add_quad(Vec3::new(min.x(), min.y(), min.z()), dz, dy, material)?; // left
+        self.add_quad(Vec3::new(min.x(), max.y(), max.z()), dx, -dz, material)?; // top
+        self.add_quad(Vec3::new(min.x(), min.y(), min.z()), dx, dz, material) // bottom
+    }
+
+    fn push_quad(&mut self, kind: u32, q: Vec3, u: Vec3, v: Vec3, material: u32) {
+        self.quads.push(Quad {
+            q,
+            kind,
+            u,
+            material,
+            v,
+            _pad: 0,
+        });
+    }
+
+    // Adds a single instance of `mesh` without transform.
+    pub fn add_mesh(&mut self, mesh: &Mesh, material: u32) {
+        let geometry = self.add_geometry(mesh, material);
+        self.add_instance(geometry, Transform::identity());
+    }
+
+    // Places `geometry` in the world. `transform` must not be singular.
+    pub fn add_instance(&mut self, geometry: u32, transform: Transform) {
+        self.push_instance(geometry, transform, transform, false);
+    }
+
+    // Places `geometry` in the world with transform `start` at time 0 and `end` at time 1.
+    // Both, and the linear interpolation between them, must not be singular.
+    pub fn add_moving_instance(&mut self, geometry: u32, start: Transform, end: Transform) {
+        self.push_instance(geometry, start, end, true);
+    }
+
+    fn push_instance(&mut self, geometry: u32, start: Transform, end: Transform, moving: bool) {
+        self.instances.push(Instance {
+            to_world: start,
+            to_object: start.inverse(),
+            to_world1: end,
+            blas_root: self.geometries[geometry as usize].root,
+            moving: moving as u32,
+            wide_root: self.geometries[geometry as usize].wide_root,
+            _pad: 0,
+        });
+    }
+
+    // World-space bounds of an instance, covering its motion.
+    pub fn instance_bounds(&self, instance: &Instance) -> Aabb {
+        let root = self.blas_nodes[instance.blas_root as usize].bounds();
+        let bounds = root.transformed(&instance.to_world);
+        if instance.moving != 0 { bounds.union(&root.transformed(&instance.to_world1)) } else { bounds }
+    }
+
+    // Object-space bounds of triangle `index` of `Scene::triangles`.
+    pub fn triangle_bounds(&self, index: usize) -> Aabb {
+        Aabb::from_points(self.triangles[index].indices.iter().map(|&i| &self.vertices[i as usize].position))
+    }
+
+    // Appends the triangles of `mesh` and builds their BLAS, without placing them in the world
+    // yet. Missing normals and tangents are computed; missing UVs default to zero. Returns
+    // the geometry index for `add_instance`.
+    pub fn add_geometry(&mut self, mesh: &Mesh, material: u32) -> u32 {
+        self.add_geometry_with(mesh, material, &mut Bvh::build)
+    }
+
+    // `add_geometry` with the BLAS built by `build`, such as `GpuLbvh::build`.
+    pub fn add_geometry_with(&mut self, mesh: &Mesh, material: u32, build: &mut dyn FnMut(&[Aabb]) -> Bvh) -> u32 {
+        // Only the missing attributes are computed, the rest of the mesh is used as it is.
+        let vertex_count = mesh.positions.len();
+        let normals = if mesh.normals.len() == vertex_count {
+            Cow::Borrowed(mesh.normals.as_slice())
+        } else {
+            Cow::Owned(mesh.vertex_normals())
+        };
+        let tangents = if mesh.tangents.len() == vertex_count && matches!(normals, Cow::Borrowed(_)) {
+            Cow::Borrowed(mesh.tangents.as_slice())
+        } else {
+            Cow::Owned(mesh.vertex_tangents(&normals))
+        };
+        let base = self.vertices.len() as u32;
+        for (i, &position) in mesh.positions.iter().enumerate() {
+            let [u, v] = mesh.uvs.get(i).copied().unwrap_or([0., 0.]);
+            self.vertices.push(Vertex {
+                position,
+                u,
+                normal: normals[i],
+                v,
+                tangent: tangents[i],
+                color: mesh.colors.get(i).copied().unwrap_or(Vec3::all(1.)),
+                _pad: 0,
+            });
+        }
+
+        let bounds: Vec<Aabb> = mesh
+            .indices
+            .iter()
+            .map(|triangle| Aabb::from_points(triangle.map(|i| &mesh.positions[i as usize])))
+            .collect();
+        let bvh = build(&bounds);
+        let (node_base, triangle_base) = (self.blas_nodes.len() as u32, self.triangles.len() as u32);
+        for &t in &bvh.order {
+            let [a, b, c] = mesh.indices[t as usize];
+            self.triangles.push(Triangle {
+                indices: [base + a, base + b, base + c],
+                material,
+            });
+        }
+        self.blas_nodes.extend(bvh.nodes.iter().map(|node| BvhNode {
+            left_first: node.left_first + if node.is_leaf() { triangle_base } else { node_base },
+            ..*node
+        }));
+        let wide = wide_bvh::collapse(&bvh.nodes);
+        let wide_root = if wide_bvh::stack_entries(&wide) <= wide_bvh::STACK_SIZE {
+            let wide_base = self.wide_blas_nodes.len() as u32;
+            self.wide_blas_nodes
+                .extend(wide.iter().map(|node| node.offset(wide_base, triangle_base)));
+            wide_base
+        } else {
+            eprintln!(
+                "warning: the wide BLAS of geometry {} needs more than {} stack entries, \
+                 it is traced in the binary layout",
+                self.geometries.len(),
+                wide_bvh::STACK_SIZE
+            );
+            wide_bvh::NO_WIDE_BLAS
+        };
+        self.geometries.push(Geometry {
+            root: node_base,
+            wide_root,
+            first_triangle: triangle_base,
+            triangle_count: mesh.indices.len() as u32,
+        });
+        self.geometries.len() as u32 - 1
+    }
+
+    // The final scene of "Ray Tracing in One Weekend" with a 10x10 grid of small spheres.
+    pub fn week1_final() -> Scene {
+        Self::sphere_field(false)
+    }
+
+    // "The Next Week" section 2.4: the same, but the diffuse spheres bounce during the
+    // shutter interval.
+    pub fn bouncing_spheres() -> Scene {
+        Self::sphere_field(true)
+    }
+
+    fn sphere_field(bouncing: bool) -> Scene {
+        const GRID_SIZE: u32 = 10; // -5 to 5 (减少球数量)
+        let mut scene = Scene::new();
+
+        let ground = scene.add_material(Material::lambertian(Vec3::all(0.5)));
+        scene.add_sphere(Vec3::new(0., -1000., 0.), 1000., ground);
+        let glass = scene.add_material(Material::dielectric(1.5));
+        scene.add_sphere(Vec3::new(0., 1., 0.), 1., glass);
+        let brown = scene.add_material(Material::lambertian(Vec3::new(0.4, 0.2, 0.1)));
+        scene.add_sphere(Vec3::new(-4., 1., 0.), 1., brown);
+        let metal = scene.add_material(Material::metal(Vec3::new(0.7, 0.6, 0.5), 0.));
+        scene.add_sphere(Vec3::new(4., 1., 0.), 1., metal);
+
+        // 使用确定性随机数，保证每次生成的场景相同
+        let rand = |seed: u32| deterministic_rand(jenkins_hash(seed));
+        for i in 0..GRID_SIZE * GRID_SIZE {
+            let grid_x = (i % GRID_SIZE) as f32 - 5.;
+            let grid_z = (i / GRID_SIZE) as f32 - 5.;
+            let center = Vec3::new(grid_x + 0.9 * rand(i * 3), 0.2, grid_z + 0.9 * rand(i * 3 + 1));
+            // 跳过与主球重叠的小球
+            if (center - Vec3::new(4., 0.2, 0.)).length() <= 0.9 {
+                continue;
+            }
+            let choose_mat = rand(i * 3 + 2);
+            let mut center1 = center;
+            let material = if choose_mat < 0.8 {
+                if bouncing {
+                    center1 += Vec3::new(0., 0.5 * rand(i * 7 + 1000), 0.);
+                }
+                let albedo = Vec3::new(
+                    rand(i * 6) * rand(i * 6 + 1),
+                    rand(i * 6 + 2) * rand(i * 6 + 3),
+                    rand(i * 6 + 4) * rand(i * 6 + 5),
+                );
+                Material::lambertian(albedo)
+            } else if choose_mat < 0.95 {
+                let albedo = Vec3::new(
+                    0.5 + 0.5 * rand(i * 4),
+                    0.5 + 0.5 * rand(i * 4 + 1),
+                    0.5 + 0.5 * rand(i * 4 + 2),
+                );
+                Material::metal(albedo, 0.5 * rand(i * 4 + 3))
+            } else {
+                Material::dielectric(1.5)
+            };
+            let material = scene.add_material(material);
+            scene.add_moving_sphere(center, center1, 0.2, material);
+        }
+        scene
+    }
+
+    // "The Next Week" section 4.3: two spheres with a checker texture.
+    pub fn checkered_spheres() -> Scene {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(5., 2., 1.5);
+        scene.view.center = Vec3::zero();
+        let checker = scene.add_texture(Texture::checker(
+            0.32,
+            Vec3::new(0.2, 0.3, 0.1),
+            Vec3::all(0.9),
+        ));
+        let material = scene.add_material(Material::lambertian(Vec3::all(1.)).with_texture(checker));
+        scene.add_sphere(Vec3::new(0., -10., 0.), 10., material);
+        scene.add_sphere(Vec3::new(0., 10., 0.), 10., material);
+        scene
+    }
+
+    // "The Next Week" section 4.6: a globe textured with an equirectangular earth map.
+    pub fn earth(image: &Path) -> Result<Scene> {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 0., 5.);
+        scene.view.center = Vec3::zero();
+        let earth = scene.add_image_texture(image)?;
+        let material = scene.add_material(Material::lambertian(Vec3::all(1.)).with_texture(earth));
+        scene.add_sphere(Vec3::zero(), 2., material);
+        Ok(scene)
+    }
+
+    // "The Next Week" section 5: marble spheres on Perlin noise ground.
+    pub fn perlin_spheres() -> Scene {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(5., 2., 1.5);
+        scene.view.center = Vec3::new(0., 1., 0.);
+        let noise = scene.add_texture(Texture::marble(4.));
+        let material = scene.add_material(Material::lambertian(Vec3::all(1.)).with_texture(noise));
+        scene.add_sphere(Vec3::new(0., -1000., 0.), 1000., material);
+        scene.add_sphere(Vec3::new(0., 2., 0.), 2., material);
+        scene
+    }
+
+    // Procedural bump and roughness maps on a triangle-mesh floor and two spheres.
+    pub fn bump_maps() -> Scene {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 1.5, 4.);
+        scene.view.center = Vec3::new(0., 0.5, 0.);
+
+        let checker = scene.add_texture(Texture::checker(0.5, Vec3::all(0.8), Vec3::all(0.3)));
+        let bumps = scene.add_texture(Texture::turbulence(6.));
+        let floor = scene.add_material(
+            Material::lambertian(Vec3::all(1.))
+                .with_texture(checker)
+                .with_bump_map(bumps, 0.05),
+        );
+        scene.add_mesh(&Mesh::plane(Vec3::zero(), 8., 8., 4.), floor);
+
+        let marble = scene.add_texture(Texture::marble(8.));
+        let stone = scene.add_material(
+            Material::lambertian(Vec3::new(0.8, 0.7, 0.6))
+                .with_texture(marble)
+                .with_bump_map(marble, 0.02),
+        );
+        scene.add_sphere(Vec3::new(-0.8, 0.6, 0.), 0.6, stone);
+
+        let noise = scene.add_texture(Texture::noise(10.));
+        let brushed = scene.add_material(
+            Material::metal(Vec3::new(0.9, 0.8, 0.6), 0.5).with_roughness_map(noise),
+        );
+        scene.add_sphere(Vec3::new(0.8, 0.6, 0.), 0.6, brushed);
+        scene
+    }
+
+    // Rows of GGX conductors and rough glass with roughness increasing from left to right.
+    pub fn microfacets() -> Scene {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 1.2, 5.);
+        scene.view.center = Vec3::new(0., 0.9, 0.);
+
+        let checker = scene.add_texture(Texture::checker(0.5, Vec3::all(0.8), Vec3::all(0.2)));
+        let ground = scene.add_material(Material::lambertian(Vec3::all(1.)).with_texture(checker));
+        scene.add_sphere(Vec3::new(0., -1000., 0.), 1000., ground);
+
+        let roughness = [0.05, 0.2, 0.4, 0.7];
+        let rows: [fn(f32) -> Material; 3] = [Material::gold, Material::aluminium, |r| {
+            Material::rough_dielectric(1.5, r)
+        }];
+        for (row, make) in rows.iter().enumerate() {
+            for (column, &r) in roughness.iter().enumerate() {
+                let material = scene.add_material(make(r));
+                let center = Vec3::new(column as f32 - 1.5, 0.4 + 0.9 * row as f32, 0.);
+                scene.add_sphere(center, 0.4, material);
+            }
+        }
+        scene
+    }
+
+    // A row of principled materials next to the basic ones, lit by the sky and a lamp.
+    pub fn principled() -> Scene {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 1.5, 6.);
+        scene.view.center = Vec3::new(0., 0.6, 0.);
+
+        let checker = scene.add_texture(Texture::checker(0.5, Vec3::all(0.8), Vec3::all(0.2)));
+        let ground = scene.add_material(Material::lambertian(Vec3::all(1.)).with_texture(checker));
+        scene.add_sphere(Vec3::new(0., -1000., 0.), 1000., ground);
+
+        let red = Vec3::new(0.8, 0.1, 0.1);
+        let variants = [
+            Principled { base_color: red, ..Default::default() },
+            Principled { base_color: red, roughness: 0.2, metallic: 1., ..Default::default() },
+            Principled { base_color: red, sheen: 1., roughness: 0.8, ..Default::default() },
+            Principled { base_color: red, clearcoat: 1., ..Default::default() },
+            Principled {
+                base_color: Vec3::all(1.),
+                transmission: 1.,
+                roughness: 0.1,
+                ..Default::default()
+            },
+        ];
+        for (i, params) in variants.iter().enumerate() {
+            let material = scene.add_material(Material::principled(params));
+            scene.add_sphere(Vec3::new(1.1 * i as f32 - 2.2, 0.5, 0.), 0.5, material);
+        }
+
+        // The basic materials, for comparison.
+        let basics = [
+            Material::lambertian(red),
+            Material::metal(red, 0.2),
+            Material::dielectric(1.45),
+        ];
+        for (i, &material) in basics.iter().enumerate() {
+            let material = scene.add_material(material);
+            scene.add_sphere(Vec3::new(1.1 * i as f32 - 1.1, 0.3, 1.2), 0.3, material);
+        }
+
+        let lamp = scene.add_material(Material::principled(&Principled {
+            base_color: Vec3::zero(),
+            emission: Vec3::new(1., 0.9, 0.7),
+            emission_strength: 4.,
+            ..Default::default()
+        }));
+        scene.add_sphere(Vec3::new(0., 3., -1.), 0.5, lamp);
+        scene
+    }
+
+    // Black and white smoke balls ("The Next Week" section 9) in a valley of height fog.
+    pub fn smoke() -> Scene {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 1.2, 6.);
+        scene.view.center = Vec3::new(0., 0.8, 0.);
+
+        let ground = scene.add_material(Material::lambertian(Vec3::new(0.48, 0.83, 0.53)));
+        scene.add_sphere(Vec3::new(0., -1000., 0.), 1000., ground);
+        let glass = scene.add_material(Material::dielectric(1.5));
+        scene.add_sphere(Vec3::new(0., 0.6, 1.5), 0.6, glass);
+
+        scene.add_medium(Medium::sphere(Vec3::new(-1.3, 1., 0.), 1., 2., Vec3::all(0.)));
+        scene.add_medium(Medium::sphere(Vec3::new(1.3, 1., 0.), 1., 2., Vec3::all(1.)).with_phase(0.6));
+        scene.fog = Fog::new(0.15, 0., 2.);
+        scene
+    }
+
+    // A procedural cloud and a fire made of emissive noise.
+    pub fn clouds() -> Result<Scene> {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 1.5, 7.);
+        scene.view.center = Vec3::new(0., 1.2, 0.);
+
+        let ground = scene.add_material(Material::lambertian(Vec3::all(0.5)));
+        scene.add_sphere(Vec3::new(0., -1000., 0.), 1000., ground);
+
+        let cloud = Volume::noise(Vec3::new(-3., 1., -1.5), Vec3::new(0.5, 3., 1.5), 4., 3.)
+            .with_albedo(Vec3::all(0.95))
+            .with_phase(0.5);
+        scene.add_volume(cloud)?;
+        let fire = Volume::noise(Vec3::new(1., 0., -0.8), Vec3::new(2.6, 2.2, 0.8), 3., 4.)
+            .with_albedo(Vec3::all(0.2))
+            .with_emission(Vec3::new(6., 2., 0.4));
+        scene.add_volume(fire)?;
+        Ok(scene)
+    }
+
+    // Renders the grid stored at `path`, inside its own bounding box.
+    pub fn volume_grid(path: &Path) -> Result<Scene> {
+        let mut scene = Scene::new();
+        let grid = DensityGrid::load(path)?;
+        let (lo, hi) = grid.bounds;
+        let center = 0.5 * (lo + hi);
+        let extent = (hi - lo).length();
+        scene.view.center = center;
+        scene.view.origin = center + Vec3::new(0., 0.2 * extent, 1.2 * extent);
+
+        let ground = scene.add_material(Material::lambertian(Vec3::all(0.5)));
+        scene.add_sphere(Vec3::new(0., lo.y() - 1000., 0.), 1000., ground);
+        let index = scene.add_density_grid(grid);
+        scene.add_volume(Volume::grid(index, lo, hi, 1.).with_emission(Vec3::new(4., 1.5, 0.3)))?;
+        Ok(scene)
+    }
+
+    // "The Next Week" section 6, plus a disk standing on an infinite plane.
+    pub fn quads() -> Result<Scene> {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 0., 9.);
+        scene.view.center = Vec3::zero();
+
+        let left_red = scene.add_material(Material::lambertian(Vec3::new(1., 0.2, 0.2)));
+        let back_green = scene.add_material(Material::lambertian(Vec3::new(0.2, 1., 0.2)));
+        let right_blue = scene.add_material(Material::lambertian(Vec3::new(0.2, 0.2, 1.)));
+        let upper_orange = scene.add_material(Material::lambertian(Vec3::new(1., 0.5, 0.)));
+        let lower_teal = scene.add_material(Material::lambertian(Vec3::new(0.2, 0.8, 0.8)));
+
+        let (x, y, z) = (Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.), Vec3::new(0., 0., 1.));
+        scene.add_quad(Vec3::new(-3., -2., 5.), -4. * z, 4. * y, left_red)?;
+        scene.add_quad(Vec3::new(-2., -2., 0.), 4. * x, 4. * y, back_green)?;
+        scene.add_quad(Vec3::new(3., -2., 1.), 4. * z, 4. * y, right_blue)?;
+        scene.add_quad(Vec3::new(-2., 3., 1.), 4. * x, 4. * z, upper_orange)?;
+        scene.add_quad(Vec3::new(-2., -3., 5.), 4. * x, -4. * z, lower_teal)?;
+
+        let checker = scene.add_texture(Texture::checker(1., Vec3::all(0.8), Vec3::all(0.3)));
+        let floor = scene.add_material(Material::lambertian(Vec3::all(1.)).with_texture(checker));
+        scene.add_plane(Vec3::new(0., -3.5, 0.), y, floor)?;
+        let gold = scene.add_material(Material::gold(0.3));
+        scene.add_disk(Vec3::new(0., 0., 2.), z, 1., gold)?;
+        Ok(scene)
+    }
+
+    // "The Next Week" section 8.3: two rotated blocks, instances of a single unit cube.
+    pub fn cornell_box() -> Result<Scene> {
+        let mut scene = Scene::new();
+        scene.background = Some(Vec3::zero());
+        scene.view.origin = Vec3::new(278., 278., -800.);
+        scene.view.center = Vec3::new(278., 278., 0.);
+
+        let red = scene.add_material(Material::lambertian(Vec3::new(0.65, 0.05, 0.05)));
+        let white = scene.add_material(Material::lambertian(Vec3::all(0.73)));
+        let green = scene.add_material(Material::lambertian(Vec3::new(0.12, 0.45, 0.15)));
+        let light = scene.add_material(Material::diffuse_light(Vec3::all(15.)));
+
+        let (x, y, z) = (Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.), Vec3::new(0., 0., 1.));
+        scene.add_quad(Vec3::new(555., 0., 0.), 555. * y, 555. * z, green)?;
+        scene.add_quad(Vec3::zero(), 555. * z, 555. * y, red)?;
+        scene.add_quad(Vec3::new(343., 554., 332.), -130. * x, -105. * z, light)?;
+        scene.add_quad(Vec3::zero(), 555. * x, 555. * z, white)?;
+        scene.add_quad(Vec3::all(555.), -555. * x, -555. * z, white)?;
+        scene.add_quad(Vec3::new(0., 0., 555.), 555. * x, 555. * y, white)?;
+
+        let cube = scene.add_geometry(&Mesh::cuboid(Vec3::zero(), Vec3::all(1.)), white);
+        let tall = Transform::scale(Vec3::new(165., 330., 165.))
+            .then(&Transform::rotation_y(15.))
+            .then(&Transform::translation(Vec3::new(265., 0., 295.)));
+        scene.add_instance(cube, tall);
+        let short = Transform::scale(Vec3::all(165.))
+            .then(&Transform::rotation_y(-18.))
+            .then(&Transform::translation(Vec3::new(130., 0., 65.)));
+        scene.add_instance(cube, short);
+        Ok(scene)
+    }
+
+    // A thousand randomly rotated and scaled cubes sharing three geometries. Every seventh
+    // cube spins about its axis during the shutter interval.
+    pub fn instances() -> Result<Scene> {
+        const GRID_SIZE: u32 = 10;
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 6., 22.);
+        scene.view.center = Vec3::new(0., 4.5, 0.);
+
+        let ground = scene.add_material(Material::lambertian(Vec3::all(0.5)));
+        scene.add_plane(Vec3::new(0., -0.5, 0.), Vec3::new(0., 1., 0.), ground)?;
+        let unit_cube = Mesh::cuboid(Vec3::all(-0.5), Vec3::all(0.5));
+        let cubes = [
+            Material::lambertian(Vec3::new(0.8, 0.3, 0.2)),
+            Material::gold(0.2),
+            Material::rough_dielectric(1.5, 0.1),
+        ]
+        .map(|material| {
+            let material = scene.add_material(material);
+            scene.add_geometry(&unit_cube, material)
+        });
+
+        let rand = |seed: u32| deterministic_rand(jenkins_hash(seed));
+        for i in 0..GRID_SIZE * GRID_SIZE * GRID_SIZE {
+            let (x, y, z) = (i % GRID_SIZE, i / GRID_SIZE % GRID_SIZE, i / (GRID_SIZE * GRID_SIZE));
+            let center = Vec3::new(x as f32 - 4.5, y as f32, z as f32 - 4.5);
+            let axis = Vec3::new(rand(i * 5) - 0.5, rand(i * 5 + 1) - 0.5, rand(i * 5 + 2) - 0.5);
+            let at_angle = |degrees: f32| {
+                Transform::scale(Vec3::all(0.3 + 0.3 * rand(i * 5 + 3)))
+                    .then(&Transform::rotation(axis + Vec3::all(1e-3), degrees))
+                    .then(&Transform::translation(center))
+            };
+            let angle = 360. * rand(i * 5 + 4);
+            let cube = cubes[(i % 3) as usize];
+            if i % 7 == 0 {
+                // Small steps keep the linearly interpolated matrices close to rotations.
+                scene.add_moving_instance(cube, at_angle(angle), at_angle(angle + 20.));
+            } else {
+                scene.add_instance(cube, at_angle(angle));
+            }
+        }
+        Ok(scene)
+    }
+
+    // A ring of columns with conical roofs around a glass torus, with tilted pipes and
+    // interlocked rings in front.
+    pub fn shapes() -> Result<Scene> {
+        const COLUMN_COUNT: u32 = 8;
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 3.5, 11.);
+        scene.view.center = Vec3::new(0., 1.2, 0.);
+
+        let checker = scene.add_texture(Texture::checker(0.5, Vec3::all(0.8), Vec3::all(0.2)));
+        let floor = scene.add_material(Material::lambertian(Vec3::all(1.)).with_texture(checker));
+        let y = Vec3::new(0., 1., 0.);
+        scene.add_plane(Vec3::zero(), y, floor)?;
+
+        let stone = scene.add_material(Material::lambertian(Vec3::new(0.75, 0.7, 0.6)));
+        let roof = scene.add_material(Material::copper(0.3));
+        for i in 0..COLUMN_COUNT {
+            let angle = i as f32 * std::f32::consts::TAU / COLUMN_COUNT as f32;
+            let base = 3.5 * Vec3::new(angle.cos(), 0., angle.sin());
+            scene.add_shape(Shape::cone(base, y, 0.35, 0.3, 0.2, stone));
+            scene.add_shape(Shape::cylinder(base, y, 0.25, 2.5, stone));
+            scene.add_shape(Shape::cone(base + 2.5 * y, y, 0.45, 0., 0.6, roof));
+        }
+
+        let glass = scene.add_material(Material::dielectric(1.5));
+        scene.add_shape(Shape::torus(Vec3::new(0., 1.2, 0.), Vec3::new(0.3, 1., 0.2), 1.2, 0.4, glass));
+
+        let gold = scene.add_material(Material::gold(0.15));
+        let aluminium = scene.add_material(Material::aluminium(0.25));
+        scene.add_shape(Shape::torus(Vec3::new(-0.5, 0.7, 3.), Vec3::new(0., 0., 1.), 0.5, 0.12, gold));
+        scene.add_shape(Shape::torus(Vec3::new(0.1, 0.7, 3.), Vec3::new(0., 1., 0.), 0.5, 0.12, aluminium));
+
+        let red = scene.add_material(Material::lambertian(Vec3::new(0.7, 0.15, 0.1)));
+        scene.add_shape(Shape::cylinder(Vec3::new(1.5, 0.2, 2.), Vec3::new(1., 0.3, 0.5), 0.2, 1.5, red));
+        scene.add_shape(Shape::cone(Vec3::new(-2.2, 0., 2.), Vec3::new(-0.4, 1., 0.3), 0.5, 0.2, 1., red));
+        Ok(scene)
+    }
+
+    // Boolean combinations of spheres, boxes and cylinders, each part in its own material so
+    // that the surviving surfaces can be told apart: a drilled rounded cube, a lens, an opened
+    // shell and a pipe.
+    pub fn csg() -> Result<Scene> {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 2.5, 6.);
+        scene.view.center = Vec3::new(0., 0.6, 0.);
+
+        let floor = scene.add_material(Material::lambertian(Vec3::all(0.6)));
+        scene.add_plane(Vec3::zero(), Vec3::new(0., 1., 0.), floor)?;
+
+        let red = scene.add_material(Material::lambertian(Vec3::new(0.7, 0.1, 0.1)));
+        let blue = scene.add_material(Material::lambertian(Vec3::new(0.1, 0.2, 0.7)));
+        let gold = scene.add_material(Material::gold(0.2));
+        let center = Vec3::new(-1.2, 0.7, 0.);
+        let drill = |axis: Vec3| Csg::cylinder(center - 0.8 * axis, center + 0.8 * axis, 0.3, gold);
+        let rounded_cube = Csg::cuboid(center - Vec3::all(0.6), center + Vec3::all(0.6), red)
+            .intersection(Csg::sphere(center, 0.8, blue));
+        let drills = drill(Vec3::new(1., 0., 0.))
+            .union(drill(Vec3::new(0., 1., 0.)))
+            .union(drill(Vec3::new(0., 0., 1.)));
+        scene.add_csg(&rounded_cube.difference(drills))?;
+
+        let glass = scene.add_material(Material::dielectric(1.5));
+        let lens = Vec3::new(1.2, 0.8, 0.);
+        let offset = Vec3::new(0., 0., 1.2);
+        scene.add_csg(&Csg::sphere(lens - offset, 1.4, glass).intersection(Csg::sphere(lens + offset, 1.4, glass)))?;
+
+        let copper = scene.add_material(Material::copper(0.25));
+        let white = scene.add_material(Material::lambertian(Vec3::all(0.9)));
+        let shell = Vec3::new(0., 0.6, -2.);
+        let window = Csg::cuboid(shell + Vec3::new(-0.3, 0., 0.), shell + Vec3::new(0.7, 0.7, 0.7), white);
+        scene.add_csg(&Csg::sphere(shell, 0.6, copper).difference(Csg::sphere(shell, 0.5, white).union(window)))?;
+
+        let pipe = Csg::cylinder(Vec3::new(-0.5, 0.25, 1.5), Vec3::new(0.7, 0.25, 1.2), 0.25, copper);
+        let bore = Csg::cylinder(Vec3::new(-0.6, 0.25, 1.525), Vec3::new(0.8, 0.25, 1.175), 0.18, white);
+        scene.add_csg(&pipe.difference(bore))?;
+        Ok(scene)
+    }
+
+    // The height map at `path` as a 100 x 20 x 100 landscape under a low sun, with a lake. If
+    // there is no file at `path`, a procedural landscape takes its place.
+    pub fn terrain(path: &Path) -> Result<Scene> {
+        let mut scene = Scene::new();
+        scene.view.origin = Vec3::new(0., 35., 75.);
+        scene.view.center = Vec3::new(0., 5., 0.);
+
+        let map = if path.exists() {
+            HeightMap::load(path)?
+        } else {
+            eprintln!("{} not found, generating a procedural landscape", path.display());
+            HeightMap::procedural(257, 0)
+        };
+        let ground = scene.add_texture(Texture::turbulence(0.5));
+        let ground = scene.add_material(Material::lambertian(Vec3::new(0.45, 0.55, 0.3)).with_texture(ground));
+        scene.add_heightfield(&map, Vec3::new(-50., 0., -50.), Vec3::new(100., 20., 100.), ground);
+        let water = scene.add_material(Material::rough_dielectric(1.33, 0.05));
+        scene.add_quad(Vec3::new(-50., 3., 50.), Vec3::new(100., 0., 0.), Vec3::new(0., 0., -100.), water)?;
+        Ok(scene)
+    }
+}
+
+// A slightly modified version of the "One-at-a-Time Hash" function by Bob Jenkins, identical
+// to `jenkins_hash` in shaders.wgsl.
+fn jenkins_hash(i: u32) -> u32 {
+    let mut x = i;
+    x = x.wrapping_add(x << 10);
+    x ^= x >> 6;
+    x = x.wrapping_add(x << 3);
+    x ^= x >> 11;
+    x = x.wrapping_add(x << 15);
+    x
+}
+
+// 确定性随机数生成器，与原来着色器中的 `deterministic_rand` 相同
+fn deterministic_rand(seed: u32) -> f32 {
+    let x = jenkins_hash(seed);
+    f32::from_bits(0x3f800000 | (x >> 9)) - 1.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csg_limits_match_the_shader() {
+        let shader = include_str!("shaders.wgsl");
+        assert!(shader.contains(&format!("const CSG_STACK_SIZE: u32 = {CSG_STACK_SIZE}u;")));
+        assert!(shader.contains(&format!("const CSG_MAX_SPANS: u32 = {CSG_MAX_SPANS}u;")));
+    }
+
+    #[test]
+    fn csg_max_spans_bounds_the_spans_of_a_ray() {
+        let sphere = |x: f32| Csg::sphere(Vec3::new(x, 0., 0.), 0.4, 0);
+        assert_eq!(sphere(0.).max_spans(), 1);
+        assert_eq!(sphere(0.).union(sphere(1.)).max_spans(), 2);
+        let pair = || sphere(0.).union(sphere(1.));
+        assert_eq!(pair().intersection(pair()).max_spans(), 3);
+        // A rod with two holes drilled across it, cut into three pieces.
+        let rod = Csg::cylinder(Vec3::new(-1., 0., 0.), Vec3::new(2., 0., 0.), 0.2, 0);
+        assert_eq!(rod.difference(pair()).max_spans(), 3);
+        let comb = (0..4).map(|i| sphere(i as f32)).reduce(Csg::union).unwrap();
+        assert_eq!(comb.max_spans(), 4);
+        assert_eq!(comb.union(sphere(4.)).max_spans(), 5);
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-4, "{a:?} != {b:?}");
+    }
+
+    fn assert_contains(bounds: &Aabb, p: Vec3) {
+        for axis in 0..3 {
+            let slack = 1e-4 * (1. + p[axis].abs());
+            let inside = bounds.min[axis] - slack <= p[axis] && p[axis] <= bounds.max[axis] + slack;
+            assert!(inside, "{p:?} outside {bounds:?}");
+        }
+    }
+
+    // Rotations, non-uniform scales and a mirror, each moved off the origin.
+    fn transforms() -> Vec<Transform> {
+        let offset = Transform::translation(Vec3::new(3., -1., 2.5));
+        [
+            Transform::identity(),
+            Transform::rotation_y(30.),
+            Transform::rotation(Vec3::new(1., 2., -0.5), 117.),
+            Transform::scale(Vec3::new(2., 0.5, 3.)).then(&Transform::rotation(Vec3::new(0., 1., 1.), -64.)),
+            Transform::scale(Vec3::new(-1., 1., 1.)).then(&Transform::rotation(Vec3::new(1., 0., 0.), 45.)),
+        ]
+        .iter()
+        .map(|transform| transform.then(&offset))
+        .collect()
+    }
+
+    #[test]
+    fn instance_transforms_invert() {
+        let mut rng = crate::lbvh::Rng(0x2545f491);
+        for transform in transforms() {
+            let mut scene = Scene::new();
+            let geometry = scene.add_geometry(&Mesh::cuboid(Vec3::all(-1.), Vec3::all(1.)), 0);
+            scene.add_instance(geometry, transform);
+            let instance = scene.instances.last().unwrap();
+            let identity = instance.to_world.then(&instance.to_object);
+            for (row, expected) in identity.rows.iter().zip(Transform::identity().rows) {
+                for (value, expected) in row.iter().zip(expected) {
+                    assert!((value - expected).abs() < 1e-5, "{identity:?}");
+                }
+            }
+            for _ in 0..16 {
+                let p = 10. * rng.vec3() - Vec3::all(5.);
+                assert_near(instance.to_object.transform_point(&transform.transform_point(&p)), p);
+            }
+        }
+    }
+
+    #[test]
+    fn transformed_bounds_contain_the_transformed_box() {
+        let mut rng = crate::lbvh::Rng(7);
+        let bounds = Aabb { min: Vec3::new(-1., 0., 2.), max: Vec3::new(0.5, 3., 2.25) };
+        for transform in transforms() {
+            let transformed = bounds.transformed(&transform);
+            for i in 0..8 {
+                let pick = |axis: usize| if i & (1 << axis) == 0 { bounds.min[axis] } else { bounds.max[axis] };
+                assert_contains(&transformed, transform.transform_point(&Vec3::new(pick(0), pick(1), pick(2))));
+            }
+            for _ in 0..16 {
+                let (t, d) = (rng.vec3(), bounds.max - bounds.min);
+                let p = bounds.min + Vec3::new(t.x() * d.x(), t.y() * d.y(), t.z() * d.z());
+                assert_contains(&transformed, transform.transform_point(&p));
+            }
+        }
+    }
+
+    #[test]
+    fn moving_instance_bounds_cover_the_motion() {
+        let mesh = Mesh::cuboid(Vec3::new(-1., 0., -0.5), Vec3::new(1., 2., 0.5));
+        let transforms = transforms();
+        for (start, end) in transforms.iter().zip(transforms.iter().rev()) {
+            let mut scene = Scene::new();
+            let geometry = scene.add_geometry(&mesh, 0);
+            scene.add_moving_instance(geometry, *start, *end);
+            let bounds = scene.instance_bounds(scene.instances.last().unwrap());
+            for t in [0., 0.25, 0.5, 1.] {
+                // The shader interpolates the matrices, so every point moves on a straight line.
+                let mut transform = *start;
+                for (row, (a, b)) in transform.rows.iter_mut().zip(start.rows.iter().zip(&end.rows)) {
+                    for (value, (a, b)) in row.iter_mut().zip(a.iter().zip(b)) {
+                        *value = a + t * (b - a);
+                    }
+                }
+                for position in &mesh.positions {
+                    assert_contains(&bounds, transform.transform_point(position));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn moving_sphere_bounds_cover_the_motion() {
+        let mut scene = Scene::new();
+        let (center0, center1, radius) = (Vec3::new(1., 0.5, -2.), Vec3::new(-0.5, 3., -1.), 0.75);
+        scene.add_moving_sphere(center0, center1, radius, 0);
+        scene.add_moving_sphere(center1, center0, radius, 0);
+        for sphere in &scene.spheres {
+            let bounds = sphere.bounds();
+            // A box contains a sphere if it contains its extreme points along the axes.
+            let axes = [Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.), Vec3::new(0., 0., 1.)];
+            for t in [0., 0.5, 1.] {
+                let center = center0 + t * (center1 - center0);
+                for axis in axes {
+                    assert_contains(&bounds, center + radius * axis);
+                    assert_contains(&bounds, center - radius * axis);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn textures_match_the_shader() {
+        let shader = include_str!("shaders.wgsl");
+        for (name, kind) in [
+            ("SOLID", TEXTURE_SOLID),
+            ("CHECKER", TEXTURE_CHECKER),
+            ("IMAGE", TEXTURE_IMAGE),
+            ("NOISE", TEXTURE_NOISE),
+            ("TURBULENCE", TEXTURE_TURBULENCE),
+            ("MARBLE", TEXTURE_MARBLE),
+        ] {
+            assert!(shader.contains(&format!("const TEXTURE_{name}: u32 = {kind}u;")), "TEXTURE_{name}");
+        }
+        // WGSL aligns each vec3f to 16 bytes and rounds the struct up to its alignment.
+        use std::mem::offset_of;
+        let offsets = [offset_of!(Texture, color0), offset_of!(Texture, kind), offset_of!(Texture, color1)];
+        assert_eq!(offsets, [0, 12, 16]);
+        assert_eq!([offset_of!(Texture, scale), offset_of!(Texture, layer), offset_of!(Texture, srgb)], [28, 32, 36]);
+        assert_eq!(size_of::<Texture>(), 48);
+    }
+
+    #[test]
+    fn texture_constructors_set_their_kind() {
+        let (even, odd) = (Vec3::new(0.2, 0.3, 0.1), Vec3::all(0.9));
+        let checker = Texture::checker(0.32, even, odd);
+        assert_eq!((checker.kind, checker.scale), (TEXTURE_CHECKER, 0.32));
+        assert_eq!(format!("{:?}", (checker.color0, checker.color1)), format!("{:?}", (even, odd)));
+        assert_eq!(Texture::solid(even).kind, TEXTURE_SOLID);
+        for (texture, kind) in [
+            (Texture::noise(4.), TEXTURE_NOISE),
+            (Texture::turbulence(4.), TEXTURE_TURBULENCE),
+            (Texture::marble(4.), TEXTURE_MARBLE),
+        ] {
+            assert_eq!((texture.kind, texture.scale), (kind, 4.));
+        }
+    }
+
+    #[test]
+    fn image_textures_get_their_own_layer() {
+        let path = std::env::temp_dir().join(format!("scene_texture_{}.png", std::process::id()));
+        RgbaImage::from_pixel(3, 2, image::Rgba([10, 20, 30, 255])).save(&path).unwrap();
+        let mut scene = Scene::new();
+        let solid = scene.add_texture(Texture::solid(Vec3::all(0.5)));
+        let color = scene.add_image_texture(&path);
+        let data = scene.add_data_texture(&path);
+        let missing = scene.add_data_texture(&path.with_extension("missing.png"));
+        std::fs::remove_file(&path).unwrap();
+        let (color, data) = (color.unwrap(), data.unwrap());
+        assert!(format!("{:#}", missing.err().unwrap()).starts_with("failed to load texture"));
+        assert_eq!([solid, color, data], [0, 1, 2]);
+        assert_eq!(scene.images.len(), 2);
+        let layers = |index: u32| {
+            let texture = &scene.textures[index as usize];
+            (texture.kind, texture.layer, texture.srgb)
+        };
+        assert_eq!(layers(color), (TEXTURE_IMAGE, 0, 1));
+        assert_eq!(layers(data), (TEXTURE_IMAGE, 1, 0));
+        assert_eq!(scene.images[1].get_pixel(2, 1).0, [10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn normal_and_bump_maps_are_set_per_material() {
+        use std::mem::offset_of;
+        assert_eq!(offset_of!(Material, normal_map), 20);
+        assert_eq!([offset_of!(Material, bump_map), offset_of!(Material, bump_scale)], [24, 28]);
+        assert_eq!(offset_of!(Material, eta), 48);
+
+        let plain = Material::lambertian(Vec3::all(0.5));
+        assert_eq!([plain.texture, plain.normal_map, plain.bump_map], [NO_TEXTURE; 3]);
+        let mapped = Material::metal(Vec3::all(0.9), 0.2).with_normal_map(3).with_bump_map(5, 0.05);
+        assert_eq!((mapped.normal_map, mapped.bump_map, mapped.bump_scale), (3, 5, 0.05));
+        assert_eq!([mapped.texture, mapped.roughness_map, mapped.metallic_map], [NO_TEXTURE; 3]);
+    }
+
+    #[test]
+    fn geometry_gets_tangents_along_u() {
+        // The plane has u along +x and v along -z. Without stored tangents, `add_geometry`
+        // computes them so that normal maps have a frame to work in.
+        let mut mesh = Mesh::plane(Vec3::zero(), 2., 2., 1.);
+        mesh.tangents.clear();
+        let mut scene = Scene::new();
+        scene.add_geometry(&mesh, 0);
+        assert_eq!(scene.vertices.len(), 4);
+        for vertex in &scene.vertices {
+            let [x, y, z, w] = vertex.tangent;
+            let tangent = Vec3::new(x, y, z);
+            assert_near(tangent, Vec3::new(1., 0., 0.));
+            assert_near(w * vertex.normal.cross(&tangent), Vec3::new(0., 0., -1.));
+        }
+    }
+
+    #[test]
+    fn media_match_the_shader() {
+        use std::mem::offset_of;
+        assert_eq!([offset_of!(Medium, radius), offset_of!(Medium, albedo), offset_of!(Medium, density)], [12, 16, 28]);
+        assert_eq!((offset_of!(Medium, g), size_of::<Medium>()), (32, 48));
+        assert_eq!([offset_of!(Fog, density), offset_of!(Fog, height), offset_of!(Fog, falloff)], [12, 16, 20]);
+        assert_eq!((offset_of!(Fog, g), size_of::<Fog>()), (24, 32));
+    }
+
+    #[test]
+    fn media_parameters_are_kept_in_range() {
+        let albedo = Vec3::new(0.9, 0.8, 0.7);
+        let medium = Medium::sphere(Vec3::new(1., 2., 3.), 0.5, 2.5, albedo).with_phase(0.3);
+        assert_eq!((medium.radius, medium.density, medium.g), (0.5, 2.5, 0.3));
+        assert_eq!(format!("{:?}", medium.albedo), format!("{albedo:?}"));
+        let fog = Fog::new(0.1, -1., 0.5).with_albedo(albedo).with_phase(-0.6);
+        assert_eq!((fog.density, fog.height, fog.falloff, fog.g), (0.1, -1., 0.5, -0.6));
+        assert_eq!(format!("{:?}", fog.albedo), format!("{albedo:?}"));
+
+        // Negative densities and falloffs mean no medium. At |g| >= 1 the Henyey-Greenstein
+        // phase function degenerates into a delta, so g stays strictly inside (-1, 1).
+        assert_eq!(Medium::sphere(Vec3::zero(), 1., -3., albedo).density, 0.);
+        assert_eq!((Fog::new(-0.2, 0., -1.).density, Fog::new(-0.2, 0., -1.).falloff), (0., 0.));
+        for (g, clamped) in [(1., 0.99), (-1., -0.99), (7.5, 0.99), (-20., -0.99)] {
+            assert_eq!(Medium::sphere(Vec3::zero(), 1., 1., albedo).with_phase(g).g, clamped);
+            assert_eq!(Fog::new(1., 0., 1.).with_phase(g).g, clamped);
+        }
+        assert_eq!(Fog::default().density, 0.);
+    }
+
+    #[test]
+    fn planar_primitives_face_their_normal() {
+        let mut scene = Scene::new();
+        let (q, u, v) = (Vec3::new(1., 2., 3.), Vec3::new(2., 0., 0.), Vec3::new(0.5, 0., -1.));
+        scene.add_quad(q, u, v, 7).unwrap();
+        let normal = Vec3::new(0.3, -1., 0.5);
+        scene.add_disk(q, normal, 0.75, 0).unwrap();
+        scene.add_plane(q, normal, 0).unwrap();
+
+        let quad = &scene.quads[0];
+        assert_eq!((quad.kind, quad.material), (QUAD_PARALLELOGRAM, 7));
+        assert_eq!(format!("{:?}", (quad.q, quad.u, quad.v)), format!("{:?}", (q, u, v)));
+        // Disks span their radius with orthogonal edges, planes repeat every unit.
+        for (quad, kind, length) in [(&scene.quads[1], QUAD_DISK, 0.75), (&scene.quads[2], QUAD_PLANE, 1.)] {
+            assert_eq!(quad.kind, kind);
+            assert!((quad.u.length() - length).abs() < 1e-5 && (quad.v.length() - length).abs() < 1e-5);
+            assert!(quad.u.dot(&quad.v).abs() < 1e-5);
+            assert_near(quad.u.cross(&quad.v).normalized(), normal.normalized());
+        }
+    }
+
+    #[test]
+    fn box_faces_point_outwards() {
+        let mut scene = Scene::new();
+        let (a, b) = (Vec3::new(2., -1., 0.5), Vec3::new(-1., 3., 0.));
+        scene.add_box(a, b, 0).unwrap();
+        assert_eq!(scene.quads.len(), 6);
+        let center = 0.5 * (a + b);
+        let bounds = Aabb::from_points(&[a, b]);
+        for quad in &scene.quads {
+            let face_center = quad.q + 0.5 * (quad.u + quad.v);
+            assert!(quad.u.cross(&quad.v).dot(&(face_center - center)) > 0., "{quad:?}");
+            for corner in [quad.q, quad.q + quad.u, quad.q + quad.v, quad.q + quad.u + quad.v] {
+                assert_contains(&bounds, corner);
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_planar_primitives_are_rejected() {
+        let (x, z) = (Vec3::new(1., 0., 0.), Vec3::new(0., 0., 1.));
+        let mut scene = Scene::new();
+        let errors = [
+            scene.add_quad(Vec3::zero(), x, -3. * x, 0),
+            scene.add_quad(Vec3::zero(), Vec3::zero(), z, 0),
+            scene.add_quad(Vec3::zero(), x, 1e-30 * z, 0),
+            scene.add_disk(Vec3::zero(), Vec3::zero(), 1., 0),
+            scene.add_disk(Vec3::zero(), z, 0., 0),
+            scene.add_disk(Vec3::zero(), z, -1., 0),
+            scene.add_disk(Vec3::zero(), z, f32::NAN, 0),
+            scene.add_plane(Vec3::zero(), Vec3::zero(), 0),
+            scene.add_box(Vec3::zero(), x + z, 0),
+        ];
+        for (i, error) in errors.into_iter().enumerate() {
+            assert!(error.is_err(), "case {i} was accepted");
+        }
+        assert!(scene.quads.is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+// scene_diff.rs
+// Incremental scene updates for editing. `SceneMirror` keeps a CPU copy of the primitives and
+// BVHs the renderer uploaded. Given the edited scene, `diff` finds the spheres, quads, shapes,
+// instances and materials that changed, refits the BVHs over them, and returns the byte ranges
+// of the GPU buffers to rewrite with `queue.write_buffer`.
+//
+// Anything else, and any change to the number of objects, needs a full upload. Refitting keeps
+// the topology of the BVHs, which slowly get worse as objects move away from where they were
+// when the scene was loaded.
+use crate::{
+    bvh::{Aabb, Bvh, BvhNode},
+    scene::{Instance, Material, Quad, Scene, Shape, Sphere},
+};
+use {
+    bytemuck::Pod,
+    std::hash::{DefaultHasher, Hash, Hasher},
+};
+
+// The GPU buffers a diff may write to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SceneBuffer {
+    Spheres,
+    SphereNodes,
+    Quads,
+    Shapes,
+    ShapeNodes,
+    Instances,
+    TlasNodes,
+    Materials,
+}
+
+pub struct BufferWrite {
+    pub buffer: SceneBuffer,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+pub enum SceneDiff {
+    // Writes that bring the GPU buffers up to date, none if nothing changed.
+    Update(Vec<BufferWrite>),
+    // The scene changed in a way the buffers cannot follow; the reason is for the user.
+    Rebuild(&'static str),
+}
+
+// One kind of primitive as uploaded: the items in scene order, and the BVH over them whose
+// leaf order is their order on the GPU.
+pub struct Uploaded<T> {
+    items: Vec<T>,
+    buffer: SceneBuffer,
+    bvh: Option<(Bvh, SceneBuffer)>,
+    // GPU position of every item.
+    slots: Vec<usize>,
+}
+
+impl<T: Pod> Uploaded<T> {
+    fn new(items: &[T], buffer: SceneBuffer) -> Self {
+        Uploaded {
+            items: items.to_vec(),
+            buffer,
+            bvh: None,
+            slots: (0..items.len()).collect(),
+        }
+    }
+
+    fn with_bvh(
+        items: &[T],
+        buffer: SceneBuffer,
+        nodes_buffer: SceneBuffer,
+        bounds: impl Fn(&T) -> Aabb,
+        build: &mut dyn FnMut(&[Aabb]) -> Bvh,
+    ) -> Self {
+        let bvh = build(&items.iter().map(bounds).collect::<Vec<_>>());
+        let mut slots = vec![0; items.len()];
+        for (slot, &i) in bvh.order.iter().enumerate() {
+            slots[i as usize] = slot;
+        }
+        Uploaded {
+            items: items.to_vec(),
+            buffer,
+            bvh: Some((bvh, nodes_buffer)),
+            slots,
+        }
+    }
+
+    // The items in the order they have on the GPU.
+    pub fn gpu_items(&self) -> Vec<T> {
+        let mut sorted = self.items.clone();
+        for (item, &slot) in self.items.iter().zip(&self.slots) {
+            sorted[slot] = *item;
+        }
+        sorted
+    }
+
+    pub fn nodes(&self) -> &[BvhNode] {
+        self.bvh.as_ref().map_or(&[], |(bvh, _)| &bvh.nodes)
+    }
+
+    // Takes over `items`, as many as before, and records the writes for the ones that
+    // changed. Returns their indices.
+    fn update(&mut self, items: &[T], writes: &mut Vec<BufferWrite>) -> Vec<usize> {
+        let changed: Vec<usize> = (0..items.len())
+            .filter(|&i| bytemuck::bytes_of(&items[i]) != bytemuck::bytes_of(&self.items[i]))
+            .collect();
+        self.items.copy_from_slice(items);
+        push_writes(writes, self.buffer, changed.iter().map(|&i| (self.slots[i], items[i])).collect());
+        changed
+    }
+
+    // Refits the BVH after the items `changed` by `update` got the new `bounds`, and records
+    // the writes for the nodes that changed.
+    fn refit(&mut self, changed: &[usize], bounds: impl Fn(&T) -> Aabb, writes: &mut Vec<BufferWrite>) {
+        let Some((bvh, buffer)) = &mut self.bvh else {
+            return;
+        };
+        if changed.is_empty() {
+            return;
+        }
+        let bounds: Vec<Aabb> = self.items.iter().map(bounds).collect();
+        let refitted = bvh.refit(&bounds, changed);
+        push_writes(writes, *buffer, refitted.into_iter().map(|i| (i, bvh.nodes[i])).collect());
+    }
+}
+
+// Appends one write per run of consecutive slots.
+fn push_writes<T: Pod>(writes: &mut Vec<BufferWrite>, buffer: SceneBuffer, mut items: Vec<(usize, T)>) {
+    items.sort_by_key(|&(slot, _)| slot);
+    for run in items.chunk_by(|a, b| b.0 == a.0 + 1) {
+        writes.push(BufferWrite {
+            buffer,
+            offset: (run[0].0 * std::mem::size_of::<T>()) as u64,
+            data: run.iter().flat_map(|(_, item)| bytemuck::bytes_of(item)).copied().collect(),
+        });
+    }
+}
+
+pub struct SceneMirror {
+    pub spheres: Uploaded<Sphere>,
+    pub quads: Uploaded<Quad>,
+    pub shapes: Uploaded<Shape>,
+    pub instances: Uploaded<Instance>,
+    pub materials: Uploaded<Material>,
+    // Hash of everything else that is uploaded.
+    fixed: u64,
+}
+
+impl SceneMirror {
+    // Builds the BVHs over the spheres, shapes and instances with `build`, `Bvh::build` or
+    // `GpuLbvh::build`. Refits after edits work on either.
+    pub fn new(scene: &Scene, build: &mut dyn FnMut(&[Aabb]) -> Bvh) -> SceneMirror {
+        use SceneBuffer::*;
+        SceneMirror {
+            spheres: Uploaded::with_bvh(&scene.spheres, Spheres, SphereNodes, Sphere::bounds, build),
+            quads: Uploaded::new(&scene.quads, Quads),
+            shapes: Uploaded::with_bvh(&scene.shapes, Shapes, ShapeNodes, Shape::bounds, build),
+            instances: Uploaded::with_bvh(
+                &scene.instances,
+                Instances,
+                TlasNodes,
+                |i| scene.instance_bounds(i),
+                build,
+            ),
+            materials: Uploaded::new(&scene.materials, Materials),
+            fixed: fixed_hash(scene),
+        }
+    }
+
+    // Compares `scene` with the uploaded one and takes over its state if the buffers can be
+    // updated in place.
+    pub fn diff(&mut self, scene: &Scene) -> SceneDiff {
+        if fixed_hash(scene) != self.fixed {
+            return SceneDiff::Rebuild("meshes, SDFs, CSG, terrain, textures or volumes changed");
+        }
+        let counts = [
+            (self.spheres.items.len(), scene.spheres.len()),
+            (self.quads.items.len(), scene.quads.len()),
+            (self.shapes.items.len(), scene.shapes.len()),
+            (self.instances.items.len(), scene.instances.len()),
+            (self.materials.items.len(), scene.materials.len()),
+        ];
+        if counts.iter().any(|(old, new)| old != new) {
+            return SceneDiff::Rebuild("objects were added or removed");
+        }
+        let mut writes = Vec::new();
+        let changed = self.spheres.update(&scene.spheres, &mut writes);
+        self.spheres.refit(&changed, Sphere::bounds, &mut writes);
+        self.quads.update(&scene.quads, &mut writes);
+        let changed = self.shapes.update(&scene.shapes, &mut writes);
+        self.shapes.refit(&changed, Shape::bounds, &mut writes);
+        let changed = self.instances.update(&scene.instances, &mut writes);
+        self.instances.refit(&changed, |i| scene.instance_bounds(i), &mut writes);
+        self.materials.update(&scene.materials, &mut writes);
+        SceneDiff::Update(writes)
+    }
+}
+
+// Hashes the uploaded scene data that `SceneMirror` does not track item by item.
+fn fixed_hash(scene: &Scene) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut add = |bytes: &[u8]| {
+        bytes.len().hash(&mut hasher);
+        hasher.write(bytes);
+    };
+    add(bytemuck::cast_slice(&scene.sdf_objects));
+    add(bytemuck::cast_slice(&scene.sdf_shapes));
+    add(bytemuck::cast_slice(&scene.csg_objects));
+    add(bytemuck::cast_slice(&scene.csg_nodes));
+    add(bytemuck::cast_slice(&scene.heightfields));
+    add(bytemuck::cast_slice(&scene.heightfield_data));
+    add(bytemuck::cast_slice(&scene.vertices));
+    add(bytemuck::cast_slice(&scene.triangles));
+    add(bytemuck::cast_slice(&scene.blas_nodes));
+    add(bytemuck::cast_slice(&scene.wide_blas_nodes));
+    add(bytemuck::cast_slice(&scene.textures));
+    add(bytemuck::cast_slice(&scene.media));
+    add(bytemuck::cast_slice(&scene.volumes));
+    add(bytemuck::bytes_of(&scene.fog));
+    add(bytemuck::cast_slice(&scene.background.map_or([0.; 4], |c| [c.x(), c.y(), c.z(), 1.])));
+    for grid in &scene.grids {
+        add(bytemuck::cast_slice(&grid.size));
+        add(bytemuck::cast_slice(&grid.voxels));
+    }
+    for image in &scene.images {
+        add(bytemuck::cast_slice(&[image.width(), image.height()]));
+        add(image.as_raw());
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            algebra::{Transform, Vec3},
+            lbvh,
+            mesh::Mesh,
+            scene::{Sdf, SdfShape},
+        },
+        std::mem::size_of,
+    };
+
+    // What `scene` changes relative to the scene with the default edits.
+    #[derive(Default, Copy, Clone)]
+    struct Edits {
+        sphere: f32,
+        quad: f32,
+        shape: f32,
+        instance: f32,
+        color: f32,
+        extra_sphere: bool,
+        sdf: bool,
+        background: bool,
+    }
+
+    const SPHERES: usize = 16;
+    const MOVED: usize = 5;
+
+    fn scene(edits: Edits) -> Scene {
+        let mut scene = Scene::new();
+        let white = scene.add_material(Material::lambertian(Vec3::all(0.8 + edits.color)));
+        let red = scene.add_material(Material::lambertian(Vec3::new(0.8, 0.1, 0.1)));
+        for i in 0..SPHERES {
+            let x = 2. * i as f32 + if i == MOVED { edits.sphere } else { 0. };
+            scene.add_sphere(Vec3::new(x, 0., 0.), 0.5, white);
+        }
+        if edits.extra_sphere {
+            scene.add_sphere(Vec3::new(0., 5., 0.), 0.5, red);
+        }
+        scene.add_quad(Vec3::new(0., edits.quad, 0.), Vec3::new(1., 0., 0.), Vec3::new(0., 0., 1.), red).unwrap();
+        for i in 0..4 {
+            let shift = if i == 2 { edits.shape } else { 0. };
+            let center = Vec3::new(3. * i as f32 + shift, 2., 0.);
+            scene.add_shape(Shape::torus(center, Vec3::new(0., 1., 0.), 0.5, 0.1, red));
+        }
+        let cube = scene.add_geometry(&Mesh::cuboid(Vec3::zero(), Vec3::all(1.)), white);
+        for i in 0..3 {
+            let shift = if i == 1 { edits.instance } else { 0. };
+            scene.add_instance(cube, Transform::translation(Vec3::new(3. * i as f32 + shift, -2., 0.)));
+        }
+        let radius = if edits.sdf { 0.6 } else { 0.5 };
+        scene.add_sdf(&Sdf::new(white).with_shape(SdfShape::sphere(Vec3::new(0., 4., 0.), radius))).unwrap();
+        if edits.background {
+            scene.background = Some(Vec3::all(0.5));
+        }
+        scene
+    }
+
+    fn diff(edits: Edits) -> SceneDiff {
+        SceneMirror::new(&scene(Edits::default()), &mut Bvh::build).diff(&scene(edits))
+    }
+
+    fn written(edits: Edits) -> Vec<SceneBuffer> {
+        let SceneDiff::Update(writes) = diff(edits) else {
+            panic!("expected an update");
+        };
+        let mut buffers: Vec<SceneBuffer> = writes.iter().map(|w| w.buffer).collect();
+        buffers.dedup();
+        buffers
+    }
+
+    fn rebuild_reason(edits: Edits) -> &'static str {
+        match diff(edits) {
+            SceneDiff::Rebuild(reason) => reason,
+            SceneDiff::Update(_) => panic!("expected a rebuild"),
+        }
+    }
+
+    #[test]
+    fn edits_update_or_rebuild() {
+        use SceneBuffer::*;
+        let edit = Edits::default();
+        assert_eq!(written(edit), []);
+        assert_eq!(written(Edits { sphere: 0.5, ..edit }), [Spheres, SphereNodes]);
+        assert_eq!(written(Edits { quad: 0.5, ..edit }), [Quads]);
+        assert_eq!(written(Edits { shape: 0.5, ..edit }), [Shapes, ShapeNodes]);
+        assert_eq!(written(Edits { instance: 0.5, ..edit }), [Instances, TlasNodes]);
+        assert_eq!(written(Edits { color: 0.1, ..edit }), [Materials]);
+        assert_eq!(
+            written(Edits { sphere: 0.5, quad: 1., color: 0.1, ..edit }),
+            [Spheres, SphereNodes, Quads, Materials]
+        );
+        assert_eq!(rebuild_reason(Edits { extra_sphere: true, ..edit }), "objects were added or removed");
+        let fixed = "meshes, SDFs, CSG, terrain, textures or volumes changed";
+        assert_eq!(rebuild_reason(Edits { sdf: true, ..edit }), fixed);
+        assert_eq!(rebuild_reason(Edits { background: true, ..edit }), fixed);
+    }
+
+    #[test]
+    fn moved_sphere_writes_its_slot_and_the_refitted_nodes() {
+        let mut mirror = SceneMirror::new(&scene(Edits::default()), &mut Bvh::build);
+        let old_nodes = mirror.spheres.nodes().to_vec();
+        let moved = scene(Edits { sphere: 0.5, ..Edits::default() });
+        let SceneDiff::Update(writes) = mirror.diff(&moved) else {
+            panic!("expected an update");
+        };
+
+        let (spheres, nodes): (Vec<_>, Vec<_>) = writes.iter().partition(|w| w.buffer == SceneBuffer::Spheres);
+        let slot = mirror.spheres.slots[MOVED];
+        assert_eq!(spheres.len(), 1);
+        assert_eq!(spheres[0].offset, (slot * size_of::<Sphere>()) as u64);
+        assert_eq!(spheres[0].data, bytemuck::bytes_of(&moved.spheres[MOVED]));
+        assert_eq!(bytemuck::bytes_of(&mirror.spheres.gpu_items()[slot]), spheres[0].data);
+
+        // The node writes cover exactly the nodes whose boxes changed, with their new contents.
+        let new_nodes = mirror.spheres.nodes();
+        let mut written = Vec::new();
+        for write in &nodes {
+            assert_eq!(write.buffer, SceneBuffer::SphereNodes);
+            let first = write.offset as usize / size_of::<BvhNode>();
+            let count = write.data.len() / size_of::<BvhNode>();
+            assert_eq!(write.data, bytemuck::cast_slice::<_, u8>(&new_nodes[first..first + count]));
+            written.extend(first..first + count);
+        }
+        let changed: Vec<usize> = (0..new_nodes.len())
+            .filter(|&i| bytemuck::bytes_of(&old_nodes[i]) != bytemuck::bytes_of(&new_nodes[i]))
+            .collect();
+        assert!(!changed.is_empty());
+        assert_eq!(written, changed);
+
+        // Diffing the same scene again writes nothing.
+        assert!(matches!(mirror.diff(&moved), SceneDiff::Update(writes) if writes.is_empty()));
+    }
+
+    #[test]
+    fn writes_merge_consecutive_slots() {
+        let mut writes = Vec::new();
+        push_writes(&mut writes, SceneBuffer::Quads, vec![(7, 70u32), (2, 20), (1, 10), (3, 30)]);
+        let writes: Vec<(u64, Vec<u8>)> = writes.into_iter().map(|w| (w.offset, w.data)).collect();
+        let bytes = |items: &[u32]| bytemuck::cast_slice::<_, u8>(items).to_vec();
+        assert_eq!(writes, [(4, bytes(&[10, 20, 30])), (28, bytes(&[70]))]);
+    }
+
+    #[test]
+    fn lbvh_trees_refit_like_sah_trees() {
+        let mut mirror = SceneMirror::new(&scene(Edits::default()), &mut lbvh::build);
+        let moved = scene(Edits { sphere: 0.5, instance: 0.5, ..Edits::default() });
+        let SceneDiff::Update(writes) = mirror.diff(&moved) else {
+            panic!("expected an update");
+        };
+        let buffers: Vec<SceneBuffer> = writes.iter().map(|w| w.buffer).collect();
+        assert!(buffers.contains(&SceneBuffer::SphereNodes) && buffers.contains(&SceneBuffer::TlasNodes));
+        let spheres: Vec<Aabb> = moved.spheres.iter().map(Sphere::bounds).collect();
+        let (bvh, _) = mirror.spheres.bvh.as_ref().unwrap();
+        lbvh::validate(&bvh.nodes, &bvh.order, &spheres).unwrap();
+        let instances: Vec<Aabb> = moved.instances.iter().map(|i| moved.instance_bounds(i)).collect();
+        let (bvh, _) = mirror.instances.bvh.as_ref().unwrap();
+        lbvh::validate(&bvh.nodes, &bvh.order, &instances).unwrap();
+    }
+}
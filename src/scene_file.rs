@@ -0,0 +1,684 @@
+// scene_file.rs
+// Scenes described in plain text, in the same `[section]` / `key = value` style as the
+// bookmark files:
+//
+//   [view]
+//   origin = 0 2 6
+//   center = 0 0.5 0
+//
+//   [material gold]
+//   type = gold
+//   roughness = 0.3
+//
+//   [sdf]
+//   material = gold
+//   smoothing = 0.2
+//   torus = 0 0.5 0  0.8 0.2
+//   sphere = 0 0.9 0  0.4
+//
+// Materials are referenced by name and must be defined before they are used. An `[sdf]`
+// section lists any number of shapes, one per line, merged with a smooth union:
+//
+//   sphere = cx cy cz  radius
+//   torus = cx cy cz  major minor
+//   box = cx cy cz  hx hy hz  rounding
+//   capsule = ax ay az  bx by bz  radius
+//
+// A `[csg]` section holds one constructive solid geometry tree, written as nested calls of
+// `union`, `intersection` and `difference` (each taking two or more operands, combined from
+// left to right) over the primitives
+//
+//   sphere(cx cy cz  radius)
+//   box(x0 y0 z0  x1 y1 z1)
+//   cylinder(ax ay az  bx by bz  radius)
+//
+// Any primitive may end with the name of its material, overriding the section's `material`:
+//
+//   [csg]
+//   material = stone
+//   tree = difference(box(-1 -1 -1  1 1 1), sphere(0 0 0  1.3 gold))
+//
+// Files referenced by a scene, such as the image of a `[heightfield]` or the PLY or STL file of
+// a `[mesh]`, are looked up relative to the scene file.
+//
+// See `Builder::add` for the other sections and their keys. `#` starts a comment. Unlike in
+// bookmark files, unknown keys are errors, since a typo would silently change the scene.
+use crate::{
+    algebra::{Transform, Vec3},
+    bvh::{Aabb, Bvh},
+    heightfield::HeightMap,
+    mesh_file,
+    scene::{Csg, Material, Scene, Sdf, SdfShape, Shape},
+};
+use {
+    anyhow::{Context, Result, anyhow, bail},
+    std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+    },
+};
+
+// Operations a CSG tree may nest, which keeps the recursion of `parse_csg` bounded. Trees the
+// shader can evaluate are much shallower anyway, see `Scene::add_csg`.
+const MAX_CSG_DEPTH: usize = 64;
+
+pub fn load(path: &Path) -> Result<Scene> {
+    load_with(path, &mut Bvh::build)
+}
+
+// `load` with the BLAS of the meshes built by `build`, see `Scene::add_geometry_with`.
+pub fn load_with(path: &Path, build: &mut dyn FnMut(&[Aabb]) -> Bvh) -> Result<Scene> {
+    let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    parse(&text, directory, build).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn parse(text: &str, directory: &Path, build: &mut dyn FnMut(&[Aabb]) -> Bvh) -> Result<Scene> {
+    let mut sections: Vec<Section> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow!("line {line_number}: unterminated section header"))?;
+            let (kind, name) = header.split_once(' ').unwrap_or((header, ""));
+            sections.push(Section {
+                kind: kind.trim(),
+                name: name.trim(),
+                line: line_number,
+                properties: Vec::new(),
+            });
+            continue;
+        }
+        let Some(section) = sections.last_mut() else {
+            bail!("line {line_number}: property outside of a section");
+        };
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {line_number}: expected `key = value`"))?;
+        section.properties.push(Property {
+            key: key.trim(),
+            value: value.trim(),
+            line: line_number,
+        });
+    }
+
+    let mut builder = Builder {
+        scene: Scene::new(),
+        materials: HashMap::new(),
+        directory: directory.to_path_buf(),
+        build,
+    };
+    for section in &sections {
+        builder
+            .add(section)
+            .with_context(|| format!("in [{}] at line {}", section.kind, section.line))?;
+    }
+    Ok(builder.scene)
+}
+
+struct Property<'a> {
+    key: &'a str,
+    value: &'a str,
+    line: usize,
+}
+
+struct Section<'a> {
+    kind: &'a str,
+    // Text after the kind in the header, e.g. the name of a material.
+    name: &'a str,
+    line: usize,
+    properties: Vec<Property<'a>>,
+}
+
+impl Section<'_> {
+    fn check_keys(&self, allowed: &[&str]) -> Result<()> {
+        match self.properties.iter().find(|p| !allowed.contains(&p.key)) {
+            Some(p) => bail!("line {}: unknown key `{}`, expected one of: {}", p.line, p.key, allowed.join(", ")),
+            None => Ok(()),
+        }
+    }
+
+    // The numbers of the last `key` line, if any.
+    fn numbers(&self, key: &str, count: usize) -> Result<Option<Vec<f32>>> {
+        let Some(p) = self.properties.iter().rev().find(|p| p.key == key) else {
+            return Ok(None);
+        };
+        parse_numbers(p.value, count)
+            .map(Some)
+            .with_context(|| format!("line {}: invalid `{key}`", p.line))
+    }
+
+    fn float_or(&self, key: &str, default: f32) -> Result<f32> {
+        Ok(self.numbers(key, 1)?.map_or(default, |v| v[0]))
+    }
+
+    fn float(&self, key: &str) -> Result<f32> {
+        self.numbers(key, 1)?.map(|v| v[0]).ok_or_else(|| anyhow!("missing `{key}`"))
+    }
+
+    fn optional_vec3(&self, key: &str) -> Result<Option<Vec3>> {
+        Ok(self.numbers(key, 3)?.map(|v| Vec3::new(v[0], v[1], v[2])))
+    }
+
+    fn vec3(&self, key: &str) -> Result<Vec3> {
+        self.optional_vec3(key)?.ok_or_else(|| anyhow!("missing `{key}`"))
+    }
+
+    // A vector that gets normalized, so it must have a length: `axis = 0 0 0` would make NaN bounds.
+    fn optional_direction(&self, key: &str) -> Result<Option<Vec3>> {
+        let direction = self.optional_vec3(key)?;
+        if let Some(d) = direction
+            && !d.length().is_normal()
+        {
+            let line = self.properties.iter().rev().find(|p| p.key == key).map_or(0, |p| p.line);
+            bail!("line {line}: `{key}` must have a nonzero length");
+        }
+        Ok(direction)
+    }
+
+    fn direction(&self, key: &str) -> Result<Vec3> {
+        self.optional_direction(key)?.ok_or_else(|| anyhow!("missing `{key}`"))
+    }
+
+    fn string(&self, key: &str) -> Option<&str> {
+        self.properties.iter().rev().find(|p| p.key == key).map(|p| p.value)
+    }
+}
+
+struct Builder<'a> {
+    scene: Scene,
+    materials: HashMap<String, u32>,
+    // Directory of the scene file.
+    directory: PathBuf,
+    // Builds the BLAS of the meshes.
+    build: &'a mut dyn FnMut(&[Aabb]) -> Bvh,
+}
+
+impl Builder<'_> {
+    fn add(&mut self, section: &Section) -> Result<()> {
+        let scene = &mut self.scene;
+        match section.kind {
+            "view" => {
+                section.check_keys(&["origin", "center", "up", "shutter", "background"])?;
+                let view = &mut scene.view;
+                view.origin = section.optional_vec3("origin")?.unwrap_or(view.origin);
+                view.center = section.optional_vec3("center")?.unwrap_or(view.center);
+                view.up = section.optional_vec3("up")?.unwrap_or(view.up);
+                if let Some(shutter) = section.numbers("shutter", 2)? {
+                    view.shutter = (shutter[0], shutter[1]);
+                }
+                scene.background = section.optional_vec3("background")?;
+            }
+            "material" => {
+                section.check_keys(&["type", "color", "fuzz", "ior", "roughness", "emission"])?;
+                if section.name.is_empty() {
+                    bail!("material without a name");
+                }
+                let color = section.optional_vec3("color")?.unwrap_or(Vec3::all(0.8));
+                let roughness = section.float_or("roughness", 0.)?;
+                let ior = section.float_or("ior", 1.5)?;
+                let material = match section.string("type").unwrap_or("lambertian") {
+                    "lambertian" => Material::lambertian(color),
+                    "metal" => Material::metal(color, section.float_or("fuzz", 0.)?),
+                    "dielectric" => Material::dielectric(ior),
+                    "rough_dielectric" => Material::rough_dielectric(ior, roughness),
+                    "gold" => Material::gold(roughness),
+                    "copper" => Material::copper(roughness),
+                    "aluminium" => Material::aluminium(roughness),
+                    "light" => Material::diffuse_light(section.vec3("emission")?),
+                    other => bail!(
+                        "unknown material type `{other}`, expected one of: lambertian, metal, \
+                         dielectric, rough_dielectric, gold, copper, aluminium, light"
+                    ),
+                };
+                let material = match section.optional_vec3("emission")? {
+                    Some(emission) => material.with_emission(emission),
+                    None => material,
+                };
+                let index = scene.add_material(material);
+                if self.materials.insert(section.name.to_string(), index).is_some() {
+                    bail!("material `{}` is defined twice", section.name);
+                }
+            }
+            "sphere" => {
+                section.check_keys(&["center", "center1", "radius", "material"])?;
+                let center = section.vec3("center")?;
+                let center1 = section.optional_vec3("center1")?.unwrap_or(center);
+                let material = self.material(section)?;
+                self.scene.add_moving_sphere(center, center1, section.float("radius")?, material);
+            }
+            "quad" => {
+                section.check_keys(&["q", "u", "v", "material"])?;
+                let material = self.material(section)?;
+                self.scene.add_quad(section.vec3("q")?, section.vec3("u")?, section.vec3("v")?, material)?;
+            }
+            "disk" => {
+                section.check_keys(&["center", "normal", "radius", "material"])?;
+                let material = self.material(section)?;
+                let (center, normal) = (section.vec3("center")?, section.direction("normal")?);
+                self.scene.add_disk(center, normal, section.float("radius")?, material)?;
+            }
+            "plane" => {
+                section.check_keys(&["point", "normal", "material"])?;
+                let material = self.material(section)?;
+                self.scene.add_plane(section.vec3("point")?, section.direction("normal")?, material)?;
+            }
+            "box" => {
+                section.check_keys(&["min", "max", "material"])?;
+                let material = self.material(section)?;
+                self.scene.add_box(section.vec3("min")?, section.vec3("max")?, material)?;
+            }
+            "cylinder" => {
+                section.check_keys(&["base", "axis", "radius", "height", "material"])?;
+                let material = self.material(section)?;
+                let axis = section.optional_direction("axis")?.unwrap_or(Vec3::new(0., 1., 0.));
+                let (radius, height) = (section.float("radius")?, section.float("height")?);
+                self.scene.add_shape(Shape::cylinder(section.vec3("base")?, axis, radius, height, material));
+            }
+            "cone" => {
+                section.check_keys(&["base", "axis", "radius", "top_radius", "height", "material"])?;
+                let material = self.material(section)?;
+                let axis = section.optional_direction("axis")?.unwrap_or(Vec3::new(0., 1., 0.));
+                let (radius, top_radius) = (section.float("radius")?, section.float_or("top_radius", 0.)?);
+                let height = section.float("height")?;
+                self.scene.add_shape(Shape::cone(section.vec3("base")?, axis, radius, top_radius, height, material));
+            }
+            "torus" => {
+                section.check_keys(&["center", "axis", "major", "minor", "material"])?;
+                let material = self.material(section)?;
+                let axis = section.optional_direction("axis")?.unwrap_or(Vec3::new(0., 1., 0.));
+                let (major, minor) = (section.float("major")?, section.float("minor")?);
+                self.scene.add_shape(Shape::torus(section.vec3("center")?, axis, major, minor, material));
+            }
+            "sdf" => {
+                section.check_keys(&[
+                    "material", "smoothing", "repeat", "bounds", "sphere", "torus", "box", "capsule",
+                ])?;
+                let mut sdf = Sdf::new(self.material(section)?)
+                    .with_smoothing(section.float_or("smoothing", 0.)?);
+                for p in &section.properties {
+                    let shape = |count| {
+                        parse_numbers(p.value, count).with_context(|| format!("line {}: invalid `{}`", p.line, p.key))
+                    };
+                    let v = |n: &[f32], i: usize| Vec3::new(n[i], n[i + 1], n[i + 2]);
+                    let shape = match p.key {
+                        "sphere" => shape(4).map(|n| SdfShape::sphere(v(&n, 0), n[3]))?,
+                        "torus" => shape(5).map(|n| SdfShape::torus(v(&n, 0), n[3], n[4]))?,
+                        "box" => shape(7).map(|n| SdfShape::round_box(v(&n, 0), v(&n, 3), n[6]))?,
+                        "capsule" => shape(7).map(|n| SdfShape::capsule(v(&n, 0), v(&n, 3), n[6]))?,
+                        _ => continue,
+                    };
+                    sdf = sdf.with_shape(shape);
+                }
+                sdf.bounds = section.numbers("bounds", 6)?.map(|n| Aabb {
+                    min: Vec3::new(n[0], n[1], n[2]),
+                    max: Vec3::new(n[3], n[4], n[5]),
+                });
+                sdf.repeat = section.optional_vec3("repeat")?.unwrap_or(Vec3::zero());
+                self.scene.add_sdf(&sdf)?;
+            }
+            "heightfield" => {
+                section.check_keys(&["image", "origin", "size", "material"])?;
+                let material = self.material(section)?;
+                let image = section.string("image").ok_or_else(|| anyhow!("missing `image`"))?;
+                let map = HeightMap::load(&self.directory.join(image))?;
+                let (origin, size) = (section.vec3("origin")?, section.vec3("size")?);
+                self.scene.add_heightfield(&map, origin, size, material);
+            }
+            "mesh" => {
+                section.check_keys(&["file", "material", "scale", "rotate_y", "translate"])?;
+                let material = self.material(section)?;
+                let file = section.string("file").ok_or_else(|| anyhow!("missing `file`"))?;
+                let mesh = mesh_file::load(&self.directory.join(file))?;
+                let transform = Transform::scale(Vec3::all(section.float_or("scale", 1.)?))
+                    .then(&Transform::rotation_y(section.float_or("rotate_y", 0.)?))
+                    .then(&Transform::translation(section.optional_vec3("translate")?.unwrap_or(Vec3::zero())));
+                let geometry = self.scene.add_geometry_with(&mesh, material, self.build);
+                self.scene.add_instance(geometry, transform);
+            }
+            "csg" => {
+                section.check_keys(&["material", "tree"])?;
+                let tree = section.string("tree").ok_or_else(|| anyhow!("missing `tree`"))?;
+                let material = |name: Option<&str>| match name {
+                    Some(name) => self.named_material(name),
+                    None => self.material(section),
+                };
+                let mut rest = tree;
+                let csg = parse_csg(&mut rest, &material, 0).context("invalid `tree`")?;
+                if !rest.trim().is_empty() {
+                    bail!("unexpected `{}` after the CSG tree", rest.trim());
+                }
+                self.scene.add_csg(&csg)?;
+            }
+            other => bail!(
+                "unknown section `{other}`, expected one of: view, material, sphere, quad, disk, \
+                 plane, box, cylinder, cone, torus, sdf, csg, heightfield, mesh"
+            ),
+        }
+        Ok(())
+    }
+
+    fn material(&self, section: &Section) -> Result<u32> {
+        self.named_material(section.string("material").ok_or_else(|| anyhow!("missing `material`"))?)
+    }
+
+    fn named_material(&self, name: &str) -> Result<u32> {
+        self.materials
+            .get(name)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown material `{name}`"))
+    }
+}
+
+// Parses the CSG expression at the start of `text` and advances `text` past it. `material`
+// resolves the material name a primitive ends with, or the default one if None. `depth` counts
+// the operations around the expression.
+fn parse_csg(text: &mut &str, material: &impl Fn(Option<&str>) -> Result<u32>, depth: usize) -> Result<Csg> {
+    let (name, rest) = text
+        .split_once('(')
+        .ok_or_else(|| anyhow!("expected `name(...)`, found `{}`", text.trim()))?;
+    let name = name.trim();
+    *text = rest;
+    match name {
+        "union" | "intersection" | "difference" => {
+            if depth == MAX_CSG_DEPTH {
+                bail!("CSG tree nested more than {MAX_CSG_DEPTH} operations deep");
+            }
+            let mut csg = parse_csg(text, material, depth + 1)?;
+            let mut operands = 1;
+            loop {
+                let rest = text.trim_start();
+                if let Some(rest) = rest.strip_prefix(')') {
+                    *text = rest;
+                    break;
+                }
+                *text = rest
+                    .strip_prefix(',')
+                    .ok_or_else(|| anyhow!("expected `,` or `)` in `{name}(...)`"))?;
+                let operand = parse_csg(text, material, depth + 1)?;
+                csg = match name {
+                    "union" => csg.union(operand),
+                    "intersection" => csg.intersection(operand),
+                    _ => csg.difference(operand),
+                };
+                operands += 1;
+            }
+            if operands < 2 {
+                bail!("`{name}` needs at least two operands");
+            }
+            Ok(csg)
+        }
+        "sphere" | "box" | "cylinder" => {
+            let (arguments, rest) = text.split_once(')').ok_or_else(|| anyhow!("unterminated `{name}(`"))?;
+            *text = rest;
+            let mut words: Vec<&str> = arguments.split_whitespace().collect();
+            let material = match words.last() {
+                Some(word) if word.parse::<f32>().is_err() => material(words.pop()),
+                _ => material(None),
+            }?;
+            let arguments = words.join(" ");
+            let numbers = |count| parse_numbers(&arguments, count).with_context(|| format!("invalid `{name}`"));
+            let v = |n: &[f32], i: usize| Vec3::new(n[i], n[i + 1], n[i + 2]);
+            Ok(match name {
+                "sphere" => numbers(4).map(|n| Csg::sphere(v(&n, 0), n[3], material))?,
+                "box" => numbers(6).map(|n| Csg::cuboid(v(&n, 0), v(&n, 3), material))?,
+                _ => {
+                    let n = numbers(7)?;
+                    if !(v(&n, 3) - v(&n, 0)).length().is_normal() {
+                        bail!("`cylinder` needs a top point different from its base");
+                    }
+                    Csg::cylinder(v(&n, 0), v(&n, 3), n[6], material)
+                }
+            })
+        }
+        other => bail!(
+            "unknown CSG node `{other}`, expected one of: union, intersection, difference, sphere, box, \
+             cylinder"
+        ),
+    }
+}
+
+fn parse_numbers(text: &str, count: usize) -> Result<Vec<f32>> {
+    let numbers = text
+        .split_whitespace()
+        .map(str::parse::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() != count {
+        bail!("expected {count} numbers, found {}", numbers.len());
+    }
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every section once, with comments, blank lines and the files of `[heightfield]` and
+    // `[mesh]` next to it.
+    const SCENE: &str = "\
+# a test scene
+[view]
+origin = 0 2 6   # behind the objects
+center = 0 0.5 0
+up = 0 1 0
+shutter = 0 0.5
+background = 0.1 0.2 0.3
+
+[material white]
+[material gold]
+type = gold
+roughness = 0.3
+[material lamp]
+type = light
+emission = 4 4 4
+
+[sphere]
+center = 0 1 0
+center1 = 0 1.2 0
+radius = 0.5
+material = white
+[quad]
+q = -1 0 -1
+u = 2 0 0
+v = 0 0 2
+material = white
+[disk]
+center = 0 3 0
+normal = 0 -1 0
+radius = 0.5
+material = lamp
+[plane]
+point = 0 0 0
+normal = 0 1 0
+material = white
+[box]
+min = 0 0 0
+max = 1 1 1
+material = gold
+[cylinder]
+base = 2 0 0
+radius = 0.3
+height = 1
+material = gold
+[cone]
+base = 3 0 0
+axis = 0 0 1
+radius = 0.3
+top_radius = 0.1
+height = 1
+material = gold
+[torus]
+center = 4 0 0
+major = 0.5
+minor = 0.1
+material = gold
+[sdf]
+material = gold
+smoothing = 0.2
+torus = 0 0.5 0  0.8 0.2
+sphere = 0 0.9 0  0.4
+[csg]
+material = white
+tree = difference(box(-1 -1 -1  1 1 1), sphere(0 0 0  1.3 gold))
+[heightfield]
+image = ground.png
+origin = -5 0 -5
+size = 10 1 10
+material = white
+[mesh]
+file = triangle.ply
+material = gold
+scale = 2
+rotate_y = 90
+translate = 0 1 0
+";
+
+    // A directory holding the files `SCENE` refers to, removed when dropped.
+    struct Files(PathBuf);
+
+    impl Files {
+        fn new(name: &str) -> Files {
+            let directory = std::env::temp_dir().join(format!("scene_file_{name}_{}", std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            image::ImageBuffer::<image::Luma<u16>, _>::from_fn(4, 3, |x, z| image::Luma([(x * z * 1000) as u16]))
+                .save(directory.join("ground.png"))
+                .unwrap();
+            let ply = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                       property float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n\
+                       0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n";
+            fs::write(directory.join("triangle.ply"), ply).unwrap();
+            Files(directory)
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn error(text: &str) -> String {
+        let files = Files::new("error");
+        match parse(text, &files.0, &mut Bvh::build) {
+            Ok(_) => panic!("parsing should fail"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn every_section_adds_its_object() {
+        let files = Files::new("valid");
+        let scene = parse(SCENE, &files.0, &mut Bvh::build).unwrap();
+        let view = &scene.view;
+        assert_eq!([view.origin.x(), view.origin.y(), view.origin.z()], [0., 2., 6.]);
+        assert_eq!([view.center.x(), view.center.y(), view.center.z()], [0., 0.5, 0.]);
+        assert_eq!(view.shutter, (0., 0.5));
+        assert!(scene.background.is_some_and(|b| [b.x(), b.y(), b.z()] == [0.1, 0.2, 0.3]));
+        assert_eq!(scene.materials.len(), 3);
+        assert_eq!(scene.spheres.len(), 1);
+        // One quad, one disk, one plane and the six sides of the box.
+        assert_eq!(scene.quads.len(), 9);
+        assert_eq!(scene.shapes.len(), 3);
+        assert_eq!((scene.sdf_objects.len(), scene.sdf_shapes.len()), (1, 2));
+        assert_eq!((scene.csg_objects.len(), scene.csg_nodes.len()), (1, 3));
+        assert_eq!((scene.heightfields.len(), scene.heightfield_data.len()), (1, 12 + 2));
+        assert_eq!((scene.geometries.len(), scene.instances.len(), scene.triangles.len()), (1, 1, 1));
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        assert_eq!(error("[view\n"), "line 1: unterminated section header");
+        assert_eq!(error("# comment\norigin = 0 0 0\n"), "line 2: property outside of a section");
+        assert_eq!(error("[view]\norigin 0 0 0\n"), "line 2: expected `key = value`");
+        assert_eq!(
+            error("[view]\n\norigin = 0 0 0\nzoom = 2\n"),
+            "in [view] at line 1: line 4: unknown key `zoom`, expected one of: origin, center, up, shutter, \
+             background"
+        );
+        assert_eq!(
+            error("[material m]\n[view]\norigin = 0 0\n"),
+            "in [view] at line 2: line 3: invalid `origin`: expected 3 numbers, found 2"
+        );
+        assert!(error("[view]\nup = 0 one 0\n").starts_with("in [view] at line 1: line 2: invalid `up`: "));
+        assert_eq!(
+            error("[material m]\n[sdf]\nmaterial = m\nsphere = 0 0 0\n"),
+            "in [sdf] at line 2: line 4: invalid `sphere`: expected 4 numbers, found 3"
+        );
+    }
+
+    #[test]
+    fn section_errors_name_the_section() {
+        let sphere = "[sphere]\ncenter = 0 0 0\nradius = 1\nmaterial = m\n";
+        assert_eq!(error(sphere), "in [sphere] at line 1: unknown material `m`");
+        let sphere = "[material m]\n[sphere]\nmaterial = m\nradius = 1\n";
+        assert_eq!(error(sphere), "in [sphere] at line 2: missing `center`");
+        assert_eq!(error("[material]\n"), "in [material] at line 1: material without a name");
+        assert_eq!(error("[material m]\n[material m]\n"), "in [material] at line 2: material `m` is defined twice");
+        assert!(error("[material m]\ntype = wood\n").contains("unknown material type `wood`"));
+        assert!(error("\n\n[cube]\n").starts_with("in [cube] at line 3: unknown section `cube`"));
+        assert!(error("[material m]\n[heightfield]\nimage = missing.png\norigin = 0 0 0\nsize = 1 1 1\nmaterial = m\n")
+            .contains("failed to load"));
+        assert!(error("[material m]\n[mesh]\nfile = missing.ply\nmaterial = m\n").contains("failed to open"));
+    }
+
+    #[test]
+    fn zero_length_directions_are_rejected() {
+        let shape = |kind: &str, keys: &str| error(&format!("[material m]\n[{kind}]\nmaterial = m\n{keys}"));
+        assert_eq!(
+            shape("cylinder", "base = 0 0 0\naxis = 0 0 0\nradius = 1\nheight = 1\n"),
+            "in [cylinder] at line 2: line 5: `axis` must have a nonzero length"
+        );
+        assert_eq!(
+            shape("cone", "base = 0 0 0\nradius = 1\nheight = 1\naxis = 0 0 0\n"),
+            "in [cone] at line 2: line 7: `axis` must have a nonzero length"
+        );
+        assert_eq!(
+            shape("torus", "axis = 0 0 0\ncenter = 0 0 0\nmajor = 1\nminor = 0.2\n"),
+            "in [torus] at line 2: line 4: `axis` must have a nonzero length"
+        );
+        assert_eq!(
+            shape("plane", "point = 0 0 0\nnormal = 0 0 0\n"),
+            "in [plane] at line 2: line 5: `normal` must have a nonzero length"
+        );
+        assert_eq!(shape("plane", "point = 0 0 0\n"), "in [plane] at line 2: missing `normal`");
+        assert_eq!(
+            shape("disk", "center = 0 0 0\nnormal = 0 0 0\nradius = 1\n"),
+            "in [disk] at line 2: line 5: `normal` must have a nonzero length"
+        );
+        assert_eq!(
+            shape("disk", "center = 0 0 0\nnormal = 0 1 0\nradius = 0\n"),
+            "in [disk] at line 2: disk radius must be positive"
+        );
+        assert_eq!(
+            shape("quad", "q = 0 0 0\nu = 1 0 0\nv = -2 0 0\n"),
+            "in [quad] at line 2: quad edges `u` and `v` are parallel or zero"
+        );
+        assert_eq!(
+            shape("box", "min = 0 0 0\nmax = 1 0 1\n"),
+            "in [box] at line 2: box corners must differ on every axis"
+        );
+    }
+
+    #[test]
+    fn csg_errors() {
+        let csg = |tree: &str| error(&format!("[material m]\n[csg]\nmaterial = m\ntree = {tree}\n"));
+        assert_eq!(
+            csg("union(sphere(0 0 0 1))"),
+            "in [csg] at line 2: invalid `tree`: `union` needs at least two operands"
+        );
+        assert!(csg("blob(0 0 0 1)").contains("invalid `tree`: unknown CSG node `blob`"));
+        assert!(csg("sphere(0 0 0 1 stone)").contains("invalid `tree`: unknown material `stone`"));
+        assert!(csg("sphere(0 0 0)").contains("invalid `tree`: invalid `sphere`: expected 4 numbers, found 3"));
+        assert!(csg("union(sphere(0 0 0 1) sphere(0 0 0 1))").contains("expected `,` or `)` in `union(...)`"));
+        assert!(csg("sphere(0 0 0 1) box(0 0 0 1 1 1)").contains("unexpected `box(0 0 0 1 1 1)` after the CSG tree"));
+        let nested = "union(".repeat(65) + &"sphere(0 0 0 1), sphere(0 0 0 1))".repeat(65);
+        assert!(csg(&nested).contains("invalid `tree`: CSG tree nested more than 64 operations deep"));
+        assert!(csg("cylinder(0 1 0 0 1 0 1)").contains("`cylinder` needs a top point different from its base"));
+    }
+}
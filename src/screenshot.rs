@@ -0,0 +1,185 @@
+// screenshot.rs
+// Saves the accumulated image as a display-ready PNG, a linear OpenEXR file and a JSON
+// sidecar describing how the image was produced. With a debug mode on, the images hold the
+// debug view, and the sidecar names the mode so it is not mistaken for a render.
+use crate::{algebra::Vec3, camera::CameraPose, json, render::DebugMode};
+use {
+    anyhow::{Context, Result},
+    image::{ImageBuffer, Rgb, Rgba},
+    std::{
+        fs,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub struct ScreenshotInfo<'a> {
+    pub scene: &'a str,
+    pub samples_per_pixel: u32,
+    pub render_time: Duration,
+    pub debug_mode: DebugMode,
+    pub pose: CameraPose,
+}
+
+// Writes `<dir>/screenshot-<UTC timestamp>.{png,exr,json}` and returns the path shared by the
+// three files, without extension. `pixels` holds linear radiance, row by row. Existing
+// screenshots are never overwritten, see `unused_stem`.
+pub fn save(
+    dir: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 4]],
+    info: &ScreenshotInfo,
+) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let stem = unused_stem(dir, &utc_timestamp(SystemTime::now()));
+
+    // Same transform as the display shader: clamp, then gamma 2.2.
+    let ldr = ImageBuffer::from_fn(width, height, |x, y| {
+        let [r, g, b, _] = pixels[(y * width + x) as usize];
+        Rgb([tonemap(r), tonemap(g), tonemap(b)])
+    });
+    let png_path = stem.with_extension("png");
+    ldr.save(&png_path)
+        .with_context(|| format!("failed to write {}", png_path.display()))?;
+
+    // The alpha channel of the accumulation texture holds depth, which is not useful here.
+    let hdr = ImageBuffer::from_fn(width, height, |x, y| {
+        let [r, g, b, _] = pixels[(y * width + x) as usize];
+        Rgba([r, g, b, 1.])
+    });
+    let exr_path = stem.with_extension("exr");
+    hdr.save(&exr_path)
+        .with_context(|| format!("failed to write {}", exr_path.display()))?;
+
+    let json_path = stem.with_extension("json");
+    fs::write(&json_path, metadata_json(width, height, info))
+        .with_context(|| format!("failed to write {}", json_path.display()))?;
+
+    Ok(stem)
+}
+
+fn tonemap(c: f32) -> u8 {
+    (c.max(0.).powf(1. / 2.2).min(1.) * 255. + 0.5) as u8
+}
+
+fn metadata_json(width: u32, height: u32, info: &ScreenshotInfo) -> String {
+    let vec3 = |v: &Vec3| format!("[{}, {}, {}]", json::number(v.x()), json::number(v.y()), json::number(v.z()));
+    let pose = &info.pose;
+    format!(
+        r#"{{
+  "scene": {},
+  "width": {width},
+  "height": {height},
+  "samples_per_pixel": {},
+  "render_time_seconds": {},
+  "debug_mode": {},
+  "camera": {{
+    "center": {},
+    "up": {},
+    "distance": {},
+    "azimuth": {},
+    "altitude": {}
+  }}
+}}
+"#,
+        json::string(info.scene),
+        info.samples_per_pixel,
+        json::number(info.render_time.as_secs_f64()),
+        json::string(info.debug_mode.name()),
+        vec3(&pose.center),
+        vec3(&pose.up),
+        json::number(pose.distance),
+        json::number(pose.azimuth),
+        json::number(pose.altitude),
+    )
+}
+
+// `<dir>/screenshot-<timestamp>`, with a `-2`, `-3`, ... suffix if a screenshot with that stem
+// already exists, e.g. from two shots within a millisecond.
+fn unused_stem(dir: &Path, timestamp: &str) -> PathBuf {
+    let taken = |stem: &Path| ["png", "exr", "json"].iter().any(|e| stem.with_extension(e).exists());
+    let mut stem = dir.join(format!("screenshot-{timestamp}"));
+    let mut counter = 1;
+    while taken(&stem) {
+        counter += 1;
+        stem = dir.join(format!("screenshot-{timestamp}-{counter}"));
+    }
+    stem
+}
+
+// Formats `time` as `YYYYMMDD-HHMMSS-mmm` in UTC, with milliseconds.
+fn utc_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+
+    // Civil-from-days conversion, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}-{:03}",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_have_milliseconds() {
+        let at = |millis: u64| utc_timestamp(UNIX_EPOCH + Duration::from_millis(millis));
+        assert_eq!(at(0), "19700101-000000-000");
+        assert_eq!(at(1_700_000_000_500), "20231114-221320-500");
+        assert_eq!(at(951_782_400_007), "20000229-000000-007");
+    }
+
+    #[test]
+    fn stems_of_existing_screenshots_get_a_counter() {
+        let dir = std::env::temp_dir().join(format!("screenshot_stems_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let timestamp = "20261019-120000-000";
+        let first = unused_stem(&dir, timestamp);
+        assert_eq!(first, dir.join("screenshot-20261019-120000-000"));
+        fs::write(first.with_extension("png"), "").unwrap();
+        let second = unused_stem(&dir, timestamp);
+        assert_eq!(second, dir.join("screenshot-20261019-120000-000-2"));
+        fs::write(second.with_extension("json"), "").unwrap();
+        assert_eq!(unused_stem(&dir, timestamp), dir.join("screenshot-20261019-120000-000-3"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_names_the_debug_mode() {
+        let pose = CameraPose {
+            center: Vec3::zero(),
+            up: Vec3::new(0., 1., 0.),
+            distance: 2.,
+            azimuth: 0.5,
+            altitude: 0.25,
+        };
+        let info = |debug_mode| ScreenshotInfo {
+            scene: "cornell_box",
+            samples_per_pixel: 64,
+            render_time: Duration::from_millis(1500),
+            debug_mode,
+            pose,
+        };
+        let off = metadata_json(800, 600, &info(DebugMode::Off));
+        assert!(off.contains("  \"render_time_seconds\": 1.5,\n  \"debug_mode\": \"off\",\n"), "{off}");
+        let normals = metadata_json(800, 600, &info(DebugMode::ShadingNormals));
+        assert!(normals.contains("  \"debug_mode\": \"shading normals\",\n"), "{normals}");
+    }
+}
@@ -292,11 +292,41 @@ const MAX_PATH_LENGTH: u32 = 50u;
 const EPSILON: f32 = 1e-3;
 const TWO_PI: f32 = 6.2831853;
 
-// 场景参数
-const GRID_SIZE: i32 = 10;  // -5 to 5 (减少球数量)
-const TOTAL_SMALL_SPHERES: u32 = 100u;  // 10 * 10
-const MAIN_SPHERES: u32 = 4u;  // 3 large spheres + 1 ground
-const TOTAL_SPHERES: u32 = TOTAL_SMALL_SPHERES + MAIN_SPHERES;
+// Temporal accumulation
+// Depth stored for pixels whose primary ray escapes to the sky.
+const SKY_DEPTH: f32 = 1e30;
+// Weight of the new sample when blending into reprojected history.
+const TEMPORAL_ALPHA: f32 = 0.2;
+// Maximum relative depth difference before reprojected history counts as disoccluded.
+const DEPTH_TOLERANCE: f32 = 0.05;
+
+const PI: f32 = 3.14159265;
+
+// Material.texture value for materials without a texture (scene::NO_TEXTURE).
+const NO_TEXTURE: u32 = 0xffffffffu;
+
+// Texture kinds, must match the constants in scene.rs.
+const TEXTURE_SOLID: u32 = 0u;
+const TEXTURE_CHECKER: u32 = 1u;
+const TEXTURE_IMAGE: u32 = 2u;
+const TEXTURE_NOISE: u32 = 3u;
+const TEXTURE_TURBULENCE: u32 = 4u;
+const TEXTURE_MARBLE: u32 = 5u;
+
+// Number of noise octaves summed by `turbulence`.
+const TURBULENCE_DEPTH: u32 = 7u;
+
+// Material kinds, must match the constants in scene.rs.
+const MATERIAL_BASIC: u32 = 0u;
+const MATERIAL_CONDUCTOR: u32 = 1u;
+const MATERIAL_ROUGH_DIELECTRIC: u32 = 2u;
+const MATERIAL_PRINCIPLED: u32 = 3u;
+
+// Smallest GGX alpha. The distribution becomes numerically singular as alpha goes to 0.
+const MIN_ALPHA: f32 = 1e-3;
+
+// Finite-difference step, in uv units and world units, used to differentiate bump maps.
+const BUMP_DELTA: f32 = 0.0009765625;
 
 alias TriangleVertices = array<vec2f, 6>;
 var<private> vertices: TriangleVertices = TriangleVertices(
@@ -314,18 +344,65 @@ var<private> vertices: TriangleVertices = TriangleVertices(
 
 struct Uniforms {
     camera: CameraUniforms,
+    prev_camera: CameraUniforms,
     width: u32,
     height: u32,
     frame_count: u32,
+    temporal: u32,
+    fog: Fog,
+    // rgb: constant background radiance, used instead of the sky gradient if w > 0.
+    background: vec4f,
+    // Non-zero to traverse the mesh BLAS in their 4-wide layout.
+    wide_bvh: u32,
+    // One of the DEBUG_* modes.
+    debug_mode: u32,
+    // View depth shown as mid-gray by DEBUG_DEPTH.
+    depth_scale: f32,
+    // If `inspect` is non-zero, the path of this pixel is traced with the RNG seeded by
+    // `inspect_seed` and recorded in `path_record`.
+    inspect_pixel: vec2u,
+    inspect_seed: u32,
+    inspect: u32,
 }
 
 @group(0) @binding(0) var<uniform> uniforms: Uniforms;
 @group(0) @binding(1) var radiance_samples_old: texture_2d<f32>;
 @group(0) @binding(2) var radiance_samples_new: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(3) var<storage, read_write> path_record: PathRecord;
+
+// Scene data uploaded from scene.rs.
+@group(1) @binding(0) var<storage, read> spheres: array<Sphere>;
+@group(1) @binding(1) var<storage, read> materials: array<Material>;
+@group(1) @binding(2) var<storage, read> textures: array<Texture>;
+@group(1) @binding(3) var image_textures: texture_2d_array<f32>;
+@group(1) @binding(4) var image_sampler: sampler;
+// Triangle meshes. (`vertices` is taken by the full-screen quad above.)
+@group(1) @binding(5) var<storage, read> mesh_vertices: array<Vertex>;
+@group(1) @binding(6) var<storage, read> mesh_triangles: array<Triangle>;
+@group(1) @binding(7) var<storage, read> media: array<Medium>;
+@group(1) @binding(8) var<storage, read> volumes: array<Volume>;
+// Density (r) and emission (g) of all grid volumes, stacked along z.
+@group(1) @binding(9) var volume_atlas: texture_3d<f32>;
+@group(1) @binding(10) var<storage, read> quads: array<Quad>;
+@group(1) @binding(11) var<storage, read> blas_nodes: array<BvhNode>;
+@group(1) @binding(12) var<storage, read> tlas_nodes: array<BvhNode>;
+@group(1) @binding(13) var<storage, read> instances: array<Instance>;
+@group(1) @binding(14) var<storage, read> sphere_nodes: array<BvhNode>;
+@group(1) @binding(15) var<storage, read> sdf_objects: array<SdfObject>;
+@group(1) @binding(16) var<storage, read> sdf_shapes: array<SdfShape>;
+@group(1) @binding(17) var<storage, read> shapes: array<Shape>;
+@group(1) @binding(18) var<storage, read> shape_nodes: array<BvhNode>;
+@group(1) @binding(19) var<storage, read> csg_objects: array<CsgObject>;
+@group(1) @binding(20) var<storage, read> csg_nodes: array<CsgNode>;
+@group(1) @binding(21) var<storage, read> heightfields: array<Heightfield>;
+@group(1) @binding(22) var<storage, read> heightfield_data: array<f32>;
+@group(1) @binding(23) var<storage, read> wide_blas_nodes: array<WideNode>;
 
 struct CameraUniforms {
   origin: vec3f,
+  shutter_open: f32,
   u: vec3f,
+  shutter_close: f32,
   v: vec3f,
   w: vec3f,
 }
@@ -347,16 +424,476 @@ fn schlick_reflectance(cosine: f32, ref_idx: f32) -> f32 {
   return r0_squared + (1.0 - r0_squared) * pow(one_minus_cosine, 5.0);
 }
 
+// Port of Cycles' `ensure_valid_reflection`: tilts the shading normal `N` towards the
+// geometric normal `Ng` just enough that the mirror reflection of `wo` stays above the
+// geometric surface. Both normals must be on the side of `wo`.
+fn ensure_valid_reflection(Ng: vec3f, wo: vec3f, N: vec3f) -> vec3f {
+  let R = 2. * dot(N, wo) * N - wo;
+  let threshold = min(0.9 * dot(Ng, wo), 0.01);
+  if dot(Ng, R) >= threshold {
+    return N;
+  }
+
+  // Work in the 2D frame spanned by Ng and the component X of N orthogonal to it.
+  let X = normalize(N - dot(N, Ng) * Ng);
+  let Ix = dot(wo, X);
+  let Iz = dot(wo, Ng);
+  let a = Ix * Ix + Iz * Iz;
+  let b = sqrt(max(Ix * Ix * (a - threshold * threshold), 0.));
+  let c = Iz * threshold + a;
+  let N1_z2 = 0.5 * (b + c) / a;
+  let N2_z2 = 0.5 * (c - b) / a;
+  var valid1 = N1_z2 > 1e-5 && N1_z2 <= 1. + 1e-5;
+  var valid2 = N2_z2 > 1e-5 && N2_z2 <= 1. + 1e-5;
+  let N1 = vec2(sqrt(max(1. - N1_z2, 0.)), sqrt(max(N1_z2, 0.)));
+  let N2 = vec2(sqrt(max(1. - N2_z2, 0.)), sqrt(max(N2_z2, 0.)));
+
+  var N_new: vec2f;
+  if valid1 && valid2 {
+    // Pick the solution whose reflection is closest to the threshold.
+    let R1 = 2. * (N1.x * Ix + N1.y * Iz) * N1.y - Iz;
+    let R2 = 2. * (N2.x * Ix + N2.y * Iz) * N2.y - Iz;
+    valid1 = R1 >= 1e-5;
+    valid2 = R2 >= 1e-5;
+    if valid1 && valid2 {
+      N_new = select(N2, N1, R1 < R2);
+    } else {
+      N_new = select(N2, N1, R1 > R2);
+    }
+  } else if valid1 {
+    N_new = N1;
+  } else if valid2 {
+    N_new = N2;
+  } else {
+    return Ng;
+  }
+  return N_new.x * X + N_new.y * Ng;
+}
+
+// Height sampled from the red channel of a bump map.
+fn bump_height(index: u32, uv: vec2f, p: vec3f) -> f32 {
+  return texture_value(index, uv, p).r;
+}
+
+// The normal used for shading at `p`: the interpolated normal perturbed by the material's
+// normal and bump maps, on the same side as `Ng` (the geometric normal facing the incoming
+// ray) and corrected so that reflections cannot go below the surface.
+fn shading_normal(material: Material, hit: Intersection, p: vec3f, Ng: vec3f, wo: vec3f) -> vec3f {
+  var N = hit.shading_normal;
+  if material.normal_map != NO_TEXTURE || material.bump_map != NO_TEXTURE {
+    let T = normalize(hit.tangent.xyz - dot(hit.tangent.xyz, N) * N);
+    let B = cross(N, T) * hit.tangent.w;
+    if material.normal_map != NO_TEXTURE {
+      let m = 2. * texture_value(material.normal_map, hit.uv, p) - 1.;
+      N = normalize(m.x * T + m.y * B + m.z * N);
+    }
+    if material.bump_map != NO_TEXTURE {
+      // Image maps vary with uv and procedural ones with p, so step both at once.
+      let h = bump_height(material.bump_map, hit.uv, p);
+      let hu = bump_height(material.bump_map, hit.uv + vec2(BUMP_DELTA, 0.), p + BUMP_DELTA * T);
+      let hv = bump_height(material.bump_map, hit.uv + vec2(0., BUMP_DELTA), p + BUMP_DELTA * B);
+      let dh = vec2(hu - h, hv - h) / BUMP_DELTA;
+      N = normalize(N - material.bump_scale * (dh.x * T + dh.y * B));
+    }
+  }
+  if dot(N, Ng) < 0. {
+    N = -N;
+  }
+  return ensure_valid_reflection(Ng, wo, N);
+}
+
+// ---------------------------------------------------------------------------------------
+// GGX (Trowbridge-Reitz) microfacet BSDFs. Directions are in the local shading frame with the
+// normal along +z, and `wo` points away from the surface towards the viewer. The same code is
+// ported to microfacet.rs for the CPU furnace test; keep the two in sync.
+
+fn ggx_d(m: vec3f, alpha: f32) -> f32 {
+  if m.z <= 0. {
+    return 0.;
+  }
+  let a2 = alpha * alpha;
+  let d = m.z * m.z * (a2 - 1.) + 1.;
+  return a2 / (PI * d * d);
+}
+
+// Smith's Lambda for the GGX distribution.
+fn ggx_lambda(w: vec3f, alpha: f32) -> f32 {
+  let cos2 = w.z * w.z;
+  let tan2 = max(1. - cos2, 0.) / max(cos2, 1e-12);
+  return 0.5 * (sqrt(1. + alpha * alpha * tan2) - 1.);
+}
+
+fn ggx_g1(w: vec3f, alpha: f32) -> f32 {
+  return 1. / (1. + ggx_lambda(w, alpha));
+}
+
+// Height-correlated masking-shadowing.
+fn ggx_g2(wo: vec3f, wi: vec3f, alpha: f32) -> f32 {
+  return 1. / (1. + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha));
+}
+
+// Density of the normals visible from `wo`, the pdf of `sample_ggx_vndf`.
+fn ggx_vndf_pdf(wo: vec3f, m: vec3f, alpha: f32) -> f32 {
+  return ggx_g1(wo, alpha) * max(dot(wo, m), 0.) * ggx_d(m, alpha) / wo.z;
+}
+
+// Samples a microfacet normal visible from `wo` (Heitz, "Sampling the GGX Distribution of
+// Visible Normals", JCGT 2018).
+fn sample_ggx_vndf(wo: vec3f, alpha: f32, u: vec2f) -> vec3f {
+  // Stretch the view direction to the hemisphere configuration.
+  let vh = normalize(vec3(alpha * wo.x, alpha * wo.y, wo.z));
+  let lensq = vh.x * vh.x + vh.y * vh.y;
+  let t1 = select(vec3(1., 0., 0.), vec3(-vh.y, vh.x, 0.) / sqrt(lensq), lensq > 0.);
+  let t2 = cross(vh, t1);
+  // Sample the projected area of the visible hemisphere.
+  let r = sqrt(u.x);
+  let phi = TWO_PI * u.y;
+  let p1 = r * cos(phi);
+  let s = 0.5 * (1. + vh.z);
+  let p2 = (1. - s) * sqrt(1. - p1 * p1) + s * r * sin(phi);
+  let nh = p1 * t1 + p2 * t2 + sqrt(max(0., 1. - p1 * p1 - p2 * p2)) * vh;
+  // Unstretch.
+  return normalize(vec3(alpha * nh.x, alpha * nh.y, max(0., nh.z)));
+}
+
+// Fresnel reflectance of a dielectric interface for the relative IOR `eta` = n_t / n_i.
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+  if sin2_t >= 1. {
+    return 1.;
+  }
+  let cos_t = sqrt(1. - sin2_t);
+  let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+  let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+  return 0.5 * (rs * rs + rp * rp);
+}
+
+// Fresnel reflectance of a conductor with the complex IOR `eta + i k`, per channel.
+fn fresnel_conductor(cos_i: f32, eta: vec3f, k: vec3f) -> vec3f {
+  let c2 = cos_i * cos_i;
+  let s2 = 1. - c2;
+  let t0 = eta * eta - k * k - s2;
+  let a2b2 = sqrt(t0 * t0 + 4. * eta * eta * k * k);
+  let t1 = a2b2 + c2;
+  let a = sqrt(max(0.5 * (a2b2 + t0), vec3(0.)));
+  let t2 = 2. * cos_i * a;
+  let rs = (t1 - t2) / (t1 + t2);
+  let t3 = c2 * a2b2 + s2 * s2;
+  let t4 = t2 * s2;
+  let rp = rs * (t3 - t4) / (t3 + t4);
+  return 0.5 * (rp + rs);
+}
+
+struct BsdfSample {
+  wi: vec3f,
+  // BSDF * |cos(wi)| / pdf, zero if the sample was absorbed.
+  weight: vec3f,
+  pdf: f32,
+}
+
+fn conductor_eval(wo: vec3f, wi: vec3f, alpha: f32, eta: vec3f, k: vec3f) -> vec3f {
+  if wo.z <= 0. || wi.z <= 0. {
+    return vec3(0.);
+  }
+  let m = normalize(wo + wi);
+  let F = fresnel_conductor(dot(wo, m), eta, k);
+  return F * ggx_d(m, alpha) * ggx_g2(wo, wi, alpha) / (4. * wo.z * wi.z);
+}
+
+fn conductor_pdf(wo: vec3f, wi: vec3f, alpha: f32) -> f32 {
+  if wo.z <= 0. || wi.z <= 0. {
+    return 0.;
+  }
+  let m = normalize(wo + wi);
+  return ggx_vndf_pdf(wo, m, alpha) / (4. * dot(wo, m));
+}
+
+fn conductor_sample(wo: vec3f, alpha: f32, eta: vec3f, k: vec3f, u: vec2f) -> BsdfSample {
+  let m = sample_ggx_vndf(wo, alpha, u);
+  let wi = reflect(-wo, m);
+  if wi.z <= 0. {
+    return BsdfSample(wi, vec3(0.), 0.);
+  }
+  let wo_m = dot(wo, m);
+  let F = fresnel_conductor(wo_m, eta, k);
+  let weight = F * ggx_g2(wo, wi, alpha) / ggx_g1(wo, alpha);
+  return BsdfSample(wi, weight, ggx_vndf_pdf(wo, m, alpha) / (4. * wo_m));
+}
+
+// Half vector of a refraction from `wo` into `wi` through an interface with relative IOR
+// `eta`, oriented along +z.
+fn refraction_half_vector(wo: vec3f, wi: vec3f, eta: f32) -> vec3f {
+  let m = normalize(wo + eta * wi);
+  return select(m, -m, m.z < 0.);
+}
+
+// Rough dielectric (Walter et al., "Microfacet Models for Refraction through Rough
+// Surfaces", 2007). `eta` is n_t / n_i for light arriving from the side of +z. Radiance is
+// not rescaled by eta² on refraction, matching the smooth dielectric.
+fn dielectric_eval(wo: vec3f, wi: vec3f, alpha: f32, eta: f32) -> f32 {
+  if wo.z <= 0. || wi.z == 0. {
+    return 0.;
+  }
+  if wi.z > 0. {
+    let m = normalize(wo + wi);
+    let F = fresnel_dielectric(dot(wo, m), eta);
+    return F * ggx_d(m, alpha) * ggx_g2(wo, wi, alpha) / (4. * wo.z * wi.z);
+  }
+  let m = refraction_half_vector(wo, wi, eta);
+  let wo_m = dot(wo, m);
+  let wi_m = dot(wi, m);
+  if wo_m <= 0. || wi_m >= 0. {
+    return 0.;
+  }
+  let F = fresnel_dielectric(wo_m, eta);
+  let denom = wo_m + eta * wi_m;
+  return (1. - F) * ggx_d(m, alpha) * ggx_g2(wo, wi, alpha) * abs(wi_m) * wo_m * eta * eta
+    / (wo.z * abs(wi.z) * denom * denom);
+}
+
+fn dielectric_pdf(wo: vec3f, wi: vec3f, alpha: f32, eta: f32) -> f32 {
+  if wo.z <= 0. || wi.z == 0. {
+    return 0.;
+  }
+  if wi.z > 0. {
+    let m = normalize(wo + wi);
+    let wo_m = dot(wo, m);
+    return fresnel_dielectric(wo_m, eta) * ggx_vndf_pdf(wo, m, alpha) / (4. * wo_m);
+  }
+  let m = refraction_half_vector(wo, wi, eta);
+  return (1. - fresnel_dielectric(dot(wo, m), eta)) * refraction_pdf(wo, wi, alpha, eta);
+}
+
+// Pdf of refracting `wo` into `wi` through a VNDF-sampled normal, without choosing between
+// reflection and refraction.
+fn refraction_pdf(wo: vec3f, wi: vec3f, alpha: f32, eta: f32) -> f32 {
+  let m = refraction_half_vector(wo, wi, eta);
+  let wo_m = dot(wo, m);
+  let wi_m = dot(wi, m);
+  if wo.z <= 0. || wi.z >= 0. || wo_m <= 0. || wi_m >= 0. {
+    return 0.;
+  }
+  let denom = wo_m + eta * wi_m;
+  let jacobian = eta * eta * abs(wi_m) / (denom * denom);
+  return ggx_vndf_pdf(wo, m, alpha) * jacobian;
+}
+
+// `u.z` chooses between reflection and refraction in proportion to the Fresnel term.
+fn dielectric_sample(wo: vec3f, alpha: f32, eta: f32, u: vec3f) -> BsdfSample {
+  let m = sample_ggx_vndf(wo, alpha, u.xy);
+  let wo_m = dot(wo, m);
+  let F = fresnel_dielectric(wo_m, eta);
+  let vndf_pdf = ggx_vndf_pdf(wo, m, alpha);
+  if u.z < F {
+    let wi = reflect(-wo, m);
+    if wi.z <= 0. {
+      return BsdfSample(wi, vec3(0.), 0.);
+    }
+    let weight = ggx_g2(wo, wi, alpha) / ggx_g1(wo, alpha);
+    return BsdfSample(wi, vec3(weight), F * vndf_pdf / (4. * wo_m));
+  }
+  let wi = refract(-wo, m, 1. / eta);
+  if wi.z >= 0. {
+    return BsdfSample(wi, vec3(0.), 0.);
+  }
+  let wi_m = dot(wi, m);
+  let denom = wo_m + eta * wi_m;
+  let jacobian = eta * eta * abs(wi_m) / (denom * denom);
+  let weight = ggx_g2(wo, wi, alpha) / ggx_g1(wo, alpha);
+  return BsdfSample(wi, vec3(weight), (1. - F) * vndf_pdf * jacobian);
+}
+
+// ---------------------------------------------------------------------------------------
+// Principled material, after Burley, "Physically Based Shading at Disney" (2012), with GGX
+// for both specular layers. It is the sum of a diffuse base with sheen, a specular layer that
+// is colored for metals, a clearcoat and rough transmission.
+
+struct Principled {
+  base_color: vec3f,
+  alpha: f32,
+  spec_f0: vec3f,
+  clearcoat_alpha: f32,
+  sheen_color: vec3f,
+  clearcoat: f32,
+  // Weights of the diffuse and transmission lobes.
+  diffuse_weight: f32,
+  transmission_weight: f32,
+  eta: f32,
+  // Lobe selection probabilities: diffuse, specular, clearcoat and transmission.
+  lobe_probabilities: vec4f,
+}
+
+fn luminance(c: vec3f) -> f32 {
+  return dot(c, vec3(0.2126, 0.7152, 0.0722));
+}
+
+fn schlick_fresnel(f0: vec3f, cos_theta: f32) -> vec3f {
+  return f0 + (1. - f0) * pow(1. - clamp(cos_theta, 0., 1.), 5.);
+}
+
+fn principled_lobes(material: Material, base_color: vec3f, roughness: f32, metallic: f32, wo: vec3f, is_front_face: bool) -> Principled {
+  var b: Principled;
+  b.base_color = base_color;
+  b.alpha = max(roughness * roughness, MIN_ALPHA);
+  b.clearcoat_alpha = max(material.clearcoat_roughness * material.clearcoat_roughness, MIN_ALPHA);
+  // specular = 0.5 gives F0 = 0.04, the reflectance of an IOR of 1.5.
+  b.spec_f0 = mix(vec3(0.08 * material.specular), base_color, metallic);
+  let lum = luminance(base_color);
+  let tint = select(vec3(1.), base_color / lum, lum > 0.);
+  b.sheen_color = material.sheen * mix(vec3(1.), tint, material.sheen_tint);
+  b.clearcoat = material.clearcoat;
+  b.diffuse_weight = (1. - metallic) * (1. - material.transmission);
+  b.transmission_weight = (1. - metallic) * material.transmission;
+  let ior = material.eta.x;
+  b.eta = select(1. / ior, ior, is_front_face);
+
+  let probabilities = vec4(
+    b.diffuse_weight * (lum + luminance(b.sheen_color)),
+    luminance(schlick_fresnel(b.spec_f0, wo.z)),
+    b.clearcoat * schlick_fresnel(vec3(0.04), wo.z).x,
+    b.transmission_weight * lum * (1. - fresnel_dielectric(wo.z, b.eta)),
+  );
+  let total = dot(probabilities, vec4(1.));
+  b.lobe_probabilities = select(vec4(0.), probabilities / total, total > 0.);
+  return b;
+}
+
+fn principled_eval(b: Principled, wo: vec3f, wi: vec3f) -> vec3f {
+  if wo.z <= 0. || wi.z == 0. {
+    return vec3(0.);
+  }
+  if wi.z < 0. {
+    return b.transmission_weight * b.base_color * dielectric_eval(wo, wi, b.alpha, b.eta);
+  }
+  let m = normalize(wo + wi);
+  let cos_d = dot(wi, m);
+  // cos_d rounds to slightly above 1 when wi = wo, and pow of a negative base is NaN, which
+  // survives the multiplication by a zero sheen color.
+  let sheen = b.sheen_color * pow(max(1. - cos_d, 0.), 5.);
+  var f = b.diffuse_weight * (b.base_color / PI + sheen);
+  let specular = ggx_d(m, b.alpha) * ggx_g2(wo, wi, b.alpha) / (4. * wo.z * wi.z);
+  f += schlick_fresnel(b.spec_f0, cos_d) * specular;
+  if b.clearcoat > 0. {
+    let clearcoat = ggx_d(m, b.clearcoat_alpha) * ggx_g2(wo, wi, b.clearcoat_alpha) / (4. * wo.z * wi.z);
+    f += b.clearcoat * schlick_fresnel(vec3(0.04), cos_d) * clearcoat;
+  }
+  return f;
+}
+
+// Pdf of `principled_sample` generating `wi`, summed over all lobes that can produce it.
+fn principled_pdf(b: Principled, wo: vec3f, wi: vec3f) -> f32 {
+  let p = b.lobe_probabilities;
+  if wi.z < 0. {
+    return p.w * refraction_pdf(wo, wi, b.alpha, b.eta);
+  }
+  return p.x * wi.z / PI + p.y * conductor_pdf(wo, wi, b.alpha) + p.z * conductor_pdf(wo, wi, b.clearcoat_alpha);
+}
+
+// Picks a lobe with `u.z`, samples it with `u.xy` and weights the result by the full BSDF
+// over the combined pdf of all lobes (one-sample MIS).
+fn principled_sample(b: Principled, wo: vec3f, u: vec3f) -> BsdfSample {
+  let p = b.lobe_probabilities;
+  var wi: vec3f;
+  if u.z < p.x {
+    // Cosine-weighted hemisphere.
+    let r = sqrt(u.x);
+    let phi = TWO_PI * u.y;
+    wi = vec3(r * cos(phi), r * sin(phi), sqrt(max(0., 1. - u.x)));
+  } else if u.z < p.x + p.y {
+    wi = reflect(-wo, sample_ggx_vndf(wo, b.alpha, u.xy));
+  } else if u.z < p.x + p.y + p.z {
+    wi = reflect(-wo, sample_ggx_vndf(wo, b.clearcoat_alpha, u.xy));
+  } else {
+    // Total internal reflection yields a zero vector, which has a zero pdf below.
+    wi = refract(-wo, sample_ggx_vndf(wo, b.alpha, u.xy), 1. / b.eta);
+  }
+  let pdf = principled_pdf(b, wo, wi);
+  if pdf <= 0. {
+    return BsdfSample(wi, vec3(0.), 0.);
+  }
+  return BsdfSample(wi, principled_eval(b, wo, wi) * abs(wi.z) / pdf, pdf);
+}
+
+// Orthonormal basis with `n` as the third column (Duff et al., "Building an Orthonormal
+// Basis, Revisited", JCGT 2017).
+fn orthonormal_basis(n: vec3f) -> mat3x3f {
+  let s = select(-1., 1., n.z >= 0.);
+  let a = -1. / (s + n.z);
+  let b = n.x * n.y * a;
+  return mat3x3(vec3(1. + s * n.x * n.x * a, s * b, -s * n.x), vec3(b, s + n.y * n.y * a, -n.y), n);
+}
+
+// Scattering off the GGX and principled materials. `N` is the shading normal and `Ng` the geometric normal,
+// both on the side of the incoming ray.
+fn scatter_microfacet(incident: vec3f, time: f32, hit: Intersection, material: Material, p: vec3f, albedo: vec3f, N: vec3f, Ng: vec3f, is_front_face: bool) -> Scatter {
+  var roughness = material.roughness;
+  if material.roughness_map != NO_TEXTURE {
+    roughness *= texture_value(material.roughness_map, hit.uv, p).g;
+  }
+  let alpha = max(roughness * roughness, MIN_ALPHA);
+  let frame = orthonormal_basis(N);
+  // Shading normals can leave the viewer slightly below the surface; clamp to grazing.
+  var wo = transpose(frame) * -incident;
+  wo.z = max(wo.z, 1e-4);
+  wo = normalize(wo);
+
+  var bsdf_sample: BsdfSample;
+  if material.kind == MATERIAL_PRINCIPLED {
+    var metallic = material.metallic;
+    if material.metallic_map != NO_TEXTURE {
+      metallic *= texture_value(material.metallic_map, hit.uv, p).b;
+    }
+    let lobes = principled_lobes(material, albedo, roughness, metallic, wo, is_front_face);
+    bsdf_sample = principled_sample(lobes, wo, vec3(rand_f32(), rand_f32(), rand_f32()));
+  } else if material.kind == MATERIAL_CONDUCTOR {
+    bsdf_sample = conductor_sample(wo, alpha, material.eta, material.k, vec2(rand_f32(), rand_f32()));
+    bsdf_sample.weight *= albedo;
+  } else {
+    let ior = material.eta.x;
+    let eta = select(1. / ior, ior, is_front_face);
+    bsdf_sample = dielectric_sample(wo, alpha, eta, vec3(rand_f32(), rand_f32(), rand_f32()));
+    if bsdf_sample.wi.z < 0. {
+      bsdf_sample.weight *= albedo;
+    }
+  }
+
+  let direction = frame * bsdf_sample.wi;
+  // Discard directions that end up on the wrong side of the actual surface.
+  if (dot(direction, Ng) > 0.) != (bsdf_sample.wi.z > 0.) {
+    bsdf_sample.weight = vec3(0.);
+  }
+  return Scatter(bsdf_sample.weight, Ray(p, direction, time));
+}
+
 fn scatter(input_ray: Ray, hit: Intersection, material: Material) -> Scatter {
+  let p = point_on_ray(input_ray, hit.t);
+  let albedo = material_albedo(material, hit.uv, p) * hit.color;
   let incident = normalize(input_ray.direction);
-  let incident_dot_normal = dot(incident, hit.normal);
-  let is_front_face = incident_dot_normal < 0.;
-  let N = select(-hit.normal, hit.normal, is_front_face);
-  let cos_theta = abs(incident_dot_normal);
+  // Which side of the surface the ray is on is decided by the geometric normal, while the
+  // shading normal N determines how light scatters.
+  let is_front_face = dot(incident, hit.normal) < 0.;
+  let Ng = select(-hit.normal, hit.normal, is_front_face);
+  let N = shading_normal(material, hit, p, Ng, -incident);
+  let cos_theta = min(abs(dot(incident, N)), 1.);
+
+  if material.kind != MATERIAL_BASIC {
+    return scatter_microfacet(incident, input_ray.time, hit, material, p, albedo, N, Ng, is_front_face);
+  }
 
   let is_transmissive = material.specular_or_ior < 0.;
   let is_specular = material.specular_or_ior > 0.;
   let is_lambertian = material.specular_or_ior == 0.;
+
+  // Roughness (green) and metallic (blue) maps, using the glTF channel layout.
+  var fuzz = max(0.0, material.specular_or_ior - 1.0); // 确保fuzz非负
+  if material.roughness_map != NO_TEXTURE {
+    let roughness = texture_value(material.roughness_map, hit.uv, p).g;
+    fuzz = select(roughness, fuzz * roughness, is_specular);
+  }
+  var is_metal = is_specular;
+  if material.metallic_map != NO_TEXTURE && !is_transmissive {
+    is_metal = rand_f32() < texture_value(material.metallic_map, hit.uv, p).b;
+  }
   
   var scattered: vec3f;
   var attenuation: vec3f;
@@ -378,31 +915,38 @@ fn scatter(input_ray: Ray, hit: Intersection, material: Material) -> Scatter {
       scattered = refract(incident, N, ref_ratio);
     }
     attenuation = vec3(1.0, 1.0, 1.0); // Dielectric无颜色衰减
-  } else if is_specular {
+  } else if is_metal {
     // 金属材质处理
     let reflected = reflect(incident, N);
-    let fuzz = max(0.0, material.specular_or_ior - 1.0); // 确保fuzz非负
     scattered = normalize(reflected + fuzz * sample_sphere());
-    attenuation = material.color;
+    attenuation = albedo;
     
-    // 确保散射方向在表面上方
-    if dot(scattered, N) <= 0.0 {
+    // 确保散射方向在几何表面上方
+    if dot(scattered, Ng) <= 0.0 {
       // 如果散射方向在表面下方，则使用纯反射
       scattered = reflect(incident, N);
     }
   } else {
     // 漫反射材质处理
     scattered = sample_lambertian(N);
-    attenuation = material.color;
+    // A perturbed shading normal can tilt the lobe below the geometric surface; mirror those
+    // directions back up instead of letting them leak through.
+    let below = dot(scattered, Ng);
+    if below <= 0. {
+      scattered -= (2. * below - EPSILON) * Ng;
+    }
+    attenuation = albedo;
   }
   
-  let output_ray = Ray(point_on_ray(input_ray, hit.t), scattered);
+  let output_ray = Ray(p, scattered, input_ray.time);
   return Scatter(attenuation, output_ray);
 }
 
 struct Ray {
   origin: vec3f,
   direction: vec3f,
+  // Moving primitives are intersected at their position at this time.
+  time: f32,
 }
 
 fn point_on_ray(ray: Ray, t: f32) -> vec3<f32> {
@@ -414,8 +958,9 @@ struct Rng {
 };
 var<private> rng: Rng;
 
-fn init_rng(pixel: vec2u) {
-  let seed = (pixel.x + pixel.y * uniforms.width) ^ jenkins_hash(uniforms.frame_count);
+// Seeds the RNG for sample number `sample` of `pixel`.
+fn init_rng(pixel: vec2u, sample: u32) {
+  let seed = (pixel.x + pixel.y * uniforms.width) ^ jenkins_hash(sample);
   rng.state = jenkins_hash(seed);
 }
 
@@ -442,9 +987,8 @@ fn rand_f32() -> f32 {
   return bitcast<f32>(0x3f800000u | (xorshift32() >> 9u)) - 1.;
 }
 
-// 确定性随机数生成器（用于场景生成）
-fn deterministic_rand(seed: u32) -> f32 {
-  let x = jenkins_hash(seed);
+// Maps a hash to a float in [0, 1), like `rand_f32` but without advancing the PRNG.
+fn hash_to_f32(x: u32) -> f32 {
   return bitcast<f32>(0x3f800000u | (x >> 9u)) - 1.;
 }
 
@@ -460,6 +1004,29 @@ fn sample_sphere() -> vec3f {
 struct Material{
   color: vec3f,
   specular_or_ior: f32,
+  // Index into `textures` that modulates `color`, or NO_TEXTURE.
+  texture: u32,
+  // Further texture indices (or NO_TEXTURE), see scene::Material.
+  normal_map: u32,
+  bump_map: u32,
+  bump_scale: f32,
+  roughness_map: u32,
+  metallic_map: u32,
+  // GGX material parameters, used when `kind` is not MATERIAL_BASIC.
+  eta: vec3f,
+  kind: u32,
+  k: vec3f,
+  roughness: f32,
+  // Radiance emitted by the surface, for every material kind.
+  emission: vec3f,
+  // Principled material parameters, see scene::Principled.
+  metallic: f32,
+  specular: f32,
+  sheen: f32,
+  sheen_tint: f32,
+  clearcoat: f32,
+  clearcoat_roughness: f32,
+  transmission: f32,
   /*
   材质编码系统：
   - specular_or_ior = 0.0: 漫反射材质(Lambertian)
@@ -476,121 +1043,42 @@ struct Material{
 }
 
 struct Intersection {
+  // Geometric normal.
   normal: vec3f,
   t: f32,
-  material: Material,
+  // Surface parameterization, used for texture lookups.
+  uv: vec2f,
+  material_index: u32,
+  // Interpolated normal, on the same side as `normal`.
+  shading_normal: vec3f,
+  // Tangent along +u in xyz; bitangent = cross(shading_normal, tangent) * w.
+  tangent: vec4f,
+  // Interpolated vertex color of meshes, white elsewhere. Multiplies the albedo.
+  color: vec3f,
 }
 
 fn no_intersection() -> Intersection {
-  return Intersection(vec3(0.), -1., Material(vec3(0.), 0.));
+  return Intersection(vec3(0.), -1., vec2(0.), 0u, vec3(0.), vec4(0.), vec3(1.));
 }
 
 fn is_intersection_valid(hit: Intersection) -> bool {
   return hit.t > 0.;
 }
 
+// Moves linearly from `center` at time 0 to `center1` at time 1.
 struct Sphere {
   center: vec3f,
   radius: f32,
-  material: Material,
+  center1: vec3f,
+  material_index: u32,
 }
 
-// 根据索引生成球的属性
-fn generate_sphere(index: u32) -> Sphere {
-  if index == 0u {
-    // 地面球 (大的漫反射球)
-    return Sphere(
-      vec3(0., -1000., 0.), 
-      1000., 
-      Material(vec3(0.5, 0.5, 0.5), 0.)  // 漫反射
-    );
-  } else if index == 1u {
-    // 中央玻璃球 (Dielectric, ir=1.5)
-    return Sphere(
-      vec3(0., 1., 0.), 
-      1.0, 
-      Material(vec3(1., 1., 1.), -1.5)  // 直接存储负的折射率
-    );
-  } else if index == 2u {
-    // 左侧棕色漫反射球
-    return Sphere(
-      vec3(-4., 1., 0.), 
-      1.0, 
-      Material(vec3(0.4, 0.2, 0.1), 0.)  // 漫反射
-    );
-  } else if index == 3u {
-    // 右侧金属球 (fuzz = 0.0)
-    return Sphere(
-      vec3(4., 1., 0.), 
-      1.0, 
-      Material(vec3(0.7, 0.6, 0.5), 1.0)  // 无fuzz的金属
-    );
-  } else {
-    // 小球
-    let small_index = index - MAIN_SPHERES;
-    let grid_x = i32(small_index % u32(GRID_SIZE)) - 5;  // 改为-5到5
-    let grid_z = i32(small_index / u32(GRID_SIZE)) - 5;  // 改为-5到5
-    
-    // 使用确定性随机数
-    let seed1 = jenkins_hash(small_index * 3u);
-    let seed2 = jenkins_hash(small_index * 3u + 1u);
-    let seed3 = jenkins_hash(small_index * 3u + 2u);
-    
-    let random1 = deterministic_rand(seed1);
-    let random2 = deterministic_rand(seed2);
-    let choose_mat = deterministic_rand(seed3);
-    
-    let center = vec3(
-      f32(grid_x) + 0.9 * random1,
-      0.2,
-      f32(grid_z) + 0.9 * random2
-    );
-    
-    // 检查是否与主球重叠
-    if length(center - vec3(4., 0.2, 0.)) <= 0.9 {
-      // 返回一个无效的球（半径为0）
-      return Sphere(center, 0., Material(vec3(0.), 0.));
-    }
-    
-    var material: Material;
-    if choose_mat < 0.8 {
-      // 漫反射材质
-      let color_seed1 = jenkins_hash(small_index * 6u);
-      let color_seed2 = jenkins_hash(small_index * 6u + 1u);
-      let color_seed3 = jenkins_hash(small_index * 6u + 2u);
-      let color_seed4 = jenkins_hash(small_index * 6u + 3u);
-      let color_seed5 = jenkins_hash(small_index * 6u + 4u);
-      let color_seed6 = jenkins_hash(small_index * 6u + 5u);
-      
-      let albedo = vec3(
-        deterministic_rand(color_seed1) * deterministic_rand(color_seed2),
-        deterministic_rand(color_seed3) * deterministic_rand(color_seed4),
-        deterministic_rand(color_seed5) * deterministic_rand(color_seed6)
-      );
-      material = Material(albedo, 0.);
-    } else if choose_mat < 0.95 {
-      // 金属材质
-      let metal_seed1 = jenkins_hash(small_index * 4u);
-      let metal_seed2 = jenkins_hash(small_index * 4u + 1u);
-      let metal_seed3 = jenkins_hash(small_index * 4u + 2u);
-      let fuzz_seed = jenkins_hash(small_index * 4u + 3u);
-      
-      let albedo = vec3(
-        0.5 + 0.5 * deterministic_rand(metal_seed1),
-        0.5 + 0.5 * deterministic_rand(metal_seed2),
-        0.5 + 0.5 * deterministic_rand(metal_seed3)
-      );
-      let fuzz = 0.5 * deterministic_rand(fuzz_seed);
-      // 编码：1.0 + fuzz，其中fuzz范围是[0, 0.5]
-      material = Material(albedo, 1.0 + fuzz);
-    } else {
-      // Dielectric材质（玻璃）
-      // 直接存储负的折射率：-1.5
-      material = Material(vec3(1., 1., 1.), -1.5);
-    }
-    
-    return Sphere(center, 0.2, material);
-  }
+// UV of a point on the unit sphere, see "The Next Week" section 4.4. u runs around the
+// y axis starting at -x and v runs from the south pole (0) to the north pole (1).
+fn sphere_uv(p: vec3f) -> vec2f {
+  let theta = acos(clamp(-p.y, -1., 1.));
+  let phi = atan2(-p.z, p.x) + PI;
+  return vec2(phi / TWO_PI, theta / PI);
 }
 
 fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
@@ -598,7 +1086,8 @@ fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
     return no_intersection();
   }
   
-  let v = ray.origin - sphere.center;
+  let center = mix(sphere.center, sphere.center1, ray.time);
+  let v = ray.origin - center;
   let a = dot(ray.direction, ray.direction);
   let b = dot(v, ray.direction);
   let c = dot(v, v) - sphere.radius * sphere.radius;
@@ -619,74 +1108,1938 @@ fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
   }
 
   let p = point_on_ray(ray, t);
-  let N = (p - sphere.center) / sphere.radius;
-  return Intersection(N, t, sphere.material);
+  let N = (p - center) / sphere.radius;
+  return Intersection(N, t, sphere_uv(N), sphere.material_index, N, sphere_tangent(N), vec3(1.));
 }
 
-fn intersect_scene(ray: Ray) -> Intersection {
-  var closest_hit = no_intersection();
-  closest_hit.t = FLT_MAX;
-  
-  for (var i = 0u; i < TOTAL_SPHERES; i += 1u) {
-    let sphere = generate_sphere(i);
-    let hit = intersect_sphere(ray, sphere);
-    if hit.t > 0. && hit.t < closest_hit.t {
-      closest_hit = hit;
+// Direction of increasing u on the unit sphere; arbitrary at the poles.
+fn sphere_tangent(N: vec3f) -> vec4f {
+  let t = vec3(N.z, 0., -N.x);
+  if dot(t, t) < 1e-12 {
+    return vec4(1., 0., 0., 1.);
+  }
+  return vec4(normalize(t), 1.);
+}
+
+// Quad kinds, see `Quad` in scene.rs.
+const QUAD_PARALLELOGRAM: u32 = 0u;
+const QUAD_DISK: u32 = 1u;
+const QUAD_PLANE: u32 = 2u;
+
+struct Quad {
+  q: vec3f,
+  kind: u32,
+  u: vec3f,
+  material_index: u32,
+  v: vec3f,
+}
+
+// "The Next Week" section 6: intersect the plane spanned by u and v, then find the planar
+// coordinates (alpha, beta) of the hit point in the basis (u, v) to test the bounds.
+fn intersect_quad(ray: Ray, quad: Quad) -> Intersection {
+  let n = cross(quad.u, quad.v);
+  let nn = dot(n, n);
+  let denom = dot(n, ray.direction);
+  if nn == 0. || abs(denom) < 1e-8 * sqrt(nn) {
+    return no_intersection();
+  }
+  let t = dot(n, quad.q - ray.origin) / denom;
+  if t <= EPSILON {
+    return no_intersection();
+  }
+
+  let planar = point_on_ray(ray, t) - quad.q;
+  let w = n / nn;
+  let alpha = dot(w, cross(planar, quad.v));
+  let beta = dot(w, cross(quad.u, planar));
+  var uv = vec2(alpha, beta);
+  switch quad.kind {
+    case QUAD_PARALLELOGRAM: {
+      if alpha < 0. || alpha > 1. || beta < 0. || beta > 1. {
+        return no_intersection();
+      }
     }
+    case QUAD_DISK: {
+      if alpha * alpha + beta * beta > 1. {
+        return no_intersection();
+      }
+      uv = 0.5 * uv + 0.5;
+    }
+    default: {}
   }
-  
-  if closest_hit.t < FLT_MAX {
-    return closest_hit;
+
+  let N = n / sqrt(nn);
+  return Intersection(N, t, uv, quad.material_index, N, vec4(normalize(quad.u), 1.), vec3(1.));
+}
+
+// Shape kinds, see `Shape` in scene.rs. Cylinders are cones with equal radii.
+const SHAPE_CONE: u32 = 0u;
+const SHAPE_TORUS: u32 = 1u;
+// Bisection steps when refining a root of the torus quartic.
+const TORUS_BISECTION_STEPS: u32 = 24u;
+
+// Capped cones run from `center` (radius `radius`) to `center + height * axis` (radius
+// `radius2`). Tori lie around `axis` with ring radius `radius` and tube radius `radius2`.
+struct Shape {
+  center: vec3f,
+  kind: u32,
+  axis: vec3f,
+  material_index: u32,
+  radius: f32,
+  radius2: f32,
+  height: f32,
+}
+
+// Right-handed frame whose second column is `axis`, so that shapes are intersected with
+// their axis along local y. `v * frame` maps to local space and `frame * v` back.
+fn shape_frame(axis: vec3f) -> mat3x3f {
+  let basis = orthonormal_basis(axis);
+  return mat3x3(basis[0], axis, -basis[1]);
+}
+
+// Direction of increasing u around the local y axis, matching `sphere_tangent`.
+fn shape_tangent(radial: vec2f) -> vec4f {
+  return vec4(radial.y, 0., -radial.x, 1.);
+}
+
+// Unit vector from the local y axis towards `p`, arbitrary on the axis.
+fn shape_radial(p: vec3f) -> vec2f {
+  return select(vec2(1., 0.), normalize(p.xz), dot(p.xz, p.xz) > 1e-12);
+}
+
+// Side of the cone x^2 + z^2 = (r0 + k y)^2 for 0 <= y <= height, closed by two disks. The
+// side has u around the axis and v along it; the caps are mapped like disks.
+fn intersect_cone(ray: Ray, shape: Shape, t_max: f32) -> Intersection {
+  let o = ray.origin;
+  let d = ray.direction;
+  let h = shape.height;
+  if h <= 0. {
+    return no_intersection();
   }
-  return no_intersection();
+  let k = (shape.radius2 - shape.radius) / h;
+  let rr = shape.radius + k * o.y;
+  let a = d.x * d.x + d.z * d.z - k * k * d.y * d.y;
+  let b = o.x * d.x + o.z * d.z - k * rr * d.y;
+  let c = o.x * o.x + o.z * o.z - rr * rr;
+
+  var hit = no_intersection();
+  var t_hit = t_max;
+  var roots = vec2(-1.);
+  if abs(a) > 1e-8 * dot(d, d) {
+    let discriminant = b * b - a * c;
+    if discriminant >= 0. {
+      let s = sqrt(discriminant);
+      roots = vec2((-b - s) / a, (-b + s) / a);
+    }
+  } else if b != 0. {
+    // Rays parallel to the slope of the cone cross it once.
+    roots.x = -0.5 * c / b;
+  }
+  for (var i = 0u; i < 2u; i += 1u) {
+    let t = roots[i];
+    let p = o + t * d;
+    if t > EPSILON && t < t_hit && p.y >= 0. && p.y <= h {
+      let radial = shape_radial(p);
+      let N = normalize(vec3(radial.x, -k, radial.y));
+      let uv = vec2(atan2(-p.z, p.x) / TWO_PI + 0.5, p.y / h);
+      hit = Intersection(N, t, uv, shape.material_index, N, shape_tangent(radial), vec3(1.));
+      t_hit = t;
+    }
+  }
+
+  if d.y != 0. {
+    for (var i = 0u; i < 2u; i += 1u) {
+      let top = i == 1u;
+      let r = select(shape.radius, shape.radius2, top);
+      let t = (select(0., h, top) - o.y) / d.y;
+      let p = o + t * d;
+      if t > EPSILON && t < t_hit && r > 0. && dot(p.xz, p.xz) <= r * r {
+        let N = vec3(0., select(-1., 1., top), 0.);
+        // Flip v on the top cap so that the bitangent follows it on both caps.
+        let uv = 0.5 + 0.5 * vec2(p.x, select(p.z, -p.z, top)) / r;
+        hit = Intersection(N, t, uv, shape.material_index, N, vec4(1., 0., 0., 1.), vec3(1.));
+        t_hit = t;
+      }
+    }
+  }
+  return hit;
 }
 
-fn sky_color(ray: Ray) -> vec3f {
-  let t = 0.5 * (normalize(ray.direction).y + 1.0);
-  return (1.0 - t) * vec3(1.0, 1.0, 1.0) + t * vec3(0.5, 0.7, 1.0);
+fn cube_root(x: f32) -> f32 {
+  return sign(x) * pow(abs(x), 1. / 3.);
 }
 
-@fragment fn display_fs(@builtin(position) pos: vec4f) -> @location(0) vec4f {
-  init_rng(vec2u(pos.xy));
+// Real roots of x^3 + a x^2 + b x + c in ascending order, padded with FLT_MAX (Cardano's
+// formula, or the trigonometric solution if there are three).
+fn solve_cubic(a: f32, b: f32, c: f32) -> vec3f {
+  let p = b - a * a / 3.;
+  let q = 2. * a * a * a / 27. - a * b / 3. + c;
+  let shift = -a / 3.;
+  let discriminant = 0.25 * q * q + p * p * p / 27.;
+  if discriminant >= 0. {
+    let s = sqrt(discriminant);
+    return vec3(cube_root(-0.5 * q + s) + cube_root(-0.5 * q - s) + shift, FLT_MAX, FLT_MAX);
+  }
+  let m = 2. * sqrt(-p / 3.);
+  let theta = acos(clamp(3. * q / (p * m), -1., 1.)) / 3.;
+  return vec3(
+    m * cos(theta - 2. * TWO_PI / 3.),
+    m * cos(theta - TWO_PI / 3.),
+    m * cos(theta),
+  ) + shift;
+}
 
-  let origin = uniforms.camera.origin;
-  let focus_distance = 1.0;
-  let aspect_ratio = f32(uniforms.width) / f32(uniforms.height);
-  
-  let offset = vec2(rand_f32() - 0.5, rand_f32() - 0.5);
-  var uv = (pos.xy + offset) / vec2f(f32(uniforms.width - 1u), f32(uniforms.height - 1u));
-  uv = (2.0 * uv - vec2(1.0)) * vec2(aspect_ratio, -1.0);    
-  
-  let camera_rotation = mat3x3(uniforms.camera.u, uniforms.camera.v, uniforms.camera.w);
-  let direction = camera_rotation * vec3(uv, focus_distance);
-  var ray = Ray(origin, direction);
-  var throughput = vec3f(1.);
-  var radiance_sample = vec3(0.);
+// s^4 + c.x s^3 + c.y s^2 + c.z s + c.w
+fn quartic(c: vec4f, s: f32) -> f32 {
+  return (((s + c.x) * s + c.y) * s + c.z) * s + c.w;
+}
 
-  var path_length = 0u;
-  while path_length < MAX_PATH_LENGTH {
-    let hit = intersect_scene(ray);
-    if !is_intersection_valid(hit) {
-      radiance_sample += throughput * sky_color(ray);
-      break;
+// Smallest root in [lo, hi] of the quartic with coefficients `c`, or -1 if there is none.
+// The roots of the derivative split the interval into pieces on which the quartic is
+// monotonic, and the first piece whose ends differ in sign is bisected. Double roots, where
+// a ray only grazes the surface, are missed.
+fn first_quartic_root(c: vec4f, lo: f32, hi: f32) -> f32 {
+  let critical = solve_cubic(0.75 * c.x, 0.5 * c.y, 0.25 * c.z);
+  var a = lo;
+  var f_a = quartic(c, a);
+  for (var i = 0u; i < 4u; i += 1u) {
+    var b = hi;
+    if i < 3u {
+      b = clamp(critical[i], a, hi);
+    }
+    let f_b = quartic(c, b);
+    if b > a && f_a * f_b <= 0. {
+      for (var step = 0u; step < TORUS_BISECTION_STEPS; step += 1u) {
+        let mid = 0.5 * (a + b);
+        let f_mid = quartic(c, mid);
+        if f_a * f_mid <= 0. {
+          b = mid;
+        } else {
+          a = mid;
+          f_a = f_mid;
+        }
+      }
+      return 0.5 * (a + b);
     }
+    a = b;
+    f_a = f_b;
+  }
+  return -1.;
+}
 
-    let scattered = scatter(ray, hit, hit.material);
-    throughput *= scattered.attenuation;
-    ray = scattered.ray;
-    path_length += 1u;
+// Points at distance `radius2` from the circle of radius `radius` in the local xz plane,
+// the roots of (|p|^2 - R^2 - r^2)^2 + 4 R^2 (y^2 - r^2) along the ray. u runs around the
+// axis and v around the tube, starting on the outside.
+fn intersect_torus(ray: Ray, shape: Shape, t_max: f32) -> Intersection {
+  let major = shape.radius;
+  let minor = shape.radius2;
+  let len = length(ray.direction);
+  let d = ray.direction / len;
+
+  // Solve from where the ray enters the bounding sphere, which keeps the coefficients small
+  // and the parameter interval short. `s` is the distance from that point.
+  let bound = major + minor;
+  let b = dot(ray.origin, d);
+  let discriminant = b * b - dot(ray.origin, ray.origin) + bound * bound;
+  if discriminant < 0. {
+    return no_intersection();
   }
-  
-  var old_sum: vec3f;
+  let t_enter = max(-b - sqrt(discriminant), 0.);
+  let t_exit = min(-b + sqrt(discriminant), t_max * len);
+  let s_min = max(EPSILON * len - t_enter, 0.);
+  let s_max = t_exit - t_enter;
+  if s_min >= s_max {
+    return no_intersection();
+  }
+  let o = ray.origin + t_enter * d;
+  let od = dot(o, d);
+  let e = dot(o, o) - major * major - minor * minor;
+  let rr4 = 4. * major * major;
+  let c = vec4(
+    4. * od,
+    4. * od * od + 2. * e + rr4 * d.y * d.y,
+    4. * od * e + 2. * rr4 * o.y * d.y,
+    e * e + rr4 * (o.y * o.y - minor * minor),
+  );
+  let s = first_quartic_root(c, s_min, s_max);
+  if s < 0. {
+    return no_intersection();
+  }
+
+  let p = o + s * d;
+  let radial = shape_radial(p);
+  let N = normalize(p - major * vec3(radial.x, 0., radial.y));
+  let tube = atan2(p.y, length(p.xz) - major);
+  let uv = vec2(atan2(-p.z, p.x) / TWO_PI + 0.5, tube / TWO_PI + select(0., 1., tube < 0.));
+  return Intersection(N, (t_enter + s) / len, uv, shape.material_index, N, shape_tangent(radial), vec3(1.));
+}
+
+fn intersect_shape(ray: Ray, shape: Shape, t_max: f32) -> Intersection {
+  let frame = shape_frame(shape.axis);
+  let local = Ray((ray.origin - shape.center) * frame, ray.direction * frame, ray.time);
+  var hit: Intersection;
+  if shape.kind == SHAPE_TORUS {
+    hit = intersect_torus(local, shape, t_max);
+  } else {
+    hit = intersect_cone(local, shape, t_max);
+  }
+  hit.normal = frame * hit.normal;
+  hit.shading_normal = hit.normal;
+  hit.tangent = vec4(frame * hit.tangent.xyz, hit.tangent.w);
+  return hit;
+}
+
+struct Vertex {
+  position: vec3f,
+  u: f32,
+  normal: vec3f,
+  v: f32,
+  tangent: vec4f,
+  color: vec3f,
+}
+
+struct Triangle {
+  indices: vec3u,
+  material_index: u32,
+}
+
+// Möller-Trumbore ray/triangle intersection. Normals, UVs and tangents are interpolated
+// from the vertices.
+fn intersect_triangle(ray: Ray, triangle: Triangle) -> Intersection {
+  let v0 = mesh_vertices[triangle.indices.x];
+  let v1 = mesh_vertices[triangle.indices.y];
+  let v2 = mesh_vertices[triangle.indices.z];
+  let e1 = v1.position - v0.position;
+  let e2 = v2.position - v0.position;
+  let pvec = cross(ray.direction, e2);
+  let det = dot(e1, pvec);
+  if abs(det) < 1e-12 {
+    return no_intersection();
+  }
+  let inv_det = 1. / det;
+  let tvec = ray.origin - v0.position;
+  let b1 = dot(tvec, pvec) * inv_det;
+  if b1 < 0. || b1 > 1. {
+    return no_intersection();
+  }
+  let qvec = cross(tvec, e1);
+  let b2 = dot(ray.direction, qvec) * inv_det;
+  if b2 < 0. || b1 + b2 > 1. {
+    return no_intersection();
+  }
+  let t = dot(e2, qvec) * inv_det;
+  if t <= EPSILON {
+    return no_intersection();
+  }
+
+  let b0 = 1. - b1 - b2;
+  let Ng = normalize(cross(e1, e2));
+  var N = normalize(b0 * v0.normal + b1 * v1.normal + b2 * v2.normal);
+  if dot(N, Ng) < 0. {
+    N = -N;
+  }
+  let uv = b0 * vec2(v0.u, v0.v) + b1 * vec2(v1.u, v1.v) + b2 * vec2(v2.u, v2.v);
+  let tangent = b0 * v0.tangent.xyz + b1 * v1.tangent.xyz + b2 * v2.tangent.xyz;
+  return Intersection(Ng, t, uv, triangle.material_index, N, vec4(tangent, v0.tangent.w), b0 * v0.color + b1 * v1.color + b2 * v2.color);
+}
+
+// Signed distance fields, see `Sdf` in scene.rs. Shape kinds:
+const SDF_SPHERE: u32 = 0u;
+const SDF_TORUS: u32 = 1u;
+const SDF_ROUND_BOX: u32 = 2u;
+const SDF_CAPSULE: u32 = 3u;
+const SDF_MAX_STEPS: u32 = 256u;
+// A march ends on the surface once the distance drops below this fraction of the distance
+// travelled (but at least SDF_MIN_HIT_DISTANCE), so that rays leaving a surface do not hit it
+// again right away.
+const SDF_HIT_DISTANCE: f32 = 1e-4;
+const SDF_MIN_HIT_DISTANCE: f32 = 1e-5;
+const SDF_NORMAL_DELTA: f32 = 1e-4;
+
+struct SdfShape {
+  center: vec3f,
+  kind: u32,
+  size: vec3f,
+  radius: f32,
+}
+
+struct SdfObject {
+  bounds_min: vec3f,
+  first_shape: u32,
+  bounds_max: vec3f,
+  shape_count: u32,
+  repeat: vec3f,
+  smoothing: f32,
+  material_index: u32,
+}
+
+// Distance functions after Inigo Quilez, "Distance functions".
+fn sdf_shape(shape: SdfShape, p: vec3f) -> f32 {
+  let q = p - shape.center;
+  switch shape.kind {
+    case SDF_TORUS: {
+      return length(vec2(length(q.xz) - shape.size.x, q.y)) - shape.radius;
+    }
+    case SDF_ROUND_BOX: {
+      let d = abs(q) - shape.size + shape.radius;
+      return length(max(d, vec3(0.))) + min(max(d.x, max(d.y, d.z)), 0.) - shape.radius;
+    }
+    case SDF_CAPSULE: {
+      // `size` holds the second endpoint.
+      let ba = shape.size - shape.center;
+      let h = clamp(dot(q, ba) / max(dot(ba, ba), 1e-12), 0., 1.);
+      return length(q - ba * h) - shape.radius;
+    }
+    default: {
+      return length(q) - shape.radius;
+    }
+  }
+}
+
+// Polynomial smooth minimum with blend radius `k`.
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+  if k <= 0. {
+    return min(a, b);
+  }
+  let h = max(k - abs(a - b), 0.) / k;
+  return min(a, b) - 0.25 * h * h * k;
+}
+
+fn sdf_distance(object: SdfObject, p: vec3f) -> f32 {
+  // Domain repetition folds space into the cell around the origin on the repeated axes.
+  let period = max(object.repeat, vec3(1e-6));
+  let q = select(p, p - period * round(p / period), object.repeat > vec3(0.));
+  var d = FLT_MAX;
+  for (var i = object.first_shape; i < object.first_shape + object.shape_count; i += 1u) {
+    d = smooth_min(d, sdf_shape(sdf_shapes[i], q), object.smoothing);
+  }
+  return d;
+}
+
+// Gradient of the distance by central differences on a tetrahedron (four evaluations).
+fn sdf_normal(object: SdfObject, p: vec3f) -> vec3f {
+  let e = vec2(1., -1.) * SDF_NORMAL_DELTA;
+  return normalize(
+    e.xyy * sdf_distance(object, p + e.xyy) + e.yyx * sdf_distance(object, p + e.yyx)
+      + e.yxy * sdf_distance(object, p + e.yxy) + e.xxx * sdf_distance(object, p + e.xxx)
+  );
+}
+
+// Sphere traces `object` between the entry into and exit from its bounding box.
+fn intersect_sdf(ray: Ray, object: SdfObject, t_max: f32) -> Intersection {
+  if object.shape_count == 0u {
+    return no_intersection();
+  }
+  let inv_dir = 1. / ray.direction;
+  let t0 = (object.bounds_min - ray.origin) * inv_dir;
+  let t1 = (object.bounds_max - ray.origin) * inv_dir;
+  let t_enter = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), max(min(t0.z, t1.z), EPSILON));
+  let t_exit = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), min(max(t0.z, t1.z), t_max));
+  nodes_visited += 1u;
+  if t_enter > t_exit {
+    return no_intersection();
+  }
+
+  // Distances are measured in world units, `t` in multiples of the ray direction.
+  let speed = length(ray.direction);
+  var t = t_enter;
+  for (var step = 0u; step < SDF_MAX_STEPS && t <= t_exit; step += 1u) {
+    let p = point_on_ray(ray, t);
+    primitive_tests += object.shape_count;
+    let d = abs(sdf_distance(object, p));
+    if d < max(SDF_HIT_DISTANCE * t * speed, SDF_MIN_HIT_DISTANCE) {
+      let N = sdf_normal(object, p);
+      let tangent = vec4(orthonormal_basis(N)[0], 1.);
+      return Intersection(N, t, vec2(0.), object.material_index, N, tangent, vec3(1.));
+    }
+    t += d / speed;
+  }
+  return no_intersection();
+}
+
+// CSG node kinds, see `Csg` in scene.rs. Leaves come before operations.
+const CSG_SPHERE: u32 = 0u;
+const CSG_CUBOID: u32 = 1u;
+const CSG_CYLINDER: u32 = 2u;
+const CSG_UNION: u32 = 3u;
+const CSG_INTERSECTION: u32 = 4u;
+const CSG_DIFFERENCE: u32 = 5u;
+// Span lists on the evaluation stack, and spans per list. Spans beyond the limit, which are
+// the farthest along the ray, are dropped; `Scene::add_csg` warns about trees that can exceed it.
+const CSG_STACK_SIZE: u32 = 8u;
+const CSG_MAX_SPANS: u32 = 4u;
+const CSG_STACK_SPANS: u32 = 32u;
+// Marks a boundary whose normal points into its primitive, i.e. the surface of a subtracted
+// primitive.
+const CSG_FLIPPED: u32 = 1u;
+
+struct CsgNode {
+  a: vec3f,
+  kind: u32,
+  b: vec3f,
+  material_index: u32,
+  radius: f32,
+}
+
+struct CsgObject {
+  bounds_min: vec3f,
+  first_node: u32,
+  bounds_max: vec3f,
+  node_count: u32,
+}
+
+// Part of the ray inside a solid, from `t.x` to `t.y`. The boundaries at both ends are
+// identified by twice the index of their leaf node, plus CSG_FLIPPED.
+struct CsgSpan {
+  t: vec2f,
+  surfaces: vec2u,
+}
+
+// Interval of the ray inside a convex primitive, empty if x > y. Unlike the other
+// intersection functions, this includes the part behind the origin.
+fn csg_leaf_interval(ray: Ray, node: CsgNode) -> vec2f {
+  let empty = vec2(1., -1.);
+  if node.kind == CSG_SPHERE {
+    let v = ray.origin - node.a;
+    let a = dot(ray.direction, ray.direction);
+    let b = dot(v, ray.direction);
+    let d = b * b - a * (dot(v, v) - node.radius * node.radius);
+    if d < 0. {
+      return empty;
+    }
+    return vec2(-b - sqrt(d), -b + sqrt(d)) / a;
+  }
+  if node.kind == CSG_CUBOID {
+    let inv_dir = 1. / ray.direction;
+    let t0 = (node.a - ray.origin) * inv_dir;
+    let t1 = (node.b - ray.origin) * inv_dir;
+    let near = min(t0, t1);
+    let far = max(t0, t1);
+    return vec2(max(max(near.x, near.y), near.z), min(min(far.x, far.y), far.z));
+  }
+
+  // A cylinder is the part of an infinite cylinder between the planes of its caps.
+  let height = length(node.b - node.a);
+  let n = (node.b - node.a) / height;
+  let o = ray.origin - node.a;
+  let oy = dot(o, n);
+  let dy = dot(ray.direction, n);
+  var slab = vec2(-FLT_MAX, FLT_MAX);
+  if dy != 0. {
+    let t0 = -oy / dy;
+    let t1 = (height - oy) / dy;
+    slab = vec2(min(t0, t1), max(t0, t1));
+  } else if oy < 0. || oy > height {
+    return empty;
+  }
+  let oc = o - oy * n;
+  let dc = ray.direction - dy * n;
+  let a = dot(dc, dc);
+  let b = dot(oc, dc);
+  let c = dot(oc, oc) - node.radius * node.radius;
+  if a < 1e-12 * dot(ray.direction, ray.direction) {
+    return select(slab, empty, c > 0.);
+  }
+  let d = b * b - a * c;
+  if d < 0. {
+    return empty;
+  }
+  return vec2(max(slab.x, (-b - sqrt(d)) / a), min(slab.y, (-b + sqrt(d)) / a));
+}
+
+// Outward normal, UV and tangent of the leaf `node` at the point `p` on its surface.
+fn csg_leaf_surface(node: CsgNode, p: vec3f) -> Intersection {
+  if node.kind == CSG_SPHERE {
+    let N = (p - node.a) / node.radius;
+    return Intersection(N, 0., sphere_uv(N), node.material_index, N, sphere_tangent(N), vec3(1.));
+  }
+  if node.kind == CSG_CUBOID {
+    // The face is the one along the axis where `p` is relatively farthest from the center.
+    let q = (2. * p - node.a - node.b) / (node.b - node.a);
+    let m = abs(q);
+    var axis = 0u;
+    if m.y > m.x && m.y >= m.z {
+      axis = 1u;
+    } else if m.z > m.x && m.z > m.y {
+      axis = 2u;
+    }
+    let side = sign(q[axis]);
+    var N = vec3(0.);
+    N[axis] = side;
+    var tangent = vec4(0., 0., 0., side);
+    tangent[(axis + 1u) % 3u] = 1.;
+    let uv = 0.5 + 0.5 * vec2(q[(axis + 1u) % 3u], q[(axis + 2u) % 3u]);
+    return Intersection(N, 0., uv, node.material_index, N, tangent, vec3(1.));
+  }
+
+  // Cylinders: the side if `p` is closer to it than to either cap plane.
+  let height = length(node.b - node.a);
+  let n = (node.b - node.a) / height;
+  let basis = orthonormal_basis(n);
+  let y = dot(p - node.a, n);
+  let radial = p - node.a - y * n;
+  let r = length(radial);
+  let x = dot(radial, basis[0]);
+  let z = dot(radial, basis[1]);
+  if abs(r - node.radius) < min(y, height - y) {
+    let N = radial / r;
+    let uv = vec2(atan2(z, x) / TWO_PI + 0.5, y / height);
+    return Intersection(N, 0., uv, node.material_index, N, vec4(cross(n, N), 1.), vec3(1.));
+  }
+  let top = y > 0.5 * height;
+  let N = select(-n, n, top);
+  let uv = 0.5 + 0.5 * vec2(x, z) / node.radius;
+  return Intersection(N, 0., uv, node.material_index, N, vec4(basis[0], select(-1., 1., top)), vec3(1.));
+}
+
+// Replaces the two span lists on top of the stack, the operands of `operation`, by its
+// result. Sweeps through the boundaries of both lists in order while tracking whether the ray
+// is inside either operand.
+fn csg_combine(spans: ptr<function, array<CsgSpan, CSG_STACK_SPANS>>, counts: ptr<function, array<u32, CSG_STACK_SIZE>>, top: u32, operation: u32) {
+  let a_first = (top - 2u) * CSG_MAX_SPANS;
+  let b_first = (top - 1u) * CSG_MAX_SPANS;
+  let a_events = 2u * (*counts)[top - 2u];
+  let b_events = 2u * (*counts)[top - 1u];
+  var result: array<CsgSpan, CSG_MAX_SPANS>;
+  var count = 0u;
+  var ia = 0u;
+  var ib = 0u;
+  var inside = false;
+  var start_t = 0.;
+  var start_surface = 0u;
+  loop {
+    if ia >= a_events && ib >= b_events {
+      break;
+    }
+    var from_a = ib >= b_events;
+    if ia < a_events && ib < b_events {
+      from_a = (*spans)[a_first + ia / 2u].t[ia % 2u] <= (*spans)[b_first + ib / 2u].t[ib % 2u];
+    }
+    var t: f32;
+    var surface: u32;
+    if from_a {
+      let span = (*spans)[a_first + ia / 2u];
+      t = span.t[ia % 2u];
+      surface = span.surfaces[ia % 2u];
+      ia += 1u;
+    } else {
+      let span = (*spans)[b_first + ib / 2u];
+      t = span.t[ib % 2u];
+      surface = span.surfaces[ib % 2u];
+      if operation == CSG_DIFFERENCE {
+        surface ^= CSG_FLIPPED;
+      }
+      ib += 1u;
+    }
+
+    // The ray is inside an operand after an odd number of its boundaries.
+    let in_a = ia % 2u == 1u;
+    let in_b = ib % 2u == 1u;
+    var in_result = in_a && !in_b;
+    if operation == CSG_UNION {
+      in_result = in_a || in_b;
+    } else if operation == CSG_INTERSECTION {
+      in_result = in_a && in_b;
+    }
+    if in_result && !inside {
+      start_t = t;
+      start_surface = surface;
+    } else if !in_result && inside && count < CSG_MAX_SPANS {
+      result[count] = CsgSpan(vec2(start_t, t), vec2(start_surface, surface));
+      count += 1u;
+    }
+    inside = in_result;
+  }
+
+  for (var i = 0u; i < count; i += 1u) {
+    (*spans)[a_first + i] = result[i];
+  }
+  (*counts)[top - 2u] = count;
+}
+
+// Evaluates the tree of `object` bottom-up on a stack of span lists, then returns the first
+// boundary of the result in front of the ray, which is an exit if the ray starts inside.
+fn intersect_csg(ray: Ray, object: CsgObject, t_max: f32) -> Intersection {
+  let inv_dir = 1. / ray.direction;
+  let t0 = (object.bounds_min - ray.origin) * inv_dir;
+  let t1 = (object.bounds_max - ray.origin) * inv_dir;
+  let t_enter = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), max(min(t0.z, t1.z), EPSILON));
+  let t_exit = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), min(max(t0.z, t1.z), t_max));
+  nodes_visited += 1u;
+  if object.node_count == 0u || t_enter > t_exit {
+    return no_intersection();
+  }
+
+  var spans: array<CsgSpan, CSG_STACK_SPANS>;
+  var counts: array<u32, CSG_STACK_SIZE>;
+  var top = 0u;
+  for (var i = object.first_node; i < object.first_node + object.node_count; i += 1u) {
+    let node = csg_nodes[i];
+    if node.kind < CSG_UNION {
+      primitive_tests += 1u;
+      let interval = csg_leaf_interval(ray, node);
+      spans[top * CSG_MAX_SPANS] = CsgSpan(interval, vec2(2u * i));
+      counts[top] = select(0u, 1u, interval.x <= interval.y);
+      top += 1u;
+    } else {
+      nodes_visited += 1u;
+      csg_combine(&spans, &counts, top, node.kind);
+      top -= 1u;
+    }
+  }
+
+  for (var i = 0u; i < counts[0]; i += 1u) {
+    let span = spans[i];
+    if span.t.y <= EPSILON {
+      continue;
+    }
+    let end = select(1u, 0u, span.t.x > EPSILON);
+    let t = span.t[end];
+    if t >= t_max {
+      break;
+    }
+    let surface = span.surfaces[end];
+    var hit = csg_leaf_surface(csg_nodes[surface / 2u], point_on_ray(ray, t));
+    if (surface & CSG_FLIPPED) != 0u {
+      hit.normal = -hit.normal;
+      hit.shading_normal = hit.normal;
+      hit.tangent.w = -hit.tangent.w;
+    }
+    hit.t = t;
+    return hit;
+  }
+  return no_intersection();
+}
+
+// Heightfields, see `Heightfield` in scene.rs. Rays are traced in grid space, where samples
+// are one unit apart and heights range over [0, 1]: first through blocks of
+// HEIGHTFIELD_BLOCK x HEIGHTFIELD_BLOCK cells, skipping those whose height range the ray
+// misses, then through the cells of the remaining blocks, each split into two triangles.
+const HEIGHTFIELD_BLOCK: i32 = 8;
+
+struct Heightfield {
+  origin: vec3f,
+  columns: u32,
+  cell_size: vec3f,
+  rows: u32,
+  first_sample: u32,
+  first_block: u32,
+  material_index: u32,
+}
+
+// State of a 2D grid traversal (Amanatides and Woo, "A Fast Voxel Traversal Algorithm for Ray
+// Tracing", 1987): the current cell and the distances at which the ray crosses the next
+// boundary along either axis.
+struct Dda {
+  cell: vec2i,
+  step: vec2i,
+  t_next: vec2f,
+  t_delta: vec2f,
+}
+
+// Starts a traversal of cells of size `size` at distance `t`, in the cell clamped to
+// [lo, hi].
+fn dda_start(origin: vec2f, direction: vec2f, t: f32, size: f32, lo: vec2i, hi: vec2i) -> Dda {
+  let cell = clamp(vec2i(floor((origin + t * direction) / size)), lo, hi);
+  let step = vec2i(sign(direction));
+  let boundary = vec2f(cell + max(step, vec2i(0))) * size;
+  let moving = direction != vec2(0.);
+  let t_next = select(vec2(FLT_MAX), (boundary - origin) / direction, moving);
+  let t_delta = select(vec2(FLT_MAX), abs(size / direction), moving);
+  return Dda(cell, step, t_next, t_delta);
+}
+
+fn dda_exit(dda: Dda) -> f32 {
+  return min(dda.t_next.x, dda.t_next.y);
+}
+
+// Rounding can carry the traversal one cell past the end of the ray's interval.
+fn dda_inside(dda: Dda, lo: vec2i, hi: vec2i) -> bool {
+  return all(dda.cell >= lo) && all(dda.cell <= hi);
+}
+
+fn dda_advance(dda: ptr<function, Dda>) {
+  if (*dda).t_next.x < (*dda).t_next.y {
+    (*dda).cell.x += (*dda).step.x;
+    (*dda).t_next.x += (*dda).t_delta.x;
+  } else {
+    (*dda).cell.y += (*dda).step.y;
+    (*dda).t_next.y += (*dda).t_delta.y;
+  }
+}
+
+fn heightfield_height(field: Heightfield, x: i32, z: i32) -> f32 {
+  let column = u32(clamp(x, 0, i32(field.columns) - 1));
+  let row = u32(clamp(z, 0, i32(field.rows) - 1));
+  return heightfield_data[field.first_sample + row * field.columns + column];
+}
+
+fn heightfield_point(field: Heightfield, cell: vec2i) -> vec3f {
+  return vec3(f32(cell.x), heightfield_height(field, cell.x, cell.y), f32(cell.y));
+}
+
+// Grid-space normal at a sample from central differences, not normalized.
+fn heightfield_normal(field: Heightfield, cell: vec2i) -> vec3f {
+  let dx = heightfield_height(field, cell.x + 1, cell.y) - heightfield_height(field, cell.x - 1, cell.y);
+  let dz = heightfield_height(field, cell.x, cell.y + 1) - heightfield_height(field, cell.x, cell.y - 1);
+  return vec3(-0.5 * dx, 1., -0.5 * dz);
+}
+
+// Moeller-Trumbore, returning (t, b1, b2) with t = FLT_MAX on a miss.
+fn triangle_barycentrics(ray: Ray, p0: vec3f, p1: vec3f, p2: vec3f) -> vec3f {
+  let miss = vec3(FLT_MAX, 0., 0.);
+  let e1 = p1 - p0;
+  let e2 = p2 - p0;
+  let pvec = cross(ray.direction, e2);
+  let det = dot(e1, pvec);
+  if abs(det) < 1e-12 {
+    return miss;
+  }
+  let inv_det = 1. / det;
+  let tvec = ray.origin - p0;
+  let b1 = dot(tvec, pvec) * inv_det;
+  let qvec = cross(tvec, e1);
+  let b2 = dot(ray.direction, qvec) * inv_det;
+  if b1 < 0. || b2 < 0. || b1 + b2 > 1. {
+    return miss;
+  }
+  return vec3(dot(e2, qvec) * inv_det, b1, b2);
+}
+
+// The corners of triangle `index` of a cell, relative to its first sample. Cells are split
+// along the diagonal from (0, 0) to (1, 1).
+fn heightfield_corners(index: u32) -> array<vec2i, 3> {
+  if index == 0u {
+    return array(vec2i(0, 0), vec2i(1, 0), vec2i(1, 1));
+  }
+  return array(vec2i(0, 0), vec2i(1, 1), vec2i(0, 1));
+}
+
+// Closest hit with the two triangles of `cell` before `t_max` as (t, b1, b2, triangle), with
+// t = FLT_MAX on a miss.
+fn heightfield_cell(ray: Ray, field: Heightfield, cell: vec2i, t_max: f32) -> vec4f {
+  var hit = vec4(FLT_MAX, 0., 0., 0.);
+  for (var i = 0u; i < 2u; i += 1u) {
+    let corners = heightfield_corners(i);
+    let p0 = heightfield_point(field, cell + corners[0]);
+    let p1 = heightfield_point(field, cell + corners[1]);
+    let p2 = heightfield_point(field, cell + corners[2]);
+    let b = triangle_barycentrics(ray, p0, p1, p2);
+    if b.x > EPSILON && b.x < min(hit.x, t_max) {
+      hit = vec4(b, f32(i));
+    }
+  }
+  return hit;
+}
+
+fn intersect_heightfield(ray: Ray, field: Heightfield, t_max: f32) -> Intersection {
+  // Also rejects the zeroed placeholder of an empty buffer.
+  if field.columns < 2u || field.rows < 2u {
+    return no_intersection();
+  }
+  // The direction is not renormalized, so `t` is the same in grid and world space.
+  let grid_ray = Ray((ray.origin - field.origin) / field.cell_size, ray.direction / field.cell_size, ray.time);
+  let o = grid_ray.origin;
+  let d = grid_ray.direction;
+  let extent = vec3(f32(field.columns - 1u), 1., f32(field.rows - 1u));
+  let t0 = -o / d;
+  let t1 = (extent - o) / d;
+  let t_enter = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), max(min(t0.z, t1.z), EPSILON));
+  let t_exit = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), min(max(t0.z, t1.z), t_max));
+  nodes_visited += 1u;
+  if t_enter > t_exit {
+    return no_intersection();
+  }
+
+  let last_cell = vec2i(extent.xz) - 1;
+  let last_block = last_cell / HEIGHTFIELD_BLOCK;
+  var blocks = dda_start(o.xz, d.xz, t_enter, f32(HEIGHTFIELD_BLOCK), vec2i(0), last_block);
+  var t = t_enter;
+  var hit = vec4(FLT_MAX, 0., 0., 0.);
+  var hit_cell = vec2i(0);
+  loop {
+    nodes_visited += 1u;
+    let t_block_exit = min(dda_exit(blocks), t_exit);
+    let block = field.first_block + 2u * u32(blocks.cell.y * (last_block.x + 1) + blocks.cell.x);
+    let y0 = o.y + t * d.y;
+    let y1 = o.y + t_block_exit * d.y;
+    if min(y0, y1) <= heightfield_data[block + 1u] && max(y0, y1) >= heightfield_data[block] {
+      let first = blocks.cell * HEIGHTFIELD_BLOCK;
+      let last = min(first + HEIGHTFIELD_BLOCK - 1, last_cell);
+      var cells = dda_start(o.xz, d.xz, t, 1., first, last);
+      loop {
+        primitive_tests += 2u;
+        hit = heightfield_cell(grid_ray, field, cells.cell, t_max);
+        if hit.x < FLT_MAX || dda_exit(cells) >= t_block_exit {
+          break;
+        }
+        dda_advance(&cells);
+        if !dda_inside(cells, first, last) {
+          break;
+        }
+      }
+      if hit.x < FLT_MAX {
+        hit_cell = cells.cell;
+        break;
+      }
+    }
+    if t_block_exit >= t_exit {
+      break;
+    }
+    dda_advance(&blocks);
+    if !dda_inside(blocks, vec2i(0), last_block) {
+      break;
+    }
+    t = t_block_exit;
+  }
+  if hit.x == FLT_MAX {
+    return no_intersection();
+  }
+
+  // Interpolate the sample normals and map them to world space with the inverse transpose
+  // of the grid-to-world scaling.
+  let corners = heightfield_corners(u32(hit.w));
+  let p0 = heightfield_point(field, hit_cell + corners[0]);
+  let p1 = heightfield_point(field, hit_cell + corners[1]);
+  let p2 = heightfield_point(field, hit_cell + corners[2]);
+  var Ng = cross(p1 - p0, p2 - p0);
+  Ng = normalize(select(-Ng, Ng, Ng.y > 0.) / field.cell_size);
+  let b0 = 1. - hit.y - hit.z;
+  let n = b0 * heightfield_normal(field, hit_cell + corners[0])
+    + hit.y * heightfield_normal(field, hit_cell + corners[1])
+    + hit.z * heightfield_normal(field, hit_cell + corners[2]);
+  var N = normalize(n / field.cell_size);
+  if dot(N, Ng) < 0. {
+    N = Ng;
+  }
+  let uv = point_on_ray(grid_ray, hit.x).xz / extent.xz;
+  // Along +x; the bitangent then follows +z, the direction of increasing v.
+  let tangent = vec4(normalize(vec3(1., 0., 0.) - N * N.x), -1.);
+  return Intersection(Ng, hit.x, uv, field.material_index, N, tangent, vec3(1.));
+}
+
+// Two-level BVH, see bvh.rs. Both levels use the same node layout: a leaf covers
+// `count` primitives starting at `left_first`, an interior node (count = 0) has its children
+// at `left_first` and `left_first + 1`.
+const BVH_STACK_SIZE: u32 = 32u;
+
+struct BvhNode {
+  min: vec3f,
+  left_first: u32,
+  max: vec3f,
+  count: u32,
+}
+
+// Affine transforms are stored as the top three rows of a 4x4 matrix. Moving instances
+// interpolate linearly from `to_world` at time 0 to `to_world1` at time 1.
+struct Instance {
+  to_world: array<vec4f, 3>,
+  to_object: array<vec4f, 3>,
+  to_world1: array<vec4f, 3>,
+  blas_root: u32,
+  moving: u32,
+  wide_root: u32,
+}
+
+fn transform_point(m: array<vec4f, 3>, p: vec3f) -> vec3f {
+  let p1 = vec4(p, 1.);
+  return vec3(dot(m[0], p1), dot(m[1], p1), dot(m[2], p1));
+}
+
+fn transform_vector(m: array<vec4f, 3>, v: vec3f) -> vec3f {
+  return vec3(dot(m[0].xyz, v), dot(m[1].xyz, v), dot(m[2].xyz, v));
+}
+
+// Multiplies by the transpose of the linear part of `inverse`, which maps normals to the
+// space `inverse` maps from.
+fn transform_normal(inverse: array<vec4f, 3>, n: vec3f) -> vec3f {
+  return n.x * inverse[0].xyz + n.y * inverse[1].xyz + n.z * inverse[2].xyz;
+}
+
+fn mix_transform(a: array<vec4f, 3>, b: array<vec4f, 3>, t: f32) -> array<vec4f, 3> {
+  return array(mix(a[0], b[0], t), mix(a[1], b[1], t), mix(a[2], b[2], t));
+}
+
+// Inverse of an affine transform. The columns of the inverse of the linear part are the
+// cross products of its rows divided by the determinant.
+fn inverse_transform(m: array<vec4f, 3>) -> array<vec4f, 3> {
+  let inv_det = 1. / dot(m[0].xyz, cross(m[1].xyz, m[2].xyz));
+  let c0 = cross(m[1].xyz, m[2].xyz) * inv_det;
+  let c1 = cross(m[2].xyz, m[0].xyz) * inv_det;
+  let c2 = cross(m[0].xyz, m[1].xyz) * inv_det;
+  let rows = transpose(mat3x3(c0, c1, c2));
+  let translation = -(c0 * m[0].w + c1 * m[1].w + c2 * m[2].w);
+  return array(vec4(rows[0], translation.x), vec4(rows[1], translation.y), vec4(rows[2], translation.z));
+}
+
+// Entry distance of the ray into the box if it is closer than `t_max`, FLT_MAX otherwise.
+fn intersect_box(ray: Ray, inv_dir: vec3f, lo: vec3f, hi: vec3f, t_max: f32) -> f32 {
+  let t0 = (lo - ray.origin) * inv_dir;
+  let t1 = (hi - ray.origin) * inv_dir;
+  let t_near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), max(min(t0.z, t1.z), EPSILON));
+  let t_far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), min(max(t0.z, t1.z), t_max));
+  return select(FLT_MAX, t_near, t_near <= t_far);
+}
+
+fn intersect_aabb(ray: Ray, inv_dir: vec3f, node: BvhNode, t_max: f32) -> f32 {
+  return intersect_box(ray, inv_dir, node.min, node.max, t_max);
+}
+
+// Only the root of a BVH without primitives has an empty (inverted) box, which the slab
+// test alone would not reject.
+fn is_empty_bvh(root: BvhNode) -> bool {
+  return root.min.x > root.max.x;
+}
+
+// BVHs whose leaves hold primitives rather than instances.
+const BVH_TRIANGLES: u32 = 0u;
+const BVH_SPHERES: u32 = 1u;
+const BVH_SHAPES: u32 = 2u;
+
+fn primitive_node(tree: u32, index: u32) -> BvhNode {
+  if tree == BVH_SPHERES {
+    return sphere_nodes[index];
+  }
+  if tree == BVH_SHAPES {
+    return shape_nodes[index];
+  }
+  return blas_nodes[index];
+}
+
+// Closest primitive hit before `t_max` in the BVH `tree` rooted at `root`: one of the BLAS
+// (with the ray in object space), the sphere BVH or the shape BVH.
+fn intersect_primitives(ray: Ray, tree: u32, root: u32, t_max: f32) -> Intersection {
+  var closest_hit = no_intersection();
+  var t_closest = t_max;
+  let inv_dir = 1. / ray.direction;
+  let root_node = primitive_node(tree, root);
+  if is_empty_bvh(root_node) || intersect_aabb(ray, inv_dir, root_node, t_closest) == FLT_MAX {
+    return closest_hit;
+  }
+  var stack: array<u32, BVH_STACK_SIZE>;
+  var stack_size = 0u;
+  var index = root;
+  loop {
+    let node = primitive_node(tree, index);
+    nodes_visited += 1u;
+    if node.count > 0u {
+      for (var i = node.left_first; i < node.left_first + node.count; i += 1u) {
+        primitive_tests += 1u;
+        var hit: Intersection;
+        if tree == BVH_SPHERES {
+          hit = intersect_sphere(ray, spheres[i]);
+        } else if tree == BVH_SHAPES {
+          hit = intersect_shape(ray, shapes[i], t_closest);
+        } else {
+          hit = intersect_triangle(ray, mesh_triangles[i]);
+        }
+        if hit.t > 0. && hit.t < t_closest {
+          closest_hit = hit;
+          t_closest = hit.t;
+        }
+      }
+    } else {
+      // Visit the nearer child first and defer the other.
+      var near = node.left_first;
+      var far = near + 1u;
+      var t_near = intersect_aabb(ray, inv_dir, primitive_node(tree, near), t_closest);
+      var t_far = intersect_aabb(ray, inv_dir, primitive_node(tree, far), t_closest);
+      if t_far < t_near {
+        let swap = near;
+        near = far;
+        far = swap;
+        let t_swap = t_near;
+        t_near = t_far;
+        t_far = t_swap;
+      }
+      if t_near < FLT_MAX {
+        if t_far < FLT_MAX && stack_size < BVH_STACK_SIZE {
+          stack[stack_size] = far;
+          stack_size += 1u;
+        }
+        index = near;
+        continue;
+      }
+    }
+    if stack_size == 0u {
+      break;
+    }
+    stack_size -= 1u;
+    index = stack[stack_size];
+  }
+  return closest_hit;
+}
+
+// A node of the 4-wide BLAS built in wide_bvh.rs. The box of child i is `origin + q * scale`
+// for its grid coordinates q, which are byte i of `bounds` (lower x, y, z, then upper x, y,
+// z); the scale along each axis is the power of two with the biased exponent in the low
+// bytes of `exponents`, whose high byte is the number of children. Byte i of `counts` is the
+// number of triangles of a leaf child starting at `children[i]`, or 0 if `children[i]` is a
+// node.
+struct WideNode {
+  origin: vec3f,
+  exponents: u32,
+  bounds: array<u32, 6>,
+  children: array<u32, 4>,
+  counts: u32,
+}
+
+// Must match STACK_SIZE in wide_bvh.rs, which only collapses BLAS whose traversal fits. The
+// overflow check below merely keeps the stack in bounds.
+const WIDE_STACK_SIZE: u32 = 64u;
+// `Instance::wide_root` of a BLAS without a wide version, traversed in the binary layout.
+const NO_WIDE_BLAS: u32 = 0xffffffffu;
+
+// Closest triangle hit before `t_max` in the wide BLAS rooted at `root`, with the ray in
+// object space. All children of a node are tested when it is fetched: leaves right away,
+// nodes pushed from far to near along with their entry distance, which culls them on pop
+// once a closer hit is known.
+fn intersect_wide_blas(ray: Ray, root: u32, t_max: f32) -> Intersection {
+  var closest_hit = no_intersection();
+  var t_closest = t_max;
+  let inv_dir = 1. / ray.direction;
+  var stack: array<u32, WIDE_STACK_SIZE>;
+  var stack_t: array<f32, WIDE_STACK_SIZE>;
+  stack[0] = root;
+  stack_t[0] = 0.;
+  var stack_size = 1u;
+  while stack_size > 0u {
+    stack_size -= 1u;
+    if stack_t[stack_size] >= t_closest {
+      continue;
+    }
+    var node = wide_blas_nodes[stack[stack_size]];
+    nodes_visited += 1u;
+    let scale = vec3(
+      bitcast<f32>(extractBits(node.exponents, 0u, 8u) << 23u),
+      bitcast<f32>(extractBits(node.exponents, 8u, 8u) << 23u),
+      bitcast<f32>(extractBits(node.exponents, 16u, 8u) << 23u),
+    );
+    // Interior children that were hit, sorted by decreasing distance.
+    var child_t: array<f32, 4>;
+    var child_index: array<u32, 4>;
+    var hit_count = 0u;
+    for (var i = 0u; i < node.exponents >> 24u; i += 1u) {
+      let offset = 8u * i;
+      let q_lo = vec3(
+        extractBits(node.bounds[0], offset, 8u),
+        extractBits(node.bounds[1], offset, 8u),
+        extractBits(node.bounds[2], offset, 8u),
+      );
+      let q_hi = vec3(
+        extractBits(node.bounds[3], offset, 8u),
+        extractBits(node.bounds[4], offset, 8u),
+        extractBits(node.bounds[5], offset, 8u),
+      );
+      let lo = node.origin + vec3f(q_lo) * scale;
+      let hi = node.origin + vec3f(q_hi) * scale;
+      let t = intersect_box(ray, inv_dir, lo, hi, t_closest);
+      if t == FLT_MAX {
+        continue;
+      }
+      let count = extractBits(node.counts, offset, 8u);
+      if count > 0u {
+        primitive_tests += count;
+        for (var j = node.children[i]; j < node.children[i] + count; j += 1u) {
+          let hit = intersect_triangle(ray, mesh_triangles[j]);
+          if hit.t > 0. && hit.t < t_closest {
+            closest_hit = hit;
+            t_closest = hit.t;
+          }
+        }
+      } else {
+        var j = hit_count;
+        while j > 0u && child_t[j - 1u] < t {
+          child_t[j] = child_t[j - 1u];
+          child_index[j] = child_index[j - 1u];
+          j -= 1u;
+        }
+        child_t[j] = t;
+        child_index[j] = node.children[i];
+        hit_count += 1u;
+      }
+    }
+    let overflow = select(0u, stack_size + hit_count - WIDE_STACK_SIZE, stack_size + hit_count > WIDE_STACK_SIZE);
+    for (var j = overflow; j < hit_count; j += 1u) {
+      stack[stack_size] = child_index[j];
+      stack_t[stack_size] = child_t[j];
+      stack_size += 1u;
+    }
+  }
+  return closest_hit;
+}
+
+// Hit of `ray` with the BLAS of `instance`, transformed back to world space. The object-space
+// direction is not renormalized, so `t` is the same in both spaces.
+fn intersect_instance(ray: Ray, instance: Instance, t_max: f32) -> Intersection {
+  var to_world = instance.to_world;
+  var to_object = instance.to_object;
+  if instance.moving != 0u {
+    to_world = mix_transform(instance.to_world, instance.to_world1, ray.time);
+    to_object = inverse_transform(to_world);
+  }
+  let object_ray = Ray(
+    transform_point(to_object, ray.origin),
+    transform_vector(to_object, ray.direction),
+    ray.time,
+  );
+  var hit: Intersection;
+  if uniforms.wide_bvh != 0u && instance.wide_root != NO_WIDE_BLAS {
+    hit = intersect_wide_blas(object_ray, instance.wide_root, t_max);
+  } else {
+    hit = intersect_primitives(object_ray, BVH_TRIANGLES, instance.blas_root, t_max);
+  }
+  if hit.t <= 0. {
+    return hit;
+  }
+  hit.normal = normalize(transform_normal(to_object, hit.normal));
+  hit.shading_normal = normalize(transform_normal(to_object, hit.shading_normal));
+  // Mirroring transforms flip the handedness of the tangent frame.
+  let m = to_world;
+  let mirrored = dot(m[0].xyz, cross(m[1].xyz, m[2].xyz)) < 0.;
+  hit.tangent = vec4(
+    normalize(transform_vector(m, hit.tangent.xyz)),
+    select(hit.tangent.w, -hit.tangent.w, mirrored),
+  );
+  return hit;
+}
+
+// Closest instance hit before `t_max`, traversing the TLAS like `intersect_primitives`.
+fn intersect_instances(ray: Ray, t_max: f32) -> Intersection {
+  var closest_hit = no_intersection();
+  var t_closest = t_max;
+  let inv_dir = 1. / ray.direction;
+  if is_empty_bvh(tlas_nodes[0]) || intersect_aabb(ray, inv_dir, tlas_nodes[0], t_closest) == FLT_MAX {
+    return closest_hit;
+  }
+  var stack: array<u32, BVH_STACK_SIZE>;
+  var stack_size = 0u;
+  var index = 0u;
+  loop {
+    let node = tlas_nodes[index];
+    nodes_visited += 1u;
+    if node.count > 0u {
+      for (var i = node.left_first; i < node.left_first + node.count; i += 1u) {
+        let hit = intersect_instance(ray, instances[i], t_closest);
+        if hit.t > 0. && hit.t < t_closest {
+          closest_hit = hit;
+          t_closest = hit.t;
+        }
+      }
+    } else {
+      var near = node.left_first;
+      var far = near + 1u;
+      var t_near = intersect_aabb(ray, inv_dir, tlas_nodes[near], t_closest);
+      var t_far = intersect_aabb(ray, inv_dir, tlas_nodes[far], t_closest);
+      if t_far < t_near {
+        let swap = near;
+        near = far;
+        far = swap;
+        let t_swap = t_near;
+        t_near = t_far;
+        t_far = t_swap;
+      }
+      if t_near < FLT_MAX {
+        if t_far < FLT_MAX && stack_size < BVH_STACK_SIZE {
+          stack[stack_size] = far;
+          stack_size += 1u;
+        }
+        index = near;
+        continue;
+      }
+    }
+    if stack_size == 0u {
+      break;
+    }
+    stack_size -= 1u;
+    index = stack[stack_size];
+  }
+  return closest_hit;
+}
+
+fn intersect_scene(ray: Ray) -> Intersection {
+  var closest_hit = no_intersection();
+  closest_hit.t = FLT_MAX;
+  
+  let sphere_hit = intersect_primitives(ray, BVH_SPHERES, 0u, closest_hit.t);
+  if sphere_hit.t > 0. {
+    closest_hit = sphere_hit;
+  }
+  let shape_hit = intersect_primitives(ray, BVH_SHAPES, 0u, closest_hit.t);
+  if shape_hit.t > 0. {
+    closest_hit = shape_hit;
+  }
+  // The quads have no BVH and are all tested. SDFs, CSG objects and heightfields count
+  // their bounding box as a node and the work inside it as they trace.
+  primitive_tests += arrayLength(&quads);
+  for (var i = 0u; i < arrayLength(&quads); i += 1u) {
+    let hit = intersect_quad(ray, quads[i]);
+    if hit.t > 0. && hit.t < closest_hit.t {
+      closest_hit = hit;
+    }
+  }
+  for (var i = 0u; i < arrayLength(&sdf_objects); i += 1u) {
+    let hit = intersect_sdf(ray, sdf_objects[i], closest_hit.t);
+    if hit.t > 0. && hit.t < closest_hit.t {
+      closest_hit = hit;
+    }
+  }
+  for (var i = 0u; i < arrayLength(&csg_objects); i += 1u) {
+    let hit = intersect_csg(ray, csg_objects[i], closest_hit.t);
+    if hit.t > 0. && hit.t < closest_hit.t {
+      closest_hit = hit;
+    }
+  }
+  for (var i = 0u; i < arrayLength(&heightfields); i += 1u) {
+    let hit = intersect_heightfield(ray, heightfields[i], closest_hit.t);
+    if hit.t > 0. && hit.t < closest_hit.t {
+      closest_hit = hit;
+    }
+  }
+  let hit = intersect_instances(ray, closest_hit.t);
+  if hit.t > 0. {
+    closest_hit = hit;
+  }
+  
+  if closest_hit.t < FLT_MAX {
+    return closest_hit;
+  }
+  return no_intersection();
+}
+
+struct Texture {
+  color0: vec3f,
+  kind: u32,
+  color1: vec3f,
+  scale: f32,
+  layer: u32,
+  // Non-zero if the image layer is sRGB-encoded.
+  srgb: u32,
+}
+
+fn srgb_to_linear(c: vec3f) -> vec3f {
+  let low = c / 12.92;
+  let high = pow((c + 0.055) / 1.055, vec3(2.4));
+  return select(high, low, c <= vec3(0.04045));
+}
+
+// Random unit gradient for a lattice point of the Perlin noise grid. The book's permutation
+// tables are replaced by hashing the cell coordinates.
+fn perlin_gradient(cell: vec3i) -> vec3f {
+  let h = jenkins_hash(bitcast<u32>(cell.x) ^ jenkins_hash(bitcast<u32>(cell.y) ^ jenkins_hash(bitcast<u32>(cell.z))));
+  let y = 1. - 2. * hash_to_f32(h);
+  let xz_r = sqrt(1. - y * y);
+  let phi = TWO_PI * hash_to_f32(jenkins_hash(h));
+  return vec3(xz_r * cos(phi), y, xz_r * sin(phi));
+}
+
+// Gradient noise in [-1, 1] with Hermite-smoothed trilinear interpolation ("The Next Week"
+// section 5.6).
+fn perlin_noise(p: vec3f) -> f32 {
+  let cell = floor(p);
+  let f = p - cell;
+  let w = f * f * (3. - 2. * f);
+  var accum = 0.;
+  for (var i = 0; i < 2; i += 1) {
+    for (var j = 0; j < 2; j += 1) {
+      for (var k = 0; k < 2; k += 1) {
+        let corner = vec3f(f32(i), f32(j), f32(k));
+        let gradient = perlin_gradient(vec3i(cell) + vec3(i, j, k));
+        let weight = mix(1. - w, w, corner);
+        accum += weight.x * weight.y * weight.z * dot(gradient, f - corner);
+      }
+    }
+  }
+  return accum;
+}
+
+fn turbulence(p: vec3f) -> f32 {
+  var accum = 0.;
+  var temp_p = p;
+  var weight = 1.;
+  for (var i = 0u; i < TURBULENCE_DEPTH; i += 1u) {
+    accum += weight * perlin_noise(temp_p);
+    weight *= 0.5;
+    temp_p *= 2.;
+  }
+  return abs(accum);
+}
+
+fn texture_value(index: u32, uv: vec2f, p: vec3f) -> vec3f {
+  let texture = textures[index];
+  switch texture.kind {
+    case TEXTURE_CHECKER: {
+      let cell = vec3i(floor(p / texture.scale));
+      let is_even = ((cell.x + cell.y + cell.z) & 1) == 0;
+      return select(texture.color1, texture.color0, is_even);
+    }
+    case TEXTURE_IMAGE: {
+      // Image rows run top to bottom while v runs bottom to top.
+      let st = vec2(uv.x, 1. - uv.y);
+      let c = textureSampleLevel(image_textures, image_sampler, st, texture.layer, 0.).rgb;
+      return select(c, srgb_to_linear(c), texture.srgb != 0u);
+    }
+    case TEXTURE_NOISE: {
+      return texture.color0 * 0.5 * (1. + perlin_noise(texture.scale * p));
+    }
+    case TEXTURE_TURBULENCE: {
+      return texture.color0 * turbulence(texture.scale * p);
+    }
+    case TEXTURE_MARBLE: {
+      return texture.color0 * 0.5 * (1. + sin(texture.scale * p.z + 10. * turbulence(p)));
+    }
+    case TEXTURE_SOLID, default: {
+      return texture.color0;
+    }
+  }
+}
+
+fn material_albedo(material: Material, uv: vec2f, p: vec3f) -> vec3f {
+  if material.texture == NO_TEXTURE {
+    return material.color;
+  }
+  return material.color * texture_value(material.texture, uv, p);
+}
+
+// ---------------------------------------------------------------------------------------
+// Participating media. Free-flight distances are measured in units of the ray parameter, so
+// ray directions must be normalized.
+
+// Homogeneous medium bounded by a sphere, see scene::Medium.
+struct Medium {
+  center: vec3f,
+  radius: f32,
+  albedo: vec3f,
+  density: f32,
+  g: f32,
+}
+
+// Exponential height fog, see scene::Fog.
+struct Fog {
+  albedo: vec3f,
+  density: f32,
+  height: f32,
+  falloff: f32,
+  g: f32,
+}
+
+// Volume kinds, must match the constants in scene.rs.
+const VOLUME_GRID: u32 = 0u;
+const VOLUME_NOISE: u32 = 1u;
+
+// Heterogeneous medium filling a box, see scene::Volume.
+struct Volume {
+  bounds_min: vec3f,
+  kind: u32,
+  bounds_max: vec3f,
+  density: f32,
+  albedo: vec3f,
+  majorant: f32,
+  emission: vec3f,
+  g: f32,
+  grid_size: vec3u,
+  z_offset: u32,
+  frequency: f32,
+}
+
+// A real scattering collision found by free-flight sampling, at `t` = FLT_MAX if none.
+// `emission` is the radiance emitted by volumes along the way, to be weighted by the path
+// throughput.
+struct MediumEvent {
+  t: f32,
+  albedo: vec3f,
+  g: f32,
+  emission: vec3f,
+}
+
+// Parametric interval of `ray` inside an axis-aligned box (slab test); empty (x >= y) if the
+// ray misses it.
+fn box_interval(ray: Ray, lo: vec3f, hi: vec3f) -> vec2f {
+  let inv_direction = 1. / ray.direction;
+  let t0 = (lo - ray.origin) * inv_direction;
+  let t1 = (hi - ray.origin) * inv_direction;
+  let t_near = min(t0, t1);
+  let t_far = max(t0, t1);
+  return vec2(max(max(t_near.x, t_near.y), t_near.z), min(min(t_far.x, t_far.y), t_far.z));
+}
+
+// Trilinearly interpolated density and emission of a grid volume at `q` in [0, 1]^3.
+fn grid_sample(volume: Volume, q: vec3f) -> vec2f {
+  let size = vec3f(volume.grid_size);
+  // Voxel centers sit at half-integer coordinates; clamp to the grid's own voxels so that
+  // neighbours in the atlas never bleed in.
+  let x = clamp(q * size - 0.5, vec3(0.), size - 1.);
+  let lo = vec3u(floor(x));
+  let hi = min(lo + 1u, volume.grid_size - 1u);
+  let f = x - floor(x);
+  var value = vec2(0.);
+  for (var i = 0u; i < 8u; i += 1u) {
+    let corner = vec3((i & 1u) != 0u, (i & 2u) != 0u, (i & 4u) != 0u);
+    let voxel = select(lo, hi, corner);
+    let weight = select(1. - f, f, corner);
+    let texel = textureLoad(volume_atlas, voxel + vec3(0u, 0u, volume.z_offset), 0).xy;
+    value += weight.x * weight.y * weight.z * texel;
+  }
+  return value;
+}
+
+// Fractal noise cloud in [0, 1] with a soft spherical falloff towards the box faces.
+fn noise_density(q: vec3f, frequency: f32) -> f32 {
+  var noise = 0.;
+  var amplitude = 0.5;
+  var x = frequency * q;
+  for (var i = 0u; i < 4u; i += 1u) {
+    noise += amplitude * perlin_noise(x);
+    amplitude *= 0.5;
+    x *= 2.;
+  }
+  let r = 2. * length(q - 0.5);
+  return clamp(1. - r + 1.5 * noise, 0., 1.);
+}
+
+// Extinction coefficient (x) and emission weight (y) of `volume` at the world-space point `p`.
+fn volume_sample(volume: Volume, p: vec3f) -> vec2f {
+  let q = (p - volume.bounds_min) / (volume.bounds_max - volume.bounds_min);
+  if volume.kind == VOLUME_NOISE {
+    let d = noise_density(q, volume.frequency);
+    return vec2(volume.density * d, d);
+  }
+  let value = grid_sample(volume, q);
+  return vec2(volume.density * value.x, value.y);
+}
+
+// Parametric interval of `ray` inside a sphere; empty (x >= y) if the ray misses it.
+fn sphere_interval(ray: Ray, center: vec3f, radius: f32) -> vec2f {
+  let v = ray.origin - center;
+  let b = dot(v, ray.direction);
+  let c = dot(v, v) - radius * radius;
+  let d = b * b - c;
+  if d < 0. {
+    return vec2(FLT_MAX, 0.);
+  }
+  let sqrt_d = sqrt(d);
+  return vec2(-b - sqrt_d, -b + sqrt_d);
+}
+
+// Delta tracking between t0 and t1: tentative collisions are drawn with the `majorant`
+// density and accepted as real with probability density / majorant. For a homogeneous
+// medium the majorant is the density and the first collision is always real. Returns
+// FLT_MAX if the ray leaves the interval.
+fn delta_track(density: f32, majorant: f32, t0: f32, t1: f32) -> f32 {
+  var t = t0;
+  loop {
+    t -= log(1. - rand_f32()) / majorant;
+    if t >= t1 {
+      return FLT_MAX;
+    }
+    if rand_f32() * majorant < density {
+      return t;
+    }
+  }
+  return FLT_MAX;
+}
+
+// Samples the free-flight distance through the height fog by inverting its optical depth,
+// which has a closed form along a straight line.
+fn sample_fog(ray: Ray) -> f32 {
+  let fog = uniforms.fog;
+  if fog.density <= 0. {
+    return FLT_MAX;
+  }
+  let optical_depth = -log(1. - rand_f32());
+  let density_at_origin = fog.density * exp(-fog.falloff * (ray.origin.y - fog.height));
+  let k = fog.falloff * ray.direction.y;
+  if abs(k) < 1e-5 {
+    return optical_depth / density_at_origin;
+  }
+  // optical depth(t) = density_at_origin * (1 - exp(-k t)) / k
+  let x = optical_depth * k / density_at_origin;
+  if x >= 1. {
+    // Going up, the fog thins out too fast to ever reach this optical depth.
+    return FLT_MAX;
+  }
+  return -log(1. - x) / k;
+}
+
+// Finds the nearest real collision with any medium before `t_max`. The media and the fog are
+// sampled independently; the nearest of their collisions is distributed like a collision in
+// their combined density.
+fn sample_media(ray: Ray, t_max: f32) -> MediumEvent {
+  var event = MediumEvent(FLT_MAX, vec3(0.), 0., vec3(0.));
+  for (var i = 0u; i < arrayLength(&media); i += 1u) {
+    let medium = media[i];
+    if medium.density <= 0. {
+      continue;
+    }
+    let interval = sphere_interval(ray, medium.center, medium.radius);
+    let t0 = max(interval.x, 0.);
+    let t1 = min(interval.y, min(t_max, event.t));
+    if t0 >= t1 {
+      continue;
+    }
+    let t = delta_track(medium.density, medium.density, t0, t1);
+    if t < event.t {
+      event = MediumEvent(t, medium.albedo, medium.g, vec3(0.));
+    }
+  }
+  let t = sample_fog(ray);
+  if t < min(t_max, event.t) {
+    event = MediumEvent(t, uniforms.fog.albedo, uniforms.fog.g, vec3(0.));
+  }
+
+  // Heterogeneous volumes go last, so that their emission is only gathered up to the nearest
+  // collision found so far. (Emission of overlapping emissive volumes can still be counted
+  // slightly past a collision in a volume tracked later.)
+  var emission = vec3(0.);
+  for (var i = 0u; i < arrayLength(&volumes); i += 1u) {
+    let volume = volumes[i];
+    if volume.majorant <= 0. {
+      continue;
+    }
+    let interval = box_interval(ray, volume.bounds_min, volume.bounds_max);
+    let t1 = min(interval.y, min(t_max, event.t));
+    var t = max(interval.x, 0.);
+    // Delta tracking. Every tentative collision also scores the emission divided by the
+    // majorant, which estimates the emission integrated along the ray up to the collision.
+    loop {
+      t -= log(1. - rand_f32()) / volume.majorant;
+      if t >= t1 {
+        break;
+      }
+      let value = volume_sample(volume, point_on_ray(ray, t));
+      emission += volume.emission * value.y / volume.majorant;
+      if rand_f32() * volume.majorant < value.x {
+        event = MediumEvent(t, volume.albedo, volume.g, vec3(0.));
+        break;
+      }
+    }
+  }
+  event.emission = emission;
+  return event;
+}
+
+// Samples a new direction around the travel direction `w` from the Henyey-Greenstein phase
+// function. Since the phase function is sampled exactly, the path weight is just the albedo.
+fn sample_henyey_greenstein(w: vec3f, g: f32) -> vec3f {
+  let u = vec2(rand_f32(), rand_f32());
+  var cos_theta: f32;
+  if abs(g) < 1e-3 {
+    cos_theta = 1. - 2. * u.x;
+  } else {
+    let s = (1. - g * g) / (1. - g + 2. * g * u.x);
+    cos_theta = (1. + g * g - s * s) / (2. * g);
+  }
+  let sin_theta = sqrt(max(0., 1. - cos_theta * cos_theta));
+  let phi = TWO_PI * u.y;
+  return orthonormal_basis(w) * vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+}
+
+fn sky_color(ray: Ray) -> vec3f {
+  if uniforms.background.w > 0. {
+    return uniforms.background.rgb;
+  }
+  let t = 0.5 * (normalize(ray.direction).y + 1.0);
+  return (1.0 - t) * vec3(1.0, 1.0, 1.0) + t * vec3(0.5, 0.7, 1.0);
+}
+
+// Projects a world-space point into the pixel grid of `camera`. Directions (`is_direction`)
+// are projected as points at infinity. Returns the pixel position in xy and the view depth
+// in z; z is negative when the point lies behind the camera.
+fn project_to_pixel(camera: CameraUniforms, p: vec3f, is_direction: bool) -> vec3f {
+  let d = select(p - camera.origin, p, is_direction);
+  let z = dot(d, camera.w);
+  if z <= EPSILON {
+    return vec3(-1.);
+  }
+  let aspect_ratio = f32(uniforms.width) / f32(uniforms.height);
+  let uv = vec2(dot(d, camera.u), dot(d, camera.v)) / z;
+  let ndc = uv / vec2(aspect_ratio, -1.0);
+  let pixel = 0.5 * (ndc + vec2(1.0)) * vec2f(f32(uniforms.width - 1u), f32(uniforms.height - 1u));
+  return vec3(pixel, z);
+}
+
+// Looks up the previous frame's average at the location `first_hit` was visible from the
+// previous camera. Returns the history color in xyz and 1 in w if it can be reused, or 0 in w
+// if the point was off-screen or disoccluded.
+fn reproject_history(first_hit: vec3f, is_sky: bool) -> vec4f {
+  let projected = project_to_pixel(uniforms.prev_camera, first_hit, is_sky);
+  let size = vec2f(f32(uniforms.width), f32(uniforms.height));
+  if projected.z <= 0. || any(projected.xy < vec2(0.)) || any(projected.xy >= size) {
+    return vec4(0.);
+  }
+  let history = textureLoad(radiance_samples_old, vec2u(projected.xy), 0);
+  // A depth of zero means the texel has never been written.
+  if history.w <= 0. {
+    return vec4(0.);
+  }
+  let history_is_sky = history.w >= SKY_DEPTH;
+  if is_sky || history_is_sky {
+    return vec4(history.xyz, f32(is_sky && history_is_sky));
+  }
+  let accepted = abs(history.w - projected.z) <= DEPTH_TOLERANCE * projected.z;
+  return vec4(history.xyz, f32(accepted));
+}
+
+// Debug visualisations, see `DebugMode` in render.rs. They replace the radiance of every
+// sample and are accumulated and displayed like it.
+const DEBUG_OFF: u32 = 0u;
+const DEBUG_SHADING_NORMALS: u32 = 1u;
+const DEBUG_GEOMETRIC_NORMALS: u32 = 2u;
+const DEBUG_UVS: u32 = 3u;
+const DEBUG_ALBEDO: u32 = 4u;
+const DEBUG_DEPTH: u32 = 5u;
+const DEBUG_NODES_VISITED: u32 = 6u;
+const DEBUG_PRIMITIVE_TESTS: u32 = 7u;
+const DEBUG_PATH_LENGTH: u32 = 8u;
+const DEBUG_NAN_INF: u32 = 9u;
+// Counts at which the heatmaps turn red, as shown by `DebugMode::name` in render.rs.
+const DEBUG_MAX_NODES: f32 = 128.;
+const DEBUG_MAX_TESTS: f32 = 64.;
+const DEBUG_MAX_PATH_LENGTH: f32 = 16.;
+
+// Traversal work since the start of the invocation, for the heatmap modes. Nodes are BVH
+// nodes, the bounding boxes of SDFs, CSG objects and heightfields, CSG operations and
+// heightfield blocks. Tests are primitives and mesh triangles, SDF shapes evaluated per march
+// step, CSG leaves, and the two triangles of every heightfield cell.
+var<private> nodes_visited: u32;
+var<private> primitive_tests: u32;
+
+// Blue through cyan, green and yellow to red as `x` goes from 0 to 1.
+fn heatmap(x: f32) -> vec3f {
+  let t = 4. * clamp(x, 0., 1.);
+  return clamp(vec3(t - 2., min(t, 4. - t), 2. - t), vec3(0.), vec3(1.));
+}
+
+// Non-finite floats have all exponent bits set; NaNs also have a non-zero mantissa.
+fn is_nan(x: f32) -> bool {
+  let bits = bitcast<u32>(x);
+  return (bits & 0x7f800000u) == 0x7f800000u && (bits & 0x007fffffu) != 0u;
+}
+
+fn is_infinite(x: f32) -> bool {
+  return (bitcast<u32>(x) & 0x7fffffffu) == 0x7f800000u;
+}
+
+// The sample shown by the debug mode instead of `radiance`, for the primary `ray` (with a
+// normalized direction) and its `hit`. Colors are picked as displayed and returned as linear
+// values, which the display encodes again.
+fn debug_sample(
+  ray: Ray,
+  hit: Intersection,
+  depth: f32,
+  nodes: u32,
+  tests: u32,
+  path_length: u32,
+  radiance: vec3f,
+) -> vec3f {
+  var color = vec3(0.);
+  switch uniforms.debug_mode {
+    case DEBUG_SHADING_NORMALS, DEBUG_GEOMETRIC_NORMALS, DEBUG_UVS, DEBUG_ALBEDO: {
+      if !is_intersection_valid(hit) {
+        break;
+      }
+      let material = materials[hit.material_index];
+      let p = point_on_ray(ray, hit.t);
+      if uniforms.debug_mode == DEBUG_SHADING_NORMALS {
+        // Shown on the side of the geometric normal, like the geometric normals.
+        let is_front_face = dot(ray.direction, hit.normal) < 0.;
+        let Ng = select(-hit.normal, hit.normal, is_front_face);
+        let N = shading_normal(material, hit, p, Ng, -ray.direction);
+        color = 0.5 * select(-N, N, is_front_face) + 0.5;
+      } else if uniforms.debug_mode == DEBUG_GEOMETRIC_NORMALS {
+        color = 0.5 * hit.normal + 0.5;
+      } else if uniforms.debug_mode == DEBUG_UVS {
+        color = vec3(fract(hit.uv), 0.);
+      } else {
+        color = material_albedo(material, hit.uv, p) * hit.color;
+      }
+    }
+    case DEBUG_DEPTH: {
+      color = vec3(min(0.5 * depth / uniforms.depth_scale, 1.));
+    }
+    case DEBUG_NODES_VISITED: {
+      color = heatmap(f32(nodes) / DEBUG_MAX_NODES);
+    }
+    case DEBUG_PRIMITIVE_TESTS: {
+      color = heatmap(f32(tests) / DEBUG_MAX_TESTS);
+    }
+    case DEBUG_PATH_LENGTH: {
+      color = heatmap(f32(path_length) / DEBUG_MAX_PATH_LENGTH);
+    }
+    case DEBUG_NAN_INF: {
+      // NaNs in magenta, infinities in cyan, over a dim gray version of the image.
+      if is_nan(radiance.x) || is_nan(radiance.y) || is_nan(radiance.z) {
+        color = vec3(1., 0., 1.);
+      } else if is_infinite(radiance.x) || is_infinite(radiance.y) || is_infinite(radiance.z) {
+        color = vec3(0., 1., 1.);
+      } else {
+        let luminance = dot(radiance, vec3(0.2126, 0.7152, 0.0722));
+        color = vec3(0.3 * luminance / (1. + luminance));
+      }
+    }
+    default: {}
+  }
+  return pow(color, vec3(2.2));
+}
+
+// A path traced by the pixel inspector, see inspector.rs. Every iteration of the path loop
+// records one vertex.
+const PATH_SURFACE: u32 = 0u;
+const PATH_MEDIUM: u32 = 1u;
+const PATH_ESCAPED: u32 = 2u;
+
+struct PathVertex {
+  position: vec3f,
+  kind: u32,
+  normal: vec3f,
+  material: u32,
+  direction: vec3f,
+  t: f32,
+  attenuation: vec3f,
+  throughput: vec3f,
+  contribution: vec3f,
+}
+
+struct PathRecord {
+  pixel: vec2u,
+  seed: u32,
+  vertex_count: u32,
+  radiance: vec3f,
+  vertices: array<PathVertex, MAX_PATH_LENGTH>,
+}
+
+fn record_vertex(vertex: PathVertex) {
+  if path_record.vertex_count < MAX_PATH_LENGTH {
+    path_record.vertices[path_record.vertex_count] = vertex;
+    path_record.vertex_count += 1u;
+  }
+}
+
+@fragment fn display_fs(@builtin(position) pos: vec4f) -> @location(0) vec4f {
+  let inspecting = uniforms.inspect != 0u && all(vec2u(pos.xy) == uniforms.inspect_pixel);
+  init_rng(vec2u(pos.xy), select(uniforms.frame_count, uniforms.inspect_seed, inspecting));
+  if inspecting {
+    path_record.vertex_count = 0u;
+  }
+
+  let origin = uniforms.camera.origin;
+  let focus_distance = 1.0;
+  let aspect_ratio = f32(uniforms.width) / f32(uniforms.height);
+  
+  let offset = vec2(rand_f32() - 0.5, rand_f32() - 0.5);
+  var uv = (pos.xy + offset) / vec2f(f32(uniforms.width - 1u), f32(uniforms.height - 1u));
+  uv = (2.0 * uv - vec2(1.0)) * vec2(aspect_ratio, -1.0);    
+  
+  let camera_rotation = mat3x3(uniforms.camera.u, uniforms.camera.v, uniforms.camera.w);
+  let direction = camera_rotation * vec3(uv, focus_distance);
+  let time = mix(uniforms.camera.shutter_open, uniforms.camera.shutter_close, rand_f32());
+  var ray = Ray(origin, direction, time);
+  var throughput = vec3f(1.);
+  var radiance_sample = vec3(0.);
+
+  // World-space position of the primary hit (or the primary direction if the ray escaped)
+  // and its view depth, used by temporal reprojection.
+  var first_hit = direction;
+  var depth = SKY_DEPTH;
+
+  // The primary ray and what it hit, and the traversal work for it, for the debug modes.
+  var primary_ray = ray;
+  var primary_hit = no_intersection();
+  var primary_nodes = 0u;
+  var primary_tests = 0u;
+
+  var path_length = 0u;
+  while path_length < MAX_PATH_LENGTH {
+    ray.direction = normalize(ray.direction);
+    let hit = intersect_scene(ray);
+    if path_length == 0u {
+      primary_ray = ray;
+      primary_hit = hit;
+      primary_nodes = nodes_visited;
+      primary_tests = primitive_tests;
+    }
+    let medium_event = sample_media(ray, select(FLT_MAX, hit.t, is_intersection_valid(hit)));
+    // Radiance added by this iteration, for the inspector.
+    var contribution = throughput * medium_event.emission;
+    radiance_sample += contribution;
+    if medium_event.t < FLT_MAX {
+      let p = point_on_ray(ray, medium_event.t);
+      if path_length == 0u {
+        first_hit = p;
+        depth = dot(first_hit - origin, uniforms.camera.w);
+      }
+      throughput *= medium_event.albedo;
+      ray = Ray(p, sample_henyey_greenstein(ray.direction, medium_event.g), ray.time);
+      if inspecting {
+        record_vertex(PathVertex(
+          p, PATH_MEDIUM, vec3(0.), 0u, ray.direction, medium_event.t, medium_event.albedo, throughput, contribution,
+        ));
+      }
+      path_length += 1u;
+      if all(throughput == vec3(0.)) {
+        break;
+      }
+      continue;
+    }
+    if !is_intersection_valid(hit) {
+      let sky = throughput * sky_color(ray);
+      radiance_sample += sky;
+      if inspecting {
+        record_vertex(PathVertex(
+          ray.origin, PATH_ESCAPED, vec3(0.), 0u, ray.direction, 0., vec3(1.), throughput, contribution + sky,
+        ));
+      }
+      break;
+    }
+    if path_length == 0u {
+      first_hit = point_on_ray(ray, hit.t);
+      depth = dot(first_hit - origin, uniforms.camera.w);
+    }
+
+    let material = materials[hit.material_index];
+    contribution += throughput * material.emission;
+    radiance_sample += throughput * material.emission;
+    let scattered = scatter(ray, hit, material);
+    throughput *= scattered.attenuation;
+    if inspecting {
+      let p = point_on_ray(ray, hit.t);
+      record_vertex(PathVertex(
+        p, PATH_SURFACE, hit.normal, hit.material_index, scattered.ray.direction, hit.t, scattered.attenuation,
+        throughput, contribution,
+      ));
+    }
+    ray = scattered.ray;
+    path_length += 1u;
+    if all(throughput == vec3(0.)) {
+      break;
+    }
+  }
+  
+  if inspecting {
+    path_record.pixel = uniforms.inspect_pixel;
+    path_record.seed = uniforms.inspect_seed;
+    path_record.radiance = radiance_sample;
+  }
+
+  if uniforms.debug_mode != DEBUG_OFF {
+    radiance_sample =
+      debug_sample(primary_ray, primary_hit, depth, primary_nodes, primary_tests, path_length, radiance_sample);
+  }
+
+  // The sample textures hold the running average of the radiance in xyz and the view depth
+  // of the latest primary hit in w.
+  var color: vec3f;
   if uniforms.frame_count > 1 {
-    old_sum = textureLoad(radiance_samples_old, vec2u(pos.xy), 0).xyz;
+    let old_average = textureLoad(radiance_samples_old, vec2u(pos.xy), 0).xyz;
+    color = mix(old_average, radiance_sample, 1. / f32(uniforms.frame_count));
+  } else if uniforms.temporal != 0u {
+    let history = reproject_history(first_hit, depth >= SKY_DEPTH);
+    color = select(radiance_sample, mix(history.xyz, radiance_sample, TEMPORAL_ALPHA), history.w > 0.);
   } else {
-    old_sum = vec3(0.);
+    color = radiance_sample;
   }
 
-  let new_sum = radiance_sample + old_sum;
-  textureStore(radiance_samples_new, vec2u(pos.xy), vec4(new_sum, 0.));
+  textureStore(radiance_samples_new, vec2u(pos.xy), vec4(color, depth));
 
-  let color = new_sum / f32(uniforms.frame_count);
   return vec4(pow(color, vec3(1. / 2.2)), 1.);
 }
\ No newline at end of file
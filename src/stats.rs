@@ -0,0 +1,301 @@
+// stats.rs
+// `--stats`: loads a scene, builds its BVHs and reports what the renderer will have to
+// traverse: primitive counts, and for every BVH its node count, depth histogram, SAH cost,
+// leaf sizes and build time, then the size of every GPU buffer. With `--json` the report is
+// printed as one JSON object instead, for dashboards.
+//
+// The sphere, shape and instance BVHs are built here the same way the renderer builds them.
+// The mesh BLAS are reported as stored in the scene; their build time comes from building
+// them again over the stored triangles.
+use crate::{
+    bvh::{self, Aabb, Bvh, BvhNode},
+    json,
+    scene::{Scene, Shape, Sphere},
+};
+use {
+    anyhow::Result,
+    std::{
+        collections::BTreeMap,
+        fmt::Write,
+        time::{Duration, Instant},
+    },
+};
+
+struct TreeStats {
+    name: String,
+    primitives: usize,
+    nodes: usize,
+    leaves: usize,
+    // Number of leaves at depth i + 1, the root being at depth 1.
+    depth_histogram: Vec<usize>,
+    // Number of leaves of every size.
+    leaf_sizes: BTreeMap<u32, usize>,
+    sah_cost: f32,
+    build_time: Duration,
+}
+
+impl TreeStats {
+    fn new(name: String, nodes: &[BvhNode], root: usize, primitives: usize, build_time: Duration) -> TreeStats {
+        let mut stats = TreeStats {
+            name,
+            primitives,
+            nodes: nodes.len(),
+            leaves: 0,
+            depth_histogram: Vec::new(),
+            leaf_sizes: BTreeMap::new(),
+            sah_cost: 0.,
+            build_time,
+        };
+        if primitives == 0 {
+            return stats;
+        }
+        let mut pending = vec![(root, 1)];
+        while let Some((index, depth)) = pending.pop() {
+            let node = &nodes[index];
+            if node.is_leaf() {
+                stats.leaves += 1;
+                if stats.depth_histogram.len() < depth {
+                    stats.depth_histogram.resize(depth, 0);
+                }
+                stats.depth_histogram[depth - 1] += 1;
+                *stats.leaf_sizes.entry(node.count).or_default() += 1;
+            } else {
+                let first = node.left_first as usize;
+                pending.extend([(first, depth + 1), (first + 1, depth + 1)]);
+            }
+        }
+        stats.sah_cost = bvh::sah_cost(nodes, root);
+        stats
+    }
+
+    fn depth(&self) -> usize {
+        self.depth_histogram.len()
+    }
+}
+
+struct Report {
+    scene: String,
+    load_time: Duration,
+    primitives: Vec<(&'static str, usize)>,
+    trees: Vec<TreeStats>,
+    // Bytes of every scene buffer and texture as uploaded by render.rs.
+    buffers: Vec<(&'static str, usize)>,
+}
+
+pub fn run(scene_name: &str, json: bool) -> Result<()> {
+    let start = Instant::now();
+    let scene = crate::load_scene(scene_name)?;
+    let load_time = start.elapsed();
+    let trees = tree_stats(&scene);
+    let report = Report {
+        scene: scene_name.to_string(),
+        load_time,
+        primitives: primitive_counts(&scene),
+        buffers: buffer_sizes(&scene, &trees),
+        trees,
+    };
+    print!("{}", if json { report.json() } else { report.text() });
+    Ok(())
+}
+
+fn primitive_counts(scene: &Scene) -> Vec<(&'static str, usize)> {
+    vec![
+        ("spheres", scene.spheres.len()),
+        ("quads", scene.quads.len()),
+        ("shapes", scene.shapes.len()),
+        ("sdf objects", scene.sdf_objects.len()),
+        ("csg objects", scene.csg_objects.len()),
+        ("heightfields", scene.heightfields.len()),
+        ("meshes", scene.geometries.len()),
+        ("instances", scene.instances.len()),
+        ("triangles", scene.triangles.len()),
+        ("vertices", scene.vertices.len()),
+        ("materials", scene.materials.len()),
+        ("textures", scene.textures.len()),
+        ("images", scene.images.len()),
+        ("media", scene.media.len()),
+        ("volumes", scene.volumes.len()),
+    ]
+}
+
+fn tree_stats(scene: &Scene) -> Vec<TreeStats> {
+    let built = |name: &str, bounds: Vec<Aabb>| {
+        let start = Instant::now();
+        let bvh = Bvh::build(&bounds);
+        TreeStats::new(name.to_string(), &bvh.nodes, 0, bounds.len(), start.elapsed())
+    };
+    let mut trees = vec![
+        built("spheres", scene.spheres.iter().map(Sphere::bounds).collect()),
+        built("shapes", scene.shapes.iter().map(Shape::bounds).collect()),
+        built("instances", scene.instances.iter().map(|i| scene.instance_bounds(i)).collect()),
+    ];
+    // The BLAS of every mesh follow each other in `blas_nodes`.
+    for (i, geometry) in scene.geometries.iter().enumerate() {
+        let end = scene.geometries.get(i + 1).map_or(scene.blas_nodes.len(), |next| next.root as usize);
+        let first = geometry.first_triangle as usize;
+        let bounds: Vec<Aabb> = (first..first + geometry.triangle_count as usize)
+            .map(|t| scene.triangle_bounds(t))
+            .collect();
+        let start = Instant::now();
+        Bvh::build(&bounds);
+        let build_time = start.elapsed();
+        // Child indices are absolute, so the slice starts at the beginning of the buffer.
+        let nodes = &scene.blas_nodes[..end];
+        let mut stats = TreeStats::new(format!("mesh {i}"), nodes, geometry.root as usize, bounds.len(), build_time);
+        stats.nodes = end - geometry.root as usize;
+        trees.push(stats);
+    }
+    trees
+}
+
+// Storage buffers hold at least one element, see `create_storage_buffer` in render.rs.
+fn buffer_size<T>(data: &[T]) -> usize {
+    data.len().max(1) * size_of::<T>()
+}
+
+// `trees` starts with the sphere, shape and instance BVHs, see `tree_stats`.
+fn buffer_sizes(scene: &Scene, trees: &[TreeStats]) -> Vec<(&'static str, usize)> {
+    let nodes = |tree: &TreeStats| tree.nodes * size_of::<BvhNode>();
+    // Image layers are scaled to the largest image, grids padded to the largest in x and y.
+    let image_size = |f: fn(&image::RgbaImage) -> u32| scene.images.iter().map(f).max().unwrap_or(1) as usize;
+    let images = image_size(|i| i.width()) * image_size(|i| i.height()) * scene.images.len().max(1) * 4;
+    let grid_size = |axis: usize| scene.grids.iter().map(|g| g.size[axis]).max().unwrap_or(1) as usize;
+    let depth = scene.grids.iter().map(|g| g.size[2] as usize).sum::<usize>().max(1);
+    let atlas = grid_size(0) * grid_size(1) * depth * size_of::<[f32; 2]>();
+    vec![
+        ("spheres", buffer_size(&scene.spheres)),
+        ("sphere nodes", nodes(&trees[0])),
+        ("materials", buffer_size(&scene.materials)),
+        ("textures", buffer_size(&scene.textures)),
+        ("quads", buffer_size(&scene.quads)),
+        ("shapes", buffer_size(&scene.shapes)),
+        ("shape nodes", nodes(&trees[1])),
+        ("sdf objects", buffer_size(&scene.sdf_objects)),
+        ("sdf shapes", buffer_size(&scene.sdf_shapes)),
+        ("csg objects", buffer_size(&scene.csg_objects)),
+        ("csg nodes", buffer_size(&scene.csg_nodes)),
+        ("heightfields", buffer_size(&scene.heightfields)),
+        ("heightfield data", buffer_size(&scene.heightfield_data)),
+        ("vertices", buffer_size(&scene.vertices)),
+        ("triangles", buffer_size(&scene.triangles)),
+        ("blas nodes", buffer_size(&scene.blas_nodes)),
+        ("wide blas nodes", buffer_size(&scene.wide_blas_nodes)),
+        ("tlas nodes", nodes(&trees[2])),
+        ("instances", buffer_size(&scene.instances)),
+        ("media", buffer_size(&scene.media)),
+        ("volumes", buffer_size(&scene.volumes)),
+        ("image textures", images),
+        ("volume atlas", atlas),
+    ]
+}
+
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.),
+        _ => format!("{:.1} MiB", bytes as f64 / (1024. * 1024.)),
+    }
+}
+
+impl Report {
+    fn total_bytes(&self) -> usize {
+        self.buffers.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    fn text(&self) -> String {
+        let mut out = String::new();
+        let ms = |d: Duration| d.as_secs_f64() * 1e3;
+        writeln!(out, "{}: loaded in {:.1} ms", self.scene, ms(self.load_time)).unwrap();
+        writeln!(out, "primitives").unwrap();
+        for (name, count) in self.primitives.iter().filter(|(_, count)| *count > 0) {
+            writeln!(out, "  {name:<18} {count:>10}").unwrap();
+        }
+        writeln!(
+            out,
+            "bvhs                 primitives      nodes     leaves  depth   sah cost   build ms"
+        )
+        .unwrap();
+        for tree in self.trees.iter().filter(|tree| tree.primitives > 0) {
+            writeln!(
+                out,
+                "  {:<18} {:>10} {:>10} {:>10} {:>6} {:>10.2} {:>10.2}",
+                tree.name,
+                tree.primitives,
+                tree.nodes,
+                tree.leaves,
+                tree.depth(),
+                tree.sah_cost,
+                ms(tree.build_time)
+            )
+            .unwrap();
+            let depths: Vec<String> = tree.depth_histogram.iter().map(usize::to_string).collect();
+            writeln!(out, "    leaves per depth: {}", depths.join(" ")).unwrap();
+            let sizes: Vec<String> = tree.leaf_sizes.iter().map(|(size, count)| format!("{size}:{count}")).collect();
+            writeln!(out, "    leaf sizes (size:leaves): {}", sizes.join(" ")).unwrap();
+        }
+        writeln!(out, "gpu memory").unwrap();
+        for (name, bytes) in &self.buffers {
+            writeln!(out, "  {name:<18} {:>10}", format_bytes(*bytes)).unwrap();
+        }
+        writeln!(out, "  {:<18} {:>10}", "total", format_bytes(self.total_bytes())).unwrap();
+        out
+    }
+
+    fn json(&self) -> String {
+        let object = |entries: &[(&str, usize)], indent: &str| {
+            let fields: Vec<String> =
+                entries.iter().map(|(name, value)| format!("{indent}  \"{name}\": {value}")).collect();
+            format!("{{\n{}\n{indent}}}", fields.join(",\n"))
+        };
+        let trees: Vec<String> = self
+            .trees
+            .iter()
+            .map(|tree| {
+                let depths: Vec<String> = tree.depth_histogram.iter().map(usize::to_string).collect();
+                let sizes: Vec<String> =
+                    tree.leaf_sizes.iter().map(|(size, count)| format!("\"{size}\": {count}")).collect();
+                format!(
+                    r#"    {{
+      "name": {},
+      "primitives": {},
+      "nodes": {},
+      "leaves": {},
+      "depth": {},
+      "depth_histogram": [{}],
+      "leaf_sizes": {{{}}},
+      "sah_cost": {},
+      "build_seconds": {}
+    }}"#,
+                    json::string(&tree.name),
+                    tree.primitives,
+                    tree.nodes,
+                    tree.leaves,
+                    tree.depth(),
+                    depths.join(", "),
+                    sizes.join(", "),
+                    json::number(tree.sah_cost),
+                    json::number(tree.build_time.as_secs_f64()),
+                )
+            })
+            .collect();
+        format!(
+            r#"{{
+  "scene": {},
+  "load_seconds": {},
+  "primitives": {},
+  "bvhs": [
+{}
+  ],
+  "buffer_bytes": {},
+  "total_bytes": {}
+}}
+"#,
+            json::string(&self.scene),
+            json::number(self.load_time.as_secs_f64()),
+            object(&self.primitives, "  "),
+            trees.join(",\n"),
+            object(&self.buffers, "  "),
+            self.total_bytes(),
+        )
+    }
+}
@@ -0,0 +1,87 @@
+// volume.rs
+// Dense voxel grids for heterogeneous volumes, read from Mitsuba's `.vol` format:
+//
+//   bytes 0..3   "VOL"
+//   byte  3      version (3)
+//   i32          encoding (1 = float32)
+//   i32 x 3      resolution in x, y and z
+//   i32          channels per voxel
+//   f32 x 6      bounding box: min x, y, z, max x, y, z
+//   f32 ...      voxels, x fastest, then y, then z, channels interleaved
+//
+// All values are little-endian. The first channel is the density; a second channel, if
+// present, is the emission (e.g. temperature mapped to brightness, for fire).
+use crate::algebra::Vec3;
+use {
+    anyhow::{Context, Result, bail},
+    std::{fs, path::Path},
+};
+
+const HEADER_SIZE: usize = 48;
+
+pub struct DensityGrid {
+    pub size: [u32; 3],
+    // Density and emission of every voxel, x fastest.
+    pub voxels: Vec<[f32; 2]>,
+    // Bounding box stored in the file.
+    pub bounds: (Vec3, Vec3),
+}
+
+impl DensityGrid {
+    pub fn load(path: &Path) -> Result<DensityGrid> {
+        let bytes =
+            fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    fn parse(bytes: &[u8]) -> Result<DensityGrid> {
+        if bytes.len() < HEADER_SIZE || &bytes[..3] != b"VOL" {
+            bail!("not a .vol file");
+        }
+        if bytes[3] != 3 {
+            bail!("unsupported .vol version {}", bytes[3]);
+        }
+        let word = |i: usize| <[u8; 4]>::try_from(&bytes[4 + 4 * i..8 + 4 * i]).unwrap();
+        let int = |i| i32::from_le_bytes(word(i));
+        let float = |i| f32::from_le_bytes(word(i));
+        if int(0) != 1 {
+            bail!("unsupported encoding {}, only float32 (1) is supported", int(0));
+        }
+        let (nx, ny, nz, channels) = (int(1), int(2), int(3), int(4));
+        if nx <= 0 || ny <= 0 || nz <= 0 || channels <= 0 {
+            bail!("invalid resolution {nx}x{ny}x{nz} with {channels} channels");
+        }
+        let bounds = (
+            Vec3::new(float(5), float(6), float(7)),
+            Vec3::new(float(8), float(9), float(10)),
+        );
+
+        let (voxel_count, channels) = (nx as usize * ny as usize * nz as usize, channels as usize);
+        let data = &bytes[HEADER_SIZE..];
+        if data.len() < voxel_count * channels * 4 {
+            bail!(
+                "expected {} bytes of voxel data, found {}",
+                voxel_count * channels * 4,
+                data.len()
+            );
+        }
+        let value = |i: usize| f32::from_le_bytes(data[4 * i..4 * i + 4].try_into().unwrap());
+        let voxels = (0..voxel_count)
+            .map(|v| {
+                let density = value(v * channels).max(0.);
+                let emission = if channels > 1 { value(v * channels + 1).max(0.) } else { 0. };
+                [density, emission]
+            })
+            .collect();
+        Ok(DensityGrid {
+            size: [nx as u32, ny as u32, nz as u32],
+            voxels,
+            bounds,
+        })
+    }
+
+    // Largest density in the grid, the majorant for delta tracking.
+    pub fn max_density(&self) -> f32 {
+        self.voxels.iter().map(|v| v[0]).fold(0., f32::max)
+    }
+}
@@ -0,0 +1,342 @@
+// wide_bvh.rs
+// 4-wide BVHs with quantized child bounds, collapsed from the binary BVHs of bvh.rs (after
+// Ylitie et al., "Efficient Incoherent Ray Traversal on GPUs Through Compressed Wide BVHs",
+// 2017, with 4 children instead of 8). A node stores the boxes of its children on a grid of
+// 255 power-of-two cells per axis over its own box, one byte per bound, so that 64 bytes
+// describe 4 children where the binary layout needs 32 bytes per child. Traversal fetches
+// fewer and smaller nodes, which matters once large meshes no longer fit in the caches.
+//
+// Only the mesh BLAS are collapsed; `intersect_wide_blas` in shaders.wgsl traverses them when
+// the wide layout is switched on.
+use crate::{
+    algebra::Vec3,
+    bvh::{Aabb, BvhNode},
+};
+use bytemuck::{Pod, Zeroable};
+
+pub const WIDTH: usize = 4;
+// Entries of the traversal stack of `intersect_wide_blas`, WIDE_STACK_SIZE in shaders.wgsl.
+// Trees that need more are traced in the binary layout, see `stack_entries`.
+pub const STACK_SIZE: u32 = 64;
+// `Geometry::wide_root` of a BLAS that has no wide version. Must match NO_WIDE_BLAS in
+// shaders.wgsl.
+pub const NO_WIDE_BLAS: u32 = u32::MAX;
+// Leaf sizes must fit in a byte. Larger binary leaves are split into several wide leaves.
+const MAX_LEAF_COUNT: u32 = 255;
+const GRID_CELLS: f32 = 255.;
+
+// A child is a leaf if its byte in `counts` is non-zero, in which case it covers that many
+// primitives starting at `children[i]`. Otherwise `children[i]` is the index of its node.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct WideNode {
+    // Corner of the grid the child boxes are quantized to.
+    origin: Vec3,
+    // Biased exponents (127 + e) of the cell sizes 2^e along x, y and z in the low three
+    // bytes, the number of children in the high byte.
+    exponents: u32,
+    // Lower x, y and z, then upper x, y and z grid coordinates of child i in byte i.
+    bounds: [u32; 6],
+    children: [u32; WIDTH],
+    counts: u32,
+    _pad: u32,
+}
+
+impl WideNode {
+    // The node with its child indices offset by `node_base` and its leaves by
+    // `primitive_base`, for storing several trees in one buffer.
+    pub fn offset(&self, node_base: u32, primitive_base: u32) -> WideNode {
+        let mut node = *self;
+        for (i, child) in node.children.iter_mut().enumerate() {
+            *child += if self.count(i) > 0 { primitive_base } else { node_base };
+        }
+        node
+    }
+
+    fn count(&self, child: usize) -> u32 {
+        (self.counts >> (8 * child)) & 0xff
+    }
+
+    fn child_count(&self) -> usize {
+        (self.exponents >> 24) as usize
+    }
+}
+
+// A part of the binary tree that becomes one child of a wide node: a binary node, or a run of
+// primitives split off a leaf that is too large, with the bounds of that leaf.
+#[derive(Copy, Clone)]
+enum Subtree {
+    Node(usize),
+    Range { first: u32, count: u32, bounds: Aabb },
+}
+
+impl Subtree {
+    // Binary leaves become ranges.
+    fn new(nodes: &[BvhNode], index: usize) -> Subtree {
+        let node = &nodes[index];
+        if node.is_leaf() {
+            Subtree::Range { first: node.left_first, count: node.count, bounds: node.bounds() }
+        } else {
+            Subtree::Node(index)
+        }
+    }
+
+    fn bounds(&self, nodes: &[BvhNode]) -> Aabb {
+        match self {
+            Subtree::Node(index) => nodes[*index].bounds(),
+            Subtree::Range { bounds, .. } => *bounds,
+        }
+    }
+
+    // The subtrees the children of a wide node over this one are picked from.
+    fn split(&self, nodes: &[BvhNode]) -> Vec<Subtree> {
+        match *self {
+            Subtree::Node(index) => {
+                let first = nodes[index].left_first as usize;
+                vec![Subtree::new(nodes, first), Subtree::new(nodes, first + 1)]
+            }
+            Subtree::Range { first, count, bounds } => {
+                let chunk = count.div_ceil(WIDTH as u32);
+                (first..first + count)
+                    .step_by(chunk as usize)
+                    .map(|start| Subtree::Range { first: start, count: chunk.min(first + count - start), bounds })
+                    .collect()
+            }
+        }
+    }
+}
+
+// Collapses a binary BVH into a wide one with the root at index 0. Child indices are relative
+// to the returned nodes, leaves index the primitive order of the binary tree.
+pub fn collapse(nodes: &[BvhNode]) -> Vec<WideNode> {
+    let mut wide = vec![WideNode::zeroed()];
+    // Without primitives, the root has no children.
+    let root = &nodes[0];
+    if !root.is_leaf() && root.bounds().is_empty() {
+        return wide;
+    }
+    let root = Subtree::new(nodes, 0);
+    let mut pending = vec![(0, match root {
+        // A root small enough to be a single leaf is the only child of the wide root.
+        Subtree::Range { count, .. } if count <= MAX_LEAF_COUNT => vec![root],
+        _ => root.split(nodes),
+    })];
+    while let Some((index, mut children)) = pending.pop() {
+        // Open the interior child with the largest surface until the node is full.
+        while children.len() < WIDTH {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| matches!(child, Subtree::Node(_)))
+                .max_by(|(_, a), (_, b)| a.bounds(nodes).half_area().total_cmp(&b.bounds(nodes).half_area()));
+            let Some((i, _)) = largest else {
+                break;
+            };
+            let opened = children.swap_remove(i);
+            children.extend(opened.split(nodes));
+        }
+
+        let boxes: Vec<Aabb> = children.iter().map(|child| child.bounds(nodes)).collect();
+        let mut node = quantize(&boxes);
+        for (i, child) in children.iter().enumerate() {
+            match *child {
+                Subtree::Range { first, count, .. } if count <= MAX_LEAF_COUNT => {
+                    node.children[i] = first;
+                    node.counts |= count << (8 * i);
+                }
+                _ => {
+                    node.children[i] = wide.len() as u32;
+                    wide.push(WideNode::zeroed());
+                    pending.push((node.children[i] as usize, child.split(nodes)));
+                }
+            }
+        }
+        wide[index] = node;
+    }
+    wide
+}
+
+// The most entries the traversal stack of the shader can hold for the tree `collapse`
+// returned. A node pushes its k interior children and then pops one, so while it traverses
+// that child the other k - 1 wait below it; any of them may be the nearest and go first.
+pub fn stack_entries(wide: &[WideNode]) -> u32 {
+    // Children always come after their parent, so a backward pass sees them first.
+    let mut entries = vec![0; wide.len()];
+    for (index, node) in wide.iter().enumerate().rev() {
+        let interior: Vec<usize> =
+            (0..node.child_count()).filter(|&i| node.count(i) == 0).map(|i| node.children[i] as usize).collect();
+        let k = interior.len() as u32;
+        entries[index] = interior.iter().map(|&child| k - 1 + entries[child]).fold(k, u32::max);
+    }
+    entries.first().copied().unwrap_or(0)
+}
+
+// A node without child references, with `boxes` on a grid over their union. Every quantized
+// box contains its original, also after the shader decodes it as `origin + q * 2^e` in f32.
+fn quantize(boxes: &[Aabb]) -> WideNode {
+    let parent = boxes.iter().fold(Aabb::empty(), |a, b| a.union(b));
+    let mut node = WideNode::zeroed();
+    node.origin = parent.min;
+    node.exponents = (boxes.len() as u32) << 24;
+    for axis in 0..3 {
+        let origin = parent.min[axis];
+        let extent = parent.max[axis] - origin;
+        // The smallest cell size that covers the box with GRID_CELLS cells.
+        let mut exponent = ((extent / GRID_CELLS).log2().ceil() as i32).clamp(-126, 127);
+        while exponent < 127 && origin + GRID_CELLS * exp2i(exponent) < parent.max[axis] {
+            exponent += 1;
+        }
+        let cell = exp2i(exponent);
+        node.exponents |= ((exponent + 127) as u32) << (8 * axis);
+        for (i, aabb) in boxes.iter().enumerate() {
+            let mut lo = ((aabb.min[axis] - origin) / cell).floor().clamp(0., GRID_CELLS);
+            while lo > 0. && origin + lo * cell > aabb.min[axis] {
+                lo -= 1.;
+            }
+            let mut hi = ((aabb.max[axis] - origin) / cell).ceil().clamp(0., GRID_CELLS);
+            while hi < GRID_CELLS && origin + hi * cell < aabb.max[axis] {
+                hi += 1.;
+            }
+            node.bounds[axis] |= (lo as u32) << (8 * i);
+            node.bounds[axis + 3] |= (hi as u32) << (8 * i);
+        }
+    }
+    node
+}
+
+fn exp2i(exponent: i32) -> f32 {
+    f32::from_bits(((exponent + 127) as u32) << 23)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            bvh::Bvh,
+            lbvh::{self, Rng},
+        },
+    };
+
+    // Triangles of size up to `size` scattered over a box of `spread` at `offset`, as their
+    // bounds.
+    fn random_mesh(rng: &mut Rng, count: usize, offset: Vec3, spread: Vec3, size: f32) -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let p = rng.vec3();
+                let a = offset + Vec3::new(p.x() * spread.x(), p.y() * spread.y(), p.z() * spread.z());
+                let corners = [a, a + size * rng.vec3(), a + size * rng.vec3()];
+                Aabb::from_points(&corners)
+            })
+            .collect()
+    }
+
+    // The box of child `i` as the shader decodes it.
+    fn decode(node: &WideNode, i: usize) -> Aabb {
+        let corner = |bounds: &[u32]| {
+            let v: Vec<f32> = (0..3)
+                .map(|axis| {
+                    let q = (bounds[axis] >> (8 * i)) & 0xff;
+                    let exponent = ((node.exponents >> (8 * axis)) & 0xff) as i32 - 127;
+                    node.origin[axis] + q as f32 * exp2i(exponent)
+                })
+                .collect();
+            Vec3::new(v[0], v[1], v[2])
+        };
+        Aabb { min: corner(&node.bounds[..3]), max: corner(&node.bounds[3..]) }
+    }
+
+    // Checks that every decoded child box contains the primitives under it and returns their
+    // bounds, adding the primitives to `seen`.
+    fn check(wide: &[WideNode], index: usize, order: &[u32], bounds: &[Aabb], seen: &mut [bool]) -> Aabb {
+        let node = &wide[index];
+        let mut union = Aabb::empty();
+        for i in 0..node.child_count() {
+            let child = node.children[i] as usize;
+            let covered = match node.count(i) {
+                0 => check(wide, child, order, bounds, seen),
+                count => order[child..child + count as usize].iter().fold(Aabb::empty(), |aabb, &p| {
+                    assert!(!seen[p as usize], "primitive {p} is in two leaves");
+                    seen[p as usize] = true;
+                    aabb.union(&bounds[p as usize])
+                }),
+            };
+            let decoded = decode(node, i);
+            assert!(decoded.contains(&covered), "node {index} child {i}: {decoded:?} misses {covered:?}");
+            union = union.union(&covered);
+        }
+        union
+    }
+
+    #[test]
+    fn quantized_boxes_contain_their_primitives() {
+        let mut rng = Rng(0x5bd1e995);
+        let meshes = [
+            random_mesh(&mut rng, 2000, Vec3::zero(), Vec3::all(1.), 0.05),
+            random_mesh(&mut rng, 2000, Vec3::new(1e4, -3e3, 7.), Vec3::all(2.), 0.01),
+            random_mesh(&mut rng, 2000, Vec3::all(-1e-3), Vec3::all(2e-3), 1e-5),
+            random_mesh(&mut rng, 2000, Vec3::new(-5., 0., -5.), Vec3::new(10., 0., 10.), 0.),
+            random_mesh(&mut rng, 2000, Vec3::new(0., 0., 100.), Vec3::new(1000., 0.01, 0.01), 0.001),
+            // Identical triangles end in one binary leaf that is split into several wide ones.
+            vec![Aabb { min: Vec3::all(1.), max: Vec3::all(2.) }; 3000],
+            random_mesh(&mut rng, 1, Vec3::zero(), Vec3::all(1.), 0.5),
+        ];
+        for bounds in meshes {
+            // The viewer collapses the LBVHs of scene files as well.
+            for bvh in [Bvh::build(&bounds), lbvh::build(&bounds)] {
+                let wide = collapse(&bvh.nodes);
+                let mut seen = vec![false; bounds.len()];
+                check(&wide, 0, &bvh.order, &bounds, &mut seen);
+                assert!(seen.iter().all(|&s| s), "a primitive is missing from the leaves");
+                assert!(stack_entries(&wide) <= STACK_SIZE);
+            }
+        }
+        assert_eq!(collapse(&Bvh::build(&[]).nodes)[0].child_count(), 0);
+    }
+
+    // A binary tree whose spine node i has a small interior node with two leaves and spine
+    // node i + 1 as children, `depth` levels deep. Each wide node over it has four interior
+    // children, three small ones and the rest of the spine.
+    fn caterpillar(depth: usize) -> Vec<BvhNode> {
+        let node = |size: f32, left_first: usize, count: u32| BvhNode {
+            min: Vec3::zero(),
+            left_first: left_first as u32,
+            max: Vec3::all(size),
+            count,
+        };
+        let mut nodes = vec![node(depth as f32 + 1., 0, 0)];
+        let (mut spine, mut primitive) = (0, 0);
+        for level in 0..depth {
+            let first = nodes.len();
+            nodes[spine].left_first = first as u32;
+            nodes.extend([
+                node(0.5, first + 2, 0),
+                node((depth - level) as f32, 0, 0),
+                node(0.25, primitive, 1),
+                node(0.25, primitive + 1, 1),
+            ]);
+            (spine, primitive) = (first + 1, primitive + 2);
+        }
+        nodes[spine].left_first = primitive as u32;
+        nodes[spine].count = 1;
+        nodes
+    }
+
+    #[test]
+    fn stack_entries_grow_with_the_waiting_siblings() {
+        // One wide node over three spine levels and their leaves: 3 small nodes and a leaf.
+        assert_eq!(stack_entries(&collapse(&caterpillar(3))), 3);
+        // Every further wide level adds three siblings waiting on the stack.
+        assert_eq!(stack_entries(&collapse(&caterpillar(6))), 3 + 3);
+        assert_eq!(stack_entries(&collapse(&caterpillar(60))), 3 * 20);
+        assert!(stack_entries(&collapse(&caterpillar(66))) > STACK_SIZE);
+    }
+
+    #[test]
+    fn constants_match_the_shader() {
+        let shader = include_str!("shaders.wgsl");
+        assert!(shader.contains(&format!("const WIDE_STACK_SIZE: u32 = {STACK_SIZE}u;")));
+        assert!(shader.contains(&format!("const NO_WIDE_BLAS: u32 = {NO_WIDE_BLAS:#x}u;")));
+    }
+}